@@ -0,0 +1,210 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::config::{AlertEvent, AlertingConfig};
+
+/// Webhook deliveries waiting for the background sender
+const ALERT_QUEUE_SIZE: usize = 64;
+/// Per-request timeout for the webhook POST
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Alerting hook (alerting.webhook_url)
+///
+/// Events are debounced per (event, subject) and POSTed as JSON by a background
+/// task, so a slow or dead webhook never holds up the caller.
+pub struct Alerter {
+    config: AlertingConfig,
+    tx: Option<mpsc::Sender<serde_json::Value>>,
+    last_sent: Mutex<HashMap<(AlertEvent, String), Instant>>,
+    sent: AtomicU64,
+    debounced: AtomicU64,
+    failed: Arc<AtomicU64>,
+}
+
+impl Alerter {
+    pub fn new(config: &AlertingConfig) -> Self {
+        let failed = Arc::new(AtomicU64::new(0));
+        let tx = config.webhook_url.clone().map(|url| {
+            info!("🔔 Alerting enabled ({} event types, debounce {}s)", config.events.len(), config.debounce_secs);
+            let (tx, rx) = mpsc::channel(ALERT_QUEUE_SIZE);
+            tokio::spawn(deliver(url, rx, failed.clone()));
+            tx
+        });
+        Self {
+            config: config.clone(),
+            tx,
+            last_sent: Mutex::new(HashMap::new()),
+            sent: AtomicU64::new(0),
+            debounced: AtomicU64::new(0),
+            failed,
+        }
+    }
+
+    /// Queue a webhook for `event` about `subject` (an upstream name, "recursion"...).
+    /// Returns false when alerting is off for this event or it was debounced.
+    pub fn notify(&self, event: AlertEvent, subject: &str, message: &str, details: serde_json::Value) -> bool {
+        let Some(tx) = &self.tx else { return false };
+        if !self.config.events.contains(&event) {
+            return false;
+        }
+        {
+            let mut last_sent = self.last_sent.lock();
+            let key = (event, subject.to_string());
+            let debounce = Duration::from_secs(self.config.debounce_secs);
+            if last_sent.get(&key).is_some_and(|at| at.elapsed() < debounce) {
+                self.debounced.fetch_add(1, Ordering::Relaxed);
+                debug!("🔔 Alert {:?} for {} debounced", event, subject);
+                return false;
+            }
+            last_sent.insert(key, Instant::now());
+        }
+
+        let text = format!("neko-dns: {}", message);
+        let payload = serde_json::json!({
+            "event": event,
+            "subject": subject,
+            "message": message,
+            "details": details,
+            "timestamp": Utc::now().to_rfc3339(),
+            // Slack and Discord incoming webhooks render these
+            "text": text,
+            "content": text,
+        });
+        if tx.try_send(payload).is_err() {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+            warn!("🔔 Alert queue full, dropping {:?} for {}", event, subject);
+            return false;
+        }
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.config.window_secs.max(1))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub fn config(&self) -> &AlertingConfig {
+        &self.config
+    }
+
+    pub fn get_stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "enabled": self.tx.is_some(),
+            "events": self.config.events,
+            "sent": self.sent.load(Ordering::Relaxed),
+            "debounced": self.debounced.load(Ordering::Relaxed),
+            "failed": self.failed.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Counter snapshot taken by the alert monitor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateSample {
+    pub queries: u64,
+    pub servfails: u64,
+    pub recursions: u64,
+    pub recursion_failures: u64,
+}
+
+/// Sliding window over counter snapshots: `push` returns how much each
+/// counter grew over the last `window`
+pub struct SlidingWindow {
+    window: Duration,
+    samples: VecDeque<(Instant, RateSample)>,
+}
+
+impl SlidingWindow {
+    pub fn new(window: Duration) -> Self {
+        Self { window, samples: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, sample: RateSample) -> RateSample {
+        self.push_at(Instant::now(), sample)
+    }
+
+    fn push_at(&mut self, now: Instant, sample: RateSample) -> RateSample {
+        self.samples.push_back((now, sample));
+        while self.samples.front().is_some_and(|(at, _)| now.duration_since(*at) > self.window) {
+            self.samples.pop_front();
+        }
+        let oldest = self.samples.front().map(|(_, s)| *s).unwrap_or(sample);
+        RateSample {
+            queries: sample.queries.saturating_sub(oldest.queries),
+            servfails: sample.servfails.saturating_sub(oldest.servfails),
+            recursions: sample.recursions.saturating_sub(oldest.recursions),
+            recursion_failures: sample.recursion_failures.saturating_sub(oldest.recursion_failures),
+        }
+    }
+}
+
+/// Background sender: POST each queued payload to the webhook
+async fn deliver(url: String, mut rx: mpsc::Receiver<serde_json::Value>, failed: Arc<AtomicU64>) {
+    let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("🔔 Alerting disabled, HTTP client init failed: {}", e);
+            return;
+        }
+    };
+    while let Some(payload) = rx.recv().await {
+        match client.post(&url).json(&payload).send().await {
+            Ok(resp) if resp.status().is_success() => debug!("🔔 Alert delivered: {}", payload["event"]),
+            Ok(resp) => {
+                failed.fetch_add(1, Ordering::Relaxed);
+                warn!("🔔 Webhook answered {} for {}", resp.status(), payload["event"]);
+            }
+            Err(e) => {
+                failed.fetch_add(1, Ordering::Relaxed);
+                warn!("🔔 Webhook delivery failed: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_debounced_per_event_and_subject() {
+        let config: AlertingConfig = toml::from_str(
+            "webhook_url = \"http://127.0.0.1:9/hook\"\nevents = [\"upstream_disabled\", \"high_servfail_rate\"]",
+        ).unwrap();
+        let alerter = Alerter::new(&config);
+        let details = serde_json::json!({});
+
+        assert!(alerter.notify(AlertEvent::UpstreamDisabled, "a", "a disabled", details.clone()));
+        assert!(!alerter.notify(AlertEvent::UpstreamDisabled, "a", "a disabled", details.clone()));
+        assert!(alerter.notify(AlertEvent::UpstreamDisabled, "b", "b disabled", details.clone()));
+        assert!(alerter.notify(AlertEvent::HighServfailRate, "a", "servfails", details.clone()));
+        // Not in alerting.events
+        assert!(!alerter.notify(AlertEvent::RecursionCircuitOpen, "recursion", "failing", details));
+        assert_eq!(alerter.get_stats()["sent"], 3);
+        assert_eq!(alerter.get_stats()["debounced"], 1);
+    }
+
+    #[test]
+    fn test_sliding_window_drops_old_samples() {
+        let mut window = SlidingWindow::new(Duration::from_secs(60));
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+        let sample = |queries, servfails| RateSample { queries, servfails, ..Default::default() };
+
+        assert_eq!(window.push_at(at(0), sample(100, 0)), RateSample::default());
+        assert_eq!(window.push_at(at(30), sample(150, 20)), sample(50, 20));
+        assert_eq!(window.push_at(at(60), sample(200, 20)), sample(100, 20));
+        // t=0 has left the window, t=30 is now the oldest
+        assert_eq!(window.push_at(at(61), sample(210, 20)), sample(60, 0));
+    }
+}
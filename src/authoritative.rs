@@ -0,0 +1,607 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::config::AuthoritativeZoneConfig;
+use crate::dns::packet;
+use crate::dns::types::{RecordType, ResponseCode};
+
+/// In-zone CNAMEs followed for one answer
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// One resource record of a zone, rdata already in wire format (uncompressed)
+#[derive(Debug, Clone)]
+struct ZoneRecord {
+    rtype: RecordType,
+    ttl: u32,
+    rdata: Vec<u8>,
+    /// Target of NS / CNAME / MX / SRV, for following chains and adding glue
+    target: Option<String>,
+}
+
+/// A zone served authoritatively ([[authoritative_zone]]), parsed from an
+/// RFC 1035 master file at startup. Names are lowercased, without the trailing dot.
+#[derive(Debug)]
+pub struct AuthoritativeZone {
+    origin: String,
+    nodes: HashMap<String, Vec<ZoneRecord>>,
+}
+
+impl AuthoritativeZone {
+    pub fn load(config: &AuthoritativeZoneConfig) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(&config.file)
+            .map_err(|e| anyhow::anyhow!("authoritative zone {}: cannot read {}: {}", config.domain, config.file, e))?;
+        Self::parse(&text, &config.domain)
+            .map_err(|e| anyhow::anyhow!("authoritative zone {} ({}): {}", config.domain, config.file, e))
+    }
+
+    /// Parse master-file text; `origin` is the zone apex and the initial $ORIGIN
+    pub fn parse(text: &str, origin: &str) -> anyhow::Result<Self> {
+        let apex = origin.trim_end_matches('.').to_lowercase();
+        let mut zone = Self { origin: apex.clone(), nodes: HashMap::new() };
+        let mut current_origin = apex.clone();
+        let mut default_ttl: Option<u32> = None;
+        let mut last_owner: Option<String> = None;
+
+        for (line_no, tokens, owner_inherited) in logical_lines(text)? {
+            let at = |e: anyhow::Error| anyhow::anyhow!("line {}: {}", line_no, e);
+            let mut tokens = tokens.into_iter().peekable();
+            let Some(first) = tokens.peek().cloned() else { continue };
+            match first.text.to_ascii_uppercase().as_str() {
+                "$ORIGIN" if !first.quoted => {
+                    tokens.next();
+                    let name = tokens.next().ok_or_else(|| at(anyhow::anyhow!("$ORIGIN without a name")))?;
+                    current_origin = absolute(&name.text, &current_origin);
+                    continue;
+                }
+                "$TTL" if !first.quoted => {
+                    tokens.next();
+                    let ttl = tokens.next().ok_or_else(|| at(anyhow::anyhow!("$TTL without a value")))?;
+                    default_ttl = Some(parse_ttl(&ttl.text).map_err(at)?);
+                    continue;
+                }
+                other if other.starts_with('$') => return Err(at(anyhow::anyhow!("unsupported directive {}", first.text))),
+                _ => {}
+            }
+
+            let owner = if owner_inherited {
+                last_owner.clone().ok_or_else(|| at(anyhow::anyhow!("record without an owner name")))?
+            } else {
+                absolute(&tokens.next().map(|t| t.text).unwrap_or_default(), &current_origin)
+            };
+
+            // [ttl] [class] type, TTL and class in either order
+            let mut ttl = None;
+            let rtype = loop {
+                let token = tokens.next().ok_or_else(|| at(anyhow::anyhow!("record without a type")))?;
+                let upper = token.text.to_ascii_uppercase();
+                if upper == "IN" {
+                    continue;
+                }
+                if ttl.is_none() && token.text.starts_with(|c: char| c.is_ascii_digit()) {
+                    ttl = Some(parse_ttl(&token.text).map_err(at)?);
+                    continue;
+                }
+                break RecordType::from_name(&upper).ok_or_else(|| at(anyhow::anyhow!("unknown record type or class {:?}", token.text)))?;
+            };
+            let fields: Vec<Token> = tokens.collect();
+            let (rdata, target) = encode_rdata(rtype, &fields, &current_origin).map_err(at)?;
+            // SOA MINIMUM is the last resort, as in BIND
+            let ttl = match ttl.or(default_ttl) {
+                Some(ttl) => ttl,
+                None if rtype == RecordType::SOA => soa_minimum(&rdata).unwrap_or(3600),
+                None => return Err(at(anyhow::anyhow!("no TTL given and no $TTL before it"))),
+            };
+            if rtype == RecordType::SOA && default_ttl.is_none() {
+                default_ttl = soa_minimum(&rdata);
+            }
+
+            last_owner = Some(owner.clone());
+            if !in_zone(&owner, &apex) {
+                tracing::warn!("📜 Zone {}: ignoring out-of-zone record {} {} (line {})", apex, owner, rtype.name(), line_no);
+                continue;
+            }
+            zone.nodes.entry(owner).or_default().push(ZoneRecord { rtype, ttl, rdata, target });
+        }
+
+        if zone.soa().is_none() {
+            anyhow::bail!("no SOA record at the zone apex {}", display(&apex));
+        }
+        Ok(zone)
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn record_count(&self) -> usize {
+        self.nodes.values().map(Vec::len).sum()
+    }
+
+    fn soa(&self) -> Option<&ZoneRecord> {
+        self.nodes.get(&self.origin)?.iter().find(|r| r.rtype == RecordType::SOA)
+    }
+
+    /// true if `qname` is the apex or below it
+    pub fn contains(&self, qname: &str) -> bool {
+        in_zone(&qname.trim_end_matches('.').to_lowercase(), &self.origin)
+    }
+
+    /// Authoritative answer (AA=1) for a query inside the zone: the records, a CNAME chain,
+    /// NODATA / NXDOMAIN with the SOA, or a referral (AA=0) below a delegation
+    pub fn answer(&self, query: &[u8], qtype: RecordType) -> anyhow::Result<Vec<u8>> {
+        let mut parsed = packet::parse_packet(query)?;
+        let qname = parsed.questions.first()
+            .map(|q| q.name.clone())
+            .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+        let name = qname.trim_end_matches('.').to_lowercase();
+        parsed.header.qr = true;
+        parsed.header.aa = true;
+        parsed.answers.clear();
+        parsed.authorities.clear();
+        parsed.additionals.retain(|r| r.rtype == RecordType::OPT);
+
+        if let Some(cut) = self.delegation(&name) {
+            parsed.header.aa = false;
+            let ns = &self.nodes[&cut];
+            for record in ns.iter().filter(|r| r.rtype == RecordType::NS) {
+                parsed.authorities.push(packet::DnsRecord::new(&display(&cut), RecordType::NS, record.ttl, record.rdata.clone()));
+            }
+            for target in ns.iter().filter_map(|r| r.target.as_ref()) {
+                for glue in self.nodes.get(target).into_iter().flatten().filter(|r| matches!(r.rtype, RecordType::A | RecordType::AAAA)) {
+                    parsed.additionals.push(packet::DnsRecord::new(&display(target), glue.rtype, glue.ttl, glue.rdata.clone()));
+                }
+            }
+            return Ok(parsed.to_wire());
+        }
+
+        let mut owner = name.clone();
+        let mut shown = qname.clone();
+        // Where the chain ended without the asked-for type: the SOA goes in the authority
+        let mut negative = false;
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let Some(records) = self.node_or_wildcard(&owner) else {
+                // RFC 6604: the rcode is about the last name in the chain
+                if !self.is_empty_non_terminal(&owner) {
+                    parsed.header.rcode = ResponseCode::NxDomain;
+                }
+                negative = true;
+                break;
+            };
+            let matching: Vec<&ZoneRecord> = records.iter()
+                .filter(|r| qtype == RecordType::ANY || r.rtype == qtype)
+                .collect();
+            if !matching.is_empty() {
+                for record in matching {
+                    parsed.answers.push(packet::DnsRecord::new(&shown, record.rtype, record.ttl, record.rdata.clone()));
+                }
+                break;
+            }
+            let Some(cname) = records.iter().find(|r| r.rtype == RecordType::CNAME) else {
+                negative = true;
+                break;
+            };
+            parsed.answers.push(packet::DnsRecord::new(&shown, RecordType::CNAME, cname.ttl, cname.rdata.clone()));
+            let target = cname.target.clone().unwrap_or_default();
+            // Out of the zone: the client's resolver takes it from here
+            if !in_zone(&target, &self.origin) || self.delegation(&target).is_some() {
+                break;
+            }
+            shown = display(&target);
+            owner = target;
+        }
+
+        // RFC 2308 §3: SOA in the authority section, its TTL capped by MINIMUM
+        if let Some(soa) = self.soa().filter(|_| negative) {
+            let ttl = soa_minimum(&soa.rdata).map_or(soa.ttl, |min| min.min(soa.ttl));
+            parsed.authorities.push(packet::DnsRecord::new(&display(&self.origin), RecordType::SOA, ttl, soa.rdata.clone()));
+        }
+        Ok(parsed.to_wire())
+    }
+
+    /// Closest delegation point (NS below the apex) at or above `name`
+    fn delegation(&self, name: &str) -> Option<String> {
+        let mut cut = None;
+        let mut candidate = name;
+        while candidate != self.origin && in_zone(candidate, &self.origin) {
+            if self.nodes.get(candidate).is_some_and(|rs| rs.iter().any(|r| r.rtype == RecordType::NS)) {
+                cut = Some(candidate.to_string());
+            }
+            let Some((_, parent)) = candidate.split_once('.') else { break };
+            candidate = parent;
+        }
+        cut
+    }
+
+    /// Records at `name`, or of the wildcard at its closest encloser (RFC 4592)
+    fn node_or_wildcard(&self, name: &str) -> Option<&Vec<ZoneRecord>> {
+        if let Some(records) = self.nodes.get(name) {
+            return Some(records);
+        }
+        if self.is_empty_non_terminal(name) {
+            return None;
+        }
+        let mut encloser = name;
+        while let Some((_, parent)) = encloser.split_once('.') {
+            encloser = parent;
+            if !in_zone(encloser, &self.origin) {
+                break;
+            }
+            if self.nodes.contains_key(encloser) || self.is_empty_non_terminal(encloser) || encloser == self.origin {
+                return self.nodes.get(&format!("*.{}", encloser));
+            }
+        }
+        None
+    }
+
+    /// A name with no records of its own but names below it
+    fn is_empty_non_terminal(&self, name: &str) -> bool {
+        let suffix = format!(".{}", name);
+        !self.nodes.contains_key(name) && self.nodes.keys().any(|n| n.ends_with(&suffix))
+    }
+}
+
+/// Modification time and length of a zone file, compared to spot edits
+type FileStamp = (Option<SystemTime>, u64);
+
+fn file_stamp(path: &str) -> anyhow::Result<FileStamp> {
+    let meta = std::fs::metadata(path)?;
+    Ok((meta.modified().ok(), meta.len()))
+}
+
+/// A zone file and the version of it being served. A changed file is parsed
+/// aside and swapped in whole; a broken edit leaves the previous version serving.
+#[derive(Debug)]
+pub struct WatchedZone {
+    config: AuthoritativeZoneConfig,
+    current: RwLock<Arc<AuthoritativeZone>>,
+    stamp: Mutex<Option<FileStamp>>,
+}
+
+impl WatchedZone {
+    pub fn load(config: &AuthoritativeZoneConfig) -> anyhow::Result<Self> {
+        let stamp = file_stamp(&config.file).ok();
+        let zone = AuthoritativeZone::load(config)?;
+        Ok(Self {
+            config: config.clone(),
+            current: RwLock::new(Arc::new(zone)),
+            stamp: Mutex::new(stamp),
+        })
+    }
+
+    /// The version being served
+    pub fn zone(&self) -> Arc<AuthoritativeZone> {
+        self.current.read().clone()
+    }
+
+    pub fn file(&self) -> &str {
+        &self.config.file
+    }
+
+    pub fn watched(&self) -> bool {
+        self.config.watch
+    }
+
+    /// Re-parse the file if it changed since the last look. Ok(true) when a new
+    /// version was swapped in; on error the old version keeps serving and the
+    /// same edit isn't retried until the file changes again.
+    pub fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let stamp = file_stamp(&self.config.file)
+            .map_err(|e| anyhow::anyhow!("authoritative zone {}: cannot stat {}: {}", self.config.domain, self.config.file, e))?;
+        {
+            let mut seen = self.stamp.lock();
+            if *seen == Some(stamp) {
+                return Ok(false);
+            }
+            *seen = Some(stamp);
+        }
+        let zone = AuthoritativeZone::load(&self.config)?;
+        *self.current.write() = Arc::new(zone);
+        Ok(true)
+    }
+}
+
+/// The authoritative zone `qname` falls in: the most specific one when they nest
+pub fn find_zone<'a>(zones: &'a [WatchedZone], qname: &str) -> Option<&'a WatchedZone> {
+    zones.iter()
+        .filter(|w| w.zone().contains(qname))
+        .max_by_key(|w| w.zone().origin.len())
+}
+
+/// A master-file token; quoted strings keep their spaces
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// Split master-file text into entries: comments dropped, parentheses joined.
+/// Each comes with its first line number and whether it starts with blank space
+/// (owner inherited from the previous record).
+fn logical_lines(text: &str) -> anyhow::Result<Vec<(usize, Vec<Token>, bool)>> {
+    let mut entries = Vec::new();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut depth = 0usize;
+    let mut start = (0, false);
+
+    for (i, line) in text.lines().enumerate() {
+        if depth == 0 {
+            start = (i + 1, line.starts_with([' ', '\t']));
+        }
+        let mut chars = line.chars().peekable();
+        let mut word = String::new();
+        let flush = |word: &mut String, tokens: &mut Vec<Token>| {
+            if !word.is_empty() {
+                tokens.push(Token { text: std::mem::take(word), quoted: false });
+            }
+        };
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '(' => { flush(&mut word, &mut tokens); depth += 1; }
+                ')' => {
+                    flush(&mut word, &mut tokens);
+                    depth = depth.checked_sub(1).ok_or_else(|| anyhow::anyhow!("line {}: unbalanced ')'", i + 1))?;
+                }
+                '"' => {
+                    flush(&mut word, &mut tokens);
+                    let mut quoted = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\\') => quoted.extend(chars.next()),
+                            Some('"') => break,
+                            Some(c) => quoted.push(c),
+                            None => anyhow::bail!("line {}: unterminated string", i + 1),
+                        }
+                    }
+                    tokens.push(Token { text: quoted, quoted: true });
+                }
+                c if c.is_whitespace() => flush(&mut word, &mut tokens),
+                c => word.push(c),
+            }
+        }
+        flush(&mut word, &mut tokens);
+        if depth == 0 && !tokens.is_empty() {
+            entries.push((start.0, std::mem::take(&mut tokens), start.1));
+        }
+    }
+    if depth > 0 {
+        anyhow::bail!("line {}: '(' never closed", start.0);
+    }
+    Ok(entries)
+}
+
+/// Owner / rdata name made absolute against `origin` ("@" is the origin itself)
+fn absolute(name: &str, origin: &str) -> String {
+    let name = name.to_lowercase();
+    if name == "@" {
+        origin.to_string()
+    } else if let Some(fqdn) = name.strip_suffix('.') {
+        fqdn.to_string()
+    } else if origin.is_empty() {
+        name
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+fn in_zone(name: &str, apex: &str) -> bool {
+    apex.is_empty() || name == apex || name.ends_with(&format!(".{}", apex))
+}
+
+/// Name as put in a record (the root is ".")
+fn display(name: &str) -> String {
+    if name.is_empty() { ".".to_string() } else { name.to_string() }
+}
+
+/// TTL in seconds or BIND units ("3600", "1h30m", "2d", "1w")
+fn parse_ttl(text: &str) -> anyhow::Result<u32> {
+    if let Ok(secs) = text.parse::<u32>() {
+        return Ok(secs);
+    }
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            'w' => 604_800,
+            _ => anyhow::bail!("bad TTL {:?}", text),
+        };
+        let n: u64 = number.parse().map_err(|_| anyhow::anyhow!("bad TTL {:?}", text))?;
+        total += n * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        anyhow::bail!("bad TTL {:?}", text);
+    }
+    u32::try_from(total).map_err(|_| anyhow::anyhow!("TTL {:?} too large", text))
+}
+
+/// MINIMUM, the last field of SOA rdata
+fn soa_minimum(rdata: &[u8]) -> Option<u32> {
+    let tail = rdata.len().checked_sub(4)?;
+    Some(u32::from_be_bytes(rdata[tail..].try_into().ok()?))
+}
+
+/// Wire rdata for the presentation fields of a record, plus the name it points at
+fn encode_rdata(rtype: RecordType, fields: &[Token], origin: &str) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+    let field = |i: usize| fields.get(i).map(|t| t.text.as_str())
+        .ok_or_else(|| anyhow::anyhow!("{} record needs more fields", rtype.name()));
+    let number = |i: usize| -> anyhow::Result<u16> {
+        field(i)?.parse().map_err(|_| anyhow::anyhow!("bad number {:?} in {} record", fields[i].text, rtype.name()))
+    };
+    let name = |i: usize| field(i).map(|n| absolute(n, origin));
+    Ok(match rtype {
+        RecordType::A => (field(0)?.parse::<Ipv4Addr>()?.octets().to_vec(), None),
+        RecordType::AAAA => (field(0)?.parse::<Ipv6Addr>()?.octets().to_vec(), None),
+        RecordType::NS | RecordType::CNAME | RecordType::PTR => {
+            let target = name(0)?;
+            (packet::encode_name(&target), Some(target))
+        }
+        RecordType::MX => {
+            let target = name(1)?;
+            let mut rdata = number(0)?.to_be_bytes().to_vec();
+            rdata.extend(packet::encode_name(&target));
+            (rdata, Some(target))
+        }
+        RecordType::SRV => {
+            let target = name(3)?;
+            let mut rdata = Vec::new();
+            for i in 0..3 {
+                rdata.extend_from_slice(&number(i)?.to_be_bytes());
+            }
+            rdata.extend(packet::encode_name(&target));
+            (rdata, Some(target))
+        }
+        RecordType::TXT => {
+            if fields.is_empty() {
+                anyhow::bail!("TXT record needs at least one string");
+            }
+            let mut rdata = Vec::new();
+            for text in fields {
+                for chunk in text.text.as_bytes().chunks(255) {
+                    rdata.push(chunk.len() as u8);
+                    rdata.extend_from_slice(chunk);
+                }
+            }
+            (rdata, None)
+        }
+        RecordType::SOA => {
+            let mut rdata = packet::encode_name(&name(0)?);
+            rdata.extend(packet::encode_name(&name(1)?));
+            // The serial is a plain number; the timers may use units
+            let serial = field(2)?;
+            let serial: u32 = serial.parse().map_err(|_| anyhow::anyhow!("bad SOA serial {:?}", serial))?;
+            rdata.extend_from_slice(&serial.to_be_bytes());
+            for i in 3..7 {
+                rdata.extend_from_slice(&parse_ttl(field(i)?)?.to_be_bytes());
+            }
+            (rdata, None)
+        }
+        other => anyhow::bail!("{} records are not supported in zone files", other.name()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            2h 15m 1w
+            300 )      ; minimum
+        IN  NS  ns1
+        IN  MX  10 mail
+ns1     IN  A   192.168.1.2
+mail    300 IN A 192.168.1.3
+        IN  AAAA fd00::3
+www     IN  CNAME nas
+nas     IN  A   192.168.1.10
+ext     IN  CNAME example.org.
+note    IN  TXT "hello world" "second \"part\""
+*.dyn   IN  A   192.168.1.99
+a.b.deep IN A   192.168.1.50
+sub     IN  NS  ns.sub
+ns.sub  IN  A   192.168.1.60
+"#;
+
+    fn zone() -> AuthoritativeZone {
+        AuthoritativeZone::parse(SAMPLE, "home.arpa").unwrap()
+    }
+
+    fn ask(zone: &AuthoritativeZone, name: &str, qtype: RecordType) -> (packet::DnsPacket, Vec<u8>) {
+        let response = zone.answer(&packet::build_query(0x1486, name, qtype, true), qtype).unwrap();
+        (packet::parse_packet(&response).unwrap(), response)
+    }
+
+    #[test]
+    fn test_parse_sample_zone() {
+        let zone = zone();
+        assert_eq!(zone.origin(), "home.arpa");
+        assert_eq!(zone.record_count(), 14);
+        let mail = &zone.nodes["mail.home.arpa"];
+        // Explicit TTL, then the inherited owner with $TTL
+        assert_eq!((mail[0].ttl, mail[1].rtype, mail[1].ttl), (300, RecordType::AAAA, 3600));
+        assert_eq!(parse_ttl("1h30m").unwrap(), 5400);
+        assert!(parse_ttl("5x").is_err());
+
+        assert!(AuthoritativeZone::parse("$TTL 60\nwww IN A 192.0.2.1\n", "home.arpa").unwrap_err().to_string().contains("no SOA"));
+        let bad = AuthoritativeZone::parse("@ 60 IN SOA a b 1 2 3 4 5\nx IN A not-an-ip\n", "home.arpa").unwrap_err();
+        assert!(bad.to_string().starts_with("line 2:"), "{}", bad);
+    }
+
+    #[test]
+    fn test_answers() {
+        let zone = zone();
+        let (a, _) = ask(&zone, "nas.home.arpa", RecordType::A);
+        assert!(a.header.aa);
+        assert_eq!(a.header.rcode, ResponseCode::NoError);
+        assert_eq!(a.answers[0].rdata, vec![192, 168, 1, 10]);
+
+        let (nx, raw) = ask(&zone, "missing.home.arpa", RecordType::A);
+        assert_eq!(nx.header.rcode, ResponseCode::NxDomain);
+        assert!(nx.answers.is_empty());
+        assert_eq!(nx.authorities[0].rtype, RecordType::SOA);
+        assert_eq!(nx.authorities[0].ttl, 300);
+        assert!(packet::format_record(&nx.authorities[0], &raw).starts_with("ns1.home.arpa hostmaster.home.arpa 2024010101"));
+
+        // NODATA: the name exists, the type doesn't; same for an empty non-terminal
+        for name in ["nas.home.arpa", "b.deep.home.arpa"] {
+            let (nodata, _) = ask(&zone, name, RecordType::MX);
+            assert_eq!(nodata.header.rcode, ResponseCode::NoError);
+            assert!(nodata.answers.is_empty());
+            assert_eq!(nodata.authorities[0].rtype, RecordType::SOA);
+        }
+
+        let (cname, raw) = ask(&zone, "WWW.home.arpa", RecordType::A);
+        assert_eq!(cname.answers.len(), 2);
+        assert_eq!(packet::format_record(&cname.answers[0], &raw), "nas.home.arpa");
+        assert_eq!(cname.answers[1].name, "nas.home.arpa");
+        let (ext, _) = ask(&zone, "ext.home.arpa", RecordType::A);
+        assert_eq!((ext.answers.len(), ext.answers[0].rtype), (1, RecordType::CNAME));
+        assert!(ext.authorities.is_empty());
+
+        let (txt, _) = ask(&zone, "note.home.arpa", RecordType::TXT);
+        assert_eq!(txt.answers[0].rdata, b"\x0bhello world\x0dsecond \"part\"".to_vec());
+
+        let (wild, _) = ask(&zone, "laptop.dyn.home.arpa", RecordType::A);
+        assert_eq!(wild.answers[0].name, "laptop.dyn.home.arpa");
+        assert_eq!(wild.answers[0].rdata, vec![192, 168, 1, 99]);
+
+        let (referral, _) = ask(&zone, "host.sub.home.arpa", RecordType::A);
+        assert!(!referral.header.aa);
+        assert_eq!(referral.authorities[0].rtype, RecordType::NS);
+        assert_eq!(referral.additionals[0].rdata, vec![192, 168, 1, 60]);
+
+        let (apex_mx, _) = ask(&zone, "home.arpa", RecordType::MX);
+        assert_eq!(apex_mx.answers[0].rdata[..2], [0, 10]);
+    }
+
+    #[test]
+    fn test_find_zone_prefers_most_specific() {
+        let watched = |zone: AuthoritativeZone| WatchedZone {
+            config: AuthoritativeZoneConfig { domain: zone.origin.clone(), file: String::new(), watch: false },
+            current: RwLock::new(Arc::new(zone)),
+            stamp: Mutex::new(None),
+        };
+        let zones = vec![
+            watched(zone()),
+            watched(AuthoritativeZone::parse("@ 60 IN SOA ns hm 1 2 3 4 5\n", "iot.home.arpa").unwrap()),
+        ];
+        assert_eq!(find_zone(&zones, "cam.iot.home.arpa.").unwrap().zone().origin(), "iot.home.arpa");
+        assert_eq!(find_zone(&zones, "nas.home.arpa").unwrap().zone().origin(), "home.arpa");
+        assert!(find_zone(&zones, "nothome.arpa").is_none());
+    }
+}
@@ -1,19 +1,130 @@
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Duration, Instant};
+use std::collections::{btree_map, BTreeMap};
+use std::sync::Arc;
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use chrono::Utc;
 use dashmap::DashMap;
-use tracing::debug;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
-use crate::config::{CacheConfig, TtlAlchemyConfig};
+use crate::compress::{self, FileWriter};
+use crate::config::{AdmissionPolicy, CacheBackend, CacheConfig, Compression, TtlAlchemyConfig, TtlMode};
 use crate::dns::types::RecordType;
 use crate::dns::packet;
+use crate::edns::{self, ClientSubnet};
+use crate::ring::RingBuffer;
+use crate::subnet::ClientNet;
 use crate::ttl_alchemy::TtlAlchemy;
 
-/// Cache key: (domain name, record type)
+/// Cache backend as seen by the engine. `CacheLayer` (in-process DashMap) is the
+/// default; with `--features redis` a Redis-backed cache can be shared by several
+/// instances. Inspection hooks default to "nothing to show" for backends that
+/// can't enumerate cheaply.
+#[async_trait]
+pub trait Cache: Send + Sync {
+    async fn get(&self, name: &str, qtype: &RecordType) -> Option<CacheLookup>;
+    /// Lookup for a client that sent ECS; backends without scoped entries (cache.ecs_scoped
+    /// is memory-only) answer as `get`
+    async fn get_for_subnet(&self, name: &str, qtype: &RecordType, _subnet: Option<&ClientSubnet>) -> Option<CacheLookup> {
+        self.get(name, qtype).await
+    }
+    /// Expired entry for stale-on-error (RFC 8767)
+    async fn get_stale(&self, name: &str, qtype: &RecordType) -> Option<CacheLookup>;
+    /// `get_stale` for a client that sent ECS, as `get_for_subnet` is to `get`
+    async fn get_stale_for_subnet(&self, name: &str, qtype: &RecordType, _subnet: Option<&ClientSubnet>) -> Option<CacheLookup> {
+        self.get_stale(name, qtype).await
+    }
+    async fn insert(&self, name: &str, qtype: &RecordType, response: &[u8], upstream_name: &str, transport: Transport);
+    /// Count a hit for TTL alchemy frequency tracking (`scope`: the lookup's `CacheLookup::scope`)
+    async fn record_hit(&self, name: &str, qtype: &RecordType, scope: Option<ClientNet>);
+    async fn flush(&self);
+    fn get_stats(&self) -> serde_json::Value;
+
+    async fn get_prefetch_candidates(&self, _threshold_ratio: f64, _min_hits: u64) -> Vec<(String, RecordType)> {
+        Vec::new()
+    }
+    fn list_entries(&self) -> Vec<serde_json::Value> {
+        Vec::new()
+    }
+    fn inspect_entry(&self, _name: &str, _qtype: &RecordType, _scope: Option<ClientNet>) -> Option<serde_json::Value> {
+        None
+    }
+    /// Stored response bytes of one entry, unmodified (GET /api/cache/entry.wire)
+    fn export_entry(&self, _name: &str, _qtype: &RecordType, _scope: Option<ClientNet>) -> Option<Vec<u8>> {
+        None
+    }
+    fn recent_evictions(&self) -> serde_json::Value {
+        serde_json::json!({ "enabled": false, "evictions": [] })
+    }
+    /// Write the live entries to `path` (persist.enabled), `batch_size` at a time.
+    /// None when a snapshot is already running or the backend keeps no local state.
+    async fn snapshot(&self, _path: &str, _batch_size: usize, _compression: Compression) -> anyhow::Result<Option<usize>> {
+        Ok(None)
+    }
+    /// Load the entries a snapshot wrote to `path`, skipping expired ones
+    fn restore(&self, _path: &str) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+    /// Pretend an entry was inserted `secs` earlier (tests only)
+    #[cfg(test)]
+    fn backdate(&self, _name: &str, _qtype: &RecordType, _secs: u64) {}
+}
+
+/// Build the configured cache backend
+pub async fn build(config: &CacheConfig, alchemy: &TtlAlchemyConfig, ring_buffer_size: usize) -> anyhow::Result<Arc<dyn Cache>> {
+    match config.backend {
+        CacheBackend::Memory => Ok(Arc::new(CacheLayer::new(config, alchemy).with_eviction_log_size(ring_buffer_size))),
+        #[cfg(feature = "redis")]
+        CacheBackend::Redis => Ok(Arc::new(crate::redis_cache::RedisCache::connect(config, alchemy).await?)),
+        #[cfg(not(feature = "redis"))]
+        CacheBackend::Redis => Err(anyhow::anyhow!("cache.backend = \"redis\" needs a build with --features redis")),
+    }
+}
+
+/// How a cached answer reached us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Forwarded, answered over UDP
+    Udp,
+    /// Forwarded, answered over TCP (truncated UDP answer refetched)
+    Tcp,
+    /// Iterative resolution
+    Recursive,
+    /// local_zones forwarding
+    LocalZone,
+    /// Uploaded through POST /api/cache/inject
+    Api,
+}
+
+impl Transport {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Recursive => "recursive",
+            Transport::LocalZone => "local_zone",
+            Transport::Api => "api",
+        }
+    }
+
+    fn from_label(label: &str) -> Option<Self> {
+        [Transport::Udp, Transport::Tcp, Transport::Recursive, Transport::LocalZone, Transport::Api]
+            .into_iter()
+            .find(|t| t.label() == label)
+    }
+}
+
+/// Cache key: (domain name, record type, ECS scope)
 #[derive(Hash, PartialEq, Eq, Clone, Debug)]
 pub struct CacheKey {
     pub name: String,
     pub qtype: u16,
+    /// Client network an ECS-scoped answer is valid for (cache.ecs_scoped);
+    /// None for answers that serve every client
+    pub scope: Option<ClientNet>,
 }
 
 /// Cached entry with metadata
@@ -24,6 +135,7 @@ pub struct CacheEntry {
     pub alchemized_ttl: u32,
     pub inserted_at: Instant,
     pub upstream_name: String,
+    pub transport: Transport,
     pub hit_count: u64,
     pub last_rdata_hash: u64,  // Hash of rdata for volatility detection
     pub rdata_changes: u32,    // How many times rdata changed
@@ -33,38 +145,150 @@ pub struct CacheEntry {
 pub struct CacheLookup {
     pub raw_response: Vec<u8>,
     pub remaining_ttl: u32,
+    /// TTL the entry was stored with
+    pub ttl: u32,
     pub upstream_name: String,
+    /// ECS scope of the entry that answered (cache.ecs_scoped); None for unscoped ones
+    pub scope: Option<ClientNet>,
+}
+
+/// How many evictions the eviction log keeps unless debug.ring_buffer_size says otherwise
+const EVICTION_LOG_SIZE: usize = 200;
+/// Slots in the admission doorkeeper; colliding keys just overwrite each other
+const ADMISSION_SLOTS: usize = 4096;
+
+/// One line of a cache snapshot (JSON lines, so it can be written a batch at a time)
+#[derive(Serialize, Deserialize)]
+struct SavedEntry {
+    name: String,
+    qtype: u16,
+    response: Vec<u8>,
+    original_ttl: u32,
+    /// Unix time the entry's TTL runs out
+    expires_at: u64,
+    upstream: String,
+    transport: String,
+    hits: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// One removed entry, for tuning max_entries
+#[derive(Clone, Debug)]
+struct EvictionRecord {
+    name: String,
+    qtype: u16,
+    /// "capacity" (evict_one) or "expired" (past TTL + stale window, dropped on lookup)
+    reason: &'static str,
+    age_secs: u64,
+    hits: u64,
+    evicted_at: String,
 }
 
 pub struct CacheLayer {
     entries: DashMap<CacheKey, CacheEntry>,
+    /// Scope prefix lengths stored per (name, qtype), each with its entry count, so an
+    /// ECS lookup probes only scopes that exist
+    scope_prefixes: DashMap<(String, u16), BTreeMap<u8, usize>>,
     config: CacheConfig,
     alchemy: TtlAlchemy,
     // Stats
     hits: AtomicU64,
     misses: AtomicU64,
     evictions: AtomicU64,
+    /// Responses not stored because they exceeded max_entry_bytes
+    oversized: AtomicU64,
+    /// Recent evictions, newest first (only filled with cache.eviction_log)
+    eviction_log: RingBuffer<EvictionRecord>,
+    /// Recent first misses for admission_policy = "second_miss": (key hash, when), by hash % slots
+    admission_sketch: Mutex<Vec<Option<(u64, Instant)>>>,
+    /// Answers not stored because it was their first miss
+    admission_rejected: AtomicU64,
+    /// Set while a snapshot runs; a trigger arriving meanwhile is dropped
+    snapshotting: AtomicBool,
+    snapshots: AtomicU64,
+    last_snapshot_ms: AtomicU64,
+    last_snapshot_entries: AtomicU64,
 }
 
 impl CacheLayer {
     pub fn new(config: &CacheConfig, alchemy_config: &TtlAlchemyConfig) -> Self {
         Self {
             entries: DashMap::new(),
+            scope_prefixes: DashMap::new(),
             config: config.clone(),
             alchemy: TtlAlchemy::new(alchemy_config),
             hits: AtomicU64::new(0),
             misses: AtomicU64::new(0),
             evictions: AtomicU64::new(0),
+            oversized: AtomicU64::new(0),
+            eviction_log: RingBuffer::new(EVICTION_LOG_SIZE),
+            admission_sketch: Mutex::new(match config.admission_policy {
+                AdmissionPolicy::Always => Vec::new(),
+                AdmissionPolicy::SecondMiss => vec![None; ADMISSION_SLOTS],
+            }),
+            admission_rejected: AtomicU64::new(0),
+            snapshotting: AtomicBool::new(false),
+            snapshots: AtomicU64::new(0),
+            last_snapshot_ms: AtomicU64::new(0),
+            last_snapshot_entries: AtomicU64::new(0),
         }
     }
 
+    /// Keep `size` entries in the eviction log (debug.ring_buffer_size)
+    pub fn with_eviction_log_size(mut self, size: usize) -> Self {
+        self.eviction_log = RingBuffer::new(size);
+        self
+    }
+
     /// Look up a cached entry
     pub async fn get(&self, name: &str, qtype: &RecordType) -> Option<CacheLookup> {
         let key = CacheKey {
             name: name.to_lowercase(),
             qtype: qtype.to_u16(),
+            scope: None,
         };
+        let found = self.lookup(key, name, qtype);
+        if found.is_none() {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
 
+    /// Look up an answer for a client that sent ECS (cache.ecs_scoped): the longest
+    /// stored scope containing its subnet wins, then the unscoped answer
+    pub async fn get_for_subnet(&self, name: &str, qtype: &RecordType, subnet: Option<&ClientSubnet>) -> Option<CacheLookup> {
+        for key in self.scoped_keys(name, qtype, subnet) {
+            if !self.entries.contains_key(&key) {
+                continue;
+            }
+            if let Some(found) = self.lookup(key, name, qtype) {
+                return Some(found);
+            }
+        }
+        self.get(name, qtype).await
+    }
+
+    /// Keys of the scopes that could hold an answer for `subnet`, longest first
+    /// (none without cache.ecs_scoped or ECS)
+    fn scoped_keys(&self, name: &str, qtype: &RecordType, subnet: Option<&ClientSubnet>) -> Vec<CacheKey> {
+        let Some(subnet) = subnet.filter(|_| self.config.ecs_scoped) else { return Vec::new() };
+        let name_key = name.to_lowercase();
+        let Some(prefixes) = self.scope_prefixes.get(&(name_key.clone(), qtype.to_u16())) else { return Vec::new() };
+        prefixes.keys().rev()
+            .filter(|&&prefix| prefix <= subnet.source_prefix)
+            .map(|&prefix| CacheKey {
+                name: name_key.clone(),
+                qtype: qtype.to_u16(),
+                scope: Some(ClientNet::masked(subnet.addr, prefix)),
+            })
+            .collect()
+    }
+
+    /// Fresh (or serve-stale) entry under `key`; an entry past every stale window is dropped
+    fn lookup(&self, key: CacheKey, name: &str, qtype: &RecordType) -> Option<CacheLookup> {
         if let Some(entry) = self.entries.get(&key) {
             let elapsed = entry.inserted_at.elapsed().as_secs() as u32;
             let ttl = entry.alchemized_ttl;
@@ -73,42 +297,137 @@ impl CacheLayer {
                 self.hits.fetch_add(1, Ordering::Relaxed);
                 return Some(CacheLookup {
                     raw_response: entry.raw_response.clone(),
-                    remaining_ttl: ttl - elapsed,
+                    remaining_ttl: self.config.jittered_ttl(ttl - elapsed),
+                    ttl,
                     upstream_name: entry.upstream_name.clone(),
+                    scope: key.scope,
                 });
             }
 
             // TTL expired - check serve-stale
-            if self.config.serve_stale {
+            if self.config.serves_stale(name) {
                 let stale_elapsed = elapsed as u64 - ttl as u64;
                 if stale_elapsed < self.config.stale_ttl_secs {
                     debug!("Serving stale entry for {} {} (stale for {}s)", name, qtype.name(), stale_elapsed);
                     self.hits.fetch_add(1, Ordering::Relaxed);
                     return Some(CacheLookup {
                         raw_response: entry.raw_response.clone(),
-                        remaining_ttl: 1, // Minimal TTL for stale
+                        remaining_ttl: stale_answer_ttl(&self.config, stale_elapsed),
+                        ttl,
                         upstream_name: format!("{} (stale)", entry.upstream_name),
+                        scope: key.scope,
                     });
                 }
             }
-        }
 
-        self.misses.fetch_add(1, Ordering::Relaxed);
+            // Past TTL and every stale window → nothing can use it any more
+            let stale_elapsed = elapsed as u64 - ttl as u64;
+            if stale_elapsed >= self.config.stale_ttl_secs {
+                drop(entry);
+                if let Some((key, entry)) = self.entries.remove(&key) {
+                    debug!("Cache expired: {} {} (stale for {}s)", key.name, qtype.name(), stale_elapsed);
+                    self.forget_scope(&key);
+                    self.log_eviction(&key, &entry, "expired");
+                }
+            }
+        }
         None
     }
 
+    /// Expired entry for stale-on-error (RFC 8767): served only after fresh resolution failed,
+    /// whether or not proactive serve_stale is on
+    pub async fn get_stale(&self, name: &str, qtype: &RecordType) -> Option<CacheLookup> {
+        let key = CacheKey {
+            name: name.to_lowercase(),
+            qtype: qtype.to_u16(),
+            scope: None,
+        };
+        self.stale_entry(key, name, qtype)
+    }
+
+    /// `get_stale` trying the client's scoped entries first, longest scope first
+    pub async fn get_stale_for_subnet(&self, name: &str, qtype: &RecordType, subnet: Option<&ClientSubnet>) -> Option<CacheLookup> {
+        let unscoped = CacheKey { name: name.to_lowercase(), qtype: qtype.to_u16(), scope: None };
+        self.scoped_keys(name, qtype, subnet).into_iter()
+            .chain(std::iter::once(unscoped))
+            .find_map(|key| self.stale_entry(key, name, qtype))
+    }
+
+    fn stale_entry(&self, key: CacheKey, name: &str, qtype: &RecordType) -> Option<CacheLookup> {
+        let entry = self.entries.get(&key)?;
+        let elapsed = entry.inserted_at.elapsed().as_secs();
+        let ttl = entry.alchemized_ttl as u64;
+        if elapsed < ttl || elapsed - ttl >= self.config.stale_ttl_secs {
+            return None;
+        }
+        debug!("Stale-on-error entry for {} {} (stale for {}s)", name, qtype.name(), elapsed - ttl);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(CacheLookup {
+            raw_response: entry.raw_response.clone(),
+            remaining_ttl: stale_answer_ttl(&self.config, elapsed - ttl),
+            ttl: ttl as u32,
+            upstream_name: format!("{} (stale)", entry.upstream_name),
+            scope: key.scope,
+        })
+    }
+
+    /// Pretend an entry was inserted `secs` earlier (tests only)
+    #[cfg(test)]
+    pub fn backdate(&self, name: &str, qtype: &RecordType, secs: u64) {
+        let key = CacheKey { name: name.to_lowercase(), qtype: qtype.to_u16(), scope: None };
+        if let Some(mut entry) = self.entries.get_mut(&key) {
+            entry.inserted_at -= Duration::from_secs(secs);
+        }
+    }
+
     /// Insert a new entry
-    pub async fn insert(&self, name: &str, qtype: &RecordType, response: &[u8], upstream_name: &str) {
+    pub async fn insert(&self, name: &str, qtype: &RecordType, response: &[u8], upstream_name: &str, transport: Transport) {
+        if self.config.never_caches(qtype) {
+            debug!("Not caching {} {}: type listed in no_cache_types", name, qtype.name());
+            return;
+        }
+        // Read before sanitizing: the scope the authoritative gave (RFC 7871 §7.3.1)
+        let scope = if self.config.ecs_scoped {
+            edns::client_subnet(response).and_then(|s| s.cache_scope())
+        } else {
+            None
+        };
+        let sanitized;
+        let response = if self.config.strict_validation {
+            match sanitize_for_cache(name, qtype, response) {
+                Some(cleaned) => {
+                    sanitized = cleaned;
+                    &sanitized[..]
+                }
+                None => return,
+            }
+        } else {
+            response
+        };
+        if response.len() > self.config.max_entry_bytes {
+            debug!("Not caching {} {}: {} bytes > max_entry_bytes {}", name, qtype.name(), response.len(), self.config.max_entry_bytes);
+            self.oversized.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
         // Extract TTL from response
-        let original_ttl = self.extract_min_ttl(response).unwrap_or(300);
+        let original_ttl = extract_min_ttl(response).unwrap_or(300);
 
         let key = CacheKey {
             name: name.to_lowercase(),
             qtype: qtype.to_u16(),
+            scope,
         };
 
+        // Refreshes of stored entries and explicit injections are always admitted
+        if transport != Transport::Api && !self.entries.contains_key(&key) && !self.admit(&key) {
+            debug!("Not caching {} {}: first miss (admission_policy)", name, qtype.name());
+            self.admission_rejected.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
         // Calculate rdata hash for volatility tracking
-        let rdata_hash = self.hash_rdata(response);
+        let rdata_hash = hash_rdata(response);
 
         // Check if entry exists (for volatility tracking)
         let (rdata_changes, hit_count) = if let Some(existing) = self.entries.get(&key) {
@@ -122,12 +441,8 @@ impl CacheLayer {
             (0, 0)
         };
 
-        // Apply TTL alchemy
-        let alchemized_ttl = self.alchemy.calculate_ttl(
-            original_ttl,
-            hit_count,
-            rdata_changes,
-        );
+        // Apply TTL alchemy (or whatever ttl_mode asks for)
+        let alchemized_ttl = stored_ttl(&self.config, &self.alchemy, qtype, original_ttl, hit_count, rdata_changes);
 
         let entry = CacheEntry {
             raw_response: response.to_vec(),
@@ -135,6 +450,7 @@ impl CacheLayer {
             alchemized_ttl,
             inserted_at: Instant::now(),
             upstream_name: upstream_name.to_string(),
+            transport,
             hit_count,
             last_rdata_hash: rdata_hash,
             rdata_changes,
@@ -145,35 +461,91 @@ impl CacheLayer {
             self.evict_one().await;
         }
 
-        self.entries.insert(key, entry);
+        let scope_index = key.scope.map(|scope| ((key.name.clone(), key.qtype), scope.prefix()));
+        if self.entries.insert(key, entry).is_none() {
+            if let Some((index, prefix)) = scope_index {
+                *self.scope_prefixes.entry(index).or_default().entry(prefix).or_default() += 1;
+            }
+        }
+    }
+
+    /// Drop a removed scoped entry from `scope_prefixes`
+    fn forget_scope(&self, key: &CacheKey) {
+        let Some(scope) = key.scope else { return };
+        let index = (key.name.clone(), key.qtype);
+        if let dashmap::mapref::entry::Entry::Occupied(mut prefixes) = self.scope_prefixes.entry(index) {
+            if let btree_map::Entry::Occupied(mut count) = prefixes.get_mut().entry(scope.prefix()) {
+                *count.get_mut() -= 1;
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+            if prefixes.get().is_empty() {
+                prefixes.remove();
+            }
+        }
+    }
+
+    /// Admission check for a key not in the cache: a second miss within the window
+    /// is admitted, a first one is remembered in the sketch
+    fn admit(&self, key: &CacheKey) -> bool {
+        if self.config.admission_policy == AdmissionPolicy::Always {
+            return true;
+        }
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut sketch = self.admission_sketch.lock();
+        let slot = &mut sketch[hash as usize % ADMISSION_SLOTS];
+        let window = Duration::from_secs(self.config.admission_window_secs);
+        match slot {
+            Some((seen, at)) if *seen == hash && at.elapsed() < window => {
+                *slot = None;
+                true
+            }
+            _ => {
+                *slot = Some((hash, Instant::now()));
+                false
+            }
+        }
     }
 
     /// Record a cache hit (for TTL alchemy frequency tracking)
-    pub async fn record_hit(&self, name: &str, qtype: &RecordType) {
+    pub async fn record_hit(&self, name: &str, qtype: &RecordType, scope: Option<ClientNet>) {
         let key = CacheKey {
             name: name.to_lowercase(),
             qtype: qtype.to_u16(),
+            scope,
         };
         if let Some(mut entry) = self.entries.get_mut(&key) {
             entry.hit_count += 1;
         }
     }
 
-    /// Get candidates for prefetching (entries nearing TTL expiry)
-    pub async fn get_prefetch_candidates(&self, threshold_ratio: f64) -> Vec<(String, RecordType)> {
+    /// Get candidates for prefetching (entries nearing TTL expiry that were hit at least `min_hits` times)
+    /// Soonest expiry first
+    pub async fn get_prefetch_candidates(&self, threshold_ratio: f64, min_hits: u64) -> Vec<(String, RecordType)> {
         let mut candidates = Vec::new();
         for entry in self.entries.iter() {
+            // A refetch carries no client subnet, so ECS-scoped answers just expire
+            if entry.hit_count < min_hits || entry.key().scope.is_some() {
+                continue;
+            }
             let elapsed = entry.inserted_at.elapsed().as_secs() as f64;
             let ttl = entry.alchemized_ttl as f64;
             if ttl > 0.0 && (elapsed / ttl) > (1.0 - threshold_ratio) && elapsed < ttl {
                 // Entry is within threshold of expiry and still valid
                 candidates.push((
+                    ttl - elapsed,
                     entry.key().name.clone(),
                     RecordType::from(entry.key().qtype),
                 ));
             }
         }
-        candidates
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        candidates.into_iter().map(|(_, name, qtype)| (name, qtype)).collect()
     }
 
     /// Evict least-recently-hit entry
@@ -192,35 +564,53 @@ impl CacheLayer {
         }
 
         if let Some(key) = oldest_key {
-            self.entries.remove(&key);
+            if let Some((key, entry)) = self.entries.remove(&key) {
+                debug!("Cache eviction (capacity): {} {} score {:.4}", key.name, RecordType::from(key.qtype).name(), lowest_score);
+                self.forget_scope(&key);
+                self.log_eviction(&key, &entry, "capacity");
+            }
             self.evictions.fetch_add(1, Ordering::Relaxed);
         }
     }
 
-    /// Extract minimum TTL from response records
-    fn extract_min_ttl(&self, response: &[u8]) -> Option<u32> {
-        let parsed = packet::parse_packet(response).ok()?;
-        let mut min_ttl = u32::MAX;
-        for record in parsed.answers.iter().chain(parsed.authorities.iter()) {
-            if record.rtype != RecordType::OPT && record.ttl < min_ttl {
-                min_ttl = record.ttl;
-            }
+    fn log_eviction(&self, key: &CacheKey, entry: &CacheEntry, reason: &'static str) {
+        if !self.config.eviction_log {
+            return;
         }
-        if min_ttl == u32::MAX { None } else { Some(min_ttl) }
+        self.eviction_log.push(EvictionRecord {
+            name: key.name.clone(),
+            qtype: key.qtype,
+            reason,
+            age_secs: entry.inserted_at.elapsed().as_secs(),
+            hits: entry.hit_count,
+            evicted_at: Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        });
     }
 
-    /// Simple hash of rdata for change detection
-    fn hash_rdata(&self, response: &[u8]) -> u64 {
-        use std::hash::{Hash, Hasher};
-        use std::collections::hash_map::DefaultHasher;
-        let mut hasher = DefaultHasher::new();
-        // Hash just the answer section for stability
-        if let Ok(parsed) = packet::parse_packet(response) {
-            for record in &parsed.answers {
-                record.rdata.hash(&mut hasher);
-            }
-        }
-        hasher.finish()
+    /// Recent evictions for the Web UI, newest first
+    pub fn recent_evictions(&self) -> serde_json::Value {
+        let evictions: Vec<serde_json::Value> = self.eviction_log.recent().iter()
+            .map(|e| serde_json::json!({
+                "name": e.name,
+                "type": RecordType::from(e.qtype).name(),
+                "reason": e.reason,
+                "age_secs": e.age_secs,
+                "hits": e.hits,
+                "evicted_at": e.evicted_at,
+            }))
+            .collect();
+        serde_json::json!({
+            "enabled": self.config.eviction_log,
+            "evictions": evictions,
+        })
+    }
+
+    /// Bytes held by the entries: stored responses and names plus the per-entry structs
+    fn memory_bytes(&self) -> usize {
+        let overhead = std::mem::size_of::<CacheKey>() + std::mem::size_of::<CacheEntry>();
+        self.entries.iter()
+            .map(|e| overhead + e.key().name.len() + e.raw_response.len() + e.upstream_name.len())
+            .sum()
     }
 
     /// Get cache stats for Web UI
@@ -234,14 +624,179 @@ impl CacheLayer {
         serde_json::json!({
             "entries": total_entries,
             "max_entries": self.config.max_entries,
+            "memory_bytes": self.memory_bytes(),
             "hits": hits,
             "misses": misses,
             "hit_rate_percent": format!("{:.1}", hit_rate),
             "evictions": self.evictions.load(Ordering::Relaxed),
+            "oversized_skipped": self.oversized.load(Ordering::Relaxed),
+            "admission_rejected": self.admission_rejected.load(Ordering::Relaxed),
+            "eviction_log": self.eviction_log.get_stats(),
             "serve_stale": self.config.serve_stale,
+            "serve_stale_domains": self.config.serve_stale_domains,
+            "snapshot": {
+                "running": self.snapshotting.load(Ordering::Relaxed),
+                "count": self.snapshots.load(Ordering::Relaxed),
+                "last_duration_ms": self.last_snapshot_ms.load(Ordering::Relaxed),
+                "last_entries": self.last_snapshot_entries.load(Ordering::Relaxed),
+            },
         })
     }
 
+    /// Snapshot the cache to `path`. Only the keys are collected under the shard locks;
+    /// entries are copied out and serialized `batch_size` at a time, yielding between
+    /// batches so lookups keep being served. None if a snapshot is already running.
+    pub async fn snapshot(&self, path: &str, batch_size: usize, compression: Compression) -> anyhow::Result<Option<usize>> {
+        if self.snapshotting.swap(true, Ordering::AcqRel) {
+            return Ok(None);
+        }
+        let started = Instant::now();
+        let written = self.write_snapshot(path, batch_size.max(1), compression).await;
+        self.snapshotting.store(false, Ordering::Release);
+        let written = written?;
+        self.snapshots.fetch_add(1, Ordering::Relaxed);
+        self.last_snapshot_ms.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.last_snapshot_entries.store(written as u64, Ordering::Relaxed);
+        Ok(Some(written))
+    }
+
+    /// Write live entries as JSON lines (via a temp file, so a crash never leaves half a file)
+    async fn write_snapshot(&self, path: &str, batch_size: usize, compression: Compression) -> anyhow::Result<usize> {
+        use std::io::Write;
+        // ECS-scoped answers belong to the clients that asked; only unscoped ones are kept
+        let keys: Vec<CacheKey> = self.entries.iter()
+            .filter(|e| e.key().scope.is_none())
+            .map(|e| e.key().clone())
+            .collect();
+        let tmp = format!("{}.tmp", path);
+        let mut out = FileWriter::create(&tmp, compression)?;
+        let mut written = 0;
+        for batch in keys.chunks(batch_size) {
+            let now = unix_now();
+            for key in batch {
+                let Some(saved) = self.entries.get(key).and_then(|e| {
+                    let left = (e.alchemized_ttl as u64).checked_sub(e.inserted_at.elapsed().as_secs())?;
+                    Some(SavedEntry {
+                        name: key.name.clone(),
+                        qtype: key.qtype,
+                        response: e.raw_response.clone(),
+                        original_ttl: e.original_ttl,
+                        expires_at: now + left,
+                        upstream: e.upstream_name.clone(),
+                        transport: e.transport.label().to_string(),
+                        hits: e.hit_count,
+                    })
+                }) else {
+                    continue;
+                };
+                serde_json::to_writer(&mut out, &saved)?;
+                out.write_all(b"\n")?;
+                written += 1;
+            }
+            tokio::task::yield_now().await;
+        }
+        out.finish()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(written)
+    }
+
+    /// Load a snapshot written by `snapshot`: expired entries are skipped, the rest keep
+    /// the TTL they had left, up to max_entries
+    pub fn restore(&self, path: &str) -> anyhow::Result<usize> {
+        use std::io::BufRead;
+        let now = unix_now();
+        let mut restored = 0;
+        for line in compress::open(path)?.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if self.entries.len() >= self.config.max_entries {
+                break;
+            }
+            let saved: SavedEntry = serde_json::from_str(&line)?;
+            let left = saved.expires_at.saturating_sub(now);
+            if left == 0 {
+                continue;
+            }
+            self.entries.insert(CacheKey { name: saved.name, qtype: saved.qtype, scope: None }, CacheEntry {
+                last_rdata_hash: hash_rdata(&saved.response),
+                raw_response: saved.response,
+                original_ttl: saved.original_ttl,
+                alchemized_ttl: left.min(u32::MAX as u64) as u32,
+                inserted_at: Instant::now(),
+                upstream_name: saved.upstream,
+                transport: Transport::from_label(&saved.transport).unwrap_or(Transport::Udp),
+                hit_count: saved.hits,
+                rdata_changes: 0,
+            });
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Decode a single cache entry's records (for the Web UI cache inspector);
+    /// `scope` picks an ECS-scoped entry (its "ecs_scope" in list_entries)
+    pub fn export_entry(&self, name: &str, qtype: &RecordType, scope: Option<ClientNet>) -> Option<Vec<u8>> {
+        let key = CacheKey {
+            name: name.to_lowercase(),
+            qtype: qtype.to_u16(),
+            scope,
+        };
+        self.entries.get(&key).map(|e| e.raw_response.clone())
+    }
+
+    pub fn inspect_entry(&self, name: &str, qtype: &RecordType, scope: Option<ClientNet>) -> Option<serde_json::Value> {
+        let key = CacheKey {
+            name: name.to_lowercase(),
+            qtype: qtype.to_u16(),
+            scope,
+        };
+        let entry = self.entries.get(&key)?;
+        let parsed = packet::parse_packet(&entry.raw_response).ok()?;
+        let elapsed = entry.inserted_at.elapsed().as_secs() as u32;
+
+        let decode = |records: &[packet::DnsRecord]| -> Vec<serde_json::Value> {
+            records.iter()
+                .filter(|r| r.rtype != RecordType::OPT)
+                .map(|r| {
+                    let mut record = serde_json::json!({
+                        "name": r.name,
+                        "type": r.rtype.name(),
+                        "ttl": r.ttl,
+                        "data": packet::format_record(r, &entry.raw_response),
+                    });
+                    if packet::is_spf(&r.rtype, &r.rdata) {
+                        record["spf"] = serde_json::json!(true);
+                    }
+                    record
+                })
+                .collect()
+        };
+
+        Some(serde_json::json!({
+            "name": key.name,
+            "type": qtype.name(),
+            "rcode": format!("{:?}", parsed.header.rcode),
+            "original_ttl": entry.original_ttl,
+            "alchemized_ttl": entry.alchemized_ttl,
+            "remaining_ttl": entry.alchemized_ttl.saturating_sub(elapsed),
+            "stale": elapsed >= entry.alchemized_ttl,
+            "upstream": entry.upstream_name,
+            "transport": entry.transport.label(),
+            "hits": entry.hit_count,
+            "answers": decode(&parsed.answers),
+            "authorities": decode(&parsed.authorities),
+            "additionals": decode(&parsed.additionals),
+        }))
+    }
+
+    /// Drop every entry
+    pub fn flush(&self) {
+        self.entries.clear();
+        self.scope_prefixes.clear();
+    }
+
     /// List all cache entries (for Web UI / journal)
     pub fn list_entries(&self) -> Vec<serde_json::Value> {
         self.entries.iter().map(|entry| {
@@ -251,16 +806,693 @@ impl CacheLayer {
             } else {
                 0
             };
-            serde_json::json!({
+            let mut listed = serde_json::json!({
                 "name": entry.key().name,
                 "type": RecordType::from(entry.key().qtype).name(),
                 "original_ttl": entry.original_ttl,
                 "alchemized_ttl": entry.alchemized_ttl,
                 "remaining_ttl": remaining,
                 "upstream": entry.upstream_name,
+                "transport": entry.transport.label(),
                 "hits": entry.hit_count,
                 "rdata_changes": entry.rdata_changes,
-            })
+            });
+            if let Some(scope) = entry.key().scope {
+                listed["ecs_scope"] = serde_json::json!(scope.to_string());
+            }
+            listed
         }).collect()
     }
 }
+
+#[async_trait]
+impl Cache for CacheLayer {
+    async fn get(&self, name: &str, qtype: &RecordType) -> Option<CacheLookup> {
+        CacheLayer::get(self, name, qtype).await
+    }
+    async fn get_for_subnet(&self, name: &str, qtype: &RecordType, subnet: Option<&ClientSubnet>) -> Option<CacheLookup> {
+        CacheLayer::get_for_subnet(self, name, qtype, subnet).await
+    }
+    async fn get_stale(&self, name: &str, qtype: &RecordType) -> Option<CacheLookup> {
+        CacheLayer::get_stale(self, name, qtype).await
+    }
+    async fn get_stale_for_subnet(&self, name: &str, qtype: &RecordType, subnet: Option<&ClientSubnet>) -> Option<CacheLookup> {
+        CacheLayer::get_stale_for_subnet(self, name, qtype, subnet).await
+    }
+    async fn insert(&self, name: &str, qtype: &RecordType, response: &[u8], upstream_name: &str, transport: Transport) {
+        CacheLayer::insert(self, name, qtype, response, upstream_name, transport).await
+    }
+    async fn record_hit(&self, name: &str, qtype: &RecordType, scope: Option<ClientNet>) {
+        CacheLayer::record_hit(self, name, qtype, scope).await
+    }
+    async fn flush(&self) {
+        CacheLayer::flush(self)
+    }
+    fn get_stats(&self) -> serde_json::Value {
+        CacheLayer::get_stats(self)
+    }
+    async fn get_prefetch_candidates(&self, threshold_ratio: f64, min_hits: u64) -> Vec<(String, RecordType)> {
+        CacheLayer::get_prefetch_candidates(self, threshold_ratio, min_hits).await
+    }
+    fn list_entries(&self) -> Vec<serde_json::Value> {
+        CacheLayer::list_entries(self)
+    }
+    fn inspect_entry(&self, name: &str, qtype: &RecordType, scope: Option<ClientNet>) -> Option<serde_json::Value> {
+        CacheLayer::inspect_entry(self, name, qtype, scope)
+    }
+    fn export_entry(&self, name: &str, qtype: &RecordType, scope: Option<ClientNet>) -> Option<Vec<u8>> {
+        CacheLayer::export_entry(self, name, qtype, scope)
+    }
+    fn recent_evictions(&self) -> serde_json::Value {
+        CacheLayer::recent_evictions(self)
+    }
+    async fn snapshot(&self, path: &str, batch_size: usize, compression: Compression) -> anyhow::Result<Option<usize>> {
+        CacheLayer::snapshot(self, path, batch_size, compression).await
+    }
+    fn restore(&self, path: &str) -> anyhow::Result<usize> {
+        CacheLayer::restore(self, path)
+    }
+    #[cfg(test)]
+    fn backdate(&self, name: &str, qtype: &RecordType, secs: u64) {
+        CacheLayer::backdate(self, name, qtype, secs)
+    }
+}
+
+/// Simple hash of rdata for change detection
+pub(crate) fn hash_rdata(response: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    use std::collections::hash_map::DefaultHasher;
+    let mut hasher = DefaultHasher::new();
+    // Hash just the answer section for stability
+    if let Ok(parsed) = packet::parse_packet(response) {
+        for record in &parsed.answers {
+            record.rdata.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+/// Extract minimum TTL from response records
+pub(crate) fn extract_min_ttl(response: &[u8]) -> Option<u32> {
+    let parsed = packet::parse_packet(response).ok()?;
+    let mut min_ttl = u32::MAX;
+    for record in parsed.answers.iter().chain(parsed.authorities.iter()) {
+        if record.rtype != RecordType::OPT && record.ttl < min_ttl {
+            min_ttl = record.ttl;
+        }
+    }
+    if min_ttl == u32::MAX { None } else { Some(min_ttl) }
+}
+
+/// TTL an answer is stored with, per cache.ttl_mode
+pub(crate) fn stored_ttl(config: &CacheConfig, alchemy: &TtlAlchemy, qtype: &RecordType, original_ttl: u32, hit_count: u64, rdata_changes: u32) -> u32 {
+    let ttl = match config.ttl_mode {
+        TtlMode::Passthrough => return original_ttl,
+        TtlMode::Clamp => alchemy.clamp_ttl(original_ttl),
+        TtlMode::Alchemy => alchemy.calculate_ttl(qtype, original_ttl, hit_count, rdata_changes),
+    };
+    config.effective_ttl(original_ttl, ttl)
+}
+
+/// TTL for a stale answer: cache.stale_answer_ttl, cut short so clients never keep it
+/// past the end of the stale window (`stale_elapsed` = seconds since the TTL ran out)
+pub(crate) fn stale_answer_ttl(config: &CacheConfig, stale_elapsed: u64) -> u32 {
+    let window_left = config.stale_ttl_secs.saturating_sub(stale_elapsed);
+    (config.stale_answer_ttl as u64).min(window_left).max(1) as u32
+}
+
+/// Largest TTL accepted into the cache. RFC 2181 §8: values with the MSB set
+/// (> ~68 years) are garbage rather than "very long".
+const MAX_PLAUSIBLE_TTL: u32 = i32::MAX as u32;
+
+/// Cache poisoning hardening: check a response against the question we asked
+/// and strip out-of-zone records before it is cached.
+///
+/// - the question section must match `name`/`qtype`
+/// - answers must be owned by the qname or a name on its CNAME chain
+/// - authority records must be owned by the qname or one of its ancestors
+/// - additionals must sit inside the zone named in the authority section
+///   (or the qname's parent when there is none); OPT is always kept
+/// - records with TTL > MAX_PLAUSIBLE_TTL are dropped
+///
+/// Returns None when the response must not be cached at all.
+pub(crate) fn sanitize_for_cache(name: &str, qtype: &RecordType, response: &[u8]) -> Option<Vec<u8>> {
+    let mut parsed = packet::parse_packet(response).ok()?;
+    let qname = name.to_lowercase();
+
+    let question_ok = parsed.questions.len() == 1
+        && parsed.questions[0].name.to_lowercase() == qname
+        && parsed.questions[0].qtype == *qtype;
+    if !question_ok {
+        warn!("Refusing to cache {} {}: question section mismatch", name, qtype.name());
+        return None;
+    }
+
+    // Follow the CNAME chain from the qname through the answer section
+    let mut chain = vec![qname.clone()];
+    let mut i = 0;
+    while i < chain.len() && chain.len() <= parsed.answers.len() + 1 {
+        let target = parsed.answers.iter()
+            .filter(|r| r.rtype == RecordType::CNAME && r.name.to_lowercase() == chain[i])
+            .find_map(|r| packet::parse_name_at_offset(response, r.rdata_offset).ok())
+            .map(|t| t.to_lowercase());
+        if let Some(t) = target {
+            if !chain.contains(&t) {
+                chain.push(t);
+            }
+        }
+        i += 1;
+    }
+
+    let before = parsed.answers.len() + parsed.authorities.len() + parsed.additionals.len();
+    let had_answers = !parsed.answers.is_empty();
+
+    parsed.answers.retain(|r| r.ttl <= MAX_PLAUSIBLE_TTL && chain.contains(&r.name.to_lowercase()));
+    parsed.authorities.retain(|r| {
+        let owner = r.name.to_lowercase();
+        r.ttl <= MAX_PLAUSIBLE_TTL && chain.iter().any(|c| in_zone(c, &owner))
+    });
+
+    let zone = parsed.authorities.iter()
+        .map(|r| r.name.to_lowercase())
+        .max_by_key(|z| z.len())
+        .unwrap_or_else(|| qname.split_once('.').map(|(_, parent)| parent.to_string()).unwrap_or_default());
+    parsed.additionals.retain(|r| {
+        r.rtype == RecordType::OPT
+            || (r.ttl <= MAX_PLAUSIBLE_TTL && in_zone(&r.name.to_lowercase(), &zone))
+    });
+
+    if had_answers && parsed.answers.is_empty() {
+        warn!("Refusing to cache {} {}: no answer records survived validation", name, qtype.name());
+        return None;
+    }
+
+    let after = parsed.answers.len() + parsed.authorities.len() + parsed.additionals.len();
+    if after == before {
+        return Some(response.to_vec());
+    }
+    debug!("Stripped {} suspicious record(s) from {} {} before caching", before - after, name, qtype.name());
+    Some(parsed.to_wire())
+}
+
+/// `name` equals `zone` or lies below it (the root zone "" contains everything)
+fn in_zone(name: &str, zone: &str) -> bool {
+    zone.is_empty()
+        || name == zone
+        || (name.len() > zone.len() && name.ends_with(zone) && name.as_bytes()[name.len() - zone.len() - 1] == b'.')
+}
+
+/// TTL alchemy switched off, for tests that want TTLs passed through untouched
+#[cfg(test)]
+pub(crate) fn alchemy() -> TtlAlchemyConfig {
+    TtlAlchemyConfig {
+        enabled: false,
+        min_ttl: 0,
+        max_ttl: 86400,
+        frequency_weight: 0.0,
+        volatility_weight: 0.0,
+        type_max_ttl: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::packet::DnsRecord;
+
+    fn config() -> CacheConfig {
+        CacheConfig {
+            max_entries: 100,
+            max_entry_bytes: 4096,
+            serve_stale: false,
+            serve_stale_domains: Vec::new(),
+            stale_ttl_secs: 0,
+            serve_stale_on_error: false,
+            stale_answer_ttl: 30,
+            prefetch_on_read_threshold: 0.0,
+            verify_sample_rate: 0.0,
+            verify_interval_secs: 300,
+            verify_max_per_round: 10,
+            answer_ttl_jitter: 0.0,
+            ecs_scoped: false,
+            strict_validation: true,
+            eviction_log: false,
+            no_cache_types: Vec::new(),
+            override_min_ttl: None,
+            override_min_ttl_keeps_zero: false,
+            ttl_mode: TtlMode::Alchemy,
+            admission_policy: AdmissionPolicy::Always,
+            admission_window_secs: 60,
+            backend: CacheBackend::Memory,
+            redis: Default::default(),
+        }
+    }
+
+    fn cache() -> CacheLayer {
+        CacheLayer::new(&config(), &alchemy())
+    }
+
+    fn response(qname: &str, qtype: RecordType, answers: Vec<DnsRecord>) -> Vec<u8> {
+        let mut parsed = packet::parse_packet(&packet::build_query(0x1234, qname, qtype, true)).unwrap();
+        parsed.header.qr = true;
+        parsed.answers = answers;
+        parsed.to_wire()
+    }
+
+    fn a(name: &str, ttl: u32, ip: [u8; 4]) -> DnsRecord {
+        DnsRecord::new(name, RecordType::A, ttl, ip.to_vec())
+    }
+
+    fn name_wire(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        for label in name.split('.') {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    async fn cached_answers(cache: &CacheLayer, name: &str, qtype: &RecordType) -> Option<Vec<String>> {
+        let hit = cache.get(name, qtype).await?;
+        let parsed = packet::parse_packet(&hit.raw_response).unwrap();
+        Some(parsed.answers.iter().map(|r| r.name.clone()).collect())
+    }
+
+    #[tokio::test]
+    async fn test_injected_unrelated_record_not_cached() {
+        let cache = cache();
+        let resp = response("www.example.com", RecordType::A, vec![
+            a("www.example.com", 300, [192, 0, 2, 1]),
+            a("bank.example.net", 300, [203, 0, 113, 66]),
+        ]);
+        cache.insert("www.example.com", &RecordType::A, &resp, "test", Transport::Udp).await;
+
+        assert_eq!(cached_answers(&cache, "www.example.com", &RecordType::A).await.unwrap(), vec!["www.example.com"]);
+        assert!(cache.get("bank.example.net", &RecordType::A).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cname_chain_kept() {
+        let cache = cache();
+        let resp = response("www.example.com", RecordType::A, vec![
+            DnsRecord::new("www.example.com", RecordType::CNAME, 300, name_wire("cdn.example.org")),
+            a("cdn.example.org", 60, [192, 0, 2, 7]),
+        ]);
+        cache.insert("www.example.com", &RecordType::A, &resp, "test", Transport::Udp).await;
+
+        let names = cached_answers(&cache, "www.example.com", &RecordType::A).await.unwrap();
+        assert_eq!(names, vec!["www.example.com", "cdn.example.org"]);
+    }
+
+    #[tokio::test]
+    async fn test_inspect_entry_decodes_records() {
+        let cache = cache();
+        let resp = response("www.example.com", RecordType::A, vec![
+            DnsRecord::new("www.example.com", RecordType::CNAME, 300, packet::encode_name("web.example.com")),
+            a("web.example.com", 120, [192, 0, 2, 1]),
+            a("web.example.com", 120, [192, 0, 2, 2]),
+        ]);
+        cache.insert("www.example.com", &RecordType::A, &resp, "upstream-x", Transport::Udp).await;
+
+        let entry = cache.inspect_entry("WWW.example.com", &RecordType::A, None).unwrap();
+        assert_eq!(entry["upstream"], "upstream-x");
+        assert_eq!(entry["stale"], false);
+        assert_eq!(entry["original_ttl"], 120);
+        let data: Vec<&str> = entry["answers"].as_array().unwrap().iter()
+            .map(|r| r["data"].as_str().unwrap())
+            .collect();
+        // CNAME target is written compressed by to_wire, so this exercises pointer decoding
+        assert_eq!(data, vec!["web.example.com", "192.0.2.1", "192.0.2.2"]);
+        assert!(cache.inspect_entry("missing.example.com", &RecordType::A, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_inspect_entry_flags_spf_txt() {
+        let cache = cache();
+        let txt = |text: &str| {
+            let mut rdata = vec![text.len() as u8];
+            rdata.extend_from_slice(text.as_bytes());
+            DnsRecord::new("example.com", RecordType::TXT, 300, rdata)
+        };
+        let resp = response("example.com", RecordType::TXT, vec![txt("v=spf1 mx -all"), txt("hello")]);
+        cache.insert("example.com", &RecordType::TXT, &resp, "test", Transport::Udp).await;
+
+        let entry = cache.inspect_entry("example.com", &RecordType::TXT, None).unwrap();
+        let answers = entry["answers"].as_array().unwrap();
+        assert_eq!(answers[0]["data"], "\"v=spf1 mx -all\"");
+        assert_eq!(answers[0]["spf"], true);
+        assert!(answers[1].get("spf").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_question_mismatch_and_garbage_ttl_rejected() {
+        let cache = cache();
+        let resp = response("other.example.com", RecordType::A, vec![a("other.example.com", 300, [192, 0, 2, 1])]);
+        cache.insert("www.example.com", &RecordType::A, &resp, "test", Transport::Udp).await;
+        assert!(cache.get("www.example.com", &RecordType::A).await.is_none());
+
+        let resp = response("www.example.com", RecordType::A, vec![a("www.example.com", 0x8000_0000, [192, 0, 2, 1])]);
+        cache.insert("www.example.com", &RecordType::A, &resp, "test", Transport::Udp).await;
+        assert!(cache.get("www.example.com", &RecordType::A).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_capacity_eviction_logged() {
+        let config = CacheConfig { max_entries: 2, eviction_log: true, ..config() };
+        let cache = CacheLayer::new(&config, &alchemy());
+        for (i, name) in ["one.example", "two.example", "three.example"].iter().enumerate() {
+            cache.insert(name, &RecordType::A, &response(name, RecordType::A, vec![a(name, 300, [192, 0, 2, i as u8])]), "test", Transport::Udp).await;
+        }
+
+        let log = cache.recent_evictions();
+        let evictions = log["evictions"].as_array().unwrap();
+        assert_eq!(evictions.len(), 1);
+        assert_eq!(evictions[0]["reason"], "capacity");
+        assert_eq!(evictions[0]["type"], "A");
+        assert_eq!(cache.entries.len(), 2);
+
+        // Expired past the stale window: dropped on lookup and logged as such
+        cache.backdate("three.example", &RecordType::A, 301);
+        assert!(cache.get("three.example", &RecordType::A).await.is_none());
+        assert_eq!(cache.recent_evictions()["evictions"][0]["reason"], "expired");
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_through_trait() {
+        let cache: Arc<dyn Cache> = build(&config(), &alchemy(), 500).await.unwrap();
+
+        let resp = response("www.example.com", RecordType::A, vec![a("www.example.com", 300, [192, 0, 2, 1])]);
+        cache.insert("www.example.com", &RecordType::A, &resp, "test", Transport::Udp).await;
+        cache.record_hit("www.example.com", &RecordType::A, None).await;
+        assert_eq!(cache.get("www.example.com", &RecordType::A).await.unwrap().upstream_name, "test");
+        assert_eq!(cache.list_entries()[0]["hits"], 1);
+        assert_eq!(cache.get_stats()["entries"], 1);
+
+        cache.flush().await;
+        assert!(cache.get("www.example.com", &RecordType::A).await.is_none());
+        assert_eq!(cache.get_stats()["entries"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_stale_answer_ttl_configured_and_capped() {
+        let config = CacheConfig { serve_stale: true, stale_ttl_secs: 600, stale_answer_ttl: 120, ..config() };
+        let cache = CacheLayer::new(&config, &alchemy());
+        let resp = response("stale.example.com", RecordType::A, vec![a("stale.example.com", 60, [192, 0, 2, 1])]);
+        cache.insert("stale.example.com", &RecordType::A, &resp, "test", Transport::Udp).await;
+
+        // 40s past the TTL: configured stale TTL, on both serve-stale paths
+        cache.backdate("stale.example.com", &RecordType::A, 100);
+        assert_eq!(cache.get("stale.example.com", &RecordType::A).await.unwrap().remaining_ttl, 120);
+        assert_eq!(cache.get_stale("stale.example.com", &RecordType::A).await.unwrap().remaining_ttl, 120);
+
+        // 50s left in the stale window: the answer must not outlive it
+        cache.backdate("stale.example.com", &RecordType::A, 510);
+        assert_eq!(cache.get("stale.example.com", &RecordType::A).await.unwrap().remaining_ttl, 50);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_skips_unpopular_entries() {
+        let cache = cache();
+        for name in ["once.example.com", "popular.example.com"] {
+            cache.insert(name, &RecordType::A, &response(name, RecordType::A, vec![a(name, 100, [192, 0, 2, 1])]), "test", Transport::Udp).await;
+            cache.backdate(name, &RecordType::A, 95);
+        }
+        for _ in 0..3 {
+            cache.record_hit("popular.example.com", &RecordType::A, None).await;
+        }
+
+        let candidates = cache.get_prefetch_candidates(0.1, 2).await;
+        assert_eq!(candidates, vec![("popular.example.com".to_string(), RecordType::A)]);
+        assert_eq!(cache.get_prefetch_candidates(0.1, 0).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_budget_goes_to_priority_types() {
+        let cache = cache();
+        for (name, qtype, rdata) in [
+            ("txt.example.com", RecordType::TXT, vec![4, b'm', b'e', b'o', b'w']),
+            ("a.example.com", RecordType::A, vec![192, 0, 2, 1]),
+            ("ns.example.com", RecordType::NS, packet::encode_name("ns1.example.com")),
+        ] {
+            let record = DnsRecord::new(name, qtype, 100, rdata);
+            cache.insert(name, &qtype, &response(name, qtype, vec![record]), "test", Transport::Udp).await;
+            cache.backdate(name, &qtype, 95);
+        }
+        let mut config: crate::config::PrefetchConfig = toml::from_str("max_per_round = 2").unwrap();
+        config.type_priority = HashMap::from([("ns".to_string(), 2), ("A".to_string(), 1)]);
+        let plan = crate::prefetch::PrefetchPlan::new(&config);
+
+        let selected = plan.select(cache.get_prefetch_candidates(0.1, 0).await);
+        assert_eq!(selected, vec![("ns.example.com".to_string(), RecordType::NS), ("a.example.com".to_string(), RecordType::A)]);
+        assert_eq!(plan.get_stats()["type_order"][0]["type"], "NS");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response_not_cached() {
+        let config = CacheConfig { max_entry_bytes: 200, ..config() };
+        let cache = CacheLayer::new(&config, &alchemy());
+
+        let small = response("small.example.com", RecordType::A, vec![a("small.example.com", 300, [192, 0, 2, 1])]);
+        let big = response("big.example.com", RecordType::A, (1..=20).map(|i| a("big.example.com", 300, [192, 0, 2, i])).collect());
+        assert!(small.len() <= 200 && big.len() > 200);
+        cache.insert("small.example.com", &RecordType::A, &small, "test", Transport::Udp).await;
+        cache.insert("big.example.com", &RecordType::A, &big, "test", Transport::Udp).await;
+
+        assert!(cache.get("small.example.com", &RecordType::A).await.is_some());
+        assert!(cache.get("big.example.com", &RecordType::A).await.is_none());
+        let stats = cache.get_stats();
+        assert_eq!(stats["entries"], 1);
+        assert_eq!(stats["oversized_skipped"], 1);
+        let overhead = std::mem::size_of::<CacheKey>() + std::mem::size_of::<CacheEntry>();
+        assert_eq!(stats["memory_bytes"], overhead + "small.example.com".len() + small.len() + "test".len());
+    }
+
+    #[tokio::test]
+    async fn test_no_cache_types_skipped() {
+        let config = CacheConfig { no_cache_types: vec!["soa".to_string()], ..config() };
+        let cache = CacheLayer::new(&config, &alchemy());
+
+        let mut soa_rdata = name_wire("ns1.example.com");
+        soa_rdata.extend(name_wire("hostmaster.example.com"));
+        soa_rdata.extend([0u8; 20]);
+        let soa = response("example.com", RecordType::SOA, vec![DnsRecord::new("example.com", RecordType::SOA, 3600, soa_rdata)]);
+        cache.insert("example.com", &RecordType::SOA, &soa, "test", Transport::Udp).await;
+        let a = response("example.com", RecordType::A, vec![a("example.com", 300, [192, 0, 2, 1])]);
+        cache.insert("example.com", &RecordType::A, &a, "test", Transport::Udp).await;
+
+        assert!(cache.get("example.com", &RecordType::SOA).await.is_none());
+        assert!(cache.get("example.com", &RecordType::A).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_override_min_ttl_floors_served_ttl() {
+        let mut config = CacheConfig { override_min_ttl: Some(120), ..config() };
+        let alchemy = alchemy();
+        let cache = CacheLayer::new(&config, &alchemy);
+        let short = response("short.example.com", RecordType::A, vec![a("short.example.com", 5, [192, 0, 2, 1])]);
+        cache.insert("short.example.com", &RecordType::A, &short, "test", Transport::Udp).await;
+        assert_eq!(cache.get("short.example.com", &RecordType::A).await.unwrap().remaining_ttl, 120);
+        // Longer TTLs are left as they are
+        let long = response("long.example.com", RecordType::A, vec![a("long.example.com", 3600, [192, 0, 2, 2])]);
+        cache.insert("long.example.com", &RecordType::A, &long, "test", Transport::Udp).await;
+        assert_eq!(cache.get("long.example.com", &RecordType::A).await.unwrap().remaining_ttl, 3600);
+
+        // TTL 0 is floored too, unless told to keep it
+        let zero = response("zero.example.com", RecordType::A, vec![a("zero.example.com", 0, [192, 0, 2, 3])]);
+        cache.insert("zero.example.com", &RecordType::A, &zero, "test", Transport::Udp).await;
+        assert_eq!(cache.get("zero.example.com", &RecordType::A).await.unwrap().remaining_ttl, 120);
+        config.override_min_ttl_keeps_zero = true;
+        let cache = CacheLayer::new(&config, &alchemy);
+        cache.insert("zero.example.com", &RecordType::A, &zero, "test", Transport::Udp).await;
+        assert!(cache.get("zero.example.com", &RecordType::A).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_second_miss_admission() {
+        let mut config = CacheConfig { admission_policy: AdmissionPolicy::SecondMiss, ..config() };
+        let alchemy = alchemy();
+        let cache = CacheLayer::new(&config, &alchemy);
+        let once = response("once.example.com", RecordType::A, vec![a("once.example.com", 300, [192, 0, 2, 1])]);
+        let twice = response("twice.example.com", RecordType::A, vec![a("twice.example.com", 300, [192, 0, 2, 2])]);
+
+        cache.insert("once.example.com", &RecordType::A, &once, "test", Transport::Udp).await;
+        assert!(cache.get("once.example.com", &RecordType::A).await.is_none());
+        cache.insert("twice.example.com", &RecordType::A, &twice, "test", Transport::Udp).await;
+        cache.insert("twice.example.com", &RecordType::A, &twice, "test", Transport::Udp).await;
+        assert!(cache.get("twice.example.com", &RecordType::A).await.is_some());
+        assert_eq!(cache.get_stats()["admission_rejected"], 2);
+
+        // A second miss outside the window counts as a first one again
+        config.admission_window_secs = 0;
+        let cache = CacheLayer::new(&config, &alchemy);
+        cache.insert("twice.example.com", &RecordType::A, &twice, "test", Transport::Udp).await;
+        cache.insert("twice.example.com", &RecordType::A, &twice, "test", Transport::Udp).await;
+        assert!(cache.get("twice.example.com", &RecordType::A).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_serve_stale_domains_restrict_stale_answers() {
+        let config = CacheConfig { serve_stale_domains: vec!["critical.example".to_string()], stale_ttl_secs: 600, ..config() };
+        let cache = CacheLayer::new(&config, &alchemy());
+        for name in ["api.critical.example", "www.other.example"] {
+            cache.insert(name, &RecordType::A, &response(name, RecordType::A, vec![a(name, 60, [192, 0, 2, 1])]), "test", Transport::Udp).await;
+            cache.backdate(name, &RecordType::A, 100);
+        }
+        let stale = cache.get("api.critical.example", &RecordType::A).await.unwrap();
+        assert_eq!(stale.remaining_ttl, 30);
+        assert!(stale.upstream_name.ends_with("(stale)"));
+        assert!(cache.get("www.other.example", &RecordType::A).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_answer_ttl_jitter_varies_remaining_ttl() {
+        let mut config = CacheConfig { answer_ttl_jitter: 0.1, ..config() };
+        let cache = CacheLayer::new(&config, &alchemy());
+        let name = "popular.example.com";
+        cache.insert(name, &RecordType::A, &response(name, RecordType::A, vec![a(name, 3600, [192, 0, 2, 1])]), "test", Transport::Udp).await;
+
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..50 {
+            let ttl = cache.get(name, &RecordType::A).await.unwrap().remaining_ttl;
+            assert!((3600 - 360..=3600).contains(&ttl), "ttl {} jittered too far", ttl);
+            seen.insert(ttl);
+        }
+        assert!(seen.len() > 1, "remaining TTL never varied");
+
+        // Never below 1, and nothing to take off a 1s remainder
+        assert_eq!(config.jittered_ttl(1), 1);
+        assert!(config.jittered_ttl(10) >= 9);
+        config.answer_ttl_jitter = 0.0;
+        assert_eq!(config.jittered_ttl(3600), 3600);
+    }
+
+    #[tokio::test]
+    async fn test_large_snapshot_does_not_block_lookups() {
+        let mut cache = cache();
+        cache.config.max_entries = 200_000;
+        let resp = response("www.example.com", RecordType::A, vec![a("www.example.com", 300, [192, 0, 2, 1])]);
+        for i in 0..100_000 {
+            cache.entries.insert(CacheKey { name: format!("host{}.example.com", i), qtype: 1, scope: None }, CacheEntry {
+                raw_response: resp.clone(),
+                original_ttl: 300,
+                alchemized_ttl: 300,
+                inserted_at: Instant::now(),
+                upstream_name: "test".to_string(),
+                transport: Transport::Udp,
+                hit_count: 0,
+                last_rdata_hash: 0,
+                rdata_changes: 0,
+            });
+        }
+        let cache = Arc::new(cache);
+        let path = std::env::temp_dir().join(format!("neko-dns-cache-snapshot-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        // Single-threaded test runtime: the lookups only get to run while the snapshot yields
+        let done = Arc::new(AtomicBool::new(false));
+        let lookups = {
+            let (cache, done) = (cache.clone(), done.clone());
+            tokio::spawn(async move {
+                let mut served = 0;
+                while !done.load(Ordering::Relaxed) {
+                    assert!(cache.get("host7.example.com", &RecordType::A).await.is_some());
+                    served += 1;
+                    tokio::task::yield_now().await;
+                }
+                served
+            })
+        };
+        // A second trigger while the first is still writing is dropped
+        let (first, second) = tokio::join!(cache.snapshot(&path, 1000, Compression::None), cache.snapshot(&path, 1000, Compression::None));
+        done.store(true, Ordering::Relaxed);
+        assert_eq!(first.unwrap(), Some(100_000));
+        assert_eq!(second.unwrap(), None);
+        let served = lookups.await.unwrap();
+        assert!(served >= 50, "only {} lookups ran during the snapshot", served);
+        assert_eq!(cache.get_stats()["snapshot"]["last_entries"], 100_000);
+
+        let mut restored = self::cache();
+        restored.config.max_entries = 200_000;
+        assert_eq!(restored.restore(&path).unwrap(), 100_000);
+        let _ = std::fs::remove_file(&path);
+        let hit = restored.get("host99999.example.com", &RecordType::A).await.unwrap();
+        assert_eq!(hit.raw_response, resp);
+        assert!(hit.remaining_ttl <= 300 && hit.remaining_ttl > 290);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_mode_selects_stored_ttl() {
+        let mut config = config();
+        let alchemy = TtlAlchemyConfig { enabled: true, min_ttl: 60, max_ttl: 3600, frequency_weight: 0.3, volatility_weight: 0.5, type_max_ttl: [("A".to_string(), 30)].into_iter().collect() };
+        let upstream = response("example.com", RecordType::A, vec![a("example.com", 10, [192, 0, 2, 1])]);
+        // Same 10s upstream TTL: alchemy lifts it to min_ttl then caps it at type_max_ttl,
+        // clamp only lifts it, passthrough keeps it
+        for (mode, expected) in [(TtlMode::Alchemy, 30), (TtlMode::Clamp, 60), (TtlMode::Passthrough, 10)] {
+            config.ttl_mode = mode;
+            let cache = CacheLayer::new(&config, &alchemy);
+            cache.insert("example.com", &RecordType::A, &upstream, "test", Transport::Udp).await;
+            assert_eq!(cache.get("example.com", &RecordType::A).await.unwrap().remaining_ttl, expected, "{:?}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scoped_entry_hits_stale_and_export() {
+        let config = CacheConfig { stale_ttl_secs: 600, serve_stale_on_error: true, ecs_scoped: true, ..config() };
+        let cache = CacheLayer::new(&config, &alchemy());
+        let name = "geo.example.com";
+        let mut parsed = packet::parse_packet(&response(name, RecordType::A, vec![a(name, 60, [192, 0, 2, 1])])).unwrap();
+        // ECS 198.51.100.0/24, scope /24
+        parsed.additionals.push(DnsRecord::new("", RecordType::OPT, 0, vec![0, 8, 0, 7, 0, 1, 24, 24, 198, 51, 100]));
+        cache.insert(name, &RecordType::A, &parsed.to_wire(), "test", Transport::Udp).await;
+
+        let client = ClientSubnet { addr: "198.51.100.7".parse().unwrap(), source_prefix: 24, scope_prefix: 0 };
+        let other = ClientSubnet { addr: "203.0.113.7".parse().unwrap(), source_prefix: 24, scope_prefix: 0 };
+        let scope = ClientNet::parse("198.51.100.0/24");
+        let hit = cache.get_for_subnet(name, &RecordType::A, Some(&client)).await.unwrap();
+        assert_eq!(hit.scope, scope);
+        cache.record_hit(name, &RecordType::A, hit.scope).await;
+        assert_eq!(cache.inspect_entry(name, &RecordType::A, scope).unwrap()["hits"], 1);
+        assert!(cache.export_entry(name, &RecordType::A, scope).is_some());
+        assert!(cache.export_entry(name, &RecordType::A, None).is_none());
+
+        for mut entry in cache.entries.iter_mut() {
+            entry.inserted_at -= Duration::from_secs(100);
+        }
+        assert!(cache.get_stale(name, &RecordType::A).await.is_none());
+        assert!(cache.get_stale_for_subnet(name, &RecordType::A, Some(&other)).await.is_none());
+        assert_eq!(cache.get_stale_for_subnet(name, &RecordType::A, Some(&client)).await.unwrap().scope, scope);
+
+        // Past the stale window the entry is dropped, and its scope with it
+        for mut entry in cache.entries.iter_mut() {
+            entry.inserted_at -= Duration::from_secs(600);
+        }
+        assert!(cache.get_for_subnet(name, &RecordType::A, Some(&client)).await.is_none());
+        assert!(cache.scope_prefixes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_snapshot_restores_identically() {
+        let cache = cache();
+        for i in 0..50 {
+            let name = format!("host{}.example.com", i);
+            let resp = response(&name, RecordType::A, vec![a(&name, 300, [192, 0, 2, i as u8])]);
+            cache.insert(&name, &RecordType::A, &resp, "test", Transport::Udp).await;
+        }
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let path = std::env::temp_dir().join(format!("neko-dns-cache-snapshot-{:?}-{}.jsonl", compression, std::process::id()));
+            let path = path.to_str().unwrap().to_string();
+            assert_eq!(cache.snapshot(&path, 7, compression).await.unwrap(), Some(50));
+            let restored = self::cache();
+            assert_eq!(restored.restore(&path).unwrap(), 50);
+            let _ = std::fs::remove_file(&path);
+            for i in 0..50 {
+                let name = format!("host{}.example.com", i);
+                let hit = restored.get(&name, &RecordType::A).await.unwrap();
+                assert_eq!(hit.raw_response, cache.export_entry(&name, &RecordType::A, None).unwrap());
+                assert_eq!(restored.inspect_entry(&name, &RecordType::A, None).unwrap()["upstream"], "test");
+            }
+        }
+    }
+}
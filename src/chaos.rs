@@ -1,64 +1,337 @@
-use crate::config::ChaosConfig;
+use crate::config::{ChaosConfig, ChaosFailureMode, DomainMatch, DomainPattern};
+use crate::dns::types::RecordType;
+use crate::subnet::ClientNet;
 use rand::Rng;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::warn;
 
 /// Chaos Engine - カオスエンジニアリング用の障害注入
 ///
-/// 有効化すると、設定された確率でSERVFAILを返す。
+/// 有効化すると、設定された確率で障害（SERVFAIL / REFUSED / 無応答 / 遅延SERVFAIL）を注入する。
 /// 自宅ネットワークのアプリケーションがDNS障害に耐えられるかテストできる。
 /// 特定のドメインを除外リストに入れることで、重要なサービスは保護可能。
+/// クエリタイプ別の確率や、対象クライアント(CIDR)の絞り込みもできる。
 pub struct ChaosEngine {
     config: ChaosConfig,
+    clients: Vec<ClientNet>,
+    /// chaos.exclude_domains, compiled once
+    exclusions: Vec<Exclusion>,
     injected_count: AtomicU64,
     checked_count: AtomicU64,
+    /// Injections per mode, indexed like MODES
+    mode_counts: [AtomicU64; 4],
+    delayed_count: AtomicU64,
+    /// Upper bound for injected delays (the server's query timeout)
+    max_delay: Duration,
 }
 
+const MODES: [(ChaosFailureMode, &str); 4] = [
+    (ChaosFailureMode::Servfail, "servfail"),
+    (ChaosFailureMode::Refused, "refused"),
+    (ChaosFailureMode::Timeout, "timeout"),
+    (ChaosFailureMode::Delay, "delay"),
+];
+
+/// A compiled chaos.exclude_domains entry; names are matched lowercased
+enum Exclusion {
+    Suffix(String),
+    /// Without the trailing dot
+    Exact(String),
+    /// The glob split at its "*"s
+    Wildcard(Vec<String>),
+    Regex(Box<regex_automata::dfa::regex::Regex>),
+}
+
+impl Exclusion {
+    fn compile(pattern: &DomainPattern) -> anyhow::Result<Self> {
+        let (kind, pattern) = match pattern {
+            DomainPattern::Suffix(suffix) => (DomainMatch::Suffix, suffix),
+            DomainPattern::Rule { kind, pattern } => (*kind, pattern),
+        };
+        let pattern = pattern.to_lowercase();
+        Ok(match kind {
+            DomainMatch::Suffix => Self::Suffix(pattern),
+            DomainMatch::Exact => Self::Exact(pattern.trim_end_matches('.').to_string()),
+            DomainMatch::Wildcard => Self::Wildcard(pattern.trim_end_matches('.').split('*').map(str::to_string).collect()),
+            DomainMatch::Regex => Self::Regex(Box::new(
+                regex_automata::dfa::regex::Regex::builder()
+                    .syntax(regex_automata::util::syntax::Config::new().case_insensitive(true).unicode(false))
+                    .build(&pattern)?,
+            )),
+        })
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            Self::Suffix(suffix) => domain.ends_with(suffix.as_str()),
+            Self::Exact(name) => domain.trim_end_matches('.') == name,
+            Self::Wildcard(parts) => glob_matches(parts, domain.trim_end_matches('.')),
+            Self::Regex(regex) => regex.is_match(domain),
+        }
+    }
+}
+
+/// `parts` is a glob split at "*": first and last are anchored, the rest found in order
+fn glob_matches(parts: &[String], name: &str) -> bool {
+    let (first, rest) = match parts.split_first() {
+        Some(split) => split,
+        None => return name.is_empty(),
+    };
+    let Some(mut name) = name.strip_prefix(first.as_str()) else { return false };
+    let Some((last, middle)) = rest.split_last() else { return name.is_empty() };
+    for part in middle {
+        match name.find(part.as_str()) {
+            Some(at) => name = &name[at + part.len()..],
+            None => return false,
+        }
+    }
+    name.len() >= last.len() && name.ends_with(last.as_str())
+}
+
+/// Error returned for chaos "timeout" injections: send no response at all
+#[derive(Debug)]
+pub struct ChaosDrop;
+
+impl std::fmt::Display for ChaosDrop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "chaos: response dropped")
+    }
+}
+
+impl std::error::Error for ChaosDrop {}
+
 impl ChaosEngine {
     pub fn new(config: &ChaosConfig) -> Self {
+        let clients = config.clients.iter()
+            .filter_map(|c| {
+                let parsed = ClientNet::parse(c);
+                if parsed.is_none() { warn!("🎲 Invalid chaos client network: {}", c); }
+                parsed
+            })
+            .collect();
+        let exclusions = config.exclude_domains.iter()
+            .filter_map(|p| match Exclusion::compile(p) {
+                Ok(exclusion) => Some(exclusion),
+                Err(e) => {
+                    warn!("🎲 Invalid chaos exclude_domains entry {:?}: {}", p, e);
+                    None
+                }
+            })
+            .collect();
         Self {
             config: config.clone(),
+            clients,
+            exclusions,
             injected_count: AtomicU64::new(0),
             checked_count: AtomicU64::new(0),
+            mode_counts: Default::default(),
+            delayed_count: AtomicU64::new(0),
+            max_delay: Duration::MAX,
         }
     }
 
-    /// Check if this query should fail (chaos injection)
-    pub fn should_fail(&self, domain: &str) -> bool {
-        if !self.config.enabled {
-            return false;
+    /// Never hold a query longer than `max` (the server's own query timeout)
+    pub fn with_max_delay(mut self, max: Duration) -> Self {
+        self.max_delay = max;
+        self
+    }
+
+    /// chaos.clients scope (everyone when empty)
+    fn client_in_scope(&self, client: Option<IpAddr>) -> bool {
+        self.config.clients.is_empty()
+            || client.is_some_and(|ip| self.clients.iter().any(|n| n.contains(ip)))
+    }
+
+    fn is_excluded(&self, domain: &str) -> bool {
+        let domain_lower = domain.to_lowercase();
+        self.exclusions.iter().any(|exclusion| exclusion.matches(&domain_lower))
+    }
+
+    /// Decide whether this query gets a failure injected, and which one.
+    /// `client` is None for internal queries (prefetch etc.), which are only
+    /// eligible when chaos isn't scoped to specific clients.
+    pub fn should_fail(&self, domain: &str, qtype: &RecordType, client: Option<IpAddr>) -> Option<ChaosFailureMode> {
+        if !self.config.enabled || self.config.failure_modes.is_empty() {
+            return None;
+        }
+        if !self.client_in_scope(client) {
+            return None;
         }
 
         self.checked_count.fetch_add(1, Ordering::Relaxed);
 
         // Check exclusion list
-        let domain_lower = domain.to_lowercase();
-        for excluded in &self.config.exclude_domains {
-            if domain_lower.ends_with(&excluded.to_lowercase()) {
-                return false;
-            }
+        if self.is_excluded(domain) {
+            return None;
         }
 
         // Roll the dice (CSPRNG - not predictable from system state)
         let roll: f64 = {
             use rand::rngs::OsRng;
-            use rand::Rng;
             OsRng.gen()
         };
-        if roll < self.config.servfail_probability {
-            self.injected_count.fetch_add(1, Ordering::Relaxed);
-            true
-        } else {
-            false
+        if roll >= self.probability_for(qtype) {
+            return None;
+        }
+
+        let mode = {
+            use rand::rngs::OsRng;
+            self.config.failure_modes[OsRng.gen_range(0..self.config.failure_modes.len())]
+        };
+        self.injected_count.fetch_add(1, Ordering::Relaxed);
+        if let Some(i) = MODES.iter().position(|(m, _)| *m == mode) {
+            self.mode_counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        Some(mode)
+    }
+
+    /// chaos.type_probabilities entry for this type, else servfail_probability
+    fn probability_for(&self, qtype: &RecordType) -> f64 {
+        let name = qtype.name();
+        self.config.type_probabilities.iter()
+            .find(|(t, _)| t.eq_ignore_ascii_case(&name))
+            .map(|(_, p)| *p)
+            .unwrap_or(self.config.servfail_probability)
+    }
+
+    /// How long the "delay" mode holds the answer
+    pub fn failure_delay(&self) -> Duration {
+        Duration::from_millis(self.config.failure_delay_ms).min(self.max_delay)
+    }
+
+    /// Latency injection: how long to hold back this (real) answer, if at all
+    pub fn should_delay(&self, domain: &str, client: Option<IpAddr>) -> Option<Duration> {
+        if !self.config.enabled || self.config.delay_probability <= 0.0
+            || !self.client_in_scope(client) || self.is_excluded(domain)
+        {
+            return None;
+        }
+        use rand::rngs::OsRng;
+        let roll: f64 = OsRng.gen();
+        if roll >= self.config.delay_probability {
+            return None;
         }
+        let (min, max) = (self.config.delay_min_ms, self.config.delay_max_ms.max(self.config.delay_min_ms));
+        let delay_ms = if min == max { min } else { OsRng.gen_range(min..=max) };
+        self.delayed_count.fetch_add(1, Ordering::Relaxed);
+        Some(Duration::from_millis(delay_ms).min(self.max_delay))
     }
 
     pub fn get_stats(&self) -> serde_json::Value {
+        let by_mode: serde_json::Map<String, serde_json::Value> = MODES.iter()
+            .zip(&self.mode_counts)
+            .map(|((_, name), count)| (name.to_string(), count.load(Ordering::Relaxed).into()))
+            .collect();
         serde_json::json!({
             "enabled": self.config.enabled,
             "probability": self.config.servfail_probability,
+            "type_probabilities": self.config.type_probabilities,
+            "clients": self.config.clients,
             "total_checked": self.checked_count.load(Ordering::Relaxed),
             "total_injected": self.injected_count.load(Ordering::Relaxed),
+            "injected_by_mode": by_mode,
+            "delay_probability": self.config.delay_probability,
+            "delays_injected": self.delayed_count.load(Ordering::Relaxed),
             "excluded_domains": self.config.exclude_domains,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chaos(extra: &str) -> ChaosEngine {
+        let config: ChaosConfig = toml::from_str(&format!("enabled = true\nservfail_probability = 0.0\n{}", extra)).unwrap();
+        ChaosEngine::new(&config)
+    }
+
+    #[test]
+    fn test_type_probability_overrides_default() {
+        let engine = chaos("type_probabilities = { AAAA = 1.0 }");
+        for _ in 0..50 {
+            assert_eq!(engine.should_fail("example.net", &RecordType::AAAA, None), Some(ChaosFailureMode::Servfail));
+            assert_eq!(engine.should_fail("example.net", &RecordType::A, None), None);
+        }
+    }
+
+    #[test]
+    fn test_configured_mode_is_used() {
+        let engine = chaos("type_probabilities = { A = 1.0 }\nfailure_modes = [\"refused\"]");
+        assert_eq!(engine.should_fail("example.net", &RecordType::A, None), Some(ChaosFailureMode::Refused));
+        assert_eq!(engine.get_stats()["injected_by_mode"]["refused"], 1);
+        assert_eq!(engine.get_stats()["injected_by_mode"]["servfail"], 0);
+    }
+
+    #[test]
+    fn test_client_scoping() {
+        let engine = chaos("type_probabilities = { A = 1.0 }\nclients = [\"192.0.2.0/24\", \"2001:db8::1\"]");
+        let a = RecordType::A;
+        assert!(engine.should_fail("example.net", &a, Some("192.0.2.77".parse().unwrap())).is_some());
+        assert!(engine.should_fail("example.net", &a, Some("::ffff:192.0.2.8".parse().unwrap())).is_some());
+        assert!(engine.should_fail("example.net", &a, Some("2001:db8::1".parse().unwrap())).is_some());
+        assert!(engine.should_fail("example.net", &a, Some("198.51.100.1".parse().unwrap())).is_none());
+        assert!(engine.should_fail("example.net", &a, Some("2001:db8::2".parse().unwrap())).is_none());
+        // Internal queries have no client and stay out of scoped chaos
+        assert!(engine.should_fail("example.net", &a, None).is_none());
+    }
+
+    #[test]
+    fn test_excluded_domain_never_fails() {
+        let engine = chaos("type_probabilities = { A = 1.0 }\nexclude_domains = [\"example.com\"]");
+        assert!(engine.should_fail("www.Example.com", &RecordType::A, None).is_none());
+    }
+
+    #[test]
+    fn test_delay_range_and_cap() {
+        let engine = chaos("delay_probability = 1.0\ndelay_min_ms = 100\ndelay_max_ms = 300");
+        for _ in 0..20 {
+            let d = engine.should_delay("slow.example.net", None).unwrap();
+            assert!(d >= Duration::from_millis(100) && d <= Duration::from_millis(300));
+        }
+        assert_eq!(engine.get_stats()["delays_injected"], 20);
+
+        let capped = chaos("delay_probability = 1.0\ndelay_min_ms = 10000\ndelay_max_ms = 10000\nexclude_domains = [\"example.com\"]")
+            .with_max_delay(Duration::from_millis(2000));
+        assert_eq!(capped.should_delay("slow.example.net", None), Some(Duration::from_millis(2000)));
+        assert_eq!(capped.failure_delay(), Duration::from_millis(2000));
+        assert_eq!(capped.should_delay("www.example.com", None), None);
+    }
+
+    #[test]
+    fn test_exclusion_match_types() {
+        let engine = chaos(r#"type_probabilities = { A = 1.0 }
+exclude_domains = [
+    "suffix.example",
+    { match = "exact", pattern = "Exact.example." },
+    { match = "wildcard", pattern = "*.bank.example" },
+    { match = "wildcard", pattern = "api-*.svc.example" },
+    { match = "regex", pattern = '^(mail|smtp)[0-9]+\.corp\.example$' },
+]"#);
+        let excluded = |name: &str| engine.should_fail(name, &RecordType::A, None).is_none();
+        // suffix (the default, plain strings)
+        assert!(excluded("www.suffix.example"));
+        // exact
+        assert!(excluded("exact.example"));
+        assert!(!excluded("www.exact.example"));
+        // wildcard
+        assert!(excluded("www.bank.example"));
+        assert!(excluded("a.b.bank.example."));
+        assert!(!excluded("bank.example"));
+        assert!(excluded("api-eu.svc.example"));
+        assert!(!excluded("web.svc.example"));
+        // regex
+        assert!(excluded("MAIL12.corp.example"));
+        assert!(!excluded("mail.corp.example"));
+        assert!(!excluded("mail1.corp.example.evil"));
+    }
+
+    #[test]
+    fn test_invalid_regex_exclusion_skipped() {
+        let engine = chaos("type_probabilities = { A = 1.0 }\nexclude_domains = [{ match = \"regex\", pattern = \"(\" }, \"example.com\"]");
+        assert_eq!(engine.exclusions.len(), 1);
+        assert!(engine.should_fail("www.example.com", &RecordType::A, None).is_none());
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::config::Compression;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Writer for a persisted file (cache snapshot, journal, infra cache), compressing as
+/// it streams per persist.compression. `finish` must be called to complete the file.
+pub enum FileWriter {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl FileWriter {
+    pub fn create(path: &str, compression: Compression) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match compression {
+            Compression::None => Self::Plain(file),
+            Compression::Gzip => Self::Gzip(flate2::write::GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Self::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    /// Write the compressor's trailer and flush everything to the file
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Self::Plain(file) => file,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(file) => file.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Open a persisted file for streaming reads, decompressing gzip or zstd by its magic
+/// bytes - files saved before persist.compression changed still load
+pub fn open(path: &str) -> io::Result<Box<dyn BufRead>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let head = file.fill_buf()?;
+    Ok(if head.starts_with(&GZIP_MAGIC) {
+        Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(file)))
+    } else if head.starts_with(&ZSTD_MAGIC) {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(file)?))
+    } else {
+        Box::new(file)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_round_trip_and_detection() {
+        let data = "line\n".repeat(10_000);
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let path = std::env::temp_dir().join(format!("neko-dns-compress-{:?}-{}", compression, std::process::id()));
+            let path = path.to_str().unwrap();
+            let mut out = FileWriter::create(path, compression).unwrap();
+            out.write_all(data.as_bytes()).unwrap();
+            out.finish().unwrap();
+            if compression != Compression::None {
+                assert!(std::fs::metadata(path).unwrap().len() < data.len() as u64 / 10);
+            }
+            let mut read = String::new();
+            open(path).unwrap().read_to_string(&mut read).unwrap();
+            assert_eq!(read, data);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -1,10 +1,30 @@
-use serde::Deserialize;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::dns::types::RecordType;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub listen: ListenConfig,
     pub upstreams: Vec<UpstreamConfig>,
+    /// How upstreams are picked for each forwarded query
+    #[serde(default)]
+    pub upstream_strategy: UpstreamStrategy,
+    /// A SERVFAIL from one upstream is returned only if no other upstream answers better
+    #[serde(default = "default_true")]
+    pub upstream_retry_servfail: bool,
+    /// Treat an empty A/AAAA NOERROR without SOA (silent filtering, not a real NODATA)
+    /// like a SERVFAIL: other upstreams, then later resolution stages, get a chance first
+    #[serde(default)]
+    pub upstream_detect_filtering: bool,
+    /// What to do when the TCP retry of a truncated UDP answer is truncated too
+    #[serde(default)]
+    pub tcp_still_truncated: StillTruncatedPolicy,
+    /// "production" turns every whimsical feature off, whatever its own section says
+    #[serde(default)]
+    pub profile: Profile,
     pub cache: CacheConfig,
     pub ttl_alchemy: TtlAlchemyConfig,
     pub prefetch: PrefetchConfig,
@@ -17,15 +37,181 @@ pub struct Config {
     #[serde(default)]
     pub neko_comment: NekoCommentConfig,
     #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub selftest: SelfTestConfig,
+    #[serde(default)]
+    pub persist: PersistConfig,
+    #[serde(default)]
+    pub parse: ParseConfig,
+    #[serde(default)]
+    pub debug: DebugConfig,
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub alerting: AlertingConfig,
+    #[serde(default)]
+    pub resolution: ResolutionConfig,
+    #[serde(default)]
     pub recursive: RecursiveConfig,
     #[serde(default)]
     pub local_zones: Vec<LocalZoneConfig>,
+    #[serde(default, rename = "health_domain")]
+    pub health_domains: Vec<HealthDomainConfig>,
+    #[serde(default, rename = "authoritative_zone")]
+    pub authoritative_zones: Vec<AuthoritativeZoneConfig>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ListenConfig {
     pub address: String,
     pub port: u16,
+    /// Serve DNS over UDP on address:port
+    #[serde(default = "default_true")]
+    pub udp: bool,
+    /// Serve DNS over TCP on address:port (off e.g. behind a stub that never falls back)
+    #[serde(default = "default_true")]
+    pub tcp: bool,
+    /// Strip authority/additional sections from responses (OPT is kept)
+    #[serde(default)]
+    pub minimal_responses: bool,
+    /// Reorder answers as CNAME chain then the queried type, for clients that only read the first record
+    #[serde(default)]
+    pub answer_queried_type_first: bool,
+    /// Query names with more labels than this are answered FORMERR without resolving
+    #[serde(default = "default_max_query_labels")]
+    pub max_query_labels: usize,
+    /// In-flight query limit (UDP datagrams + TCP connections); beyond it new ones are dropped
+    #[serde(default = "default_max_concurrent_queries")]
+    pub max_concurrent_queries: usize,
+    /// Queries one TCP connection may have in flight at once (RFC 7766 pipelining)
+    #[serde(default = "default_tcp_pipeline_depth")]
+    pub tcp_pipeline_depth: usize,
+    /// A TCP client must get its next whole message in within this long, or the connection
+    /// is closed (idle keepalives and half-sent messages alike)
+    #[serde(default = "default_tcp_idle_timeout_ms")]
+    pub tcp_idle_timeout_ms: u64,
+    /// UDP payload size advertised in our OPT record to EDNS clients
+    #[serde(default = "default_edns_udp_size")]
+    pub edns_udp_size: u16,
+    /// Largest UDP response sent whatever size the client advertises (at least 512);
+    /// bigger ones go out truncated so the client retries over TCP
+    #[serde(default)]
+    pub max_udp_response_size: Option<u16>,
+    /// Compress names in responses (RFC 1035 §4.1.4). Off writes every name in full for
+    /// clients that mishandle pointers; responses get bigger and hit TC=1 sooner over UDP
+    #[serde(default = "default_true")]
+    pub compress_responses: bool,
+    /// Query types answered without resolving anything (e.g. ["HTTPS", "SVCB"] so clients fall back to A/AAAA)
+    #[serde(default)]
+    pub refuse_types: Vec<String>,
+    /// How refuse_types are answered: empty NOERROR ("nodata") or "refused"
+    #[serde(default)]
+    pub refuse_types_response: RefuseTypesResponse,
+    /// Which clients get AAAA answers; "nodata_for_ipv4_clients" keeps hosts on
+    /// IPv4-only networks from trying (and timing out on) IPv6 first
+    #[serde(default)]
+    pub aaaa_policy: AaaaPolicy,
+    /// Client answers faster than this are held back to it, so cache hits and misses
+    /// time alike; every hit pays the full floor (0 = off)
+    #[serde(default)]
+    pub min_response_time_ms: u64,
+    /// Answer RFC 6761 special-use names ourselves: localhost (and its loopback
+    /// reverse names) synthesized, *.invalid NXDOMAIN; never resolved or forwarded
+    #[serde(default = "default_true")]
+    pub rfc6761: bool,
+    /// With rfc6761, REFUSE *.local (mDNS names, RFC 6762) instead of resolving them
+    #[serde(default)]
+    pub rfc6761_refuse_local: bool,
+    /// Serve the RFC 6303 reverse zones (10.in-addr.arpa, 168.192.in-addr.arpa, d.f.ip6.arpa...)
+    /// as empty local zones, so private address lookups never leave the box; a local_zone
+    /// or authoritative_zone covering the name still wins
+    #[serde(default = "default_true")]
+    pub rfc6303: bool,
+}
+
+impl ListenConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(bad) = self.refuse_types.iter().find(|t| RecordType::from_name(t).is_none()) {
+            anyhow::bail!("listen.refuse_types: unknown record type {:?}", bad);
+        }
+        if !self.udp && !self.tcp {
+            anyhow::bail!("listen.udp and listen.tcp are both off: nothing would be served");
+        }
+        Ok(())
+    }
+
+    /// true if queries of this type are answered without resolving (listen.refuse_types)
+    pub fn refuses(&self, qtype: &RecordType) -> bool {
+        self.refuse_types.iter().any(|t| RecordType::from_name(t).as_ref() == Some(qtype))
+    }
+
+    /// true if an AAAA query from `client` is answered NODATA (listen.aaaa_policy)
+    pub fn hides_aaaa_from(&self, client: IpAddr) -> bool {
+        match self.aaaa_policy {
+            AaaaPolicy::Normal => false,
+            AaaaPolicy::NodataForIpv4Clients => match client {
+                IpAddr::V4(_) => true,
+                IpAddr::V6(v6) => v6.to_ipv4_mapped().is_some(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefuseTypesResponse {
+    /// NOERROR with no answers (plus the synthetic SOA when negative.synthetic_soa is on)
+    #[default]
+    Nodata,
+    /// REFUSED
+    Refused,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AaaaPolicy {
+    /// AAAA answered as resolved, for everyone
+    #[default]
+    Normal,
+    /// AAAA queries from an IPv4 source address (IPv4-mapped included) get an empty NODATA
+    NodataForIpv4Clients,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Profile {
+    /// Every feature follows its own setting
+    #[default]
+    Default,
+    /// No curiosity walk, journey TXT, neko comment TXT or chaos
+    Production,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StillTruncatedPolicy {
+    /// Use what came over TCP as the answer, with TC cleared
+    #[default]
+    Accept,
+    /// Treat it as a failure of that server (counts against its trust / RTT)
+    Fail,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamStrategy {
+    /// Query every enabled upstream at once, first answer wins
+    #[default]
+    RaceAll,
+    /// Lowest average latency first, the rest as fallbacks
+    Fastest,
+    /// Random order weighted by trust score
+    Weighted,
+    /// Configured order, next one only on failure
+    Sequential,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -35,16 +221,244 @@ pub struct UpstreamConfig {
     pub port: u16,
     #[serde(default = "default_timeout_ms")]
     pub timeout_ms: u64,
+    /// DSCP codepoint (0-63) for queries to this upstream, e.g. 48 = CS6
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// Local address queries to this upstream are sent from (multi-homed hosts)
+    #[serde(default)]
+    pub source_address: Option<std::net::IpAddr>,
+    /// Shorten the timeout from recent latency (mean + 4·stddev) when another upstream
+    /// can still be tried; timeout_ms stays the ceiling
+    #[serde(default = "default_true")]
+    pub adaptive_timeout: bool,
+    /// Floor for the adaptive timeout
+    #[serde(default = "default_min_timeout_ms")]
+    pub min_timeout_ms: u64,
+    /// Always set the DO bit on queries to this upstream, so DNSSEC records get
+    /// fetched (and cached) for non-DO clients too
+    #[serde(default)]
+    pub set_do: bool,
+    /// EDNS UDP payload size put on queries to this upstream (adds an OPT if the client sent none)
+    #[serde(default)]
+    pub edns_size: Option<u16>,
+    /// Shadow upstream: asked alongside the others, but its answers are only compared
+    /// with the one returned to the client (for vetting a candidate upstream)
+    #[serde(default)]
+    pub shadow: bool,
+}
+
+/// The serde defaults; name and address are empty and must be set
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            address: String::new(),
+            port: 53,
+            timeout_ms: default_timeout_ms(),
+            dscp: None,
+            source_address: None,
+            adaptive_timeout: true,
+            min_timeout_ms: default_min_timeout_ms(),
+            set_do: false,
+            edns_size: None,
+            shadow: false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct CacheConfig {
     #[serde(default = "default_max_entries")]
     pub max_entries: usize,
+    /// Responses larger than this are served but not cached (a few huge TCP answers
+    /// shouldn't take the space of thousands of normal ones)
+    #[serde(default = "default_max_entry_bytes")]
+    pub max_entry_bytes: usize,
     #[serde(default)]
     pub serve_stale: bool,
+    /// When non-empty, only names under these domains (suffix match) are served stale,
+    /// whatever serve_stale says; everything else must be fresh
+    #[serde(default)]
+    pub serve_stale_domains: Vec<String>,
     #[serde(default = "default_stale_ttl")]
     pub stale_ttl_secs: u64,
+    /// Serve an expired entry (up to stale_ttl_secs old) only when fresh resolution fails
+    #[serde(default)]
+    pub serve_stale_on_error: bool,
+    /// TTL put on stale answers (RFC 8767 §4 recommends 30s); never runs past stale_ttl_secs
+    #[serde(default = "default_stale_answer_ttl")]
+    pub stale_answer_ttl: u32,
+    /// A hit on an entry with less than this fraction of its TTL left is answered from
+    /// the cache right away and refreshed in the background (0 = off)
+    #[serde(default)]
+    pub prefetch_on_read_threshold: f64,
+    /// Fraction of cached entries re-resolved each verify_interval_secs (cache bypassed)
+    /// and compared with what is cached, to catch corruption or divergent upstreams (0 = off)
+    #[serde(default)]
+    pub verify_sample_rate: f64,
+    #[serde(default = "default_verify_interval")]
+    pub verify_interval_secs: u64,
+    /// Cap on the re-resolutions of one round, whatever the sample rate and cache size
+    #[serde(default = "default_verify_max_per_round")]
+    pub verify_max_per_round: usize,
+    /// Take a random amount, up to this fraction, off the remaining TTL of each cache hit,
+    /// so downstream caches that fetched together don't all expire together (0 = off)
+    #[serde(default)]
+    pub answer_ttl_jitter: f64,
+    /// Store answers carrying EDNS Client Subnet under the scope the authoritative
+    /// returned, so one /24-scoped answer serves that whole /24 (memory backend only)
+    #[serde(default)]
+    pub ecs_scoped: bool,
+    /// Drop out-of-zone / mismatched / garbage-TTL records before caching
+    #[serde(default = "default_true")]
+    pub strict_validation: bool,
+    /// Keep the most recent evictions (capacity / expiry) for /api/cache/evictions
+    #[serde(default)]
+    pub eviction_log: bool,
+    /// Record types never stored (e.g. ["SOA", "TXT"]); answers are still returned fresh
+    #[serde(default)]
+    pub no_cache_types: Vec<String>,
+    /// Every cached entry is served with at least this TTL, whatever upstream, ttl_alchemy or
+    /// type_max_ttl said - cuts re-resolution on a metered link at the cost of serving
+    /// changed records for up to this long
+    #[serde(default)]
+    pub override_min_ttl: Option<u32>,
+    /// Leave TTL 0 answers out of override_min_ttl (only ttl_alchemy.min_ttl applies to them)
+    #[serde(default)]
+    pub override_min_ttl_keeps_zero: bool,
+    /// How the stored TTL is derived from the upstream one: ttl_alchemy (default),
+    /// only its min_ttl / max_ttl clamp, or the upstream TTL untouched
+    #[serde(default)]
+    pub ttl_mode: TtlMode,
+    /// Which missing entries get stored: every answer (default) or only names missed
+    /// twice within admission_window_secs, so one-off lookups don't churn the cache
+    /// (memory backend only)
+    #[serde(default)]
+    pub admission_policy: AdmissionPolicy,
+    #[serde(default = "default_admission_window")]
+    pub admission_window_secs: u64,
+    /// Where entries live: in-process (default) or a Redis shared between instances
+    #[serde(default)]
+    pub backend: CacheBackend,
+    /// Only read by builds with `--features redis`
+    #[serde(default)]
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    pub redis: RedisCacheConfig,
+}
+
+/// Largest cache.answer_ttl_jitter: staggering expiry, not shortening TTLs noticeably
+const MAX_ANSWER_TTL_JITTER: f64 = 0.25;
+
+impl CacheConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(bad) = self.no_cache_types.iter().find(|t| RecordType::from_name(t).is_none()) {
+            anyhow::bail!("cache.no_cache_types: unknown record type {:?}", bad);
+        }
+        if !(0.0..1.0).contains(&self.prefetch_on_read_threshold) {
+            anyhow::bail!("cache.prefetch_on_read_threshold must be in [0, 1), got {}", self.prefetch_on_read_threshold);
+        }
+        if !(0.0..=1.0).contains(&self.verify_sample_rate) {
+            anyhow::bail!("cache.verify_sample_rate must be in [0, 1], got {}", self.verify_sample_rate);
+        }
+        if !(0.0..=MAX_ANSWER_TTL_JITTER).contains(&self.answer_ttl_jitter) {
+            anyhow::bail!("cache.answer_ttl_jitter must be in [0, {}], got {}", MAX_ANSWER_TTL_JITTER, self.answer_ttl_jitter);
+        }
+        if self.ecs_scoped && self.backend != CacheBackend::Memory {
+            anyhow::bail!("cache.ecs_scoped needs cache.backend = \"memory\"");
+        }
+        if self.ttl_mode == TtlMode::Passthrough && self.override_min_ttl.is_some() {
+            anyhow::bail!("cache.override_min_ttl has no effect with cache.ttl_mode = \"passthrough\"");
+        }
+        Ok(())
+    }
+
+    /// Remaining TTL as handed to the client, after answer_ttl_jitter; never below 1
+    pub fn jittered_ttl(&self, remaining_ttl: u32) -> u32 {
+        let spread = (remaining_ttl as f64 * self.answer_ttl_jitter) as u32;
+        if spread == 0 {
+            return remaining_ttl;
+        }
+        use rand::Rng;
+        remaining_ttl.saturating_sub(rand::thread_rng().gen_range(0..=spread)).max(1)
+    }
+
+    /// true if a hit with `remaining_ttl` of `ttl` should refresh in the background
+    pub fn refreshes_on_read(&self, remaining_ttl: u32, ttl: u32) -> bool {
+        ttl > 0 && (remaining_ttl as f64) < ttl as f64 * self.prefetch_on_read_threshold
+    }
+
+    /// TTL an entry is kept for after cache.override_min_ttl
+    pub fn effective_ttl(&self, original_ttl: u32, ttl: u32) -> u32 {
+        match self.override_min_ttl {
+            Some(_) if original_ttl == 0 && self.override_min_ttl_keeps_zero => ttl,
+            Some(min) => ttl.max(min),
+            None => ttl,
+        }
+    }
+
+    /// true if an expired entry for `name` may be served (serve_stale / serve_stale_domains)
+    pub fn serves_stale(&self, name: &str) -> bool {
+        if self.serve_stale_domains.is_empty() {
+            return self.serve_stale;
+        }
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.serve_stale_domains.iter().any(|domain| {
+            let suffix = domain.trim_end_matches('.').to_lowercase();
+            name == suffix || name.ends_with(&format!(".{}", suffix))
+        })
+    }
+
+    /// true if responses of this type are never stored (cache.no_cache_types)
+    pub fn never_caches(&self, qtype: &RecordType) -> bool {
+        self.no_cache_types.iter().any(|t| RecordType::from_name(t).as_ref() == Some(qtype))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TtlMode {
+    /// The upstream TTL as is: no ttl_alchemy, no clamps, no override_min_ttl
+    Passthrough,
+    /// Dynamic TTLs from ttl_alchemy (as configured there)
+    #[default]
+    Alchemy,
+    /// Only ttl_alchemy.min_ttl / max_ttl (and override_min_ttl) are applied
+    Clamp,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdmissionPolicy {
+    /// Store every cacheable answer
+    #[default]
+    Always,
+    /// First miss is only remembered; the entry is stored on the second miss
+    SecondMiss,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    #[default]
+    Memory,
+    /// Needs a build with `--features redis`
+    Redis,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub struct RedisCacheConfig {
+    #[serde(default = "default_redis_url")]
+    pub url: String,
+    /// Key prefix, so several deployments can share one Redis
+    #[serde(default = "default_redis_prefix")]
+    pub prefix: String,
+}
+
+impl Default for RedisCacheConfig {
+    fn default() -> Self {
+        Self { url: default_redis_url(), prefix: default_redis_prefix() }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -61,6 +475,9 @@ pub struct TtlAlchemyConfig {
     /// Volatility weight: how much response changes shorten TTL
     #[serde(default = "default_vol_weight")]
     pub volatility_weight: f64,
+    /// Per-type TTL ceiling applied after the alchemy and the global clamp (e.g. { TXT = 60 })
+    #[serde(default)]
+    pub type_max_ttl: HashMap<String, u32>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -75,6 +492,24 @@ pub struct PrefetchConfig {
     pub learn_patterns: bool,
     #[serde(default = "default_prefetch_interval")]
     pub check_interval_secs: u64,
+    /// Only prefetch entries hit at least this many times (one-off names just expire)
+    #[serde(default = "default_prefetch_min_hits")]
+    pub min_hits: u64,
+    /// Refreshes per check_interval_secs round (0 = every candidate)
+    #[serde(default)]
+    pub max_per_round: usize,
+    /// Record type → priority; higher types are refreshed first when max_per_round
+    /// can't cover every candidate (unlisted types: 0). Ties go to the soonest expiry
+    #[serde(default)]
+    pub type_priority: HashMap<String, i32>,
+    /// At startup, resolve the delegations (NS and glue) of the zones queried most in
+    /// the saved journal (journal.path), so the first queries of the day skip the walk
+    /// down from the root (recursive mode only)
+    #[serde(default)]
+    pub prime_from_history: bool,
+    /// How many of the most queried zones prime_from_history resolves
+    #[serde(default = "default_prime_history_zones")]
+    pub prime_history_zones: usize,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -87,6 +522,38 @@ pub struct TrustConfig {
     /// How often to recalculate trust scores
     #[serde(default = "default_trust_interval")]
     pub recalc_interval_secs: u64,
+    /// Successful queries a new upstream needs before it is trusted fully (0 = no slow-start)
+    #[serde(default = "default_slow_start_queries")]
+    pub slow_start_queries: u64,
+    /// Trust a new upstream starts at, ramping linearly to its real score
+    #[serde(default = "default_slow_start_score")]
+    pub slow_start_score: f64,
+}
+
+/// One chaos.exclude_domains entry
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum DomainPattern {
+    /// "example.com": the name ends with it
+    Suffix(String),
+    Rule {
+        #[serde(rename = "match", default)]
+        kind: DomainMatch,
+        pattern: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainMatch {
+    #[default]
+    Suffix,
+    /// The whole name, case-insensitive
+    Exact,
+    /// Glob: "*" stands for any run of characters ("*.bank.com" = every name under bank.com)
+    Wildcard,
+    /// Regular expression searched in the lowercased name (anchor it with ^...$ for a full match)
+    Regex,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -96,15 +563,50 @@ pub struct ChaosConfig {
     /// Probability of injecting a SERVFAIL (0.0 - 1.0)
     #[serde(default = "default_chaos_probability")]
     pub servfail_probability: f64,
-    /// Domains to exclude from chaos mode
+    /// Domains to exclude from chaos mode: a bare string matches by suffix, or
+    /// { match = "exact" | "wildcard" | "regex" | "suffix", pattern = "..." }
+    #[serde(default)]
+    pub exclude_domains: Vec<DomainPattern>,
+    /// Per-type probability overriding servfail_probability (e.g. { AAAA = 0.5 })
     #[serde(default)]
-    pub exclude_domains: Vec<String>,
+    pub type_probabilities: HashMap<String, f64>,
+    /// Only inject chaos for these clients (CIDR or bare IP); empty = every client
+    #[serde(default)]
+    pub clients: Vec<String>,
+    /// Failure modes to pick from at random when chaos triggers
+    #[serde(default = "default_chaos_failure_modes")]
+    pub failure_modes: Vec<ChaosFailureMode>,
+    /// How long the "delay" mode waits before answering SERVFAIL
+    #[serde(default = "default_chaos_failure_delay_ms")]
+    pub failure_delay_ms: u64,
+    /// Probability of delaying a real answer (latency injection, independent of failures)
+    #[serde(default)]
+    pub delay_probability: f64,
+    /// Injected delay is picked from delay_min_ms..=delay_max_ms (equal = fixed)
+    #[serde(default = "default_chaos_delay_ms")]
+    pub delay_min_ms: u64,
+    #[serde(default = "default_chaos_delay_ms")]
+    pub delay_max_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChaosFailureMode {
+    /// Answer SERVFAIL
+    Servfail,
+    /// Answer REFUSED
+    Refused,
+    /// Send nothing - the client times out
+    Timeout,
+    /// Wait failure_delay_ms, then answer SERVFAIL
+    Delay,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct JournalConfig {
     #[serde(default = "default_true")]
     pub enabled: bool,
+    /// Saved here on shutdown and read back (within retention_hours) at startup
     pub path: Option<String>,
     /// Max journal entries before rotation
     #[serde(default = "default_journal_max")]
@@ -112,6 +614,15 @@ pub struct JournalConfig {
     /// Retention period in hours
     #[serde(default = "default_journal_retention")]
     pub retention_hours: u64,
+    /// Fraction of queries journaled (0.0–1.0). Metrics still count every query.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// Separate rate for cache hits (falls back to sample_rate)
+    #[serde(default)]
+    pub cache_hit_sample_rate: Option<f64>,
+    /// Journal failures (FORMERR, SERVFAIL, chaos injections...) regardless of sampling
+    #[serde(default = "default_true")]
+    pub always_log_errors: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -121,8 +632,57 @@ pub struct NegativeCacheConfig {
     /// Enable speculative negative caching for typo-like domains
     #[serde(default)]
     pub speculative: bool,
+    /// Typo variants cached per NXDOMAIN
+    #[serde(default = "default_speculative_max_variants")]
+    pub speculative_max_variants: usize,
+    /// Speculative entries added per minute across all NXDOMAINs (0 = unlimited), so a
+    /// flood of distinct NXDOMAINs can't fill the negative cache with guesses
+    #[serde(default = "default_speculative_max_per_minute")]
+    pub speculative_max_per_minute: u32,
+    /// Also guess typos that hit a neighbouring key (QWERTY row), after deletions and swaps
+    #[serde(default)]
+    pub speculative_keyboard: bool,
+    /// Query types typo variants are guessed for (a typo'd PTR or SRV name means nothing)
+    #[serde(default = "default_speculative_types")]
+    pub speculative_types: Vec<String>,
     #[serde(default = "default_neg_ttl")]
     pub default_ttl: u32,
+    /// Floor on the negative TTL, so a tiny SOA minimum doesn't turn a name that keeps
+    /// missing into a re-query storm (0 = SOA minimum as is)
+    #[serde(default)]
+    pub min_ttl: u32,
+    /// Cap on the negative TTL, so a transient NXDOMAIN doesn't stick around
+    #[serde(default = "default_neg_max_ttl")]
+    pub max_ttl: u32,
+    /// Put a synthetic SOA in the authority section of negative answers neko-dns makes up
+    /// itself (or caches without one), so downstream caches can negatively cache them (RFC 2308)
+    #[serde(default = "default_true")]
+    pub synthetic_soa: bool,
+    /// Negative TTL (SOA TTL and MINIMUM) of that synthetic SOA
+    #[serde(default = "default_neg_ttl")]
+    pub synthetic_soa_ttl: u32,
+}
+
+impl NegativeCacheConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(bad) = self.speculative_types.iter().find(|t| RecordType::from_name(t).is_none()) {
+            anyhow::bail!("negative.speculative_types: unknown record type {:?}", bad);
+        }
+        if self.min_ttl > self.max_ttl {
+            anyhow::bail!("negative.min_ttl ({}) is above negative.max_ttl ({})", self.min_ttl, self.max_ttl);
+        }
+        Ok(())
+    }
+
+    /// Negative TTL to cache with: the SOA minimum (or default_ttl) within min_ttl..=max_ttl
+    pub fn clamp_ttl(&self, ttl: u32) -> u32 {
+        ttl.clamp(self.min_ttl, self.max_ttl.max(self.min_ttl))
+    }
+
+    /// true if NXDOMAINs of this type get speculative typo variants (negative.speculative_types)
+    pub fn speculates_on(&self, qtype: &RecordType) -> bool {
+        self.speculative_types.iter().any(|t| RecordType::from_name(t).as_ref() == Some(qtype))
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -132,6 +692,21 @@ pub struct EdnsConfig {
     /// Custom EDNS option code (65001-65534 range for private use)
     #[serde(default = "default_edns_code")]
     pub custom_option_code: u16,
+    /// Private-use option that asks for the resolution journey TXT on this query
+    /// (with recursive.journey_txt_only_on_request)
+    #[serde(default = "default_journey_option_code")]
+    pub journey_option_code: u16,
+    /// Name Server Identifier (RFC 5001) returned when the client sends an empty NSID option
+    #[serde(default)]
+    pub nsid: Option<String>,
+    /// Drop RRSIG/NSEC/NSEC3/DNSKEY/DS from answers to clients that didn't set DO
+    /// (the cache keeps the signed copy)
+    #[serde(default = "default_true")]
+    pub strip_dnssec_for_non_do: bool,
+    /// Forward every query with DO set, so one signed cache entry serves DO and
+    /// non-DO clients alike (only with strip_dnssec_for_non_do)
+    #[serde(default = "default_true")]
+    pub fetch_with_do: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -142,20 +717,376 @@ pub struct WebConfig {
     pub address: String,
     #[serde(default = "default_web_port")]
     pub port: u16,
+    /// Accept POST /api/cache/inject (lets anyone who reaches the Web UI plant cache entries)
+    #[serde(default)]
+    pub allow_cache_inject: bool,
+    /// Show punycode (xn--) names in Unicode in the cache list, the journal and the
+    /// dashboard; the ACE form stays the name and the search key
+    #[serde(default)]
+    pub decode_idn: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct NekoCommentConfig {
     #[serde(default = "default_true")]
     pub enabled: bool,
+    /// Leave DNSSEC answers (AD bit or RRSIGs present) without the feature/journey TXT
+    #[serde(default = "default_true")]
+    pub skip_signed_answers: bool,
+    /// Also put a machine-readable resolution summary (source, latency, upstream)
+    /// into the response OPT, for EDNS clients
+    #[serde(default)]
+    pub edns_metadata: bool,
+    /// Put a few-byte health summary (mode, recursion circuit, cache hit rate, version)
+    /// into the OPT of every response to an EDNS client, for fleet monitoring
+    #[serde(default)]
+    pub health_in_edns: bool,
+    /// How much the feature TXT says (off, compact, verbose)
+    #[serde(default)]
+    pub verbosity: NekoVerbosity,
+    /// Owner name of the feature TXT
+    #[serde(default = "default_neko_record_name")]
+    pub record_name: String,
+}
+
+impl NekoCommentConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        let name = self.record_name.trim_end_matches('.');
+        if name.is_empty() || name.len() > 253 || name.split('.').any(|l| l.is_empty() || l.len() > 63) {
+            anyhow::bail!("neko_comment.record_name {:?} is not a valid domain name", self.record_name);
+        }
+        Ok(())
+    }
 }
 
 impl Default for NekoCommentConfig {
     fn default() -> Self {
-        Self { enabled: true }
+        Self {
+            enabled: true,
+            skip_signed_answers: true,
+            edns_metadata: false,
+            health_in_edns: false,
+            verbosity: NekoVerbosity::default(),
+            record_name: default_neko_record_name(),
+        }
     }
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NekoVerbosity {
+    /// No feature TXT (the cat message stays)
+    Off,
+    /// Feature tags, winning upstream and total latency
+    #[default]
+    Compact,
+    /// Compact plus the answering upstream, resolution vs total latency and remaining cache TTL
+    Verbose,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsConfig {
+    /// Health-check names answered from a short private cache, kept out of
+    /// metrics, the journal and prefetch
+    #[serde(default)]
+    pub synthetic_names: Vec<String>,
+    /// How long a synthetic answer is reused before resolving again
+    #[serde(default = "default_synthetic_ttl")]
+    pub synthetic_ttl_secs: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self { synthetic_names: Vec::new(), synthetic_ttl_secs: default_synthetic_ttl() }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SelfTestConfig {
+    /// Resolve a canary name once at startup; failure shows as degraded in /healthz
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_selftest_name")]
+    pub name: String,
+    #[serde(default = "default_selftest_type", rename = "type")]
+    pub qtype: String,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self { enabled: false, name: default_selftest_name(), qtype: default_selftest_type() }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PersistConfig {
+    /// Save the answer cache to `path` and restore it at startup (memory backend only)
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_persist_path")]
+    pub path: String,
+    /// Seconds between background snapshots (0 = only at shutdown)
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_interval_secs: u64,
+    /// Entries serialized per batch; the snapshot yields to queries between batches
+    #[serde(default = "default_snapshot_batch_size")]
+    pub snapshot_batch_size: usize,
+    /// Compression of the saved files: this snapshot, journal.path and the recursive
+    /// infra cache. Loading detects the format, so it can be changed at any time.
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+impl Default for PersistConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_persist_path(),
+            snapshot_interval_secs: default_snapshot_interval(),
+            snapshot_batch_size: default_snapshot_batch_size(),
+            compression: Compression::None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// [logging]: read on its own by `LoggingConfig::peek`, since tracing is set up
+/// before the rest of the config is loaded
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LoggingConfig {
+    /// Log line format; NEKO_DNS_LOG_FORMAT overrides it
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, event fields (qname, qtype, latency_ms...) as keys
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Just the [logging] section of the config at `path`, read before tracing is set up
+    /// (anything unreadable means the default; the full load reports it)
+    pub fn peek(path: &str) -> Self {
+        #[derive(Deserialize)]
+        struct Section {
+            #[serde(default)]
+            logging: LoggingConfig,
+        }
+        read_with_includes(Path::new(path), &mut Vec::new()).ok()
+            .and_then(|table| toml::Value::Table(table).try_into::<Section>().ok())
+            .map(|s| s.logging)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ParseConfig {
+    /// Packets claiming more records than this in one section are rejected before
+    /// any of them is parsed (crafted counts would otherwise cost a loop each)
+    #[serde(default = "default_max_records_per_section")]
+    pub max_records_per_section: usize,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        Self { max_records_per_section: default_max_records_per_section() }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DebugConfig {
+    /// Record every outbound query (recursive + upstream) for /api/tap; off by default
+    #[serde(default)]
+    pub query_tap: bool,
+    /// Entries each diagnostic ring buffer (tap, eviction log) keeps before
+    /// dropping the oldest
+    #[serde(default = "default_ring_buffer_size")]
+    pub ring_buffer_size: usize,
+    /// How many recent outbound queries the tap keeps (unset: ring_buffer_size)
+    #[serde(default)]
+    pub query_tap_size: Option<usize>,
+    /// Answer queries for echo_name with TXT records describing the query as
+    /// received (client address, transport, flags, EDNS), for client testing
+    #[serde(default = "default_true")]
+    pub echo: bool,
+    #[serde(default = "default_echo_name")]
+    pub echo_name: String,
+    /// Answer neko-dns.version / .stats / .mode / .features TXT with live server
+    /// state, without resolving (REFUSED while identity.hide)
+    #[serde(default = "default_true")]
+    pub diagnostic_names: bool,
+}
+
+impl DebugConfig {
+    /// Capacity of the tap's ring buffer
+    pub fn tap_size(&self) -> usize {
+        self.query_tap_size.unwrap_or(self.ring_buffer_size)
+    }
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        Self { query_tap: false, ring_buffer_size: default_ring_buffer_size(), query_tap_size: None, echo: true, echo_name: default_echo_name(), diagnostic_names: true }
+    }
+}
+
+/// What the server says about itself: CH TXT id.server / hostname.bind and
+/// version.server / version.bind, and the NSID option
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct IdentityConfig {
+    /// Answer to id.server / hostname.bind, and the NSID when edns.nsid is unset (unset: REFUSED)
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Answer to version.server / version.bind (unset: "neko-dns <version>")
+    #[serde(default)]
+    pub version_string: Option<String>,
+    /// REFUSED for every identity probe and no NSID, whatever is configured
+    #[serde(default)]
+    pub hide: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AlertingConfig {
+    /// Where alerts are POSTed as JSON (Slack/Discord incoming webhook etc.); unset = no alerts
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// Which events trigger a webhook
+    #[serde(default = "default_alert_events")]
+    pub events: Vec<AlertEvent>,
+    /// Minimum seconds between two alerts for the same event and subject
+    #[serde(default = "default_alert_debounce")]
+    pub debounce_secs: u64,
+    /// Sliding window for the SERVFAIL / recursion failure rates (seconds)
+    #[serde(default = "default_alert_window")]
+    pub window_secs: u64,
+    /// Fewer queries than this in a window never alert
+    #[serde(default = "default_alert_min_queries")]
+    pub min_queries: u64,
+    /// high_servfail_rate fires when this share of a window's answers are SERVFAIL
+    #[serde(default = "default_servfail_rate_threshold")]
+    pub servfail_rate_threshold: f64,
+    /// recursion_circuit_open fires when this share of a window's recursions fail
+    /// (or when no root server is reachable)
+    #[serde(default = "default_recursion_failure_threshold")]
+    pub recursion_failure_threshold: f64,
+}
+
+impl Default for AlertingConfig {
+    fn default() -> Self {
+        Self {
+            webhook_url: None,
+            events: default_alert_events(),
+            debounce_secs: default_alert_debounce(),
+            window_secs: default_alert_window(),
+            min_queries: default_alert_min_queries(),
+            servfail_rate_threshold: default_servfail_rate_threshold(),
+            recursion_failure_threshold: default_recursion_failure_threshold(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertEvent {
+    /// The trust scorer disabled an upstream
+    UpstreamDisabled,
+    /// Recursion is mostly failing (and falling back to forwarding)
+    RecursionCircuitOpen,
+    /// SERVFAIL share of answers above servfail_rate_threshold
+    HighServfailRate,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResolutionConfig {
+    /// Stages tried in this order until one answers. Stages listed before "cache"
+    /// run ahead of the cache lookup (e.g. local zones first for split horizon).
+    #[serde(default = "default_resolution_order")]
+    pub order: Vec<ResolutionStage>,
+    /// Start in offline mode: answer from the cache (stale entries included) and
+    /// send nothing upstream; misses get SERVFAIL. Toggled at runtime via /api/offline.
+    #[serde(default)]
+    pub offline: bool,
+    /// Answer when no stage applies to the name at all (e.g. recursion off and no
+    /// forward stage), sent with an EDE saying so
+    #[serde(default, rename = "default")]
+    pub default_answer: NoRouteAnswer,
+}
+
+impl Default for ResolutionConfig {
+    fn default() -> Self {
+        Self { order: default_resolution_order(), offline: false, default_answer: NoRouteAnswer::default() }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoRouteAnswer {
+    #[default]
+    Servfail,
+    Refused,
+    Nxdomain,
+}
+
+impl ResolutionConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.order.is_empty() {
+            anyhow::bail!("resolution.order is empty");
+        }
+        if let Some(dup) = self.order.iter().enumerate().find(|(i, s)| self.order[..*i].contains(s)) {
+            anyhow::bail!("resolution.order lists {:?} twice", dup.1);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolutionStage {
+    /// Negative cache, then the answer cache
+    Cache,
+    /// local_zones forwarding
+    LocalZone,
+    /// Iterative resolution (skipped while recursion is off or not ready yet)
+    Recursive,
+    /// Upstream race
+    Forward,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SecurityConfig {
+    /// DNS rebinding protection: strip loopback / RFC 1918 / link-local addresses from
+    /// answers for names outside the local zones
+    #[serde(default)]
+    pub deny_private_answers: bool,
+    /// Names (and their subdomains) still allowed to resolve to private addresses (split horizon)
+    #[serde(default)]
+    pub private_answer_exceptions: Vec<String>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct LocalZoneConfig {
     /// ドメインサフィックス (e.g. "mynk.home")
@@ -168,6 +1099,89 @@ pub struct LocalZoneConfig {
     /// タイムアウト (ms)
     #[serde(default = "default_local_timeout")]
     pub timeout_ms: u64,
+    /// 追加の転送先 ("ip" または "ip:port", ポート省略時はport)。serverの予備として使う
+    #[serde(default)]
+    pub servers: Vec<String>,
+    /// server/serversの使い方 (既定 sequential: 書いた順にフェイルオーバー, race_all: 同時に投げて最初の応答)
+    #[serde(default = "default_local_zone_strategy")]
+    pub strategy: UpstreamStrategy,
+    /// 全サーバーが失敗したときに最初からやり直す回数
+    #[serde(default = "default_local_zone_retries")]
+    pub retries: u32,
+}
+
+impl LocalZoneConfig {
+    /// server followed by servers, as upstreams of this zone alone
+    pub fn upstream_configs(&self) -> Vec<UpstreamConfig> {
+        std::iter::once((self.server.clone(), self.port))
+            .chain(self.servers.iter().map(|s| server_target(s, self.port)))
+            .map(|(address, port)| UpstreamConfig {
+                name: format!("{}:{}", address, port),
+                address,
+                port,
+                timeout_ms: self.timeout_ms,
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+/// "ip:port" or just "ip" (on `port`)
+fn server_target(server: &str, port: u16) -> (String, u16) {
+    match server.parse::<std::net::SocketAddr>() {
+        Ok(addr) => (addr.ip().to_string(), addr.port()),
+        Err(_) => (server.to_string(), port),
+    }
+}
+
+/// Zone answered authoritatively from an RFC 1035 master file
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuthoritativeZoneConfig {
+    /// Zone apex (e.g. "home.arpa"), also the file's initial $ORIGIN
+    pub domain: String,
+    /// Path of the zone file
+    pub file: String,
+    /// Re-read the file when its mtime or size changes (checked every few seconds)
+    #[serde(default = "default_true")]
+    pub watch: bool,
+}
+
+/// Name answered with a constant (liveness probe target independent of upstream health)
+#[derive(Debug, Deserialize, Clone)]
+pub struct HealthDomainConfig {
+    pub name: String,
+    /// Answer to A queries
+    #[serde(default)]
+    pub a: Option<std::net::Ipv4Addr>,
+    /// Several A values with weights, for crude load balancing (takes over from `a`)
+    #[serde(default)]
+    pub a_pool: Vec<WeightedAddress>,
+    /// Whether an A query gets one weighted-random pool member or all of them
+    #[serde(default)]
+    pub pool_answer: PoolAnswer,
+    /// Answer to TXT queries
+    #[serde(default)]
+    pub txt: Option<String>,
+    #[serde(default = "default_health_domain_ttl")]
+    pub ttl: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WeightedAddress {
+    pub address: std::net::Ipv4Addr,
+    /// Relative share of the answers (0 = never handed out)
+    #[serde(default = "default_pool_weight")]
+    pub weight: u32,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolAnswer {
+    /// One member per query, picked by weight
+    #[default]
+    One,
+    /// Every member with a non-zero weight
+    All,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -190,12 +1204,149 @@ pub struct RecursiveConfig {
     /// 好奇心散歩を有効にする
     #[serde(default)]
     pub curiosity_walk: bool,
+    /// 散歩先で先回り解決するレコードタイプ (既定: A と AAAA の両方)
+    #[serde(default = "default_curiosity_types")]
+    pub curiosity_types: Vec<String>,
     /// 解決の旅路 (Journey) TXTレコードを追加する
     #[serde(default = "default_true")]
     pub journey_txt: bool,
+    /// 旅路TXTはEDNS旅路オプション (edns.journey_option_code) 付きのクエリにだけ付ける
+    #[serde(default = "default_true")]
+    pub journey_txt_only_on_request: bool,
+    /// この秒数を過ぎても終わらない旅路 (中断された解決) は捨てる
+    #[serde(default = "default_journey_max_age")]
+    pub journey_max_age_secs: u64,
+    /// Web UI / 旅路TXT用に残す完了済み旅路の数
+    #[serde(default = "default_journey_history")]
+    pub journey_history: usize,
+    /// 1つの旅路に残すステップの上限 (超えたら最後の1枠を最新のステップで上書きし、捨てた数を数える)
+    #[serde(default = "default_journey_max_steps")]
+    pub journey_max_steps: usize,
     /// 好奇心キャッシュのglue TTL (秒)
     #[serde(default = "default_glue_ttl")]
     pub glue_ttl_secs: u64,
+    /// ". NS" (プライミングクエリ) にルートヒントから即答する
+    #[serde(default = "default_true")]
+    pub root_ns_from_hints: bool,
+    /// ルート/権威サーバーRTTの再プローブ間隔 (秒, 0で無効)
+    #[serde(default = "default_root_reprobe_interval")]
+    pub root_reprobe_interval_secs: u64,
+    /// glueの無いNS名はサブ再帰せずupstreamへフォワードして解決する (失敗時は再帰にフォールバック)
+    #[serde(default)]
+    pub ns_resolution_via_upstream: bool,
+    /// 再帰問い合わせソケットに付けるDSCP値 (0-63, 例: 48 = CS6)
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// 応答が大きいと分かっているタイプはUDPを飛ばして最初からTCPで問い合わせる (例: ["DNSKEY", "ANY"])
+    #[serde(default)]
+    pub tcp_first_types: Vec<String>,
+    /// 再帰問い合わせのUDPソケットを問い合わせ先にconnect()し、他の送信元からのデータグラムをカーネルで捨てる
+    #[serde(default = "default_true")]
+    pub connect_udp_sockets: bool,
+    /// 委任キャッシュの最小TTL (秒)。NS/glueのTTLをこの範囲に収める (0のままならTTL 0の委任はキャッシュしない)
+    #[serde(default)]
+    pub deleg_min_ttl_secs: u64,
+    /// 委任キャッシュの最大TTL (秒)
+    #[serde(default = "default_deleg_max_ttl")]
+    pub deleg_max_ttl_secs: u64,
+    /// キャッシュ済み委任のNSが全滅したら、諦める前に親ゾーンから委任を取り直す (委任キャッシュも更新)
+    #[serde(default = "default_true")]
+    pub refetch_failed_delegations: bool,
+    /// 再帰問い合わせの送信元IPv4アドレス (マルチホーム環境向け, 未指定ならOS任せ)
+    #[serde(default)]
+    pub source_address: Option<std::net::Ipv4Addr>,
+    /// 再帰問い合わせの送信元IPv6アドレス
+    #[serde(default)]
+    pub source_address_v6: Option<std::net::Ipv6Addr>,
+    /// 再帰問い合わせのUDP送信元ポートをランダムに選ぶ範囲の下限 (1024以上, RFC 5452)
+    #[serde(default = "default_source_port_min")]
+    pub source_port_min: u16,
+    /// 送信元ポート範囲の上限
+    #[serde(default = "default_source_port_max")]
+    pub source_port_max: u16,
+    /// 起動時にルートからこのTLDの委任を取得しておく (最初のクエリでルート往復を省く, 例: ["com", "net", "jp"])
+    #[serde(default)]
+    pub prime_tlds: Vec<String>,
+    /// ウォームアップでどのルートにも届かなかったときの動作 (forward: upstreamへフォワード, servfail: EDE付きSERVFAILで即答)
+    #[serde(default)]
+    pub roots_unreachable: RootsUnreachableAction,
+    /// 再帰解決が失敗したらupstreamへフォワードする (false: 第三者に問い合わせずEDE付きSERVFAIL)
+    #[serde(default = "default_true")]
+    pub fallback_to_forward: bool,
+    /// UDPで来たANYクエリへの答え方 (full: そのまま解決, minimal: RFC 8482のHINFO, tcp: TC=1の空応答でTCPへ誘導)
+    #[serde(default)]
+    pub any_over_udp: AnyOverUdp,
+    /// RD=0 (反復) クエリはキャッシュにあれば答え、無ければ解決せずに一番近いキャッシュ済み委任のNS+グルーをリファラルで返す
+    #[serde(default = "default_true")]
+    pub iterative_referrals: bool,
+    /// 連続でタイムアウトした権威サーバーIPを選択から外す秒数 (他に候補が無いときは使う, 明けたら1回だけ試す, 0で無効)
+    #[serde(default = "default_quarantine_secs")]
+    pub quarantine_secs: u64,
+    /// ウォームアップ/再プローブ/TLDプライミングで同時に飛ばすプローブの上限
+    #[serde(default = "default_probe_concurrency")]
+    pub probe_concurrency: usize,
+    /// glueの無い委任で同時にアドレス解決するNS名の数 (最初に解決できたものですぐ先へ進む)
+    #[serde(default = "default_ns_resolution_parallelism")]
+    pub ns_resolution_parallelism: usize,
+    /// 同時に進める再帰解決の上限。超えたクエリは待たせずSERVFAIL (EDE "server busy") にする (0で無制限)
+    #[serde(default = "default_max_concurrent_resolutions")]
+    pub max_concurrent_resolutions: usize,
+    /// サーバー選択の根拠 (候補ごとのスコア・バンド内か・選ばれたか) を旅路のSELECTステップに残す (デバッグ用)
+    #[serde(default)]
+    pub trace_selection: bool,
+    /// 権威サーバーRTT (infra cache) をファイルに保存し、再起動後も引き継ぐ
+    #[serde(default)]
+    pub persist_infra_cache: bool,
+    /// infra cache の保存先
+    #[serde(default = "default_infra_cache_path")]
+    pub infra_cache_path: String,
+    /// infra cache の定期保存間隔 (秒, 終了時にも保存する)
+    #[serde(default = "default_infra_cache_save_interval")]
+    pub infra_cache_save_interval_secs: u64,
+    /// 読み込み時、最後の通信からこの秒数を過ぎたエントリは捨てる
+    #[serde(default = "default_infra_cache_max_age")]
+    pub infra_cache_max_age_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RootsUnreachableAction {
+    /// upstreamが設定されていればフォワード (無ければservfailと同じ)
+    #[default]
+    Forward,
+    /// SERVFAIL + EDE 22 "No Reachable Authority" で即答
+    Servfail,
+}
+
+/// How an ANY query is answered (recursive.any_over_udp)
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnyOverUdp {
+    /// Resolved like any other type, on every transport
+    #[default]
+    Full,
+    /// A single synthesized HINFO "RFC8482" (RFC 8482 §4.2), on every transport
+    Minimal,
+    /// Empty with TC=1 over UDP, so only clients that retry over TCP get the full answer
+    Tcp,
+}
+
+impl RecursiveConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(bad) = self.curiosity_types.iter().find(|t| RecordType::from_name(t).is_none()) {
+            anyhow::bail!("recursive.curiosity_types: unknown record type {:?}", bad);
+        }
+        if self.source_port_min < 1024 {
+            anyhow::bail!("recursive.source_port_min must be 1024 or above, got {}", self.source_port_min);
+        }
+        if self.source_port_min > self.source_port_max {
+            anyhow::bail!(
+                "recursive.source_port_min ({}) is above source_port_max ({})",
+                self.source_port_min, self.source_port_max
+            );
+        }
+        Ok(())
+    }
 }
 
 impl Default for RecursiveConfig {
@@ -207,32 +1358,103 @@ impl Default for RecursiveConfig {
             parallel_branches: default_parallel_branches(),
             query_timeout_ms: default_recursive_timeout(),
             curiosity_walk: false,
+            curiosity_types: default_curiosity_types(),
             journey_txt: true,
+            journey_txt_only_on_request: true,
+            journey_max_age_secs: default_journey_max_age(),
+            journey_history: default_journey_history(),
+            journey_max_steps: default_journey_max_steps(),
             glue_ttl_secs: default_glue_ttl(),
+            root_ns_from_hints: true,
+            root_reprobe_interval_secs: default_root_reprobe_interval(),
+            ns_resolution_via_upstream: false,
+            dscp: None,
+            tcp_first_types: Vec::new(),
+            connect_udp_sockets: true,
+            deleg_min_ttl_secs: 0,
+            deleg_max_ttl_secs: default_deleg_max_ttl(),
+            refetch_failed_delegations: true,
+            source_address: None,
+            source_address_v6: None,
+            source_port_min: default_source_port_min(),
+            source_port_max: default_source_port_max(),
+            prime_tlds: Vec::new(),
+            roots_unreachable: RootsUnreachableAction::default(),
+            fallback_to_forward: true,
+            any_over_udp: AnyOverUdp::default(),
+            iterative_referrals: true,
+            quarantine_secs: default_quarantine_secs(),
+            probe_concurrency: default_probe_concurrency(),
+            max_concurrent_resolutions: default_max_concurrent_resolutions(),
+            ns_resolution_parallelism: default_ns_resolution_parallelism(),
+            trace_selection: false,
+            persist_infra_cache: false,
+            infra_cache_path: default_infra_cache_path(),
+            infra_cache_save_interval_secs: default_infra_cache_save_interval(),
+            infra_cache_max_age_secs: default_infra_cache_max_age(),
         }
     }
 }
 
 // Default value functions
+fn default_source_port_min() -> u16 { 49152 }
+fn default_source_port_max() -> u16 { 65535 }
 fn default_timeout_ms() -> u64 { 2000 }
+fn default_min_timeout_ms() -> u64 { 50 }
 fn default_max_entries() -> usize { 100_000 }
+fn default_max_entry_bytes() -> usize { 4096 }
+fn default_local_zone_strategy() -> UpstreamStrategy { UpstreamStrategy::Sequential }
+fn default_local_zone_retries() -> u32 { 1 }
+fn default_health_domain_ttl() -> u32 { 5 }
+fn default_pool_weight() -> u32 { 1 }
+fn default_stale_answer_ttl() -> u32 { 30 }
+fn default_verify_interval() -> u64 { 300 }
+fn default_verify_max_per_round() -> usize { 10 }
 fn default_stale_ttl() -> u64 { 86400 }
 fn default_true() -> bool { true }
+fn default_quarantine_secs() -> u64 { 60 }
 fn default_min_ttl() -> u32 { 30 }
 fn default_max_ttl() -> u32 { 86400 }
 fn default_freq_weight() -> f64 { 0.3 }
 fn default_vol_weight() -> f64 { 0.5 }
+fn default_neko_record_name() -> String { "neko-dns.features".to_string() }
 fn default_prefetch_threshold() -> f64 { 0.1 }
 fn default_prefetch_interval() -> u64 { 10 }
+fn default_prefetch_min_hits() -> u64 { 2 }
+fn default_prime_history_zones() -> usize { 20 }
 fn default_trust_threshold() -> f64 { 0.5 }
 fn default_trust_interval() -> u64 { 60 }
+fn default_slow_start_queries() -> u64 { 10 }
+fn default_slow_start_score() -> f64 { 0.2 }
 fn default_chaos_probability() -> f64 { 0.01 }
+fn default_chaos_failure_modes() -> Vec<ChaosFailureMode> { vec![ChaosFailureMode::Servfail] }
+fn default_chaos_failure_delay_ms() -> u64 { 2000 }
+fn default_chaos_delay_ms() -> u64 { 500 }
 fn default_journal_max() -> usize { 1_000_000 }
 fn default_journal_retention() -> u64 { 168 }
+fn default_sample_rate() -> f64 { 1.0 }
 fn default_neg_ttl() -> u32 { 300 }
+fn default_neg_max_ttl() -> u32 { 86400 }
+fn default_speculative_max_variants() -> usize { 10 }
+fn default_speculative_max_per_minute() -> u32 { 1000 }
 fn default_edns_code() -> u16 { 65001 }
+fn default_journey_option_code() -> u16 { 65002 }
+fn default_journey_max_age() -> u64 { 120 }
+fn default_journey_history() -> usize { 100 }
+fn default_journey_max_steps() -> usize { 64 }
 fn default_web_address() -> String { "0.0.0.0".to_string() }
 fn default_web_port() -> u16 { 8053 }
+fn default_resolution_order() -> Vec<ResolutionStage> {
+    vec![ResolutionStage::Cache, ResolutionStage::LocalZone, ResolutionStage::Recursive, ResolutionStage::Forward]
+}
+fn default_alert_events() -> Vec<AlertEvent> {
+    vec![AlertEvent::UpstreamDisabled, AlertEvent::RecursionCircuitOpen, AlertEvent::HighServfailRate]
+}
+fn default_alert_debounce() -> u64 { 600 }
+fn default_alert_window() -> u64 { 60 }
+fn default_alert_min_queries() -> u64 { 20 }
+fn default_servfail_rate_threshold() -> f64 { 0.25 }
+fn default_recursion_failure_threshold() -> f64 { 0.5 }
 fn default_dns_port() -> u16 { 53 }
 fn default_local_timeout() -> u64 { 1000 }
 fn default_root_hints_path() -> String { "root.hints".to_string() }
@@ -240,13 +1462,289 @@ fn default_max_depth() -> u32 { 20 }
 fn default_parallel_branches() -> u32 { 3 }
 fn default_recursive_timeout() -> u64 { 2000 }
 fn default_glue_ttl() -> u64 { 3600 }
+fn default_probe_concurrency() -> usize { 16 }
+fn default_max_concurrent_resolutions() -> usize { 256 }
+fn default_ns_resolution_parallelism() -> usize { 3 }
+fn default_curiosity_types() -> Vec<String> { vec!["A".to_string(), "AAAA".to_string()] }
+fn default_speculative_types() -> Vec<String> { vec!["A".to_string(), "AAAA".to_string()] }
+fn default_infra_cache_path() -> String { "infra-cache.json".to_string() }
+fn default_infra_cache_save_interval() -> u64 { 300 }
+fn default_infra_cache_max_age() -> u64 { 86400 }
+fn default_root_reprobe_interval() -> u64 { 300 }
+fn default_deleg_max_ttl() -> u64 { 86400 }
+fn default_synthetic_ttl() -> u64 { 5 }
+fn default_selftest_name() -> String { "dns.google".to_string() }
+fn default_selftest_type() -> String { "A".to_string() }
+fn default_persist_path() -> String { "cache-snapshot.jsonl".to_string() }
+fn default_snapshot_interval() -> u64 { 300 }
+fn default_snapshot_batch_size() -> usize { 1000 }
+fn default_max_query_labels() -> usize { 128 }
+fn default_admission_window() -> u64 { 60 }
+fn default_max_records_per_section() -> usize { 1000 }
+fn default_max_concurrent_queries() -> usize { 4096 }
+fn default_edns_udp_size() -> u16 { 1232 }
+fn default_tcp_pipeline_depth() -> usize { 16 }
+fn default_tcp_idle_timeout_ms() -> u64 { 10_000 }
+fn default_redis_url() -> String { "redis://127.0.0.1:6379/".to_string() }
+fn default_redis_prefix() -> String { "neko-dns:".to_string() }
+fn default_ring_buffer_size() -> usize { 500 }
+fn default_echo_name() -> String { "echo.neko-dns".to_string() }
+
+/// Parse a config file with the files its `include = [...]` lists merged in, in
+/// order, over it: tables are overlaid, arrays concatenated, other values replaced.
+/// Include paths are relative to the including file; `stack` holds the files
+/// being read, to catch cycles.
+fn read_with_includes(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<toml::Table> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read config file '{}': {}", path.display(), e))?;
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&canonical) {
+        anyhow::bail!("Config include cycle: '{}' includes itself", path.display());
+    }
+    let mut table: toml::Table = toml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Failed to parse config '{}': {}", path.display(), e))?;
+    let includes = match table.remove("include") {
+        None => Vec::new(),
+        Some(toml::Value::Array(items)) => items.into_iter()
+            .map(|item| match item {
+                toml::Value::String(include) => Ok(include),
+                other => Err(anyhow::anyhow!("Invalid include in '{}': {} is not a path", path.display(), other)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        Some(other) => anyhow::bail!("Invalid include in '{}': expected an array of paths, got {}", path.display(), other),
+    };
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for include in includes {
+        let included = read_with_includes(&dir.join(include), stack)?;
+        merge_tables(&mut table, included);
+    }
+    stack.pop();
+    Ok(table)
+}
+
+fn merge_tables(into: &mut toml::Table, from: toml::Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(overlay)) => merge_tables(existing, overlay),
+            (Some(toml::Value::Array(existing)), toml::Value::Array(more)) => existing.extend(more),
+            (_, value) => { into.insert(key, value); }
+        }
+    }
+}
 
 impl Config {
     pub fn load(path: &str) -> anyhow::Result<Self> {
-        let content = std::fs::read_to_string(path)
-            .map_err(|e| anyhow::anyhow!("Failed to read config file '{}': {}", path, e))?;
-        let config: Config = toml::from_str(&content)
+        let table = read_with_includes(Path::new(path), &mut Vec::new())?;
+        let mut config: Config = toml::Value::Table(table).try_into()
             .map_err(|e| anyhow::anyhow!("Failed to parse config '{}': {}", path, e))?;
+        config.listen.validate()
+            .and_then(|_| config.resolution.validate())
+            .and_then(|_| config.neko_comment.validate())
+            .and_then(|_| config.cache.validate())
+            .and_then(|_| config.negative.validate())
+            .and_then(|_| config.recursive.validate())
+            .map_err(|e| anyhow::anyhow!("Invalid config '{}': {}", path, e))?;
+        let disabled = config.apply_profile();
+        if !disabled.is_empty() {
+            tracing::info!("Production profile: disabled {}", disabled.join(", "));
+        }
+        for target in config.drop_self_targets() {
+            tracing::error!("🔁 {} points at our own listen address {}:{}, skipping it", target, config.listen.address, config.listen.port);
+        }
         Ok(config)
     }
+
+    /// Remove upstreams and local zones that would forward to our own listen
+    /// address (a query loop); returns what was removed
+    pub fn drop_self_targets(&mut self) -> Vec<String> {
+        let Ok(listen_ip) = self.listen.address.parse::<std::net::IpAddr>() else { return Vec::new() };
+        let listen = std::net::SocketAddr::new(listen_ip, self.listen.port);
+        let loops = |address: &str, port: u16| address.parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| crate::loop_guard::is_self_target(listen, std::net::SocketAddr::new(ip, port)));
+        let mut dropped = Vec::new();
+        self.upstreams.retain(|u| {
+            let keep = !loops(&u.address, u.port);
+            if !keep { dropped.push(format!("upstream {}", u.name)); }
+            keep
+        });
+        self.local_zones.retain(|z| {
+            let keep = !loops(&z.server, z.port);
+            if !keep { dropped.push(format!("local zone {}", z.domain)); }
+            keep
+        });
+        for zone in &mut self.local_zones {
+            let port = zone.port;
+            let domain = zone.domain.clone();
+            zone.servers.retain(|s| {
+                let (address, port) = server_target(s, port);
+                let keep = !loops(&address, port);
+                if !keep { dropped.push(format!("local zone {} server {}", domain, s)); }
+                keep
+            });
+        }
+        dropped
+    }
+
+    /// Force off what the profile excludes; returns the features that were on
+    pub fn apply_profile(&mut self) -> Vec<&'static str> {
+        let mut disabled = Vec::new();
+        if self.profile != Profile::Production {
+            return disabled;
+        }
+        let features: [(&'static str, &mut bool); 4] = [
+            ("curiosity walk", &mut self.recursive.curiosity_walk),
+            ("journey TXT", &mut self.recursive.journey_txt),
+            ("neko comment", &mut self.neko_comment.enabled),
+            ("chaos", &mut self.chaos.enabled),
+        ];
+        for (name, flag) in features {
+            if std::mem::take(flag) {
+                disabled.push(name);
+            }
+        }
+        disabled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_production_profile_disables_whimsy() {
+        let shipped = include_str!("../neko-dns.toml").replace("profile = \"default\"", "profile = \"production\"");
+        let mut config: Config = toml::from_str(&shipped).unwrap();
+        config.recursive.curiosity_walk = true;
+        config.recursive.journey_txt = true;
+        config.neko_comment.enabled = true;
+        config.chaos.enabled = true;
+
+        let disabled = config.apply_profile();
+        assert_eq!(disabled, vec!["curiosity walk", "journey TXT", "neko comment", "chaos"]);
+        assert!(!config.recursive.curiosity_walk);
+        assert!(!config.recursive.journey_txt);
+        assert!(!config.neko_comment.enabled);
+        assert!(!config.chaos.enabled);
+
+        // Default profile leaves the individual settings alone
+        config.profile = Profile::Default;
+        config.chaos.enabled = true;
+        assert!(config.apply_profile().is_empty());
+        assert!(config.chaos.enabled);
+    }
+
+    #[test]
+    fn test_resolution_order_validated() {
+        let config: Config = toml::from_str(include_str!("../neko-dns.toml")).unwrap();
+        assert_eq!(config.resolution.order, default_resolution_order());
+
+        let parse = |order: &str| toml::from_str::<ResolutionConfig>(&format!("order = {}", order));
+        let custom = parse(r#"["local_zone", "cache", "forward"]"#).unwrap();
+        assert_eq!(custom.order, vec![ResolutionStage::LocalZone, ResolutionStage::Cache, ResolutionStage::Forward]);
+        assert!(custom.validate().is_ok());
+        assert!(parse(r#"["cache", "dns_over_carrier_pigeon"]"#).is_err());
+        assert!(parse("[]").unwrap().validate().is_err());
+        assert!(parse(r#"["cache", "forward", "cache"]"#).unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_neko_record_name_validated() {
+        let parse = |name: &str| toml::from_str::<NekoCommentConfig>(&format!("record_name = {:?}", name)).unwrap();
+        assert!(parse("diag.example.").validate().is_ok());
+        assert!(parse("").validate().is_err());
+        assert!(parse("a..b").validate().is_err());
+        assert!(parse(&"x".repeat(64)).validate().is_err());
+        assert_eq!(NekoCommentConfig::default().verbosity, NekoVerbosity::Compact);
+    }
+
+    #[test]
+    fn test_no_cache_types_validated() {
+        let parse = |types: &str| toml::from_str::<CacheConfig>(&format!("no_cache_types = {}", types)).unwrap();
+        assert!(parse(r#"["SOA", "txt", "TYPE65"]"#).validate().is_ok());
+        assert!(parse(r#"["SOA", "NOPE"]"#).validate().is_err());
+    }
+
+    #[test]
+    fn test_answer_ttl_jitter_validated() {
+        let parse = |jitter: &str| toml::from_str::<CacheConfig>(&format!("answer_ttl_jitter = {}", jitter)).unwrap();
+        assert!(parse("0.1").validate().is_ok());
+        assert!(parse("0.5").validate().is_err());
+        assert!(parse("-0.1").validate().is_err());
+    }
+
+    #[test]
+    fn test_self_targets_dropped() {
+        let mut config: Config = toml::from_str(include_str!("../neko-dns.toml")).unwrap();
+        config.listen.address = "0.0.0.0".to_string();
+        config.listen.port = 5353;
+        let mut myself = config.upstreams[0].clone();
+        myself.name = "myself".to_string();
+        myself.address = "127.0.0.1".to_string();
+        myself.port = 5353;
+        config.upstreams.push(myself);
+        config.local_zones.push(toml::from_str("domain = \"home\"\nserver = \"127.0.0.1\"\nport = 5353").unwrap());
+        let upstreams = config.upstreams.len();
+
+        assert_eq!(config.drop_self_targets(), vec!["upstream myself", "local zone home"]);
+        assert_eq!(config.upstreams.len(), upstreams - 1);
+        assert!(config.upstreams.iter().all(|u| u.name != "myself"));
+        assert!(config.local_zones.iter().all(|z| z.domain != "home"));
+    }
+
+    #[test]
+    fn test_refuse_types_validated() {
+        let parse = |types: &str| toml::from_str::<ListenConfig>(&format!("address = \"127.0.0.1\"\nport = 53\nrefuse_types = {}", types)).unwrap();
+        let listen = parse(r#"["https", "SVCB"]"#);
+        assert!(listen.validate().is_ok());
+        assert!(listen.refuses(&RecordType::HTTPS));
+        assert!(!listen.refuses(&RecordType::A));
+        assert_eq!(listen.refuse_types_response, RefuseTypesResponse::Nodata);
+        assert!(parse(r#"["HTTPS", "NOPE"]"#).validate().is_err());
+    }
+
+    #[test]
+    fn test_includes_merged() {
+        let dir = std::env::temp_dir().join(format!("neko-dns-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let main = dir.join("neko-dns.toml");
+        let mut base = include_str!("../neko-dns.toml").replacen("[listen]", "include = [\"conf.d/upstreams.toml\"]\n\n[listen]", 1);
+        base.push('\n');
+        std::fs::write(&main, base).unwrap();
+        std::fs::write(dir.join("conf.d/upstreams.toml"), r#"
+            [[upstreams]]
+            name = "included"
+            address = "192.0.2.53"
+            port = 53
+            timeout_ms = 1000
+
+            [cache]
+            max_entries = 1234
+        "#).unwrap();
+
+        let shipped: Config = toml::from_str(include_str!("../neko-dns.toml")).unwrap();
+        let config = Config::load(main.to_str().unwrap()).unwrap();
+        assert_eq!(config.upstreams.len(), shipped.upstreams.len() + 1);
+        assert_eq!(config.upstreams.last().unwrap().name, "included");
+        // Tables overlay: the rest of [cache] comes from the main file
+        assert_eq!(config.cache.max_entries, 1234);
+        assert_eq!(config.cache.serve_stale, shipped.cache.serve_stale);
+
+        // conf.d/upstreams.toml pulling the main file back in is a cycle
+        std::fs::write(dir.join("conf.d/upstreams.toml"), "include = [\"../neko-dns.toml\"]\n").unwrap();
+        let err = Config::load(main.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("include cycle"), "{}", err);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_source_port_range_validated() {
+        let parse = |ports: &str| toml::from_str::<RecursiveConfig>(ports).unwrap();
+        let default = parse("");
+        assert_eq!((default.source_port_min, default.source_port_max), (49152, 65535));
+        assert!(default.validate().is_ok());
+        assert!(parse("source_port_min = 20000\nsource_port_max = 29999").validate().is_ok());
+        assert!(parse("source_port_min = 53\nsource_port_max = 60000").validate().is_err());
+        assert!(parse("source_port_min = 60000\nsource_port_max = 50000").validate().is_err());
+    }
 }
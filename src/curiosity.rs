@@ -36,6 +36,9 @@ struct ZoneKnowledge {
     curiosity_score: f64,
 }
 
+/// 同じターゲットを再散歩しない時間窓 (秒)
+const WALK_DEDUP_SECS: u64 = 300;
+
 pub struct CuriosityCache {
     /// NS名 → IPアドレスのglueキャッシュ
     glue: Arc<DashMap<String, GlueEntry>>,
@@ -43,6 +46,8 @@ pub struct CuriosityCache {
     zone_knowledge: Arc<DashMap<String, ZoneKnowledge>>,
     /// 散歩候補 (先回り解決したいドメインリスト)
     walk_queue: Arc<RwLock<Vec<String>>>,
+    /// 最近散歩したターゲット → 散歩した時刻 (重複散歩の防止)
+    recently_walked: Arc<DashMap<String, Instant>>,
     /// 散歩で実際に解決した数
     walk_count: Arc<std::sync::atomic::AtomicU64>,
     /// 散歩で発見したキャッシュヒット数
@@ -57,6 +62,7 @@ impl Clone for CuriosityCache {
             glue: self.glue.clone(),
             zone_knowledge: self.zone_knowledge.clone(),
             walk_queue: self.walk_queue.clone(),
+            recently_walked: self.recently_walked.clone(),
             walk_count: self.walk_count.clone(),
             walk_hits: self.walk_hits.clone(),
             glue_ttl_secs: self.glue_ttl_secs,
@@ -70,6 +76,7 @@ impl CuriosityCache {
             glue: Arc::new(DashMap::new()),
             zone_knowledge: Arc::new(DashMap::new()),
             walk_queue: Arc::new(RwLock::new(Vec::new())),
+            recently_walked: Arc::new(DashMap::new()),
             walk_count: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             walk_hits: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             glue_ttl_secs,
@@ -134,14 +141,23 @@ impl CuriosityCache {
         let prefixes = ["www", "mail", "ns1", "ns2", "mx", "api"];
 
         let prefix = prefixes[OsRng.gen_range(0..prefixes.len())];
-        let walk_target = format!("{}.{}", prefix, current_zone);
+        let walk_target = format!("{}.{}", prefix, current_zone).to_lowercase();
+
+        // 最近歩いた場所・もうキューにある場所には行かない
+        if self.walked_recently(&walk_target) {
+            trace!("🐱 Curiosity walk: {} visited recently, skipping", walk_target);
+            return;
+        }
+        let mut queue = self.walk_queue.write();
+        if queue.contains(&walk_target) {
+            return;
+        }
 
         debug!("🐱 Curiosity walk: wandering to {}", walk_target);
         self.walk_count
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
         // 散歩キューに追加 (実際の解決はメインエンジンが行う)
-        let mut queue = self.walk_queue.write();
         if queue.len() < 50 {
             // キューが溢れないように
             queue.push(walk_target);
@@ -154,10 +170,28 @@ impl CuriosityCache {
         queue.pop()
     }
 
+    /// 散歩済みとして記録する。時間窓内に既に散歩済みならfalse (解決不要)
+    pub fn mark_walked(&self, target: &str) -> bool {
+        let key = target.to_lowercase();
+        if self.walked_recently(&key) {
+            return false;
+        }
+        self.recently_walked.insert(key, Instant::now());
+        true
+    }
+
+    fn walked_recently(&self, key: &str) -> bool {
+        self.recently_walked
+            .get(key)
+            .is_some_and(|t| t.elapsed().as_secs() < WALK_DEDUP_SECS)
+    }
+
     /// 期限切れエントリのクリーンアップ
     pub fn cleanup(&self) {
         let ttl = self.glue_ttl_secs;
         self.glue.retain(|_, entry| entry.inserted_at.elapsed().as_secs() < ttl);
+        self.recently_walked
+            .retain(|_, t| t.elapsed().as_secs() < WALK_DEDUP_SECS);
 
         // 1時間以上見てないゾーンを忘れる
         self.zone_knowledge
@@ -197,3 +231,38 @@ impl CuriosityCache {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_not_walked_twice_within_window() {
+        let cc = CuriosityCache::new(60);
+        assert!(cc.mark_walked("www.example.com"));
+        assert!(!cc.mark_walked("WWW.example.com"));
+        assert!(cc.mark_walked("mail.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_random_walk_dedups_queue() {
+        let cc = CuriosityCache::new(60);
+        for _ in 0..100 {
+            cc.random_walk("example.com").await;
+        }
+        let mut queued = Vec::new();
+        while let Some(t) = cc.pop_walk_target() {
+            assert!(!queued.contains(&t), "duplicate walk target {}", t);
+            assert!(cc.mark_walked(&t));
+            queued.push(t);
+        }
+
+        // 散歩済みのターゲットは窓内では再投入されない
+        for _ in 0..100 {
+            cc.random_walk("example.com").await;
+        }
+        while let Some(t) = cc.pop_walk_target() {
+            assert!(!queued.contains(&t), "re-walked {}", t);
+        }
+    }
+}
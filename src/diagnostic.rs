@@ -0,0 +1,78 @@
+use crate::dns::packet;
+use crate::dns::types::RecordType;
+
+/// What a neko-dns.* diagnostic name asks about (debug.diagnostic_names).
+/// Only these exact names are answered, so a real neko-dns.<tld> still resolves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topic {
+    /// neko-dns.version: crate version
+    Version,
+    /// neko-dns.stats: uptime, queries, cache size and hit rate
+    Stats,
+    /// neko-dns.mode: recursive or forwarding, profile, offline, maintenance
+    Mode,
+    /// neko-dns.features: the optional features that are on
+    Features,
+}
+
+/// The diagnostic name `qname` is, if any
+pub fn topic(qname: &str) -> Option<Topic> {
+    match qname.trim_end_matches('.').to_lowercase().as_str() {
+        "neko-dns.version" => Some(Topic::Version),
+        "neko-dns.stats" => Some(Topic::Stats),
+        "neko-dns.mode" => Some(Topic::Mode),
+        "neko-dns.features" => Some(Topic::Features),
+        _ => None,
+    }
+}
+
+/// Answer for a diagnostic name: one TXT per fact (TTL 0), NODATA for types other than TXT/ANY
+pub fn diagnostic_response(query: &[u8], qtype: RecordType, facts: &[String]) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = packet::parse_packet(query)?;
+    let qname = parsed.questions.first()
+        .map(|q| q.name.clone())
+        .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+    parsed.header.qr = true;
+    parsed.header.aa = true;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    parsed.additionals.clear();
+    if !matches!(qtype, RecordType::TXT | RecordType::ANY) {
+        packet::add_negative_soa(&mut parsed, 0);
+        return Ok(parsed.to_wire());
+    }
+    for fact in facts {
+        let mut rdata = Vec::new();
+        for chunk in fact.as_bytes().chunks(255) {
+            rdata.push(chunk.len() as u8);
+            rdata.extend_from_slice(chunk);
+        }
+        parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::TXT, 0, rdata));
+    }
+    Ok(parsed.to_wire())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::types::ResponseCode;
+
+    #[test]
+    fn test_topics() {
+        assert_eq!(topic("NEKO-DNS.VERSION."), Some(Topic::Version));
+        assert_eq!(topic("neko-dns.stats"), Some(Topic::Stats));
+        assert_eq!(topic("neko-dns.features"), Some(Topic::Features));
+        assert_eq!(topic("neko-dns.com"), None);
+        assert_eq!(topic("version.neko-dns.example"), None);
+    }
+
+    #[test]
+    fn test_other_types_are_nodata() {
+        let query = packet::build_query(3, "neko-dns.version", RecordType::A, true);
+        let facts = vec!["neko-dns 1.0".to_string()];
+        let parsed = packet::parse_packet(&diagnostic_response(&query, RecordType::A, &facts).unwrap()).unwrap();
+        assert_eq!(parsed.header.rcode, ResponseCode::NoError);
+        assert!(parsed.answers.is_empty());
+        assert_eq!(parsed.authorities[0].rtype, RecordType::SOA);
+    }
+}
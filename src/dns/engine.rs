@@ -1,29 +1,61 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::{info, debug, warn};
+use dashmap::DashMap;
+use tokio::net::TcpStream;
+use tracing::{info, debug, error, warn};
 
-use crate::config::Config;
-use crate::cache::CacheLayer;
+use crate::config::{AlertEvent, AnyOverUdp, ChaosFailureMode, Config, HealthDomainConfig, LocalZoneConfig, NoRouteAnswer, PoolAnswer, Profile, RefuseTypesResponse, ResolutionStage, RootsUnreachableAction};
+use crate::cache::{Cache, Transport};
 use crate::upstream::UpstreamManager;
-use crate::chaos::ChaosEngine;
-use crate::journal::Journal;
+use crate::chaos::{ChaosDrop, ChaosEngine};
+use crate::journal::{Journal, JournalKind};
 use crate::dns::packet;
-use crate::dns::types::RecordType;
-use crate::edns::EdnsHandler;
+use crate::dns::tcp::{self, StillTruncated};
+use crate::dns::types::{DnsClass, RecordType};
+use crate::edns::{EdnsHandler, HealthMode, HealthSummary, EDE_NOT_READY, EDE_NO_REACHABLE_AUTHORITY, EDE_OTHER, EDE_STALE_ANSWER};
 use crate::negative::NegativeCache;
 use crate::neko_comment::{NekoComment, QueryFeatures};
 use crate::recursive::RecursiveResolver;
 use crate::journey::JourneyTracker;
 use crate::curiosity::CuriosityCache;
-use crate::metrics::MetricsCounters;
+use crate::metrics::{MetricsCounters, TruncateReason};
+use crate::spoof::SpoofMonitor;
+use crate::loop_guard::LoopGuard;
+use crate::tap::QueryTap;
+use crate::rebind::RebindGuard;
+use crate::alerting::{Alerter, RateSample, SlidingWindow};
+use crate::maintenance::{self, Maintenance, MaintenanceMode};
+use crate::echo::{self, ClientTransport};
+use crate::diagnostic::{self, Topic};
+use crate::identity;
+use crate::prefetch::PrefetchPlan;
+use crate::special_use;
+use crate::subnet::ClientNet;
+use crate::locally_served;
+use crate::authoritative::{self, WatchedZone};
+
+/// How long the startup self-test waits for root warmup before running anyway
+const SELFTEST_READY_WAIT_SECS: u64 = 10;
+
+/// How often watched zone files are checked for edits
+const ZONE_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// TTL of the RFC 8482 HINFO answer to ANY (recursive.any_over_udp = "minimal")
+const MINIMAL_ANY_TTL: u32 = 3600;
+
+/// Aging entries waiting for a read refresh; beyond this, hits just don't queue one
+const READ_REFRESH_QUEUE_SIZE: usize = 256;
+
+/// A fresh answer: (response, who answered, latency, original TTL, transport)
+type Fresh = (Vec<u8>, String, Duration, u32, Transport);
 
 /// Core query engine - handles all DNS query processing
 pub struct QueryEngine {
     pub config: Arc<Config>,
-    pub cache: Arc<CacheLayer>,
+    pub cache: Arc<dyn Cache>,
     pub upstream: Arc<UpstreamManager>,
     pub chaos: Arc<ChaosEngine>,
     pub journal: Arc<Journal>,
@@ -33,23 +65,144 @@ pub struct QueryEngine {
     pub recursive: Option<Arc<RecursiveResolver>>,
     pub journey: Arc<JourneyTracker>,
     pub curiosity: Arc<CuriosityCache>,
+    prefetch_plan: PrefetchPlan,
     pub metrics: Arc<MetricsCounters>,
+    pub tap: Arc<QueryTap>,
+    pub rebind: Arc<RebindGuard>,
+    /// Background root priming query in flight
+    root_priming: Arc<AtomicBool>,
+    /// UDP queries currently being handled, so client retransmits aren't resolved twice
+    udp_in_flight: DashMap<UdpQueryKey, ()>,
+    /// Answers for metrics.synthetic_names, kept apart from the main cache
+    synthetic_cache: DashMap<(String, u16), (Vec<u8>, std::time::Instant)>,
+    /// Outcome of the startup canary resolution (None until it has run)
+    selftest: parking_lot::RwLock<Option<SelfTestResult>>,
+    /// One permit per in-flight UDP query / TCP connection (listen.max_concurrent_queries)
+    query_slots: Arc<tokio::sync::Semaphore>,
+    /// Aging entries hit by clients, waiting for their background refresh
+    /// (cache.prefetch_on_read_threshold); the receiver is taken by run_read_refresher
+    read_refresh_tx: tokio::sync::mpsc::Sender<(String, RecordType)>,
+    read_refresh_rx: parking_lot::Mutex<Option<tokio::sync::mpsc::Receiver<(String, RecordType)>>>,
+    /// Entries queued for a read refresh, so every hit meanwhile doesn't queue it again
+    read_refresh_pending: DashMap<(String, u16), ()>,
+    /// Manual refreshes in progress; concurrent requests for a name share one resolution
+    refreshing: DashMap<(String, u16), RefreshCell>,
+    /// Cache-miss resolutions in progress (client misses and prefetches alike);
+    /// a miss for a question already being resolved waits for that answer
+    resolving: DashMap<ResolveKey, ResolveCell>,
+    pub alerter: Arc<Alerter>,
+    /// Upstream / authoritative responses rejected as possibly spoofed
+    pub spoof: Arc<SpoofMonitor>,
+    /// Recognizes our own forwarded queries coming back in
+    pub loops: Arc<LoopGuard>,
+    /// TCP answers that were still truncated (tcp_still_truncated)
+    pub still_truncated: Arc<StillTruncated>,
+    /// Servers of each local zone (lowercased domain → its own upstream set)
+    local_zone_servers: HashMap<String, UpstreamManager>,
+    /// Zones answered from their zone files ([[authoritative_zone]]), reloaded on change
+    authoritative: Vec<WatchedZone>,
+    /// Runtime maintenance switch (POST /api/maintenance)
+    pub maintenance: Maintenance,
+    /// Offline mode: cache-only answers, nothing sent out (resolution.offline, POST /api/offline)
+    offline: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfTestResult {
+    pub ok: bool,
+    pub latency_ms: u64,
+    pub detail: String,
+}
+
+/// (client, transaction ID, qname, qtype) - a retransmit repeats all four
+type UdpQueryKey = (SocketAddr, u16, String, u16);
+
+/// Shared outcome of one manual refresh (error kept as text so waiters can clone it)
+type RefreshCell = Arc<tokio::sync::OnceCell<Result<serde_json::Value, String>>>;
+
+/// (qname lowercased, qtype, DO, client ECS network with cache.ecs_scoped) - what
+/// decides the upstream answer to a miss
+type ResolveKey = (String, u16, bool, Option<ClientNet>);
+
+/// Shared outcome of one cache-miss resolution
+type ResolveCell = Arc<tokio::sync::OnceCell<Result<Fresh, Arc<anyhow::Error>>>>;
+
+/// Removes the in-flight entry once the original query is done
+struct InFlightGuard<'a> {
+    map: &'a DashMap<UdpQueryKey, ()>,
+    key: Option<UdpQueryKey>,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.map.remove(&key);
+        }
+    }
 }
 
 impl QueryEngine {
     pub async fn new(config: Arc<Config>) -> anyhow::Result<Self> {
-        let cache = Arc::new(CacheLayer::new(&config.cache, &config.ttl_alchemy));
-        let upstream = Arc::new(UpstreamManager::new(&config.upstreams).await?);
-        let chaos = Arc::new(ChaosEngine::new(&config.chaos));
-        let journal = Arc::new(Journal::new(&config.journal)?);
-        let edns = Arc::new(EdnsHandler::new(&config.edns));
+        let cache = crate::cache::build(&config.cache, &config.ttl_alchemy, config.debug.ring_buffer_size).await?;
+        if config.persist.enabled {
+            match cache.restore(&config.persist.path) {
+                Ok(n) => info!("💾 Cache: restored {} entries from {}", n, config.persist.path),
+                Err(e) => warn!("💾 Cache: nothing restored from {}: {}", config.persist.path, e),
+            }
+        }
+        let tap = Arc::new(QueryTap::new(&config.debug));
+        let spoof = Arc::new(SpoofMonitor::new());
+        let loops = Arc::new(LoopGuard::new());
+        let still_truncated = Arc::new(StillTruncated::new(config.tcp_still_truncated));
+        // One signed cache entry for everyone; non-DO clients get it stripped in finalize_response
+        let force_do = config.edns.fetch_with_do && config.edns.strip_dnssec_for_non_do;
+        let upstream = Arc::new(
+            UpstreamManager::new(&config.upstreams).await?
+                .with_selector(crate::upstream::selector_for(config.upstream_strategy))
+                .with_servfail_retry(config.upstream_retry_servfail)
+                .with_filtering_detection(config.upstream_detect_filtering)
+                .with_forced_do(force_do)
+                .with_slow_start(if config.trust.enabled { config.trust.slow_start_queries } else { 0 }, config.trust.slow_start_score)
+                .with_tap(tap.clone())
+                .with_spoof_monitor(spoof.clone())
+                .with_loop_guard(loops.clone())
+                .with_still_truncated(still_truncated.clone()),
+        );
+        // Injected delays never outlast the server's own query timeout
+        let query_timeout_ms = if config.recursive.enabled {
+            config.recursive.query_timeout_ms
+        } else {
+            config.upstreams.iter().map(|u| u.timeout_ms).max().unwrap_or(0)
+        };
+        let chaos = Arc::new(ChaosEngine::new(&config.chaos).with_max_delay(Duration::from_millis(query_timeout_ms)));
+        let journal = Arc::new(Journal::new(&config.journal)?.with_compression(config.persist.compression));
+        let edns = Arc::new(EdnsHandler::new(&crate::config::EdnsConfig {
+            nsid: config.identity.nsid(config.edns.nsid.as_deref()),
+            ..config.edns.clone()
+        }));
         let negative = Arc::new(NegativeCache::new(&config.negative));
         let neko_comment = Arc::new(NekoComment::new(&config.neko_comment));
+        let rebind = Arc::new(
+            RebindGuard::new(&config.security, &config.local_zones)
+                .with_allowed_zones(config.authoritative_zones.iter().map(|z| z.domain.as_str()))
+                .with_negative_soa(config.negative.synthetic_soa.then_some(config.negative.synthetic_soa_ttl)),
+        );
+        let alerter = Arc::new(Alerter::new(&config.alerting));
+        let offline = Arc::new(AtomicBool::new(config.resolution.offline));
+        if config.resolution.offline {
+            warn!("📴 Offline mode: answering from the cache only, no queries leave this server");
+        }
 
         // 再帰解決エンジン (有効な場合のみ初期化)
         let recursive = if config.recursive.enabled {
-            match RecursiveResolver::new(&config.recursive) {
+            match RecursiveResolver::new(&config.recursive, upstream.clone()) {
                 Ok(r) => {
+                    let r = r.with_tap(tap.clone())
+                        .with_spoof_monitor(spoof.clone())
+                        .with_still_truncated(still_truncated.clone())
+                        .with_offline(offline.clone())
+                        .with_compression(config.persist.compression);
+                    r.start_root_warmup();
                     info!("🌲 Recursive resolution enabled (parallel DFS, {} branches)", config.recursive.parallel_branches);
                     Some(Arc::new(r))
                 }
@@ -63,19 +216,48 @@ impl QueryEngine {
             None
         };
 
-        let journey = Arc::new(JourneyTracker::new(config.recursive.journey_txt));
+        let journey = Arc::new(JourneyTracker::new(config.recursive.journey_txt)
+            .with_max_active_age(Duration::from_secs(config.recursive.journey_max_age_secs))
+            .with_limits(config.recursive.journey_history, config.recursive.journey_max_steps));
         let curiosity = Arc::new(CuriosityCache::new(config.recursive.glue_ttl_secs));
 
-        // ローカルゾーン情報をログ出力
-        if !config.local_zones.is_empty() {
-            for zone in &config.local_zones {
-                info!("🏠 Local zone: *.{} -> {}:{}", zone.domain, zone.server, zone.port);
-            }
+        // ローカルゾーン情報をログ出力 + ゾーンごとのサーバー群 (upstreamと同じレース/フェイルオーバー)
+        let mut local_zone_servers = HashMap::new();
+        for zone in &config.local_zones {
+            let configs = zone.upstream_configs();
+            let servers: Vec<&str> = configs.iter().map(|c| c.name.as_str()).collect();
+            info!("🏠 Local zone: *.{} -> {} ({:?}, {} retries)", zone.domain, servers.join(", "), zone.strategy, zone.retries);
+            let manager = UpstreamManager::new(&configs).await?
+                .with_selector(crate::upstream::selector_for(zone.strategy))
+                .with_forced_do(force_do)
+                .with_spoof_monitor(spoof.clone())
+                .with_loop_guard(loops.clone())
+                .with_still_truncated(still_truncated.clone());
+            local_zone_servers.insert(zone.domain.trim_end_matches('.').to_lowercase(), manager);
+        }
+
+        // 📜 Zone files: a broken one stops startup rather than answering half a zone
+        let mut authoritative = Vec::new();
+        for zone in &config.authoritative_zones {
+            let loaded = WatchedZone::load(zone)?;
+            let served = loaded.zone();
+            info!("📜 Authoritative zone {} loaded from {} ({} records)", served.origin(), zone.file, served.record_count());
+            authoritative.push(loaded);
+        }
+
+        // 🧭 Say so loudly when misses can't go anywhere, instead of answering resolution.default without a word
+        match check_resolution_paths(&config, recursive.is_some()) {
+            ResolutionPaths::Complete => {}
+            ResolutionPaths::Partial(warning) => warn!("🧭 {}", warning),
+            ResolutionPaths::Missing(problem) => error!("🧭 {}", problem),
         }
 
         let metrics = Arc::new(MetricsCounters::new());
+        let query_slots = Arc::new(tokio::sync::Semaphore::new(config.listen.max_concurrent_queries));
+        let (read_refresh_tx, read_refresh_rx) = tokio::sync::mpsc::channel(READ_REFRESH_QUEUE_SIZE);
 
         Ok(Self {
+            prefetch_plan: PrefetchPlan::new(&config.prefetch),
             config,
             cache,
             upstream,
@@ -88,34 +270,470 @@ impl QueryEngine {
             journey,
             curiosity,
             metrics,
+            root_priming: Arc::new(AtomicBool::new(false)),
+            udp_in_flight: DashMap::new(),
+            synthetic_cache: DashMap::new(),
+            selftest: parking_lot::RwLock::new(None),
+            query_slots,
+            tap,
+            rebind,
+            read_refresh_tx,
+            read_refresh_rx: parking_lot::Mutex::new(Some(read_refresh_rx)),
+            read_refresh_pending: DashMap::new(),
+            refreshing: DashMap::new(),
+            resolving: DashMap::new(),
+            alerter,
+            spoof,
+            loops,
+            still_truncated,
+            local_zone_servers,
+            authoritative,
+            maintenance: Maintenance::new(),
+            offline,
         })
     }
 
     /// Handle a raw DNS query and return raw response bytes
     pub async fn handle_query(&self, query_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.handle_query_from(None, query_data).await
+    }
+
+    /// Same as handle_query, for a query received from `client`
+    /// (None = internally generated, e.g. prefetch)
+    pub async fn handle_query_from(&self, client: Option<IpAddr>, query_data: &[u8]) -> anyhow::Result<Vec<u8>> {
         let start = std::time::Instant::now();
+        let response = self.answer(client, query_data, false).await;
+        if let (Some(client), Ok(answer), Ok((qname, qtype))) = (client, &response, packet::extract_query_info(query_data)) {
+            debug!(
+                client = %client,
+                qname = %qname,
+                qtype = %qtype.name(),
+                rcode = answer.get(3).map_or(0, |b| b & 0x0f),
+                latency_ms = start.elapsed().as_millis() as u64,
+                "Answered"
+            );
+        }
+        // ⏱️ listen.min_response_time_ms: timing must not tell an observer what is cached
+        let floor = Duration::from_millis(self.config.listen.min_response_time_ms);
+        if client.is_some() {
+            if let Some(rest) = floor.checked_sub(start.elapsed()) {
+                tokio::time::sleep(rest).await;
+            }
+        }
+        response
+    }
+
+    /// A query from a client socket: the echo name and ANY (recursive.any_over_udp) are
+    /// answered here, where the source port and transport are still known; everything
+    /// else goes to handle_query_from
+    async fn handle_client_query(&self, client: SocketAddr, transport: ClientTransport, query_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let info = packet::extract_query_info(query_data).ok();
+        if info.as_ref().is_some_and(|(qname, _)| echo::is_echo_name(&self.config.debug, qname)) {
+            let response = echo::echo_response(query_data, client, transport)?;
+            return Ok(self.finalize_response(query_data, response));
+        }
+        if info.as_ref().is_some_and(|(_, qtype)| *qtype == RecordType::ANY) {
+            match (self.config.recursive.any_over_udp, transport) {
+                (AnyOverUdp::Minimal, _) => {
+                    debug!("Minimal ANY answer to {} (RFC 8482)", client);
+                    let response = packet::build_minimal_any(query_data, MINIMAL_ANY_TTL)?;
+                    return Ok(self.finalize_response(query_data, response));
+                }
+                (AnyOverUdp::Tcp, ClientTransport::Udp) => {
+                    debug!("ANY over UDP from {}: TC=1 to push it to TCP", client);
+                    self.metrics.inc_truncated(TruncateReason::AnyOverUdp);
+                    return Ok(self.finalize_response(query_data, packet::build_truncated(query_data)?));
+                }
+                _ => {}
+            }
+        }
+        self.handle_query_from(Some(client.ip()), query_data).await
+    }
+
+    async fn answer(&self, client: Option<IpAddr>, query_data: &[u8], bypass_cache: bool) -> anyhow::Result<Vec<u8>> {
         let mut features = QueryFeatures::new();
+        let mut response = self.process_query(client, query_data, bypass_cache, &mut features).await?;
+        // 🛡️ Post-resolution, so cache hits are filtered too
+        if let Some(filtered) = self.rebind.filter(&response) {
+            response = filtered;
+        }
+        // 🐱 Machine-readable twin of the feature TXT (negotiate_opt keeps it on our OPT)
+        if self.config.neko_comment.edns_metadata && self.edns.client_has_opt(query_data) {
+            match self.edns.add_resolution_metadata(&response, &features) {
+                Ok(Some(with_metadata)) => response = with_metadata,
+                Ok(None) => {}
+                Err(e) => debug!("Resolution metadata not added: {}", e),
+            }
+        }
+        Ok(self.finalize_response(query_data, response))
+    }
+
+    /// Re-resolve a name without looking at the cache and replace its cached entry
+    /// (POST /api/cache/refresh). Concurrent refreshes of the same name coalesce.
+    pub async fn refresh(&self, name: &str, qtype: RecordType) -> anyhow::Result<serde_json::Value> {
+        let key = (name.to_lowercase(), qtype.to_u16());
+        let cell = self.refreshing.entry(key.clone()).or_default().clone();
+        let outcome = cell.get_or_init(|| async {
+            let query = packet::build_query(random_query_id(), name, qtype, true);
+            let result = self.answer(None, &query, true).await
+                .and_then(|response| self.refresh_summary(name, qtype, &response))
+                .map_err(|e| e.to_string());
+            self.refreshing.remove(&key);
+            result
+        }).await;
+        outcome.clone().map_err(|e| anyhow::anyhow!(e))
+    }
+
+    fn refresh_summary(&self, name: &str, qtype: RecordType, response: &[u8]) -> anyhow::Result<serde_json::Value> {
+        let parsed = packet::parse_packet(response)?;
+        let answers: Vec<serde_json::Value> = parsed.answers.iter()
+            .map(|r| serde_json::json!({
+                "name": r.name,
+                "type": r.rtype.name(),
+                "ttl": r.ttl,
+                "data": packet::format_record(r, response),
+            }))
+            .collect();
+        info!("🐱 Refreshed {} {} via API ({:?}, {} answers)", name, qtype.name(), parsed.header.rcode, answers.len());
+        Ok(serde_json::json!({
+            "name": name,
+            "type": qtype.name(),
+            "rcode": format!("{:?}", parsed.header.rcode),
+            "ttl": parsed.answers.iter().map(|r| r.ttl).min(),
+            "answers": answers,
+            "cache": self.cache.inspect_entry(name, &qtype, None),
+        }))
+    }
+
+    /// Put an uploaded wire-format response into the cache as is (POST /api/cache/inject).
+    /// Only well-formed NOERROR responses with a single question are accepted.
+    pub async fn inject(&self, response: &[u8]) -> anyhow::Result<serde_json::Value> {
+        let parsed = packet::parse_packet(response)?;
+        if !parsed.header.qr {
+            anyhow::bail!("not a response (QR=0)");
+        }
+        if parsed.header.rcode != crate::dns::types::ResponseCode::NoError {
+            anyhow::bail!("only NOERROR responses can be cached, got {:?}", parsed.header.rcode);
+        }
+        let [question] = parsed.questions.as_slice() else {
+            anyhow::bail!("expected exactly one question, got {}", parsed.questions.len());
+        };
+        self.cache.insert(&question.name, &question.qtype, response, "api-inject", Transport::Api).await;
+        info!("🐱 Injected {} {} into the cache via API ({} answers)", question.name, question.qtype.name(), parsed.answers.len());
+        Ok(serde_json::json!({
+            "name": question.name,
+            "type": question.qtype.name(),
+            "answers": parsed.answers.len(),
+            "cache": self.cache.inspect_entry(&question.name, &question.qtype, None),
+        }))
+    }
+
+    /// Reserve a slot for a new query task; None (counted as dropped) when saturated.
+    /// The slot is freed when the permit is dropped.
+    pub fn try_acquire_query_slot(&self) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        match self.query_slots.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                self.metrics.queries_dropped_saturated.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Handle a UDP query from `client`. Returns None for a retransmit of a
+    /// query that is still in flight - the original's answer carries the same
+    /// ID, so the client gets that one.
+    pub async fn handle_udp_query(&self, client: SocketAddr, query_data: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        // 🔁 Our own forwarded query came back: forwarding it again would loop, so it goes unanswered
+        if self.loops.is_own_query(client) {
+            return Ok(None);
+        }
+        let key = packet::extract_query_info(query_data).ok().map(|(qname, qtype)| {
+            let id = u16::from_be_bytes([query_data[0], query_data[1]]);
+            (client, id, qname.to_lowercase(), qtype.to_u16())
+        });
+        if let Some(ref key) = key {
+            if self.udp_in_flight.insert(key.clone(), ()).is_some() {
+                debug!("Dropping retransmit from {} (id {:#06x}, {})", client, key.1, key.2);
+                return Ok(None);
+            }
+        }
+        let _guard = InFlightGuard { map: &self.udp_in_flight, key };
+        match self.handle_client_query(client, ClientTransport::Udp, query_data).await {
+            Err(e) if e.is::<ChaosDrop>() => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    /// Make a UDP response fit what the client can take (listen.max_udp_response_size caps it
+    /// further). Our own feature / journey TXT go first; if the answer still doesn't fit it is
+    /// sent empty with TC=1 so the client retries over TCP.
+    pub fn fit_udp(&self, query_data: &[u8], response: Vec<u8>) -> Vec<u8> {
+        let client_limit = packet::udp_payload_limit(query_data);
+        let cap = self.config.listen.max_udp_response_size.map(|cap| (cap as usize).max(512));
+        let limit = cap.map_or(client_limit, |cap| cap.min(client_limit));
+        if response.len() <= limit {
+            return response;
+        }
+        if let Ok(stripped) = self.neko_comment.strip_own_records(&response).map(|r| self.compress_as_configured(r)) {
+            if stripped.len() <= limit {
+                self.metrics.inc_truncated(TruncateReason::FeatureTxt);
+                return stripped;
+            }
+        }
+        let reason = match cap {
+            Some(cap) if cap < client_limit => TruncateReason::HardCap,
+            _ => TruncateReason::ClientSize,
+        };
+        debug!("UDP response of {} bytes over the {}-byte limit ({}), sending TC=1", response.len(), limit, reason.label());
+        self.metrics.inc_truncated(reason);
+        packet::truncate_for_udp(&response, limit).unwrap_or(response)
+    }
+
+    /// Response post-processing applied to every outgoing answer
+    fn finalize_response(&self, query_data: &[u8], mut response: Vec<u8>) -> Vec<u8> {
+        packet::set_response_flags(query_data, &mut response);
+        // The cache has the signed copy; a non-DO client only gets what it can use
+        if self.config.edns.strip_dnssec_for_non_do && !self.edns.client_dnssec_ok(query_data) {
+            if let Ok((_, qtype)) = packet::extract_query_info(query_data) {
+                match packet::strip_dnssec(&response, qtype) {
+                    Ok(stripped) => response = stripped,
+                    Err(e) => debug!("DNSSEC records not stripped: {}", e),
+                }
+            }
+        }
+        if self.edns.nsid_requested(query_data) {
+            match self.edns.add_nsid(&response) {
+                Ok(with_nsid) => response = with_nsid,
+                Err(e) => debug!("NSID not added: {}", e),
+            }
+        }
+        if self.config.listen.answer_queried_type_first {
+            match packet::order_answers(&response) {
+                Ok(ordered) => response = ordered,
+                Err(e) => debug!("Answer reordering skipped: {}", e),
+            }
+        }
+        if self.config.listen.minimal_responses {
+            let client_rd = query_data.len() > 2 && query_data[2] & 0x01 != 0;
+            match packet::minimize_response(&response, client_rd) {
+                Ok(minimal) => response = minimal,
+                Err(e) => debug!("Minimal response rewrite skipped: {}", e),
+            }
+        }
+        if self.config.neko_comment.health_in_edns && self.edns.client_has_opt(query_data) {
+            match self.edns.add_health(&response, self.health_summary()) {
+                Ok(with_health) => response = with_health,
+                Err(e) => debug!("Health option not added: {}", e),
+            }
+        }
+        // Last, so our OPT follows the feature/journey TXT
+        match self.edns.negotiate_opt(query_data, &response, self.config.listen.edns_udp_size) {
+            Ok(negotiated) => response = negotiated,
+            Err(e) => debug!("EDNS OPT negotiation skipped: {}", e),
+        }
+        // Before fit_udp, so the size limit applies to what is actually sent
+        self.compress_as_configured(response)
+    }
+
+    /// Current state for the health option (neko_comment.health_in_edns)
+    fn health_summary(&self) -> HealthSummary {
+        use std::sync::atomic::Ordering;
+        let mode = match (self.is_offline(), self.recursive.is_some()) {
+            (true, _) => HealthMode::Offline,
+            (false, true) => HealthMode::Recursive,
+            (false, false) => HealthMode::Forwarding,
+        };
+        let circuit_open = self.recursive.as_ref().is_some_and(|r| r.roots_unreachable());
+        HealthSummary::new(
+            mode,
+            circuit_open,
+            self.metrics.cache_hits.load(Ordering::Relaxed),
+            self.metrics.cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Cached and upstream answers keep whatever compression they came with, and our own
+    /// rewrites compress; with listen.compress_responses off every name is expanded again
+    fn compress_as_configured(&self, response: Vec<u8>) -> Vec<u8> {
+        if self.config.listen.compress_responses {
+            return response;
+        }
+        match packet::decompress(&response) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                debug!("Response left compressed: {}", e);
+                response
+            }
+        }
+    }
+
+    async fn process_query(&self, client: Option<IpAddr>, query_data: &[u8], bypass_cache: bool, features: &mut QueryFeatures) -> anyhow::Result<Vec<u8>> {
+        let start = std::time::Instant::now();
 
         // Parse the incoming query
         let (qname, qtype) = packet::extract_query_info(query_data)?;
-        debug!("Query: {} {}", qname, qtype.name());
+        debug!(qname = %qname, qtype = %qtype.name(), "Query");
+
+        // 🩺 Health domains: a configured constant, nothing is resolved or cached
+        if let Some(domain) = self.config.health_domains.iter().find(|d| d.name.trim_end_matches('.').eq_ignore_ascii_case(&qname)) {
+            return health_domain_response(query_data, qtype, domain);
+        }
+
+        // 📊 Health-check names stay out of metrics, the journal and prefetch
+        if self.config.metrics.synthetic_names.iter().any(|n| n.trim_end_matches('.').eq_ignore_ascii_case(&qname)) {
+            return self.answer_synthetic(query_data, &qname, qtype).await;
+        }
 
         // 📊 Metrics: count query
         self.metrics.queries_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         self.metrics.inc_query_type(&qtype.name());
 
+        // Absurdly deep names would cost a delegation lookup per label - reject up front
+        let label_count = if qname.is_empty() { 0 } else { qname.split('.').count() };
+        if label_count > self.config.listen.max_query_labels {
+            debug!("Rejecting {}-label query name (max {})", label_count, self.config.listen.max_query_labels);
+            self.journal.record_query(&qname, &qtype, "FORMERR", 0, start.elapsed(), JournalKind::Error).await;
+            return packet::build_formerr(query_data);
+        }
+
+        // 🪪 CH TXT identity probes (id.server, version.bind, ...) answered from [identity]
+        if let Some(probe) = packet::extract_query_class(query_data).and_then(|c| identity::probe(&qname, c, qtype)) {
+            debug!("Identity probe {} ({:?})", qname, probe);
+            return identity::probe_response(&self.config.identity, query_data, probe);
+        }
+
+        // Only class IN is served; anything else would be resolved and cached as if it were IN
+        if let Some(qclass) = packet::extract_query_class(query_data).filter(|c| *c != DnsClass::IN) {
+            debug!("Refusing {} {} in class {:?}", qname, qtype.name(), qclass);
+            self.metrics.inc_answer_rcode(crate::dns::types::ResponseCode::Refused);
+            self.journal.record_query(&qname, &qtype, "REFUSED", 0, start.elapsed(), JournalKind::Error).await;
+            return packet::build_refused(query_data);
+        }
+
+        // 🏠 RFC 6761 special-use names: localhost / *.invalid (/ *.local) never leave the box
+        if let Some(special) = self.config.listen.rfc6761
+            .then(|| special_use::classify(&qname, self.config.listen.rfc6761_refuse_local))
+            .flatten()
+        {
+            debug!("Special-use name {} {} ({:?})", qname, qtype.name(), special);
+            let response = special_use::special_response(query_data, qtype, special)?;
+            self.metrics.inc_answer_rcode(packet::parse_packet(&response)?.header.rcode);
+            self.journal.record_query(&qname, &qtype, special.label(), 0, start.elapsed(), JournalKind::Resolved).await;
+            return Ok(response);
+        }
+
+        // 🐾 neko-dns.* diagnostic names: live server state as TXT
+        if let Some(topic) = diagnostic::topic(&qname).filter(|_| self.config.debug.diagnostic_names) {
+            debug!("Diagnostic name {} {} ({:?})", qname, qtype.name(), topic);
+            if self.config.identity.hide {
+                return packet::build_refused(query_data);
+            }
+            self.journal.record_query(&qname, &qtype, "DIAGNOSTIC", 0, start.elapsed(), JournalKind::Resolved).await;
+            return diagnostic::diagnostic_response(query_data, qtype, &self.diagnostic_facts(topic));
+        }
+
+        // 📜 Authoritative zones: answered from the zone file, never resolved or cached
+        if let Some(zone) = authoritative::find_zone(&self.authoritative, &qname).map(|w| w.zone()) {
+            debug!("Authoritative answer for {} {} from zone {}", qname, qtype.name(), zone.origin());
+            let response = zone.answer(query_data, qtype)?;
+            self.metrics.inc_answer_rcode(packet::parse_packet(&response)?.header.rcode);
+            self.journal.record_query(&qname, &qtype, "AUTHORITATIVE", 0, start.elapsed(), JournalKind::Resolved).await;
+            return Ok(response);
+        }
+
+        // 🏠 RFC 6303: private reverse zones are answered here unless a local zone forwards them
+        if let Some(zone) = self.config.listen.rfc6303
+            .then(|| locally_served::zone_of(&qname))
+            .flatten()
+            .filter(|_| match_local_zone(&self.config.local_zones, &qname).is_none())
+        {
+            debug!("Locally served zone {} for {} {}", zone, qname, qtype.name());
+            let response = locally_served::local_response(query_data, qtype, zone)?;
+            self.metrics.inc_answer_rcode(packet::parse_packet(&response)?.header.rcode);
+            self.journal.record_query(&qname, &qtype, "RFC6303", 0, start.elapsed(), JournalKind::Resolved).await;
+            return Ok(response);
+        }
+
+        // 🚫 listen.refuse_types: answered before any resolution
+        if self.config.listen.refuses(&qtype) {
+            debug!("Refusing {} {} (listen.refuse_types)", qname, qtype.name());
+            return match self.config.listen.refuse_types_response {
+                RefuseTypesResponse::Refused => {
+                    self.metrics.inc_answer_rcode(crate::dns::types::ResponseCode::Refused);
+                    self.journal.record_query(&qname, &qtype, "REFUSED_TYPE", 0, start.elapsed(), JournalKind::Error).await;
+                    packet::build_refused(query_data)
+                }
+                RefuseTypesResponse::Nodata => {
+                    self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    self.journal.record_query(&qname, &qtype, "REFUSED_TYPE", 0, start.elapsed(), JournalKind::Resolved).await;
+                    self.synthetic_nodata(query_data)
+                }
+            };
+        }
+
+        // 🌐 listen.aaaa_policy: no AAAA for clients that reached us over IPv4
+        if qtype == RecordType::AAAA && client.is_some_and(|c| self.config.listen.hides_aaaa_from(c)) {
+            debug!("AAAA {} answered NODATA for IPv4 client (listen.aaaa_policy)", qname);
+            self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.journal.record_query(&qname, &qtype, "AAAA_POLICY", 0, start.elapsed(), JournalKind::Resolved).await;
+            return self.synthetic_nodata(query_data);
+        }
+
+        // 🚧 Maintenance mode: answer without resolving
+        let maintenance = self.maintenance.check(&qname);
+        match maintenance {
+            Some(MaintenanceMode::ServfailAll) => {
+                self.metrics.servfail_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                self.journal.record_query(&qname, &qtype, "MAINTENANCE", 0, start.elapsed(), JournalKind::Error).await;
+                return self.maintenance_servfail(query_data);
+            }
+            Some(MaintenanceMode::Redirect(ip)) => {
+                self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                self.journal.record_query(&qname, &qtype, "MAINTENANCE", 0, start.elapsed(), JournalKind::Resolved).await;
+                return maintenance::redirect_response(query_data, qtype, ip);
+            }
+            Some(MaintenanceMode::ServeCacheOnly) | None => {}
+        }
+        // 📴 Offline mode answers like serve_cache_only, for every name
+        let offline = self.is_offline();
+        let cache_only = offline || maintenance == Some(MaintenanceMode::ServeCacheOnly);
+
         // Check chaos mode - maybe inject a failure
-        if self.chaos.should_fail(&qname) {
-            info!("🎲 Chaos mode: injecting SERVFAIL for {}", qname);
+        if let Some(mode) = self.chaos.should_fail(&qname, &qtype, client) {
+            info!("🎲 Chaos mode: injecting {:?} for {}", mode, qname);
             features.chaos_triggered = true;
-            self.metrics.servfail_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            self.journal.record_query(&qname, &qtype, "CHAOS_SERVFAIL", 0, start.elapsed()).await;
-            let mut response = packet::build_servfail(query_data)?;
+            let mut response = match mode {
+                ChaosFailureMode::Timeout => {
+                    self.journal.record_query(&qname, &qtype, "CHAOS_TIMEOUT", 0, start.elapsed(), JournalKind::Error).await;
+                    return Err(ChaosDrop.into());
+                }
+                ChaosFailureMode::Refused => {
+                    self.metrics.inc_answer_rcode(crate::dns::types::ResponseCode::Refused);
+                    self.journal.record_query(&qname, &qtype, "CHAOS_REFUSED", 0, start.elapsed(), JournalKind::Error).await;
+                    packet::build_refused(query_data)?
+                }
+                ChaosFailureMode::Servfail | ChaosFailureMode::Delay => {
+                    if mode == ChaosFailureMode::Delay {
+                        tokio::time::sleep(self.chaos.failure_delay()).await;
+                    }
+                    self.metrics.servfail_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    self.journal.record_query(&qname, &qtype, "CHAOS_SERVFAIL", 0, start.elapsed(), JournalKind::Error).await;
+                    packet::build_servfail(query_data)?
+                }
+            };
             features.latency_ms = Some(start.elapsed().as_millis() as u64);
-            packet::append_feature_record(&mut response, &self.neko_comment, &features);
+            packet::append_feature_record(&mut response, &self.neko_comment, features);
             return Ok(response);
         }
 
+        // Latency injection: hold the query back, then answer it for real
+        if let Some(delay) = self.chaos.should_delay(&qname, client) {
+            info!("🎲 Chaos mode: delaying {} by {}ms", qname, delay.as_millis());
+            features.chaos_triggered = true;
+            tokio::time::sleep(delay).await;
+        }
+
         // Check EDNS custom options in query
         let edns_meta = self.edns.extract_options(query_data);
         if let Some(ref meta) = edns_meta {
@@ -123,128 +741,218 @@ impl QueryEngine {
             features.edns_detected = true;
         }
 
+        // 🧭 resolution.order: stages listed before "cache" get the first go
+        let stages = self.config.resolution.order.as_slice();
+        let cache_at = stages.iter().position(|s| *s == ResolutionStage::Cache);
+        let (before_cache, after_cache) = match cache_at {
+            Some(i) => (&stages[..i], &stages[i + 1..]),
+            None => (&[][..], stages),
+        };
+        let mut early = None;
+        if !before_cache.is_empty() && !cache_only {
+            match self.resolve_fresh(query_data, &qname, qtype, before_cache, features, &self.metrics).await {
+                // A SERVFAIL here still leaves the cache to try
+                Ok(answer) if !packet::is_servfail(&answer.0) => early = Some(answer),
+                Ok(_) => {}
+                Err(e) => debug!("Stages before the cache gave no answer for {} {}: {}", qname, qtype.name(), e),
+            }
+        }
+        let skip_cache = bypass_cache || early.is_some() || cache_at.is_none();
+
         // Check negative cache
-        if let Some(neg_response) = self.negative.check(&qname, &qtype) {
+        let negative_hit = if skip_cache { None } else { self.negative.check(&qname, &qtype) };
+        if let Some(neg_response) = negative_hit {
             debug!("Negative cache hit: {} {}", qname, qtype.name());
             features.negative_cache_hit = true;
             self.metrics.negative_cache_hits.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             self.metrics.cache_hits.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            self.metrics.nxdomain_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             features.latency_ms = Some(start.elapsed().as_millis() as u64);
             let mut response = neg_response;
-            packet::append_feature_record(&mut response, &self.neko_comment, &features);
-            self.journal.record_query(&qname, &qtype, "NEGATIVE_CACHE_HIT", 0, start.elapsed()).await;
+            self.metrics.inc_answer_rcode(packet::parse_packet(&response)
+                .map(|p| p.header.rcode)
+                .unwrap_or(crate::dns::types::ResponseCode::NxDomain));
+            // Cached NXDOMAINs without an SOA would not be negatively cacheable downstream
+            if self.config.negative.synthetic_soa {
+                if let Ok(mut parsed) = packet::parse_packet(&response) {
+                    if !parsed.authorities.iter().any(|r| r.rtype == RecordType::SOA) {
+                        packet::add_negative_soa(&mut parsed, self.config.negative.synthetic_soa_ttl);
+                        response = parsed.to_wire();
+                    }
+                }
+            }
+            packet::append_feature_record(&mut response, &self.neko_comment, features);
+            self.journal.record_query(&qname, &qtype, "NEGATIVE_CACHE_HIT", 0, start.elapsed(), JournalKind::CacheHit).await;
             return Ok(response);
         }
 
-        // Check cache
-        if let Some(cached) = self.cache.get(&qname, &qtype).await {
+        // Check cache (cache.ecs_scoped: by the client's subnet first)
+        let client_subnet = if self.config.cache.ecs_scoped { crate::edns::client_subnet(query_data) } else { None };
+        let cached = if skip_cache { None } else { self.cache.get_for_subnet(&qname, &qtype, client_subnet.as_ref()).await };
+        if let Some(cached) = cached {
             debug!("Cache hit: {} {} (remaining TTL: {}s)", qname, qtype.name(), cached.remaining_ttl);
             features.cache_hit = true;
             features.ttl_alchemy = true;
+            features.answered_by = Some(cached.upstream_name.clone());
+            features.cache_ttl_remaining = Some(cached.remaining_ttl);
             self.metrics.cache_hits.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             features.latency_ms = Some(start.elapsed().as_millis() as u64);
             let mut response = packet::build_response(query_data, &cached.raw_response, cached.remaining_ttl)?;
+            if let Some(ref subnet) = client_subnet {
+                response = crate::edns::echo_client_subnet(&response, subnet)?;
+            }
             // 🐱 Feature notification
-            packet::append_feature_record(&mut response, &self.neko_comment, &features);
-            self.journal.record_query(&qname, &qtype, &cached.upstream_name, cached.remaining_ttl, start.elapsed()).await;
+            packet::append_feature_record(&mut response, &self.neko_comment, features);
+            self.journal.record_query(&qname, &qtype, &cached.upstream_name, cached.remaining_ttl, start.elapsed(), JournalKind::CacheHit).await;
 
             // Record hit for prefetch/TTL alchemy
-            self.cache.record_hit(&qname, &qtype).await;
+            self.cache.record_hit(&qname, &qtype, cached.scope).await;
+
+            // ⏩ cache.prefetch_on_read_threshold: aging entry → refresh behind the answer
+            if !cache_only && self.config.cache.refreshes_on_read(cached.remaining_ttl, cached.ttl) {
+                self.queue_read_refresh(&qname, qtype);
+            }
 
             return Ok(response);
         }
 
-        // Cache miss - try local zone forwarding, recursive resolution, or upstream forwarding
-        debug!("Cache miss: {} {} - resolving", qname, qtype.name());
-        features.cache_miss = true;
-        self.metrics.cache_misses.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // 🌱 Root priming fast path: answer ". NS" from root hints without a round trip.
+        // RD=1 clients also kick off a real priming query so a fresh copy lands in the cache.
+        if let Some(ref recursive) = self.recursive {
+            if early.is_none() && self.config.recursive.root_ns_from_hints && qname.is_empty() && qtype == RecordType::NS {
+                let mut response = recursive.root_hints_response(query_data)?;
+                if query_data[2] & 0x01 != 0 && !offline {
+                    self.spawn_root_priming(recursive.clone());
+                }
+                self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                features.latency_ms = Some(start.elapsed().as_millis() as u64);
+                packet::append_feature_record(&mut response, &self.neko_comment, features);
+                self.journal.record_query(&qname, &qtype, "root-hints", 0, start.elapsed(), JournalKind::Resolved).await;
+                return Ok(response);
+            }
+        }
 
-        // 🏠 Check local zones first
-        let local_zone_result = self.try_local_zone_forward(query_data, &qname).await;
+        // 🧭 RD=0: an iterative client gets the best delegation we know instead of a resolution
+        if let Some(ref recursive) = self.recursive {
+            let client_rd = query_data.len() > 2 && query_data[2] & 0x01 != 0;
+            if early.is_none() && !client_rd && self.config.recursive.iterative_referrals {
+                let mut response = recursive.referral_response(query_data, &qname)?;
+                self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                features.latency_ms = Some(start.elapsed().as_millis() as u64);
+                packet::append_feature_record(&mut response, &self.neko_comment, features);
+                self.journal.record_query(&qname, &qtype, "REFERRAL", 0, start.elapsed(), JournalKind::Resolved).await;
+                return Ok(response);
+            }
+        }
 
-        let (result_response, result_upstream_name, result_latency, result_original_ttl) = 
-            if let Some((response, latency)) = local_zone_result {
-                // ローカルドメイン転送成功
-                features.local_zone = true;
-                self.metrics.local_zone_queries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                let ttl = packet::parse_packet(&response)
-                    .ok()
-                    .and_then(|p| p.answers.first().map(|a| a.ttl))
-                    .unwrap_or(0);
-                (response, "local-zone".to_string(), latency, ttl)
-            } else if let Some(ref recursive) = self.recursive {
-                // 🌲 再帰解決モード
-                features.recursive = true;
-                features.parallel_dfs = true;
-                self.metrics.recursive_queries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                let start_resolve = std::time::Instant::now();
-                match recursive.resolve(&qname, qtype, &self.curiosity, &self.journey).await {
-                    Ok(mut response) => {
-                        let latency = start_resolve.elapsed();
-                        self.metrics.recursive_successes.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                        self.metrics.record_recursive_latency(latency.as_micros() as u64);
-                        let ttl = packet::parse_packet(&response)
-                            .ok()
-                            .and_then(|p| p.answers.first().map(|a| a.ttl))
-                            .unwrap_or(0);
-                        // 元クエリのトランザクションIDをコピー
-                        if response.len() >= 12 && query_data.len() >= 2 {
-                            response[0] = query_data[0];
-                            response[1] = query_data[1];
-                            // RA=1 (Recursion Available) を設定
-                            response[3] |= 0x80;
-                        }
-                        features.journey_recorded = true;
-                        (response, "recursive".to_string(), latency, ttl)
-                    }
-                    Err(e) => {
-                        warn!("🌲 Recursive resolution failed for {} {}: {}, falling back to upstream", qname, qtype.name(), e);
-                        self.metrics.recursive_failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                        // フォールバック: upstream forwarding
-                        features.recursive = false;
-                        features.upstream_forward = true;
-                        self.metrics.upstream_queries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                        let result = self.upstream.race_query(query_data).await?;
-                        features.upstream_winner = Some(result.upstream_name.clone());
-                        (result.response, result.upstream_name, result.latency, result.original_ttl)
+        // 🚧 serve_cache_only / offline: a miss is answered from stale data or not at all
+        if cache_only {
+            let stale = if bypass_cache { None } else { self.cache.get_stale_for_subnet(&qname, &qtype, client_subnet.as_ref()).await };
+            if let Some(stale) = stale {
+                self.metrics.stale_serves.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                self.journal.record_query(&qname, &qtype, &stale.upstream_name, stale.remaining_ttl, start.elapsed(), JournalKind::CacheHit).await;
+                let response = packet::build_response(query_data, &stale.raw_response, stale.remaining_ttl)?;
+                return match client_subnet {
+                    Some(ref subnet) => crate::edns::echo_client_subnet(&response, subnet),
+                    None => Ok(response),
+                };
+            }
+            self.metrics.servfail_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            if offline {
+                self.journal.record_query(&qname, &qtype, "OFFLINE", 0, start.elapsed(), JournalKind::Error).await;
+                return self.servfail_with_ede(query_data, EDE_NO_REACHABLE_AUTHORITY, "offline mode");
+            }
+            self.journal.record_query(&qname, &qtype, "MAINTENANCE", 0, start.elapsed(), JournalKind::Error).await;
+            return self.maintenance_servfail(query_data);
+        }
+
+        // Cache miss - the remaining stages (local zone, recursion, forwarding by default)
+        let fresh = match early {
+            Some(answer) => Ok(answer),
+            None => {
+                debug!("Cache miss: {} {} - resolving", qname, qtype.name());
+                if cache_at.is_some() {
+                    features.cache_miss = true;
+                    self.metrics.cache_misses.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+                self.resolve_miss(query_data, &qname, qtype, after_cache, features).await
+            }
+        };
+
+        // 🥫 Stale-on-error (RFC 8767): fresh resolution failed → fall back to an expired entry
+        let fresh_failed = match &fresh {
+            Ok((response, ..)) => packet::parse_packet(response)
+                .map(|p| p.header.rcode == crate::dns::types::ResponseCode::ServFail)
+                .unwrap_or(true),
+            Err(_) => true,
+        };
+        if fresh_failed && self.config.cache.serve_stale_on_error {
+            if let Some(stale) = self.cache.get_stale_for_subnet(&qname, &qtype, client_subnet.as_ref()).await {
+                info!("🥫 Resolution failed for {} {}, serving stale answer", qname, qtype.name());
+                features.serve_stale = true;
+                features.answered_by = Some(stale.upstream_name.clone());
+                features.cache_ttl_remaining = Some(stale.remaining_ttl);
+                self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let mut response = packet::build_response(query_data, &stale.raw_response, stale.remaining_ttl)?;
+                if let Some(ref subnet) = client_subnet {
+                    response = crate::edns::echo_client_subnet(&response, subnet)?;
+                }
+                if self.edns.client_has_opt(query_data) {
+                    match self.edns.add_ede(&response, EDE_STALE_ANSWER, "resolution failed, serving stale") {
+                        Ok(with_ede) => response = with_ede,
+                        Err(e) => debug!("EDE not added: {}", e),
                     }
                 }
-            } else {
-                // 📡 フォワーディングモード
-                features.upstream_forward = true;
-                self.metrics.upstream_queries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                let result = self.upstream.race_query(query_data).await?;
-                features.upstream_winner = Some(result.upstream_name.clone());
-                (result.response, result.upstream_name, result.latency, result.original_ttl)
-            };
+                features.latency_ms = Some(start.elapsed().as_millis() as u64);
+                packet::append_feature_record(&mut response, &self.neko_comment, features);
+                self.journal.record_query(&qname, &qtype, &stale.upstream_name, stale.remaining_ttl, start.elapsed(), JournalKind::CacheHit).await;
+                return Ok(response);
+            }
+        }
+        // 🧭 No stage applies to this name: resolution.default decides the answer
+        if fresh.as_ref().is_err_and(|e| e.is::<NoRoute>()) {
+            debug!("No resolution stage for {} {}, answering with resolution.default", qname, qtype.name());
+            let response = self.no_route_response(query_data)?;
+            self.metrics.inc_answer_rcode(packet::parse_packet(&response)?.header.rcode);
+            self.journal.record_query(&qname, &qtype, "NO_ROUTE", 0, start.elapsed(), JournalKind::Error).await;
+            return Ok(response);
+        }
+        let (result_response, result_upstream_name, result_latency, result_original_ttl, result_transport) = fresh?;
+        features.answered_by = Some(result_upstream_name.clone());
+        features.resolve_latency_ms = Some(result_latency.as_millis() as u64);
 
         // Parse response for caching
         let response_packet = packet::parse_packet(&result_response)?;
 
-        // Check if NXDOMAIN - add to negative cache
-        if response_packet.header.rcode == crate::dns::types::ResponseCode::NxDomain {
+        // NXDOMAIN, or NODATA with an SOA - add to negative cache under the SOA negative TTL
+        // (the positive cache would go by the SOA record's own TTL instead of its MINIMUM)
+        let nodata = response_packet.header.rcode == crate::dns::types::ResponseCode::NoError
+            && response_packet.answers.is_empty()
+            && packet::soa_negative_ttl(&response_packet).is_some();
+        if nodata || response_packet.header.rcode == crate::dns::types::ResponseCode::NxDomain {
             self.negative.insert(&qname, &qtype, &result_response);
-            self.metrics.nxdomain_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             debug!("Cached negative response for {} {}", qname, qtype.name());
         }
 
         // Cache the response (TTL alchemy will be applied internally)
-        if response_packet.header.rcode == crate::dns::types::ResponseCode::NoError {
-            self.metrics.noerror_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            self.cache.insert(&qname, &qtype, &result_response, &result_upstream_name).await;
-        } else if response_packet.header.rcode == crate::dns::types::ResponseCode::ServFail {
-            self.metrics.servfail_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if response_packet.header.rcode == crate::dns::types::ResponseCode::NoError && !nodata {
+            self.cache.insert(&qname, &qtype, &result_response, &result_upstream_name, result_transport).await;
         }
+        self.metrics.inc_answer_rcode(response_packet.header.rcode);
 
         // Record in journal
+        let kind = match response_packet.header.rcode {
+            crate::dns::types::ResponseCode::NoError | crate::dns::types::ResponseCode::NxDomain => JournalKind::Resolved,
+            _ => JournalKind::Error,
+        };
         self.journal.record_query(
             &qname,
             &qtype,
             &result_upstream_name,
             result_original_ttl,
             start.elapsed(),
+            kind,
         ).await;
 
         // Update upstream latency for trust scoring (forwarding mode only)
@@ -263,10 +971,13 @@ impl QueryEngine {
         // 🐱 Feature notification (ASCII-only, shows triggered features)
         let mut response = result_response;
         features.latency_ms = Some(start.elapsed().as_millis() as u64);
-        packet::append_feature_record(&mut response, &self.neko_comment, &features);
+        packet::append_feature_record(&mut response, &self.neko_comment, features);
 
-        // 🗺️ Resolution Journey TXT (recursive mode only)
-        if self.recursive.is_some() {
+        // 🗺️ Resolution Journey TXT (recursive mode only, on request unless configured otherwise)
+        if self.recursive.is_some()
+            && journey_requested(self.config.recursive.journey_txt_only_on_request, self.edns.journey_requested(edns_meta.as_ref()))
+            && !self.neko_comment.skips_response(&response)
+        {
             if let Some(journey_txt) = self.journey.build_journey_txt(&qname) {
                 let arcount = u16::from_be_bytes([response[10], response[11]]);
                 let new_arcount = arcount.wrapping_add(1);
@@ -280,42 +991,111 @@ impl QueryEngine {
         Ok(response)
     }
 
-    /// Handle TCP DNS queries (length-prefixed)
-    pub async fn handle_tcp(&self, mut stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+    /// Handle TCP DNS queries (length-prefixed). Up to listen.tcp_pipeline_depth queries
+    /// per connection run concurrently and each response is written as soon as it is ready;
+    /// clients match them up by transaction ID (RFC 7766 §6.2.1.1).
+    pub async fn handle_tcp(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
         debug!("TCP connection from {}", addr);
         self.metrics.tcp_queries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-        loop {
-            // Read 2-byte length prefix
-            let mut len_buf = [0u8; 2];
-            match stream.read_exact(&mut len_buf).await {
-                Ok(_) => {}
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e.into()),
+        let depth = self.config.listen.tcp_pipeline_depth.max(1);
+        let slots = Arc::new(tokio::sync::Semaphore::new(depth));
+        let (mut reader, mut writer) = stream.into_split();
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(depth);
+        let writer_task = tokio::spawn(async move {
+            while let Some(response) = rx.recv().await {
+                tcp::write_message(&mut writer, &response).await?;
             }
-            let msg_len = u16::from_be_bytes(len_buf) as usize;
+            Ok::<_, std::io::Error>(())
+        });
 
-            if msg_len == 0 || msg_len > 65535 {
-                break;
-            }
+        // Read length-prefixed messages until the client closes or goes quiet mid-way
+        let idle = Duration::from_millis(self.config.listen.tcp_idle_timeout_ms);
+        loop {
+            let msg_buf = match tokio::time::timeout(idle, tcp::read_message(&mut reader)).await {
+                Ok(Ok(Some(msg))) if !msg.is_empty() => msg,
+                Ok(Ok(_)) => break,
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {
+                    debug!("TCP connection from {} idle for {}ms, closing", addr, idle.as_millis());
+                    break;
+                }
+            };
+            // Pipeline full → stop reading until a query finishes
+            let permit = slots.clone().acquire_owned().await?;
+            let engine = self.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                if let Some(response) = engine.tcp_response(addr, &msg_buf).await {
+                    let _ = tx.send(response).await;
+                }
+            });
+        }
 
-            // Read message
-            let mut msg_buf = vec![0u8; msg_len];
-            stream.read_exact(&mut msg_buf).await?;
+        // The writer finishes once every in-flight query has sent its answer
+        drop(tx);
+        writer_task.await??;
+        Ok(())
+    }
 
-            // Process query
-            let response = match self.handle_query(&msg_buf).await {
-                Ok(r) => r,
-                Err(_) => packet::build_servfail(&msg_buf)?,
-            };
+    /// Answer one TCP query; None when chaos says to stay silent
+    async fn tcp_response(&self, addr: SocketAddr, msg_buf: &[u8]) -> Option<Vec<u8>> {
+        let response = match self.handle_client_query(addr, ClientTransport::Tcp, msg_buf).await {
+            Ok(r) => r,
+            Err(e) if e.is::<ChaosDrop>() => return None,
+            Err(_) => packet::build_servfail(msg_buf).ok()?,
+        };
+        if response.len() > u16::MAX as usize {
+            warn!("TCP response for {} exceeds 65535 bytes, sending SERVFAIL", addr);
+            return packet::build_servfail(msg_buf).ok();
+        }
+        Some(response)
+    }
+
+    /// Forwarding mode is always ready; recursive mode once a root server has answered
+    pub fn is_ready(&self) -> bool {
+        self.recursive.as_ref().is_none_or(|r| r.is_ready())
+    }
 
-            // Send response with length prefix
-            let resp_len = (response.len() as u16).to_be_bytes();
-            stream.write_all(&resp_len).await?;
-            stream.write_all(&response).await?;
+    /// Turn offline mode on or off at runtime (in memory only)
+    pub fn set_offline(&self, enabled: bool) {
+        if self.offline.swap(enabled, std::sync::atomic::Ordering::Relaxed) != enabled {
+            if enabled {
+                warn!("📴 Offline mode on: answering from the cache only");
+            } else {
+                info!("📴 Offline mode off: resolving again");
+            }
         }
+    }
 
-        Ok(())
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Refresh the cached ". NS" with a real priming query (RFC 8109) in the background
+    fn spawn_root_priming(&self, recursive: Arc<RecursiveResolver>) {
+        if self.root_priming.swap(true, std::sync::atomic::Ordering::AcqRel) {
+            return;
+        }
+        let in_flight = self.root_priming.clone();
+        let cache = self.cache.clone();
+        let curiosity = self.curiosity.clone();
+        let journey = self.journey.clone();
+        tokio::spawn(async move {
+            match recursive.resolve("", RecordType::NS, &curiosity, &journey).await {
+                Ok(response) => {
+                    let primed = packet::parse_packet(&response)
+                        .map(|p| p.header.rcode == crate::dns::types::ResponseCode::NoError && p.header.ancount > 0)
+                        .unwrap_or(false);
+                    if primed {
+                        cache.insert("", &RecordType::NS, &response, "recursive", Transport::Recursive).await;
+                    }
+                }
+                Err(e) => debug!("🌱 Root priming failed: {}", e),
+            }
+            in_flight.store(false, std::sync::atomic::Ordering::Release);
+        });
     }
 
     /// Prefetch loop - periodically check for entries nearing expiry
@@ -329,56 +1109,226 @@ impl QueryEngine {
 
         loop {
             tokio::time::sleep(interval).await;
+            if self.is_offline() {
+                continue;
+            }
             let candidates = self.cache.get_prefetch_candidates(
                 self.config.prefetch.threshold_ratio,
+                self.config.prefetch.min_hits,
             ).await;
+            let candidates = self.prefetch_plan.select(candidates);
 
             for (name, qtype) in candidates {
-                debug!("Prefetching: {} {}", name, qtype.name());
-                // Use handle_query so recursive mode is respected
-                let query = packet::build_query({ use rand::rngs::OsRng; use rand::Rng; OsRng.gen() }, &name, qtype, true);
-                let _ = self.handle_query(&query).await;
+                let _ = self.prefetch_once(&name, qtype).await;
             }
         }
     }
 
-    /// Trust scorer loop - periodically recalculate upstream trust scores
-    pub async fn run_trust_scorer(&self) {
-        if !self.config.trust.enabled {
+    /// Re-resolve one entry nearing expiry. It goes through the usual query path past
+    /// the cache, so recursive mode is respected and a client missing the same name
+    /// meanwhile shares this resolution.
+    pub async fn prefetch_once(&self, name: &str, qtype: RecordType) -> anyhow::Result<Vec<u8>> {
+        debug!("Prefetching: {} {}", name, qtype.name());
+        let query = packet::build_query(random_query_id(), name, qtype, true);
+        self.answer(None, &query, true).await
+    }
+
+    /// Re-resolve a sample of cached entries every cache.verify_interval_secs
+    pub async fn run_cache_verifier(&self) {
+        let cache = &self.config.cache;
+        if cache.verify_sample_rate <= 0.0 || cache.verify_interval_secs == 0 {
             return;
         }
-
-        let interval = std::time::Duration::from_secs(self.config.trust.recalc_interval_secs);
-        info!("Trust scorer started (interval: {:?})", interval);
-
+        let interval = Duration::from_secs(cache.verify_interval_secs);
+        info!("Cache verifier started (interval: {:?}, sample rate: {})", interval, cache.verify_sample_rate);
         loop {
             tokio::time::sleep(interval).await;
-            self.upstream.recalculate_trust_scores(self.config.trust.min_score).await;
+            if self.is_offline() {
+                continue;
+            }
+            self.verify_cache_sample().await;
         }
     }
 
-    /// Get stats for Web UI
-    pub fn get_stats(&self) -> serde_json::Value {
-        let mut stats = serde_json::json!({
-            "cache": self.cache.get_stats(),
-            "upstreams": self.upstream.get_stats(),
-            "journal": self.journal.get_stats(),
-            "chaos": self.chaos.get_stats(),
-            "negative_cache": self.negative.get_stats(),
-            "journey": self.journey.get_stats(),
-            "curiosity": self.curiosity.get_stats(),
-        });
-
-        if let Some(ref recursive) = self.recursive {
+    /// One verification round: re-resolve a random sample of the cache past the cache
+    /// (which also stores the fresh answer) and compare the answers; returns the entries
+    /// that differed. Entries uploaded through the API are left alone.
+    pub async fn verify_cache_sample(&self) -> Vec<(String, RecordType)> {
+        let config = &self.config.cache;
+        let sample: Vec<(String, RecordType)> = {
+            use rand::seq::SliceRandom;
+            let entries: Vec<(String, RecordType)> = self.cache.list_entries().iter()
+                .filter(|e| e["transport"] != Transport::Api.label())
+                .filter_map(|e| Some((e["name"].as_str()?.to_string(), RecordType::from_name(e["type"].as_str()?)?)))
+                .collect();
+            let want = ((entries.len() as f64 * config.verify_sample_rate).ceil() as usize).min(config.verify_max_per_round);
+            entries.choose_multiple(&mut rand::thread_rng(), want).cloned().collect()
+        };
+        let mut mismatches = Vec::new();
+        for (name, qtype) in sample {
+            let Some(cached) = self.cache.export_entry(&name, &qtype, None) else { continue };
+            let Ok(fresh) = self.prefetch_once(&name, qtype).await else { continue };
+            let (Some(before), Some(after)) = (packet::answer_digest(&cached), packet::answer_digest(&fresh)) else { continue };
+            self.metrics.cache_verifications.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            if before != after {
+                warn!("🔍 Cache verify: {} {} was {:?}, fresh answer is {:?}", name, qtype.name(), before, after);
+                self.metrics.cache_verify_mismatches.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                mismatches.push((name, qtype));
+            }
+        }
+        mismatches
+    }
+
+    /// Queue a background refresh of an aging entry a client just read
+    fn queue_read_refresh(&self, name: &str, qtype: RecordType) {
+        let key = (name.to_lowercase(), qtype.to_u16());
+        if self.read_refresh_pending.insert(key.clone(), ()).is_some() {
+            return;
+        }
+        if self.read_refresh_tx.try_send((name.to_string(), qtype)).is_err() {
+            debug!("Read refresh queue full, not refreshing {} {}", name, qtype.name());
+            self.read_refresh_pending.remove(&key);
+        }
+    }
+
+    /// Refresh the aging entries queued by cache hits (cache.prefetch_on_read_threshold)
+    pub async fn run_read_refresher(&self) {
+        let Some(mut rx) = self.read_refresh_rx.lock().take() else {
+            return;
+        };
+        while let Some((name, qtype)) = rx.recv().await {
+            debug!("Refreshing on read: {} {}", name, qtype.name());
+            let _ = self.prefetch_once(&name, qtype).await;
+            self.read_refresh_pending.remove(&(name.to_lowercase(), qtype.to_u16()));
+        }
+    }
+
+    /// Trust scorer loop - periodically recalculate upstream trust scores
+    pub async fn run_trust_scorer(&self) {
+        if !self.config.trust.enabled {
+            return;
+        }
+
+        let interval = std::time::Duration::from_secs(self.config.trust.recalc_interval_secs);
+        info!("Trust scorer started (interval: {:?})", interval);
+
+        loop {
+            tokio::time::sleep(interval).await;
+            self.rescore_upstreams().await;
+        }
+    }
+
+    /// One trust scoring round; upstreams it disables raise an alert
+    pub async fn rescore_upstreams(&self) {
+        let min_score = self.config.trust.min_score;
+        for (name, score) in self.upstream.recalculate_trust_scores(min_score).await {
+            self.alerter.notify(
+                AlertEvent::UpstreamDisabled,
+                &name,
+                &format!("upstream {} disabled by the trust scorer (score {:.2} < {:.2})", name, score, min_score),
+                serde_json::json!({ "upstream": name, "trust_score": score, "min_score": min_score }),
+            );
+        }
+    }
+
+    /// 🔔 Watch SERVFAIL and recursion failure rates over alerting.window_secs
+    pub async fn run_alert_monitor(&self) {
+        if !self.alerter.is_enabled() {
+            return;
+        }
+        let window_len = self.alerter.window();
+        let tick = (window_len / 6).max(Duration::from_secs(1));
+        let mut window = SlidingWindow::new(window_len);
+        loop {
+            tokio::time::sleep(tick).await;
+            let m = &self.metrics;
+            let recursion_failures = m.recursive_failures.load(std::sync::atomic::Ordering::Relaxed);
+            let delta = window.push(RateSample {
+                queries: m.queries_total.load(std::sync::atomic::Ordering::Relaxed),
+                servfails: m.servfail_total.load(std::sync::atomic::Ordering::Relaxed),
+                recursions: m.recursive_successes.load(std::sync::atomic::Ordering::Relaxed) + recursion_failures,
+                recursion_failures,
+            });
+            self.check_alert_rates(&delta);
+        }
+    }
+
+    fn check_alert_rates(&self, delta: &RateSample) {
+        let alerting = self.alerter.config();
+        if delta.queries >= alerting.min_queries {
+            let rate = delta.servfails as f64 / delta.queries as f64;
+            if rate >= alerting.servfail_rate_threshold {
+                self.alerter.notify(
+                    AlertEvent::HighServfailRate,
+                    "servfail",
+                    &format!("{:.0}% of answers were SERVFAIL in the last {}s", rate * 100.0, alerting.window_secs),
+                    serde_json::json!({ "queries": delta.queries, "servfails": delta.servfails, "rate": rate }),
+                );
+            }
+        }
+
+        let Some(recursive) = self.recursive.as_ref() else { return };
+        if recursive.roots_unreachable() {
+            self.alerter.notify(
+                AlertEvent::RecursionCircuitOpen,
+                "recursion",
+                "no root server reachable, recursion bypassed",
+                serde_json::json!({ "roots_unreachable": true }),
+            );
+        } else if delta.recursions >= alerting.min_queries {
+            let rate = delta.recursion_failures as f64 / delta.recursions as f64;
+            if rate >= alerting.recursion_failure_threshold {
+                self.alerter.notify(
+                    AlertEvent::RecursionCircuitOpen,
+                    "recursion",
+                    &format!("{:.0}% of recursive resolutions failed in the last {}s", rate * 100.0, alerting.window_secs),
+                    serde_json::json!({ "recursions": delta.recursions, "failures": delta.recursion_failures, "rate": rate }),
+                );
+            }
+        }
+    }
+
+    /// Get stats for Web UI. Each section reads its own counters; the query
+    /// counters come from one snapshot, so their hit rate matches their hits and misses.
+    pub fn get_stats(&self) -> serde_json::Value {
+        let mut stats = serde_json::json!({
+            "queries": self.metrics.snapshot().to_json(),
+            "cache": self.cache.get_stats(),
+            "upstreams": self.upstream.get_stats(),
+            "journal": self.journal.get_stats(),
+            "chaos": self.chaos.get_stats(),
+            "negative_cache": self.negative.get_stats(),
+            "journey": self.journey.get_stats(),
+            "curiosity": self.curiosity.get_stats(),
+            "prefetch": self.prefetch_plan.get_stats(),
+            "security": self.rebind.get_stats(),
+            "alerting": self.alerter.get_stats(),
+            "spoofed_responses": self.spoof.get_stats(),
+            "query_loops_detected": self.loops.detected(),
+            "tcp_still_truncated": self.still_truncated.count(),
+            "tap": self.tap.get_stats(),
+            "offline": self.is_offline(),
+        });
+
+        if let Some(ref recursive) = self.recursive {
             stats["recursive"] = recursive.get_stats();
             stats["mode"] = serde_json::json!("recursive");
         } else {
             stats["mode"] = serde_json::json!("forwarding");
         }
 
+        if !self.authoritative.is_empty() {
+            let zones: Vec<serde_json::Value> = self.authoritative.iter()
+                .map(|w| w.zone())
+                .map(|z| serde_json::json!({ "domain": z.origin(), "records": z.record_count() }))
+                .collect();
+            stats["authoritative_zones"] = serde_json::json!(zones);
+        }
+
         if !self.config.local_zones.is_empty() {
             let zones: Vec<serde_json::Value> = self.config.local_zones.iter().map(|z| {
-                serde_json::json!({ "domain": z.domain, "server": format!("{}:{}", z.server, z.port) })
+                let servers: Vec<String> = z.upstream_configs().into_iter().map(|c| c.name).collect();
+                serde_json::json!({ "domain": z.domain, "server": format!("{}:{}", z.server, z.port), "servers": servers })
             }).collect();
             stats["local_zones"] = serde_json::json!(zones);
         }
@@ -386,6 +1336,46 @@ impl QueryEngine {
         stats
     }
 
+    /// Zone file watcher: picks up edits to [[authoritative_zone]] files without a restart
+    pub async fn run_zone_watcher(&self) {
+        if !self.authoritative.iter().any(|w| w.watched()) {
+            return;
+        }
+        loop {
+            tokio::time::sleep(ZONE_WATCH_INTERVAL).await;
+            self.reload_authoritative_zones();
+        }
+    }
+
+    /// Re-parse watched zone files that changed; returns how many were swapped in
+    pub fn reload_authoritative_zones(&self) -> usize {
+        let mut reloaded = 0;
+        for watched in self.authoritative.iter().filter(|w| w.watched()) {
+            match watched.reload_if_changed() {
+                Ok(true) => {
+                    let zone = watched.zone();
+                    info!("📜 Authoritative zone {} reloaded from {} ({} records)", zone.origin(), watched.file(), zone.record_count());
+                    reloaded += 1;
+                }
+                Ok(false) => {}
+                Err(e) => warn!("📜 {}; still serving the previous version", e),
+            }
+        }
+        reloaded
+    }
+
+    /// Drops journeys of resolutions that never finished (recursive mode only)
+    pub async fn run_journey_sweeper(&self) {
+        if !self.config.recursive.enabled || !self.config.recursive.journey_txt {
+            return;
+        }
+        let interval = Duration::from_secs(self.config.recursive.journey_max_age_secs.max(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            self.journey.sweep_abandoned();
+        }
+    }
+
     /// 好奇心散歩ループ - バックグラウンドで散歩キューを処理
     pub async fn run_curiosity_walk_loop(&self) {
         if !self.config.recursive.enabled || !self.config.recursive.curiosity_walk {
@@ -397,14 +1387,18 @@ impl QueryEngine {
 
         loop {
             tokio::time::sleep(interval).await;
+            // オフライン中は散歩しない (キューはそのまま)
+            if self.is_offline() {
+                continue;
+            }
 
             // 散歩キューからターゲットを取得して解決
             while let Some(target) = self.curiosity.pop_walk_target() {
-                if self.cache.get(&target, &RecordType::A).await.is_none() {
-                    debug!("🐱 Curiosity walk: resolving {}", target);
-                    let query = packet::build_query({ use rand::rngs::OsRng; use rand::Rng; OsRng.gen() }, &target, RecordType::A, true);
-                    let _ = self.handle_query(&query).await;
+                // キャッシュ済みのものも散歩済みとして記録 → 窓内は再投入されない
+                if !self.curiosity.mark_walked(&target) {
+                    continue;
                 }
+                self.walk_target(&target).await;
             }
 
             // 定期クリーンアップ
@@ -412,64 +1406,486 @@ impl QueryEngine {
         }
     }
 
-    /// 🏠 ローカルゾーン転送: ドメインがローカルゾーンにマッチする場合、指定サーバーに転送
-    async fn try_local_zone_forward(&self, query_data: &[u8], qname: &str) -> Option<(Vec<u8>, Duration)> {
-        let qname_lower = qname.to_lowercase();
+    /// 散歩先を recursive.curiosity_types の各タイプで解決 (キャッシュ済みのタイプは飛ばす)
+    async fn walk_target(&self, target: &str) {
+        for qtype in self.config.recursive.curiosity_types.iter().filter_map(|t| RecordType::from_name(t)) {
+            if self.cache.get(target, &qtype).await.is_none() {
+                debug!("🐱 Curiosity walk: resolving {} {}", target, qtype.name());
+                let query = packet::build_query(random_query_id(), target, qtype, true);
+                let _ = self.handle_query(&query).await;
+            }
+        }
+    }
 
-        for zone in &self.config.local_zones {
-            let domain_suffix = zone.domain.to_lowercase();
-            // "mynk.home" matches "foo.mynk.home" and "mynk.home" itself
-            if qname_lower == domain_suffix || qname_lower.ends_with(&format!(".{}", domain_suffix)) {
-                debug!("🏠 Local zone match: {} -> {}:{}", qname, zone.server, zone.port);
+    /// Prefetch root → TLD delegations (recursive.prime_tlds) once the roots answer,
+    /// then those of the zones queried most in the saved journal (prefetch.prime_from_history)
+    pub async fn run_tld_priming(&self) {
+        let Some(recursive) = self.recursive.as_ref() else { return };
+        let from_history = self.config.prefetch.prime_from_history;
+        if from_history && self.config.journal.path.is_none() {
+            warn!("🗺️ prefetch.prime_from_history: journal.path is not set, there is no saved history to prime from");
+        }
+        if self.config.recursive.prime_tlds.is_empty() && !from_history {
+            return;
+        }
+        while self.is_offline() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        for _ in 0..SELFTEST_READY_WAIT_SECS * 10 {
+            if recursive.is_ready() { break; }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        if !self.config.recursive.prime_tlds.is_empty() {
+            recursive.prime_tlds().await;
+        }
+        if from_history {
+            // Names answered here never reach the resolver; priming them would only leak them
+            let zones = self.journal.top_zones(self.config.prefetch.prime_history_zones, |name| !self.answered_locally(name));
+            recursive.prime_zones(&zones, &self.curiosity, &self.journey).await;
+        }
+    }
 
-                let addr: SocketAddr = match format!("{}:{}", zone.server, zone.port).parse() {
-                    Ok(a) => a,
-                    Err(e) => {
-                        warn!("🏠 Invalid local zone server address {}:{}: {}", zone.server, zone.port, e);
-                        return None;
-                    }
-                };
+    /// true if queries for `name` are answered without resolving (special-use names,
+    /// authoritative zones, RFC 6303 zones, local zones)
+    fn answered_locally(&self, name: &str) -> bool {
+        (self.config.listen.rfc6761 && special_use::classify(name, self.config.listen.rfc6761_refuse_local).is_some())
+            || authoritative::find_zone(&self.authoritative, name).is_some()
+            || (self.config.listen.rfc6303 && locally_served::zone_of(name).is_some())
+            || match_local_zone(&self.config.local_zones, name).is_some()
+    }
 
-                let timeout = Duration::from_millis(zone.timeout_ms);
+    /// Periodically save the recursive resolver's infra cache (recursive.persist_infra_cache)
+    pub async fn run_infra_cache_saver(&self) {
+        if let Some(recursive) = self.recursive.as_ref() {
+            recursive.run_infra_cache_saver().await;
+        }
+    }
+
+    /// TXT facts for a neko-dns.* diagnostic name
+    fn diagnostic_facts(&self, topic: Topic) -> Vec<String> {
+        use std::sync::atomic::Ordering;
+        match topic {
+            Topic::Version => vec![format!("neko-dns {}", env!("CARGO_PKG_VERSION"))],
+            Topic::Stats => {
+                let cache = self.cache.get_stats();
+                vec![
+                    format!("uptime={}s", self.metrics.start_time.elapsed().as_secs()),
+                    format!("queries={}", self.metrics.queries_total.load(Ordering::Relaxed)),
+                    format!("cache_entries={}", cache["entries"].as_u64().unwrap_or(0)),
+                    format!("cache_hit_rate={}%", cache["hit_rate_percent"].as_str().unwrap_or("0.0")),
+                ]
+            }
+            Topic::Mode => vec![
+                format!("mode={}", if self.recursive.is_some() { "recursive" } else { "forward" }),
+                format!("profile={}", match self.config.profile {
+                    Profile::Default => "default",
+                    Profile::Production => "production",
+                }),
+                format!("offline={}", self.is_offline()),
+                format!("maintenance={}", self.maintenance.status()["mode"].as_str().unwrap_or("off")),
+            ],
+            Topic::Features => {
+                let config = &self.config;
+                let features: Vec<&str> = [
+                    ("ttl_alchemy", config.ttl_alchemy.enabled),
+                    ("prefetch", config.prefetch.enabled),
+                    ("trust", config.trust.enabled),
+                    ("chaos", config.chaos.enabled),
+                    ("journal", config.journal.enabled),
+                    ("negative", config.negative.enabled),
+                    ("neko_comment", config.neko_comment.enabled),
+                    ("recursive", config.recursive.enabled),
+                    ("curiosity_walk", config.recursive.enabled && config.recursive.curiosity_walk),
+                    ("journey_txt", config.recursive.enabled && config.recursive.journey_txt),
+                    ("persist", config.persist.enabled),
+                ].into_iter().filter(|(_, on)| *on).map(|(name, _)| name).collect();
+                vec![format!("features={}", if features.is_empty() { "none".to_string() } else { features.join(",") })]
+            }
+        }
+    }
+
+    /// Snapshot the answer cache to persist.path; a trigger while one runs is skipped
+    pub async fn snapshot_cache(&self) -> Option<usize> {
+        let persist = &self.config.persist;
+        if !persist.enabled {
+            return None;
+        }
+        match self.cache.snapshot(&persist.path, persist.snapshot_batch_size, persist.compression).await {
+            Ok(Some(n)) => {
+                debug!("💾 Cache: saved {} entries to {}", n, persist.path);
+                Some(n)
+            }
+            Ok(None) => {
+                debug!("💾 Cache: snapshot already running, trigger skipped");
+                None
+            }
+            Err(e) => {
+                warn!("💾 Cache: saving to {} failed: {}", persist.path, e);
+                None
+            }
+        }
+    }
+
+    /// Snapshot the answer cache every persist.snapshot_interval_secs
+    pub async fn run_cache_snapshotter(&self) {
+        let persist = &self.config.persist;
+        if !persist.enabled || persist.snapshot_interval_secs == 0 {
+            return;
+        }
+        let mut ticker = tokio::time::interval(Duration::from_secs(persist.snapshot_interval_secs));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.snapshot_cache().await;
+        }
+    }
+
+    /// Flush state that should survive a restart
+    pub fn save_state(&self) {
+        if let Some(recursive) = self.recursive.as_ref() {
+            recursive.save_infra_cache();
+        }
+        self.journal.save();
+    }
+
+    /// 🩺 起動時セルフテスト - ルートウォームアップを待ってからカナリア名を解決
+    pub async fn run_selftest(&self) {
+        if !self.config.selftest.enabled {
+            return;
+        }
+        while self.is_offline() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        for _ in 0..SELFTEST_READY_WAIT_SECS * 10 {
+            if self.is_ready() { break; }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        self.selftest().await;
+    }
+
+    /// Resolve the canary (uncounted, like synthetic names) and record the outcome
+    pub async fn selftest(&self) -> SelfTestResult {
+        let name = self.config.selftest.name.trim_end_matches('.');
+        let result = match RecordType::from_name(&self.config.selftest.qtype) {
+            None => SelfTestResult { ok: false, latency_ms: 0, detail: format!("unknown type {}", self.config.selftest.qtype) },
+            Some(qtype) => {
+                let query = packet::build_query(random_query_id(), name, qtype, true);
                 let start = std::time::Instant::now();
+                let outcome = self.resolve_fresh(&query, name, qtype, &self.config.resolution.order, &mut QueryFeatures::new(), &MetricsCounters::new()).await;
+                let latency_ms = start.elapsed().as_millis() as u64;
+                match outcome.and_then(|(response, ..)| packet::parse_packet(&response)) {
+                    Ok(p) if p.header.rcode == crate::dns::types::ResponseCode::NoError && !p.answers.is_empty() => {
+                        SelfTestResult { ok: true, latency_ms, detail: format!("{} answers", p.answers.len()) }
+                    }
+                    Ok(p) => SelfTestResult { ok: false, latency_ms, detail: format!("{:?}, {} answers", p.header.rcode, p.answers.len()) },
+                    Err(e) => SelfTestResult { ok: false, latency_ms, detail: e.to_string() },
+                }
+            }
+        };
+        if result.ok {
+            info!("🩺 Self-test OK: {} {} in {}ms ({})", name, self.config.selftest.qtype, result.latency_ms, result.detail);
+        } else {
+            warn!("🩺 Self-test FAILED: {} {} ({}) - marking degraded", name, self.config.selftest.qtype, result.detail);
+        }
+        *self.selftest.write() = Some(result.clone());
+        result
+    }
+
+    /// Health for /healthz: degraded only when the self-test ran and failed
+    pub fn health(&self) -> (bool, serde_json::Value) {
+        let selftest = self.selftest.read().clone();
+        let healthy = selftest.as_ref().is_none_or(|r| r.ok);
+        let selftest_json = match selftest {
+            Some(r) => serde_json::json!({"ok": r.ok, "latency_ms": r.latency_ms, "detail": r.detail}),
+            None => serde_json::json!(null),
+        };
+        (healthy, serde_json::json!({
+            "status": if healthy { "ok" } else { "degraded" },
+            "ready": self.is_ready(),
+            "selftest": selftest_json,
+        }))
+    }
+
+    /// Answer a synthetic name from its private cache, resolving (uncounted) when stale
+    async fn answer_synthetic(&self, query_data: &[u8], qname: &str, qtype: RecordType) -> anyhow::Result<Vec<u8>> {
+        let key = (qname.to_lowercase(), qtype.to_u16());
+        let ttl = Duration::from_secs(self.config.metrics.synthetic_ttl_secs);
+        if let Some(entry) = self.synthetic_cache.get(&key) {
+            let age = entry.1.elapsed();
+            if age < ttl {
+                return packet::build_response(query_data, &entry.0, (ttl - age).as_secs().max(1) as u32);
+            }
+        }
+
+        let mut features = QueryFeatures::new();
+        let (response, ..) = self.resolve_fresh(query_data, qname, qtype, &self.config.resolution.order, &mut features, &MetricsCounters::new()).await?;
+        // Don't pin a failure - the next probe should see recovery
+        if !packet::is_servfail(&response) {
+            self.synthetic_cache.insert(key, (response.clone(), std::time::Instant::now()));
+        }
+        Ok(response)
+    }
+
+    /// SERVFAIL for maintenance mode, with EDE 14 (Not Ready) for EDNS clients
+    fn maintenance_servfail(&self, query_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.servfail_with_ede(query_data, EDE_NOT_READY, "maintenance mode")
+    }
+
+    /// Empty NOERROR, with the synthetic SOA when negative.synthetic_soa is on
+    fn synthetic_nodata(&self, query_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let response = packet::build_nodata(query_data)?;
+        if !self.config.negative.synthetic_soa {
+            return Ok(response);
+        }
+        let mut parsed = packet::parse_packet(&response)?;
+        packet::add_negative_soa(&mut parsed, self.config.negative.synthetic_soa_ttl);
+        Ok(parsed.to_wire())
+    }
+
+    /// resolution.default answer, with EDE "Other" for clients that sent OPT
+    fn no_route_response(&self, query_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let response = match self.config.resolution.default_answer {
+            NoRouteAnswer::Servfail => packet::build_servfail(query_data)?,
+            NoRouteAnswer::Refused => packet::build_refused(query_data)?,
+            NoRouteAnswer::Nxdomain => {
+                let mut parsed = packet::parse_packet(&packet::build_nodata(query_data)?)?;
+                parsed.header.rcode = crate::dns::types::ResponseCode::NxDomain;
+                if self.config.negative.synthetic_soa {
+                    packet::add_negative_soa(&mut parsed, self.config.negative.synthetic_soa_ttl);
+                }
+                parsed.to_wire()
+            }
+        };
+        self.with_ede(query_data, response, EDE_OTHER, "no resolution stage applies to this name")
+    }
+
+    /// SERVFAIL carrying an Extended DNS Error for clients that sent OPT
+    fn servfail_with_ede(&self, query_data: &[u8], info_code: u16, extra_text: &str) -> anyhow::Result<Vec<u8>> {
+        self.with_ede(query_data, packet::build_servfail(query_data)?, info_code, extra_text)
+    }
+
+    fn with_ede(&self, query_data: &[u8], response: Vec<u8>, info_code: u16, extra_text: &str) -> anyhow::Result<Vec<u8>> {
+        if !self.edns.client_has_opt(query_data) {
+            return Ok(response);
+        }
+        match self.edns.add_ede(&response, info_code, extra_text) {
+            Ok(with_ede) => Ok(with_ede),
+            Err(e) => {
+                debug!("EDE not added: {}", e);
+                Ok(response)
+            }
+        }
+    }
+
+    /// Resolve a cache miss through the fresh stages of `stages` (resolution.order,
+    /// "cache" entries skipped), stopping at the first that answers. A failing
+    /// recursion ends the chain with SERVFAIL when recursive.fallback_to_forward = false;
+    /// `NoRoute` when every stage passed without trying.
+    /// Counters go to `metrics`, which is a scratch set for synthetic (health-check) names.
+    async fn resolve_fresh(
+        &self,
+        query_data: &[u8],
+        qname: &str,
+        qtype: RecordType,
+        stages: &[ResolutionStage],
+        features: &mut QueryFeatures,
+        metrics: &MetricsCounters,
+    ) -> anyhow::Result<Fresh> {
+        let mut last_error = None;
+        let mut filtered = None;
+        for stage in stages.iter().filter(|s| **s != ResolutionStage::Cache) {
+            match self.resolve_stage(*stage, query_data, qname, qtype, features, metrics).await {
+                // upstream_detect_filtering: a later stage may have the real answer
+                Ok(Some(answer)) if self.config.upstream_detect_filtering && *stage == ResolutionStage::Forward
+                    && crate::upstream::looks_filtered(&answer.0) => {
+                    debug!("Forwarded answer for {} {} looks filtered, trying the next stage", qname, qtype.name());
+                    filtered = Some(answer);
+                }
+                Ok(Some(answer)) => return Ok(answer),
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("{:?} stage failed for {} {}: {}", stage, qname, qtype.name(), e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if let Some(answer) = filtered {
+            return Ok(answer);
+        }
+        Err(last_error.unwrap_or_else(|| NoRoute.into()))
+    }
+
+    /// resolve_fresh for a cache miss; concurrent misses of the same question share
+    /// the first one's resolution and get its answer under their own transaction ID
+    async fn resolve_miss(
+        &self,
+        query_data: &[u8],
+        qname: &str,
+        qtype: RecordType,
+        stages: &[ResolutionStage],
+        features: &mut QueryFeatures,
+    ) -> anyhow::Result<Fresh> {
+        // Scoped answers differ per subnet (and echo the asking client's ECS), so only
+        // clients of the same ECS network share a resolution
+        let subnet = self.config.cache.ecs_scoped
+            .then(|| crate::edns::client_subnet(query_data))
+            .flatten()
+            .map(|ecs| ClientNet::masked(ecs.addr, ecs.source_prefix));
+        let key = (qname.to_lowercase(), qtype.to_u16(), self.edns.client_dnssec_ok(query_data), subnet);
+        let cell = self.resolving.entry(key.clone()).or_default().clone();
+        let mut led = false;
+        let outcome = cell.get_or_init(|| async {
+            led = true;
+            let result = self.resolve_fresh(query_data, qname, qtype, stages, features, &self.metrics).await
+                .map_err(Arc::new);
+            self.resolving.remove(&key);
+            result
+        }).await;
+        if !led {
+            debug!("Joined the resolution in flight for {} {}", qname, qtype.name());
+        }
+        match outcome {
+            Ok(fresh) => {
+                let mut fresh = fresh.clone();
+                if fresh.0.len() >= 2 && query_data.len() >= 2 {
+                    fresh.0[..2].copy_from_slice(&query_data[..2]);
+                }
+                Ok(fresh)
+            }
+            Err(e) if e.is::<NoRoute>() => Err(NoRoute.into()),
+            Err(e) => Err(anyhow::anyhow!("{:#}", e)),
+        }
+    }
 
-                match Self::query_local_zone(query_data, addr, timeout).await {
+    /// One fresh stage: Ok(None) passes the query on to the next stage
+    async fn resolve_stage(
+        &self,
+        stage: ResolutionStage,
+        query_data: &[u8],
+        qname: &str,
+        qtype: RecordType,
+        features: &mut QueryFeatures,
+        metrics: &MetricsCounters,
+    ) -> anyhow::Result<Option<Fresh>> {
+        match stage {
+            ResolutionStage::Cache => Ok(None),
+            ResolutionStage::LocalZone => {
+                // 🏠 ローカルドメイン転送成功 → 呼び出し側で通常どおりポジティブ/ネガティブキャッシュされる
+                let Some(answer) = self.try_local_zone_forward(query_data, qname).await else { return Ok(None) };
+                features.local_zone = true;
+                metrics.local_zone_queries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Ok(Some((answer.response, "local-zone".to_string(), answer.latency, answer.ttl, Transport::LocalZone)))
+            }
+            ResolutionStage::Recursive => {
+                let Some(recursive) = self.recursive.as_ref() else { return Ok(None) };
+                if !recursive.is_ready() {
+                    if self.fail_fast_without_roots() {
+                        // 🌲 ルート全滅: タイムアウトを待たずにSERVFAIL (EDE 22)
+                        debug!("🌲 All root servers unreachable, failing {} {} fast", qname, qtype.name());
+                        let response = self.servfail_with_ede(query_data, EDE_NO_REACHABLE_AUTHORITY, "all root servers unreachable")?;
+                        return Ok(Some((response, "roots-unreachable".to_string(), Duration::ZERO, 0, Transport::Recursive)));
+                    }
+                    // ルートウォームアップ完了前は次のステージ (通常はフォワード) へ
+                    debug!("🌲 Recursion not ready yet, passing {} {} on", qname, qtype.name());
+                    return Ok(None);
+                }
+                // 🌲 再帰解決モード
+                features.recursive = true;
+                features.parallel_dfs = true;
+                metrics.recursive_queries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let start_resolve = std::time::Instant::now();
+                let resolved = recursive.resolve(qname, qtype, &self.curiosity, &self.journey).await
+                    .and_then(|response| {
+                        // The resolver answers SERVFAIL when every branch failed; without the
+                        // fallback that is a failure of its own and gets the EDE below
+                        if packet::is_servfail(&response) && !self.config.recursive.fallback_to_forward {
+                            anyhow::bail!("no server gave an answer");
+                        }
+                        Ok(response)
+                    });
+                match resolved {
                     Ok(mut response) => {
-                        let latency = start.elapsed();
+                        let latency = start_resolve.elapsed();
+                        metrics.recursive_successes.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        metrics.record_recursive_latency(latency.as_micros() as u64);
+                        let ttl = packet::parse_packet(&response)
+                            .ok()
+                            .and_then(|p| p.answers.first().map(|a| a.ttl))
+                            .unwrap_or(0);
                         // 元クエリのトランザクションIDをコピー
-                        if response.len() >= 2 && query_data.len() >= 2 {
+                        if response.len() >= 12 && query_data.len() >= 2 {
                             response[0] = query_data[0];
                             response[1] = query_data[1];
                         }
-                        // RA=1 (Recursion Available) を設定
-                        if response.len() >= 4 {
-                            response[3] |= 0x80;
-                        }
-                        info!("🏠 Local zone {} -> {}:{} ({:.1}ms)", qname, zone.server, zone.port, latency.as_millis());
-                        return Some((response, latency));
+                        features.journey_recorded = true;
+                        Ok(Some((response, "recursive".to_string(), latency, ttl, Transport::Recursive)))
+                    }
+                    Err(e) if e.is::<crate::recursive::ResolverBusy>() => {
+                        // Backpressure on the expensive path: neither queued nor passed on
+                        let response = self.servfail_with_ede(query_data, EDE_OTHER, "server busy")?;
+                        Ok(Some((response, "recursive-busy".to_string(), start_resolve.elapsed(), 0, Transport::Recursive)))
+                    }
+                    Err(e) if !self.config.recursive.fallback_to_forward => {
+                        // フォールバック無効: 第三者にクエリを漏らさずSERVFAIL
+                        warn!("🌲 Recursive resolution failed for {} {}: {}", qname, qtype.name(), e);
+                        metrics.recursive_failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let response = self.servfail_with_ede(query_data, EDE_NO_REACHABLE_AUTHORITY, "recursion failed")?;
+                        Ok(Some((response, "recursive".to_string(), start_resolve.elapsed(), 0, Transport::Recursive)))
                     }
                     Err(e) => {
-                        warn!("🏠 Local zone query failed for {} -> {}:{}: {}", qname, zone.server, zone.port, e);
-                        return None;
+                        // フォールバック: 次のステージ (通常はupstream forwarding)
+                        warn!("🌲 Recursive resolution failed for {} {}: {}, trying the next stage", qname, qtype.name(), e);
+                        metrics.recursive_failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        features.recursive = false;
+                        Err(e)
                     }
                 }
             }
+            ResolutionStage::Forward => {
+                // 📡 フォワーディング
+                features.upstream_forward = true;
+                metrics.upstream_queries.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let result = self.upstream.race_query(query_data).await?;
+                features.upstream_winner = Some(result.upstream_name.clone());
+                Ok(Some((result.response, result.upstream_name, result.latency, result.original_ttl, result.transport)))
+            }
         }
-
-        None
     }
 
-    /// ローカルゾーンサーバーへの単純UDP転送
-    async fn query_local_zone(query: &[u8], addr: SocketAddr, timeout: Duration) -> anyhow::Result<Vec<u8>> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.send_to(query, addr).await?;
+    /// Recursion enabled but the warmup reached no root, and there is nothing to
+    /// forward to (or recursive.roots_unreachable = "servfail")
+    fn fail_fast_without_roots(&self) -> bool {
+        self.recursive.as_ref().is_some_and(|r| r.roots_unreachable())
+            && (self.config.recursive.roots_unreachable == RootsUnreachableAction::Servfail
+                || self.config.upstreams.is_empty())
+    }
 
-        let mut buf = vec![0u8; 4096];
-        let len = tokio::time::timeout(timeout, socket.recv(&mut buf))
-            .await
-            .map_err(|_| anyhow::anyhow!("Timeout querying local zone {}", addr))??;
+    /// 🏠 ローカルゾーン転送: ドメインがローカルゾーンにマッチする場合、指定サーバーに転送
+    async fn try_local_zone_forward(&self, query_data: &[u8], qname: &str) -> Option<LocalZoneAnswer> {
+        let zone = match_local_zone(&self.config.local_zones, qname)?;
+        let servers = self.local_zone_servers.get(&zone.domain.trim_end_matches('.').to_lowercase())?;
+        debug!("🏠 Local zone match: {} -> {}", qname, zone.domain);
 
-        Ok(buf[..len].to_vec())
+        let start = std::time::Instant::now();
+        let mut attempt = 0;
+        loop {
+            match servers.race_query(query_data).await {
+                Ok(result) => {
+                    let latency = start.elapsed();
+                    info!("🏠 Local zone {} -> {} ({:.1}ms)", qname, result.upstream_name, latency.as_millis());
+                    return LocalZoneAnswer::new(result.response, latency);
+                }
+                // A backend blip shouldn't send an internal name out to the recursion/forwarders
+                Err(e) if attempt < zone.retries => {
+                    attempt += 1;
+                    debug!("🏠 Local zone query failed for {}, retry {}/{}: {}", qname, attempt, zone.retries, e);
+                }
+                Err(e) => {
+                    warn!("🏠 Local zone query failed for {} ({} attempts): {}", qname, attempt + 1, e);
+                    return None;
+                }
+            }
+        }
     }
 
     /// ジャーニー履歴取得 (API用)
@@ -477,3 +1893,2219 @@ impl QueryEngine {
         self.journey.get_history(limit)
     }
 }
+
+/// Every fresh stage passed the query on without trying it (answered per resolution.default)
+#[derive(Debug)]
+struct NoRoute;
+
+impl std::fmt::Display for NoRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no resolution stage applies")
+    }
+}
+
+impl std::error::Error for NoRoute {}
+
+/// 🏠 ローカルゾーンサーバーの応答
+struct LocalZoneAnswer {
+    response: Vec<u8>,
+    latency: Duration,
+    /// 肯定応答は最初のanswerのTTL、NXDOMAIN/NODATAはSOAのネガティブTTL
+    ttl: u32,
+}
+
+impl LocalZoneAnswer {
+    /// パースできない応答はNone (通常の解決にフォールバック)
+    fn new(response: Vec<u8>, latency: Duration) -> Option<Self> {
+        let parsed = match packet::parse_packet(&response) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("🏠 Unparseable local zone response: {}", e);
+                return None;
+            }
+        };
+        let ttl = match parsed.answers.first() {
+            Some(answer) => answer.ttl,
+            None => packet::soa_negative_ttl(&parsed).unwrap_or(0),
+        };
+        Some(Self { response, latency, ttl })
+    }
+}
+
+/// Static answer for a [[health_domain]]: its A (or a_pool pick) / TXT, NODATA for anything else
+fn health_domain_response(query: &[u8], qtype: RecordType, domain: &HealthDomainConfig) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = packet::parse_packet(query)?;
+    let qname = parsed.questions.first()
+        .map(|q| q.name.clone())
+        .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+    parsed.header.qr = true;
+    parsed.header.aa = true;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    let pool = pool_addresses(domain);
+    match (qtype, domain.a, domain.txt.as_deref()) {
+        (RecordType::A, _, _) if !pool.is_empty() => {
+            for a in pool {
+                parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::A, domain.ttl, a.octets().to_vec()));
+            }
+        }
+        (RecordType::A, Some(a), _) => {
+            parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::A, domain.ttl, a.octets().to_vec()));
+        }
+        (RecordType::TXT, _, Some(txt)) => {
+            let mut rdata = Vec::new();
+            for chunk in txt.as_bytes().chunks(255) {
+                rdata.push(chunk.len() as u8);
+                rdata.extend_from_slice(chunk);
+            }
+            parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::TXT, domain.ttl, rdata));
+        }
+        _ => packet::add_negative_soa(&mut parsed, domain.ttl),
+    }
+    Ok(parsed.to_wire())
+}
+
+/// The a_pool members to answer with: one picked by weight, or all of them (pool_answer)
+fn pool_addresses(domain: &HealthDomainConfig) -> Vec<std::net::Ipv4Addr> {
+    let members = domain.a_pool.iter().filter(|m| m.weight > 0);
+    if domain.pool_answer == PoolAnswer::All {
+        return members.map(|m| m.address).collect();
+    }
+    let total: u64 = members.clone().map(|m| m.weight as u64).sum();
+    if total == 0 {
+        return Vec::new();
+    }
+    let mut point = { use rand::Rng; rand::thread_rng().gen_range(0..total) };
+    for member in members {
+        if point < member.weight as u64 {
+            return vec![member.address];
+        }
+        point -= member.weight as u64;
+    }
+    Vec::new()
+}
+
+/// Unpredictable query ID for the queries the engine sends itself (RFC 5452)
+pub(crate) fn random_query_id() -> u16 {
+    use rand::rngs::OsRng;
+    use rand::Rng;
+    OsRng.gen()
+}
+
+/// What cache misses can be resolved by, as checked at startup
+#[derive(Debug, PartialEq)]
+enum ResolutionPaths {
+    /// Forwarding or recursion (or a local zone for ".") takes any name
+    Complete,
+    /// Only the local / authoritative zones resolve; the message says which
+    Partial(String),
+    /// Nothing does: every miss gets resolution.default
+    Missing(String),
+}
+
+/// Resolution paths of this configuration, given whether the recursive resolver came up
+fn check_resolution_paths(config: &Config, recursive_up: bool) -> ResolutionPaths {
+    let order = &config.resolution.order;
+    if order.contains(&ResolutionStage::Forward) && !config.upstreams.is_empty() {
+        return ResolutionPaths::Complete;
+    }
+    if order.contains(&ResolutionStage::Recursive) && recursive_up {
+        return ResolutionPaths::Complete;
+    }
+    let local_zones: Vec<&str> = if order.contains(&ResolutionStage::LocalZone) {
+        config.local_zones.iter().map(|z| z.domain.as_str()).collect()
+    } else {
+        Vec::new()
+    };
+    if local_zones.iter().any(|d| d.trim_end_matches('.').is_empty()) {
+        return ResolutionPaths::Complete;
+    }
+    let why = match (config.recursive.enabled, order.contains(&ResolutionStage::Recursive)) {
+        (true, true) => "the recursive resolver failed to start",
+        (true, false) => "resolution.order has no \"recursive\" stage",
+        (false, _) => "recursion is disabled",
+    };
+    let forward = if order.contains(&ResolutionStage::Forward) { "no upstreams are left" } else { "resolution.order has no \"forward\" stage" };
+    let mut zones: Vec<&str> = local_zones;
+    zones.extend(config.authoritative_zones.iter().map(|z| z.domain.as_str()));
+    if zones.is_empty() {
+        return ResolutionPaths::Missing(format!(
+            "No resolution path: {} and {}, so every cache miss is answered with resolution.default ({:?})",
+            why, forward, config.resolution.default_answer
+        ));
+    }
+    ResolutionPaths::Partial(format!(
+        "Only {} can be resolved: {} and {}; every other name gets resolution.default ({:?})",
+        zones.join(", "), why, forward, config.resolution.default_answer
+    ))
+}
+
+/// qnameを担当するローカルゾーン: 重なっている場合は最長一致 (iot.home は home より優先)
+fn match_local_zone<'a>(zones: &'a [LocalZoneConfig], qname: &str) -> Option<&'a LocalZoneConfig> {
+    let qname = qname.trim_end_matches('.').to_lowercase();
+    zones.iter()
+        .filter(|zone| {
+            let suffix = zone.domain.trim_end_matches('.').to_lowercase();
+            // "mynk.home" matches "foo.mynk.home" and "mynk.home" itself
+            qname == suffix || qname.ends_with(&format!(".{}", suffix))
+        })
+        .max_by_key(|zone| zone.domain.trim_end_matches('.').len())
+}
+
+/// 旅路TXTを付けるか判定: EDNS旅路オプション (edns.journey_option_code) 付きのクエリだけ
+fn journey_requested(only_on_request: bool, opted_in: bool) -> bool {
+    !only_on_request || opted_in
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use tokio::net::UdpSocket;
+
+    /// Minimal config forwarding to a single upstream at `upstream`
+    fn test_config(upstream: SocketAddr, extra: &str) -> Config {
+        let toml_str = format!(r#"
+            [listen]
+            address = "127.0.0.1"
+            port = 0
+            [[upstreams]]
+            name = "stub"
+            address = "{}"
+            port = {}
+            timeout_ms = 500
+            [cache]
+            [ttl_alchemy]
+            enabled = false
+            min_ttl = 0
+            [prefetch]
+            enabled = false
+            [trust]
+            [chaos]
+            [journal]
+            enabled = false
+            [negative]
+            [edns]
+            [web]
+            enabled = false
+            [neko_comment]
+            enabled = false
+            {}
+        "#, upstream.ip(), upstream.port(), extra);
+        toml::from_str(&toml_str).unwrap()
+    }
+
+    /// Upstream stub: answers with one A record (192.0.2.1, TTL 60), or SERVFAIL while `failing` is set
+    async fn spawn_upstream(failing: Arc<AtomicBool>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                resp.header.ra = true;
+                if failing.load(Ordering::Relaxed) {
+                    resp.header.rcode = crate::dns::types::ResponseCode::ServFail;
+                } else {
+                    let qname = resp.questions[0].name.clone();
+                    resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, 1]));
+                }
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        addr
+    }
+
+    fn edns_query(name: &str) -> Vec<u8> {
+        let mut query = packet::build_query(0x3131, name, RecordType::A, true);
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        query
+    }
+
+    fn has_ede(response: &[u8]) -> bool {
+        let parsed = packet::parse_packet(response).unwrap();
+        parsed.additionals.iter()
+            .filter(|r| r.rtype == RecordType::OPT)
+            .any(|r| r.rdata.len() >= 2 && r.rdata[..2] == [0, 15])
+    }
+
+    #[tokio::test]
+    async fn test_stale_on_error_only_after_failure() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let upstream = spawn_upstream(failing.clone()).await;
+        let config = test_config(upstream, "");
+        assert!(!config.cache.serve_stale);
+        let mut config = config;
+        config.cache.serve_stale_on_error = true;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let name = "stale.example.com";
+
+        engine.handle_query(&edns_query(name)).await.unwrap();
+        engine.cache.backdate(name, &RecordType::A, 120);
+
+        // Fresh resolution works → fresh answer, no stale marker
+        let fresh = engine.handle_query(&edns_query(name)).await.unwrap();
+        let parsed = packet::parse_packet(&fresh).unwrap();
+        assert_eq!(parsed.answers[0].ttl, 60);
+        assert!(!has_ede(&fresh));
+
+        // Fresh resolution fails → expired entry with EDE "Stale Answer"
+        engine.cache.backdate(name, &RecordType::A, 120);
+        failing.store(true, Ordering::Relaxed);
+        let stale = engine.handle_query(&edns_query(name)).await.unwrap();
+        let parsed = packet::parse_packet(&stale).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NoError);
+        assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, 1]);
+        assert_eq!(parsed.answers[0].ttl, 30);
+        assert!(has_ede(&stale));
+    }
+
+    #[tokio::test]
+    async fn test_stale_on_error_disabled_passes_failure_through() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let upstream = spawn_upstream(failing.clone()).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let name = "stale.example.com";
+
+        engine.handle_query(&edns_query(name)).await.unwrap();
+        engine.cache.backdate(name, &RecordType::A, 120);
+        failing.store(true, Ordering::Relaxed);
+        let response = engine.handle_query(&edns_query(name)).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, crate::dns::types::ResponseCode::ServFail);
+    }
+
+    #[test]
+    fn test_journey_only_on_request() {
+        let edns = EdnsHandler::new(&test_config("127.0.0.1:53".parse().unwrap(), "").edns);
+        let journey_opt = crate::edns::EdnsMeta { options: vec![(65002, Vec::new())] };
+        let other_opt = crate::edns::EdnsMeta { options: vec![(65001, b"mood=curious".to_vec())] };
+
+        assert!(!journey_requested(true, edns.journey_requested(None)));
+        assert!(!journey_requested(true, edns.journey_requested(Some(&other_opt))));
+        assert!(journey_requested(true, edns.journey_requested(Some(&journey_opt))));
+        // Opt-out restores the old always-on behaviour
+        assert!(journey_requested(false, edns.journey_requested(None)));
+
+        // Straight from the wire, as dig +ednsopt=65002 sends it
+        let mut query = packet::build_query(0x2433, "example.com", RecordType::A, true);
+        query[11] = 1;
+        query.extend_from_slice(&edns.build_opt_record(&[(65002, b"")]));
+        assert!(edns.journey_requested(edns.extract_options(&query).as_ref()));
+        assert!(!edns.journey_requested(edns.extract_options(&edns_query("example.com")).as_ref()));
+    }
+
+    #[tokio::test]
+    async fn test_forwards_until_recursion_ready() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        // Roots in TEST-NET-1 never answer, so warmup can't complete
+        let hints = std::env::temp_dir().join(format!("neko-dns-unready-{}.hints", std::process::id()));
+        std::fs::write(&hints, ".  3600000  NS  a.root-servers.net.\na.root-servers.net.  3600000  A  192.0.2.200\n").unwrap();
+        let extra = format!("[recursive]\nenabled = true\nroot_hints_path = \"{}\"\nroot_reprobe_interval_secs = 0\n", hints.display());
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, &extra))).await.unwrap();
+        std::fs::remove_file(&hints).unwrap();
+
+        assert!(engine.recursive.is_some());
+        assert!(!engine.is_ready());
+        let response = engine.handle_query(&edns_query("early.example.com")).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, 1]);
+        assert_eq!(engine.metrics.recursive_queries.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_udp_retransmit_resolved_once() {
+        // Upstream that counts queries and answers after 200ms
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let upstream = socket.local_addr().unwrap();
+        let seen = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = seen.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut resp = buf[..len].to_vec();
+                resp[2] |= 0x80;
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    let _ = socket.send_to(&resp, peer).await;
+                });
+            }
+        });
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let client: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let query = packet::build_query(0x4242, "retransmit.example.com", RecordType::A, true);
+
+        let (first, second) = tokio::join!(
+            engine.handle_udp_query(client, &query),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                engine.handle_udp_query(client, &query).await
+            },
+        );
+        assert!(first.unwrap().is_some());
+        assert!(second.unwrap().is_none());
+        assert_eq!(seen.load(Ordering::Relaxed), 1);
+
+        // Same ID from another client port is a different query
+        let other: SocketAddr = "192.0.2.10:40001".parse().unwrap();
+        assert!(engine.handle_udp_query(other, &query).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_deep_name_rejected_before_resolution() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let deep = vec!["a"; 200].join(".");
+
+        let response = engine.handle_query(&packet::build_query(0x0bad, &deep, RecordType::A, true)).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::FormErr);
+        assert!(parsed.answers.is_empty());
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.metrics.cache_misses.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_synthetic_name_not_counted() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let upstream = spawn_upstream(failing.clone()).await;
+        let config = test_config(upstream, "[metrics]\nsynthetic_names = [\"health.example.com.\"]\nsynthetic_ttl_secs = 60\n");
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        let first = engine.handle_query(&edns_query("Health.Example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&first).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+        // Second probe comes from the synthetic cache, even with the upstream down
+        failing.store(true, Ordering::Relaxed);
+        let second = engine.handle_query(&edns_query("health.example.com")).await.unwrap();
+        let parsed = packet::parse_packet(&second).unwrap();
+        assert_eq!(parsed.header.id, 0x3131);
+        assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, 1]);
+
+        assert_eq!(engine.metrics.cache_misses.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.metrics.queries_total.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+        assert!(engine.cache.get("health.example.com", &RecordType::A).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_selftest_result_reflected_in_health() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let upstream = spawn_upstream(failing.clone()).await;
+        let config = test_config(upstream, "[selftest]\nenabled = true\nname = \"canary.example.com\"\n");
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        // Not run yet → not degraded
+        assert!(engine.health().0);
+
+        assert!(engine.selftest().await.ok);
+        let (healthy, body) = engine.health();
+        assert!(healthy);
+        assert_eq!(body["status"], "ok");
+
+        failing.store(true, Ordering::Relaxed);
+        assert!(!engine.selftest().await.ok);
+        let (healthy, body) = engine.health();
+        assert!(!healthy);
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["selftest"]["ok"], false);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_query_slots_drop_when_saturated() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.listen.max_concurrent_queries = 2;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        let first = engine.try_acquire_query_slot().unwrap();
+        let _second = engine.try_acquire_query_slot().unwrap();
+        assert!(engine.try_acquire_query_slot().is_none());
+        assert_eq!(engine.metrics.queries_dropped_saturated.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        assert!(engine.try_acquire_query_slot().is_some());
+        assert_eq!(engine.metrics.queries_dropped_saturated.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_chaos_modes() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.chaos.enabled = true;
+        config.chaos.servfail_probability = 1.0;
+        config.chaos.failure_modes = vec![ChaosFailureMode::Refused];
+        let engine = QueryEngine::new(Arc::new(config.clone())).await.unwrap();
+        let response = engine.handle_query(&edns_query("refused.example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, crate::dns::types::ResponseCode::Refused);
+
+        // Timeout mode: no datagram goes back, and the upstream is never asked
+        config.chaos.failure_modes = vec![ChaosFailureMode::Timeout];
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let client: SocketAddr = "192.0.2.10:5300".parse().unwrap();
+        assert!(engine.handle_udp_query(client, &edns_query("dropped.example.com")).await.unwrap().is_none());
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_chaos_delay_applied() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.chaos.enabled = true;
+        config.chaos.servfail_probability = 0.0;
+        config.chaos.delay_probability = 1.0;
+        config.chaos.delay_min_ms = 150;
+        config.chaos.delay_max_ms = 150;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        let started = std::time::Instant::now();
+        let response = engine.handle_query(&edns_query("slow.example.com")).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        // The real answer still comes back
+        assert_eq!(packet::parse_packet(&response).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+        assert_eq!(engine.chaos.get_stats()["delays_injected"], 1);
+    }
+
+    /// Local zone backend: NXDOMAIN + SOA for everything, preceded by a
+    /// datagram with the wrong transaction ID that must be ignored
+    async fn spawn_local_zone_nxdomain(hits: Arc<std::sync::atomic::AtomicU64>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                hits.fetch_add(1, Ordering::Relaxed);
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                resp.header.aa = true;
+                resp.header.rcode = crate::dns::types::ResponseCode::NxDomain;
+                let mut soa = packet::encode_name("ns.mynk.home");
+                soa.extend(packet::encode_name("hostmaster.mynk.home"));
+                for v in [1u32, 3600, 600, 86400, 120] {
+                    soa.extend_from_slice(&v.to_be_bytes());
+                }
+                resp.authorities.push(packet::DnsRecord::new("mynk.home", RecordType::SOA, 300, soa));
+                let good = resp.to_wire();
+                let mut spoofed = good.clone();
+                spoofed[0] ^= 0xff;
+                let _ = socket.send_to(&spoofed, peer).await;
+                let _ = socket.send_to(&good, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_local_zone_nxdomain_negatively_cached() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let hits = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let local = spawn_local_zone_nxdomain(hits.clone()).await;
+        let config = test_config(upstream, &format!(
+            "[[local_zones]]\ndomain = \"mynk.home\"\nserver = \"127.0.0.1\"\nport = {}\ntimeout_ms = 500\n",
+            local.port(),
+        ));
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        for _ in 0..2 {
+            let response = engine.handle_query(&edns_query("missing.mynk.home")).await.unwrap();
+            let parsed = packet::parse_packet(&response).unwrap();
+            assert_eq!(parsed.header.id, 0x3131);
+            assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NxDomain);
+            assert!(parsed.header.ra);
+        }
+        // Second answer came from the negative cache, not the backend
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.negative_cache_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_edns_query_gets_our_opt() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+
+        let response = engine.handle_query(&edns_query("opt.example.com")).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        let opt = parsed.additionals.last().unwrap();
+        assert_eq!(opt.rtype, RecordType::OPT);
+        assert_eq!(opt.rclass.to_u16(), 1232);
+        assert_eq!(parsed.additionals.iter().filter(|r| r.rtype == RecordType::OPT).count(), 1);
+
+        let plain = engine.handle_query(&packet::build_query(0x0101, "opt.example.com", RecordType::A, true)).await.unwrap();
+        assert!(packet::parse_packet(&plain).unwrap().additionals.iter().all(|r| r.rtype != RecordType::OPT));
+    }
+
+    /// (RD, RA, AD, CD) header bits of a packet
+    fn flag_bits(packet: &[u8]) -> (bool, bool, bool, bool) {
+        (packet[2] & 0x01 != 0, packet[3] & 0x80 != 0, packet[3] & 0x20 != 0, packet[3] & 0x10 != 0)
+    }
+
+    fn query_with_cd(id: u16, name: &str, qtype: RecordType, rd: bool) -> Vec<u8> {
+        let mut query = packet::build_query(id, name, qtype, rd);
+        query[3] |= 0x10;
+        query
+    }
+
+    #[tokio::test]
+    async fn test_response_flags_on_every_path() {
+        // Upstream that sets AD=1 (as if it had validated) and RA=0
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                resp.header.ra = false;
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, 1]));
+                let mut wire = resp.to_wire();
+                wire[3] |= 0x20;
+                let _ = socket.send_to(&wire, peer).await;
+            }
+        });
+        let mut config = test_config(upstream, "");
+        config.chaos.enabled = true;
+        config.chaos.type_probabilities.insert("TXT".to_string(), 1.0);
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        // Forwarded: RD and CD echoed, RA set, upstream's AD dropped
+        let forwarded = engine.handle_query(&query_with_cd(0x0a01, "flags.example.com", RecordType::A, true)).await.unwrap();
+        assert_eq!(flag_bits(&forwarded), (true, true, false, true));
+
+        // Cache hit for a client with RD=0 and no CD
+        let cached = engine.handle_query(&packet::build_query(0x0a02, "flags.example.com", RecordType::A, false)).await.unwrap();
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+        assert_eq!(flag_bits(&cached), (false, true, false, false));
+
+        // Our own SERVFAIL (chaos injection)
+        let servfail = engine.handle_query(&query_with_cd(0x0a03, "flags.example.com", RecordType::TXT, true)).await.unwrap();
+        assert_eq!(packet::parse_packet(&servfail).unwrap().header.rcode, crate::dns::types::ResponseCode::ServFail);
+        assert_eq!(flag_bits(&servfail), (true, true, false, true));
+
+        // Recursive mode: ". NS" answered from the root hints
+        let hints = std::env::temp_dir().join(format!("neko-dns-flags-{}.hints", std::process::id()));
+        std::fs::write(&hints, ".  3600000  NS  a.root-servers.net.\na.root-servers.net.  3600000  A  192.0.2.200\n").unwrap();
+        let extra = format!("[recursive]\nenabled = true\nroot_hints_path = \"{}\"\nroot_reprobe_interval_secs = 0\n", hints.display());
+        let recursive = QueryEngine::new(Arc::new(test_config(upstream, &extra))).await.unwrap();
+        std::fs::remove_file(&hints).unwrap();
+        let mut query = packet::build_query(0x0a04, "", RecordType::NS, false);
+        query[3] |= 0x20;
+        let priming = recursive.handle_query(&query).await.unwrap();
+        assert_eq!(flag_bits(&priming), (false, true, false, false));
+    }
+
+    #[tokio::test]
+    async fn test_journal_sampled_out_still_counted() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.journal.enabled = true;
+        config.journal.sample_rate = 0.0;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        for _ in 0..3 {
+            engine.handle_query(&edns_query("sampled.example.com")).await.unwrap();
+        }
+        assert!(engine.journal.recent(10).is_empty());
+        assert_eq!(engine.journal.get_stats()["sampled_out"], 3);
+        assert_eq!(engine.metrics.queries_total.load(Ordering::Relaxed), 3);
+        assert_eq!(engine.metrics.cache_hits.load(Ordering::Relaxed), 2);
+    }
+
+    /// Resolves every name to 192.168.1.10
+    async fn spawn_private_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 168, 1, 10]));
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_private_answers_filtered_for_public_names() {
+        let upstream = spawn_private_upstream().await;
+        let local = spawn_private_upstream().await;
+        let mut config = test_config(upstream, &format!(
+            "[[local_zones]]\ndomain = \"mynk.home\"\nserver = \"127.0.0.1\"\nport = {}\ntimeout_ms = 500\n",
+            local.port(),
+        ));
+        config.security.deny_private_answers = true;
+        config.security.private_answer_exceptions = vec!["intranet.example.com".to_string()];
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        // Public name → NODATA, also when served again from the cache
+        for id in [0x0b01, 0x0b02] {
+            let response = engine.handle_query(&packet::build_query(id, "evil.example.net", RecordType::A, true)).await.unwrap();
+            let parsed = packet::parse_packet(&response).unwrap();
+            assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NoError);
+            assert!(parsed.answers.is_empty());
+        }
+        assert_eq!(engine.get_stats()["security"]["filtered_responses"], 2);
+
+        // Local (trusted) zone and exception list keep their private answers
+        for name in ["nas.mynk.home", "wiki.intranet.example.com"] {
+            let response = engine.handle_query(&packet::build_query(0x0b03, name, RecordType::A, true)).await.unwrap();
+            let parsed = packet::parse_packet(&response).unwrap();
+            assert_eq!(parsed.answers[0].rdata, vec![192, 168, 1, 10], "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_pipelined_queries_answered_out_of_order() {
+        // Upstream that holds "slow.example.com" back for 300ms
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let upstream = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, 1]));
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    if qname.starts_with("slow") {
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                    }
+                    let _ = socket.send_to(&resp.to_wire(), peer).await;
+                });
+            }
+        });
+        let engine = Arc::new(QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            engine.handle_tcp(stream, addr).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(server).await.unwrap();
+        tcp::write_message(&mut client, &packet::build_query(0x0c01, "slow.example.com", RecordType::A, true)).await.unwrap();
+        tcp::write_message(&mut client, &packet::build_query(0x0c02, "fast.example.com", RecordType::A, true)).await.unwrap();
+        let ids: Vec<u16> = tokio::time::timeout(Duration::from_secs(2), async {
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let response = tcp::read_message(&mut client).await.unwrap().unwrap();
+                ids.push(u16::from_be_bytes([response[0], response[1]]));
+            }
+            ids
+        }).await.unwrap();
+        assert_eq!(ids, vec![0x0c02, 0x0c01]);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_replaces_cached_entry() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let name = "refresh.example.com";
+
+        engine.handle_query(&edns_query(name)).await.unwrap();
+        engine.cache.backdate(name, &RecordType::A, 40);
+        assert_eq!(engine.cache.inspect_entry(name, &RecordType::A, None).unwrap()["remaining_ttl"], 20);
+
+        // Two concurrent refreshes → one upstream query, entry re-inserted with a full TTL
+        let (a, b) = tokio::join!(engine.refresh(name, RecordType::A), engine.refresh(name, RecordType::A));
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a, b);
+        assert_eq!(a["ttl"], 60);
+        assert_eq!(a["answers"][0]["data"], "192.0.2.1");
+        assert_eq!(a["cache"]["remaining_ttl"], 60);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_all_roots_unreachable_fails_fast_with_ede() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        // TEST-NET-1 roots never answer; probes give up after query_timeout_ms
+        let hints = std::env::temp_dir().join(format!("neko-dns-noroots-{}.hints", std::process::id()));
+        std::fs::write(&hints, ".  3600000  NS  a.root-servers.net.\na.root-servers.net.  3600000  A  192.0.2.201\n").unwrap();
+        let extra = format!(
+            "[recursive]\nenabled = true\nroot_hints_path = \"{}\"\nroot_reprobe_interval_secs = 0\nquery_timeout_ms = 100\nroots_unreachable = \"servfail\"\n",
+            hints.display(),
+        );
+        let engine = Arc::new(QueryEngine::new(Arc::new(test_config(upstream, &extra))).await.unwrap());
+        std::fs::remove_file(&hints).unwrap();
+
+        let recursive = engine.recursive.clone().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !recursive.roots_unreachable() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }).await.expect("warmup should give up on the roots");
+
+        let start = std::time::Instant::now();
+        let response = engine.handle_query(&edns_query("blocked.example.com")).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100), "took {:?}", start.elapsed());
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::ServFail);
+        let opt = parsed.additionals.iter().find(|r| r.rtype == RecordType::OPT).unwrap();
+        assert_eq!(opt.rdata[..6], [0, 15, 0, 30, 0, 22]);
+        assert_eq!(engine.metrics.recursive_queries.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+        assert!(crate::metrics::render_metrics(&engine).contains("nekonsd_root_unreachable 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_cache_entry_wire_round_trip() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        engine.handle_query(&edns_query("wire.example.com")).await.unwrap();
+
+        let wire = engine.cache.export_entry("wire.example.com", &RecordType::A, None).unwrap();
+        assert_eq!(packet::parse_packet(&wire).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+
+        engine.cache.flush().await;
+        assert!(engine.cache.export_entry("wire.example.com", &RecordType::A, None).is_none());
+        let injected = engine.inject(&wire).await.unwrap();
+        assert_eq!(injected["name"], "wire.example.com");
+        assert_eq!(engine.cache.export_entry("wire.example.com", &RecordType::A, None).unwrap(), wire);
+
+        // Served from the injected entry, no new upstream query
+        let before = engine.metrics.upstream_queries.load(Ordering::Relaxed);
+        let response = engine.handle_query(&edns_query("wire.example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), before);
+
+        // Garbage, queries and error responses are refused
+        assert!(engine.inject(&wire[..7]).await.is_err());
+        assert!(engine.inject(&edns_query("query.example.com")).await.is_err());
+        assert!(engine.inject(&packet::build_servfail(&edns_query("fail.example.com")).unwrap()).await.is_err());
+    }
+
+    /// Webhook stub: answers 200 to every POST and forwards the JSON body
+    async fn spawn_webhook(tx: tokio::sync::mpsc::UnboundedSender<serde_json::Value>) -> SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    loop {
+                        let Ok(n) = stream.read(&mut buf).await else { return };
+                        if n == 0 { return; }
+                        request.extend_from_slice(&buf[..n]);
+                        let text = String::from_utf8_lossy(&request).to_string();
+                        let Some((head, body)) = text.split_once("\r\n\r\n") else { continue };
+                        let length = head.lines()
+                            .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if body.len() < length { continue; }
+                        let _ = tx.send(serde_json::from_str(body).unwrap());
+                        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+                        return;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_webhook_fires_when_upstream_disabled() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let webhook = spawn_webhook(tx).await;
+        // Nothing listens on the upstream port, so every forwarded query fails
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let mut config = test_config(dead, "");
+        config.alerting.webhook_url = Some(format!("http://{}/hook", webhook));
+        let engine = Arc::new(QueryEngine::new(Arc::new(config)).await.unwrap());
+
+        let mut queries = tokio::task::JoinSet::new();
+        for i in 0..10 {
+            let engine = engine.clone();
+            queries.spawn(async move { engine.handle_query(&edns_query(&format!("q{}.example.com", i))).await });
+        }
+        while queries.join_next().await.is_some() {}
+
+        engine.rescore_upstreams().await;
+        let alert = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(alert["event"], "upstream_disabled");
+        assert_eq!(alert["subject"], "stub");
+        assert!(alert["details"]["trust_score"].as_f64().unwrap() < 0.5);
+
+        // Still disabled next round: no second alert
+        engine.rescore_upstreams().await;
+        assert_eq!(engine.alerter.get_stats()["sent"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_synthesized_negative_answers_carry_soa() {
+        let upstream = spawn_private_upstream().await;
+        let mut config = test_config(upstream, "");
+        config.security.deny_private_answers = true;
+        config.negative.synthetic_soa_ttl = 120;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        // Rebinding guard NODATA
+        let response = engine.handle_query(&packet::build_query(0x0c01, "evil.example.net", RecordType::A, true)).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert!(parsed.answers.is_empty());
+        let soa = parsed.authorities.iter().find(|r| r.rtype == RecordType::SOA).unwrap();
+        assert_eq!(soa.name, "example.net");
+        assert_eq!(packet::soa_negative_ttl(&parsed), Some(120));
+
+        // Negative cache hit for an NXDOMAIN that came without an SOA
+        let mut nx = packet::parse_packet(&packet::build_query(0x0c02, "gone.example.org", RecordType::A, true)).unwrap();
+        nx.header.qr = true;
+        nx.header.rcode = crate::dns::types::ResponseCode::NxDomain;
+        engine.negative.insert("gone.example.org", &RecordType::A, &nx.to_wire());
+        let response = engine.handle_query(&packet::build_query(0x0c02, "gone.example.org", RecordType::A, true)).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NxDomain);
+        assert_eq!(packet::soa_negative_ttl(&parsed), Some(120));
+    }
+
+    #[tokio::test]
+    async fn test_serve_cache_only_never_resolves() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        engine.handle_query(&edns_query("cached.example.com")).await.unwrap();
+
+        let request = serde_json::from_value(serde_json::json!({"enabled": true, "mode": "serve_cache_only"})).unwrap();
+        assert_eq!(engine.maintenance.apply(&request).unwrap()["mode"], "serve_cache_only");
+        let forwarded = engine.metrics.upstream_queries.load(Ordering::Relaxed);
+
+        let hit = packet::parse_packet(&engine.handle_query(&edns_query("cached.example.com")).await.unwrap()).unwrap();
+        assert_eq!(hit.answers[0].rdata, vec![192, 0, 2, 1]);
+        let miss = engine.handle_query(&edns_query("new.example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&miss).unwrap().header.rcode, crate::dns::types::ResponseCode::ServFail);
+        assert!(has_ede(&miss));
+        // Not even through a manual refresh
+        assert_eq!(engine.refresh("cached.example.com", RecordType::A).await.unwrap()["rcode"], "ServFail");
+
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), forwarded);
+        assert_eq!(engine.metrics.recursive_queries.load(Ordering::Relaxed), 0);
+        assert!(engine.cache.get("new.example.com", &RecordType::A).await.is_none());
+
+        // Back to normal
+        engine.maintenance.apply(&serde_json::from_value(serde_json::json!({"enabled": false})).unwrap()).unwrap();
+        let resolved = engine.handle_query(&edns_query("new.example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&resolved).unwrap().answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_local_zones_prefer_most_specific() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let home_hits = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let iot_hits = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let home = spawn_local_zone_nxdomain(home_hits.clone()).await;
+        let iot = spawn_local_zone_nxdomain(iot_hits.clone()).await;
+        // The broader zone is listed first
+        let config = test_config(upstream, &format!(
+            "[[local_zones]]\ndomain = \"home\"\nserver = \"127.0.0.1\"\nport = {}\n\
+             [[local_zones]]\ndomain = \"iot.home\"\nserver = \"127.0.0.1\"\nport = {}\n",
+            home.port(), iot.port(),
+        ));
+        assert_eq!(match_local_zone(&config.local_zones, "FOO.iot.home.").unwrap().domain, "iot.home");
+        assert_eq!(match_local_zone(&config.local_zones, "iot.home").unwrap().domain, "iot.home");
+        assert_eq!(match_local_zone(&config.local_zones, "nas.home").unwrap().domain, "home");
+        assert!(match_local_zone(&config.local_zones, "notiot.homes").is_none());
+
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        engine.handle_query(&edns_query("foo.iot.home")).await.unwrap();
+        assert_eq!(iot_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(home_hits.load(Ordering::Relaxed), 0);
+        engine.handle_query(&edns_query("nas.home")).await.unwrap();
+        assert_eq!(home_hits.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_recursion_not_forwarded_when_fallback_disabled() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        // TEST-NET-1 root never answers, so every resolution fails
+        let hints = std::env::temp_dir().join(format!("neko-dns-nofallback-{}.hints", std::process::id()));
+        std::fs::write(&hints, ".  3600000  NS  a.root-servers.net.\na.root-servers.net.  3600000  A  192.0.2.202\n").unwrap();
+        let extra = format!(
+            "[recursive]\nenabled = true\nroot_hints_path = \"{}\"\nroot_reprobe_interval_secs = 0\nquery_timeout_ms = 100\nfallback_to_forward = false\n",
+            hints.display(),
+        );
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, &extra))).await.unwrap();
+        std::fs::remove_file(&hints).unwrap();
+        engine.recursive.as_ref().unwrap().force_ready();
+
+        let response = engine.handle_query(&edns_query("private.example.com")).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::ServFail);
+        let opt = parsed.additionals.iter().find(|r| r.rtype == RecordType::OPT).unwrap();
+        assert_eq!(opt.rdata[..6], [0, 15, 0, 18, 0, 22]);
+        assert_eq!(engine.metrics.recursive_failures.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.upstream.get_stats()[0]["total_queries"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_non_in_class_refused() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = Arc::new(QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap());
+
+        let mut query = packet::build_query(0x2438, "example.com", RecordType::A, true);
+        let class_at = query.len() - 2;
+        query[class_at..].copy_from_slice(&4u16.to_be_bytes()); // HS
+        let response = engine.handle_query(&query).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.id, 0x2438);
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::Refused);
+        assert_eq!(parsed.questions[0].qclass, DnsClass::HS);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+        assert!(crate::metrics::render_metrics(&engine).contains("unbound_answer_rcodes_total{rcode=\"REFUSED\"} 1\n"));
+
+        // Class IN still resolves
+        let response = engine.handle_query(&packet::build_query(0x2439, "example.com", RecordType::A, true)).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, crate::dns::types::ResponseCode::NoError);
+    }
+
+    #[tokio::test]
+    async fn test_health_domain_static_answer() {
+        // Upstream is down: the health domain must not care
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(true))).await;
+        let config = test_config(upstream, "[[health_domain]]\nname = \"alive.neko.test\"\na = \"192.0.2.99\"\ntxt = \"ok\"\n");
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        let a = packet::parse_packet(&engine.handle_query(&edns_query("Alive.Neko.Test")).await.unwrap()).unwrap();
+        assert_eq!(a.header.id, 0x3131);
+        assert_eq!(a.answers.len(), 1);
+        assert_eq!(a.answers[0].rdata, vec![192, 0, 2, 99]);
+        assert_eq!(a.answers[0].ttl, 5);
+
+        let txt = engine.handle_query(&packet::build_query(0x2441, "alive.neko.test", RecordType::TXT, true)).await.unwrap();
+        let txt = packet::parse_packet(&txt).unwrap();
+        assert_eq!(txt.answers[0].rtype, RecordType::TXT);
+        assert_eq!(txt.answers[0].rdata, b"\x02ok".to_vec());
+
+        let aaaa = engine.handle_query(&packet::build_query(0x2442, "alive.neko.test", RecordType::AAAA, true)).await.unwrap();
+        let aaaa = packet::parse_packet(&aaaa).unwrap();
+        assert_eq!(aaaa.header.rcode, crate::dns::types::ResponseCode::NoError);
+        assert!(aaaa.answers.is_empty());
+
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.cache.get_stats()["entries"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_resolution_metadata_option() {
+        use crate::edns::{decode_resolution_metadata as metadata, ResolutionSource};
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let hints = std::env::temp_dir().join(format!("neko-dns-metadata-{}.hints", std::process::id()));
+        std::fs::write(&hints, ".  3600000  NS  a.root-servers.net.\na.root-servers.net.  3600000  A  192.0.2.202\n").unwrap();
+        let extra = format!(
+            "edns_metadata = true\n[recursive]\nenabled = true\nroot_hints_path = \"{}\"\nroot_reprobe_interval_secs = 0\nquery_timeout_ms = 100\nfallback_to_forward = false\n",
+            hints.display(),
+        );
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, &extra))).await.unwrap();
+        std::fs::remove_file(&hints).unwrap();
+        engine.recursive.as_ref().unwrap().force_ready();
+        let response = engine.handle_query(&edns_query("private.example.com")).await.unwrap();
+        let (source, _, upstream_name) = metadata(&response).unwrap();
+        assert_eq!((source, upstream_name.as_str()), (ResolutionSource::Recursive, "recursive"));
+
+        let mut cached = packet::parse_packet(&packet::build_query(1, "cached.example.com", RecordType::A, true)).unwrap();
+        cached.header.qr = true;
+        cached.answers.push(packet::DnsRecord::new("cached.example.com", RecordType::A, 300, vec![192, 0, 2, 7]));
+        engine.inject(&cached.to_wire()).await.unwrap();
+        let response = engine.handle_query(&edns_query("cached.example.com")).await.unwrap();
+        let (source, latency, upstream_name) = metadata(&response).unwrap();
+        assert_eq!((source, upstream_name.as_str()), (ResolutionSource::Cache, "api-inject"));
+        assert!(latency < 1000);
+        // The feature TXT is off here; only EDNS clients get the option
+        assert_eq!(packet::parse_packet(&response).unwrap().header.arcount, 1);
+        let plain = engine.handle_query(&packet::build_query(2, "cached.example.com", RecordType::A, true)).await.unwrap();
+        assert!(metadata(&plain).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_health_option_on_every_response() {
+        use crate::edns::decode_health;
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, "health_in_edns = true\n"))).await.unwrap();
+
+        // A miss, then a hit: half the lookups hit
+        engine.handle_query(&edns_query("www.example.com")).await.unwrap();
+        let response = engine.handle_query(&edns_query("www.example.com")).await.unwrap();
+        let health = decode_health(&response).unwrap();
+        assert_eq!(health.mode, HealthMode::Forwarding);
+        assert!(!health.circuit_open);
+        assert_eq!(health.hit_rate_tenths, Some(5));
+        let [major, minor, patch] = health.version;
+        assert_eq!(format!("{}.{}.{}", major, minor, patch), env!("CARGO_PKG_VERSION"));
+
+        // Answers made up here carry it too
+        let diagnostic = engine.handle_query(&edns_query("neko-dns.version")).await.unwrap();
+        assert!(decode_health(&diagnostic).is_some());
+        engine.set_offline(true);
+        let offline = engine.handle_query(&edns_query("www.example.com")).await.unwrap();
+        assert_eq!(decode_health(&offline).unwrap().mode, HealthMode::Offline);
+        // Only EDNS clients get the option
+        let plain = engine.handle_query(&packet::build_query(2, "www.example.com", RecordType::A, true)).await.unwrap();
+        assert!(decode_health(&plain).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_local_zone_before_cache_order() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let hits = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let local = spawn_local_zone_nxdomain(hits.clone()).await;
+        let config = test_config(upstream, &format!(
+            "[resolution]\norder = [\"local_zone\", \"cache\", \"forward\"]\n[[local_zones]]\ndomain = \"mynk.home\"\nserver = \"127.0.0.1\"\nport = {}\ntimeout_ms = 500\n",
+            local.port(),
+        ));
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        for _ in 0..2 {
+            let response = engine.handle_query(&edns_query("missing.mynk.home")).await.unwrap();
+            assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, crate::dns::types::ResponseCode::NxDomain);
+        }
+        // The local zone answers ahead of the (negative) cache every time
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+        assert_eq!(engine.metrics.negative_cache_hits.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.metrics.cache_misses.load(Ordering::Relaxed), 0);
+
+        // Names outside the local zones still go through the cache, then upstream
+        for _ in 0..2 {
+            engine.handle_query(&edns_query("www.example.com")).await.unwrap();
+        }
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.cache_hits.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_before_recursion_order() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let hints = std::env::temp_dir().join(format!("neko-dns-order-{}.hints", std::process::id()));
+        std::fs::write(&hints, ".  3600000  NS  a.root-servers.net.\na.root-servers.net.  3600000  A  192.0.2.202\n").unwrap();
+        let extra = format!(
+            "[resolution]\norder = [\"cache\", \"forward\", \"recursive\"]\n[recursive]\nenabled = true\nroot_hints_path = \"{}\"\nroot_reprobe_interval_secs = 0\nquery_timeout_ms = 100\n",
+            hints.display(),
+        );
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, &extra))).await.unwrap();
+        std::fs::remove_file(&hints).unwrap();
+        engine.recursive.as_ref().unwrap().force_ready();
+
+        let response = engine.handle_query(&edns_query("www.example.com")).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, 1]);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.recursive_queries.load(Ordering::Relaxed), 0);
+    }
+
+    /// Upstream stub: TC=1 over UDP, the real answer (192.0.2.9) over TCP on the same port
+    async fn spawn_truncating_upstream() -> SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                resp.header.tc = true;
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut len = [0u8; 2];
+                if stream.read_exact(&mut len).await.is_err() { continue; }
+                let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+                if stream.read_exact(&mut query).await.is_err() { continue; }
+                let mut resp = packet::parse_packet(&query).unwrap();
+                resp.header.qr = true;
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, 9]));
+                let wire = resp.to_wire();
+                let _ = stream.write_all(&(wire.len() as u16).to_be_bytes()).await;
+                let _ = stream.write_all(&wire).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_cache_entry_records_transport() {
+        let upstream = spawn_truncating_upstream().await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+
+        let response = engine.handle_query(&edns_query("big.example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().answers[0].rdata, vec![192, 0, 2, 9]);
+        let entry = engine.cache.inspect_entry("big.example.com", &RecordType::A, None).unwrap();
+        assert_eq!(entry["transport"], "tcp");
+        assert_eq!(entry["upstream"], "stub");
+        assert!(engine.cache.list_entries().iter().any(|e| e["name"] == "big.example.com" && e["transport"] == "tcp"));
+    }
+
+    #[tokio::test]
+    async fn test_looped_forward_goes_unanswered() {
+        // Our own UDP listener, which the only upstream points back at
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut config = test_config(socket.local_addr().unwrap(), "");
+        config.upstreams[0].timeout_ms = 200;
+        let engine = Arc::new(QueryEngine::new(Arc::new(config)).await.unwrap());
+        let received = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        {
+            let (engine, socket, received) = (engine.clone(), socket.clone(), received.clone());
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 512];
+                while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                    received.fetch_add(1, Ordering::Relaxed);
+                    let (engine, socket, query) = (engine.clone(), socket.clone(), buf[..len].to_vec());
+                    tokio::spawn(async move {
+                        if let Ok(Some(response)) = engine.handle_udp_query(peer, &query).await {
+                            let _ = socket.send_to(&response, peer).await;
+                        }
+                    });
+                }
+            });
+        }
+
+        let query = packet::build_query(0x4242, "loop.example", RecordType::A, true);
+        let _ = engine.handle_udp_query("192.0.2.10:40000".parse().unwrap(), &query).await;
+        // The forwarded copy came back once and was not forwarded again
+        assert_eq!(received.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.loops.detected(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_health_domain_weighted_pool() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(true))).await;
+        let config = test_config(upstream, "[[health_domain]]\nname = \"svc.neko.test\"\na = \"192.0.2.99\"\n\
+            a_pool = [{ address = \"192.0.2.1\", weight = 3 }, { address = \"192.0.2.2\" }, { address = \"192.0.2.3\", weight = 0 }]\n\
+            [[health_domain]]\nname = \"all.neko.test\"\npool_answer = \"all\"\n\
+            a_pool = [{ address = \"192.0.2.1\" }, { address = \"192.0.2.2\" }, { address = \"192.0.2.3\", weight = 0 }]\n");
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        let mut picks: HashMap<u8, u32> = HashMap::new();
+        for id in 0..2000u16 {
+            let response = engine.handle_query(&packet::build_query(id, "svc.neko.test", RecordType::A, true)).await.unwrap();
+            let parsed = packet::parse_packet(&response).unwrap();
+            assert_eq!(parsed.answers.len(), 1);
+            *picks.entry(parsed.answers[0].rdata[3]).or_default() += 1;
+        }
+        // 3:1, the weight 0 member (and the plain `a`) never handed out
+        assert_eq!(picks.keys().copied().collect::<std::collections::BTreeSet<_>>(), [1, 2].into());
+        let share = picks[&1] as f64 / 2000.0;
+        assert!((0.7..0.8).contains(&share), "192.0.2.1 got {:.3} of the answers", share);
+
+        let all = engine.handle_query(&packet::build_query(1, "all.neko.test", RecordType::A, true)).await.unwrap();
+        let all: Vec<Vec<u8>> = packet::parse_packet(&all).unwrap().answers.into_iter().map(|r| r.rdata).collect();
+        assert_eq!(all, vec![vec![192, 0, 2, 1], vec![192, 0, 2, 2]]);
+    }
+
+    /// Reads queries and never answers
+    async fn spawn_silent(hits: Arc<std::sync::atomic::AtomicU64>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while socket.recv_from(&mut buf).await.is_ok() {
+                hits.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_local_zone_fails_over_to_next_server() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let dead_hits = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let dead = spawn_silent(dead_hits.clone()).await;
+        let hits = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let backup = spawn_local_zone_nxdomain(hits.clone()).await;
+        let lonely_hits = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let lonely = spawn_silent(lonely_hits.clone()).await;
+        let config = test_config(upstream, &format!(
+            "[[local_zones]]\ndomain = \"mynk.home\"\nserver = \"127.0.0.1\"\nport = {}\nservers = [\"127.0.0.1:{}\"]\ntimeout_ms = 200\n\
+             [[local_zones]]\ndomain = \"lonely.home\"\nserver = \"127.0.0.1\"\nport = {}\ntimeout_ms = 100\nretries = 2\n",
+            dead.port(), backup.port(), lonely.port(),
+        ));
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        // First server times out, the second one answers - nothing leaks upstream
+        let response = engine.handle_query(&edns_query("nas.mynk.home")).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, crate::dns::types::ResponseCode::NxDomain);
+        assert_eq!(dead_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+
+        // Every attempt failed: asked 1 + retries times before falling through
+        engine.handle_query(&edns_query("nas.lonely.home")).await.unwrap();
+        assert_eq!(lonely_hits.load(Ordering::Relaxed), 3);
+    }
+
+    /// Answers every query with an A record, its RRSIG and an NSEC in the authority section
+    async fn spawn_signed_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                let qname = resp.questions[0].name.clone();
+                resp.header.qr = true;
+                resp.header.ra = true;
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, 1]));
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::Unknown(46), 60, vec![0; 20]));
+                resp.authorities.push(packet::DnsRecord::new(&qname, RecordType::Unknown(47), 60, vec![0; 4]));
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_dnssec_records_only_for_do_clients() {
+        let upstream = spawn_signed_upstream().await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let types = |response: &[u8]| {
+            let parsed = packet::parse_packet(response).unwrap();
+            parsed.answers.iter().chain(&parsed.authorities).map(|r| r.rtype.to_u16()).collect::<Vec<_>>()
+        };
+        let mut do_query = edns_query("signed.example");
+        let len = do_query.len();
+        do_query[len - 4] = 0x80; // OPT flags: DO
+
+        assert_eq!(types(&engine.handle_query(&edns_query("signed.example")).await.unwrap()), vec![1]);
+        // Served from the cache, which kept the signed copy
+        assert_eq!(types(&engine.handle_query(&do_query).await.unwrap()), vec![1, 46, 47]);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+        assert_eq!(types(&engine.handle_query(&packet::build_query(7, "signed.example", RecordType::A, true)).await.unwrap()), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_offline_mode_answers_from_cache_only() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, "[resolution]\noffline = true"))).await.unwrap();
+        assert!(engine.is_offline());
+        let mut cached = packet::parse_packet(&packet::build_query(0x0d01, "cached.example.com", RecordType::A, true)).unwrap();
+        cached.header.qr = true;
+        cached.answers.push(packet::DnsRecord::new("cached.example.com", RecordType::A, 300, vec![192, 0, 2, 9]));
+        engine.cache.insert("cached.example.com", &RecordType::A, &cached.to_wire(), "stub", Transport::Udp).await;
+
+        let hit = packet::parse_packet(&engine.handle_query(&edns_query("cached.example.com")).await.unwrap()).unwrap();
+        assert_eq!(hit.answers[0].rdata, vec![192, 0, 2, 9]);
+        let miss = engine.handle_query(&edns_query("new.example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&miss).unwrap().header.rcode, crate::dns::types::ResponseCode::ServFail);
+        assert!(has_ede(&miss));
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+
+        // Back online through the runtime switch
+        engine.set_offline(false);
+        let resolved = engine.handle_query(&edns_query("new.example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&resolved).unwrap().answers.len(), 1);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recursive_nodata_negatively_cached_with_soa_minimum() {
+        // Authoritative for example.test: NODATA with an SOA whose MINIMUM (120) is below its TTL
+        let auth_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let auth = auth_socket.local_addr().unwrap();
+        let hits = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = auth_socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                pkt.header.qr = true;
+                pkt.header.aa = true;
+                let mut soa = packet::encode_name("ns.example.test");
+                soa.extend(packet::encode_name("hostmaster.example.test"));
+                for value in [1u32, 3600, 600, 86400, 120] {
+                    soa.extend_from_slice(&value.to_be_bytes());
+                }
+                pkt.authorities.push(packet::DnsRecord::new("example.test", RecordType::SOA, 3600, soa));
+                let _ = auth_socket.send_to(&pkt.to_wire(), peer).await;
+            }
+        });
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let hints = std::env::temp_dir().join(format!("neko-dns-nodata-{}.hints", std::process::id()));
+        std::fs::write(&hints, ".  3600000  NS  a.root-servers.net.\na.root-servers.net.  3600000  A  192.0.2.203\n").unwrap();
+        let extra = format!(
+            "[recursive]\nenabled = true\nroot_hints_path = \"{}\"\nroot_reprobe_interval_secs = 0\nquery_timeout_ms = 200\nfallback_to_forward = false\n",
+            hints.display(),
+        );
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, &extra))).await.unwrap();
+        std::fs::remove_file(&hints).unwrap();
+        let recursive = engine.recursive.as_ref().unwrap();
+        recursive.force_ready();
+        recursive.seed_delegation("example.test", auth);
+
+        let query = packet::build_query(0x0e01, "www.example.test", RecordType::AAAA, true);
+        let first = packet::parse_packet(&engine.handle_query(&query).await.unwrap()).unwrap();
+        assert_eq!(first.header.rcode, crate::dns::types::ResponseCode::NoError);
+        assert!(first.answers.is_empty());
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+
+        let ttl = engine.negative.remaining_ttl("www.example.test", &RecordType::AAAA).unwrap();
+        assert!((119..=120).contains(&ttl), "negative TTL {}", ttl);
+        assert!(engine.cache.get("www.example.test", &RecordType::AAAA).await.is_none());
+
+        // Same type again: the negative cache answers, NOERROR and not NXDOMAIN
+        let second = packet::parse_packet(&engine.handle_query(&query).await.unwrap()).unwrap();
+        assert_eq!(second.header.rcode, crate::dns::types::ResponseCode::NoError);
+        assert!(second.answers.is_empty());
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.negative_cache_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.nxdomain_total.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stats_hit_rate_matches_reported_counts() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = Arc::new(QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap());
+        let load = {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                for i in 0..200u32 {
+                    engine.handle_query(&edns_query(&format!("n{}.example.com", i % 20))).await.unwrap();
+                }
+            })
+        };
+        let consistent = |section: &serde_json::Value, hits: &str, misses: &str| {
+            let (hits, misses) = (section[hits].as_u64().unwrap(), section[misses].as_u64().unwrap());
+            let expected = if hits + misses > 0 { hits as f64 / (hits + misses) as f64 * 100.0 } else { 0.0 };
+            assert_eq!(section["hit_rate_percent"], format!("{:.1}", expected), "{}", section);
+        };
+        while !load.is_finished() {
+            let stats = engine.get_stats();
+            consistent(&stats["queries"], "cache_hits", "cache_misses");
+            consistent(&stats["cache"], "hits", "misses");
+            tokio::task::yield_now().await;
+        }
+        let stats = engine.get_stats();
+        assert_eq!(stats["queries"]["total"], 200);
+        assert_eq!(stats["queries"]["cache_misses"], 20);
+        assert_eq!(stats["queries"]["hit_rate_percent"], "90.0");
+    }
+
+    #[tokio::test]
+    async fn test_echo_name_reports_tcp_transport() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = Arc::new(QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            engine.handle_tcp(stream, addr).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(server).await.unwrap();
+        let local = client.local_addr().unwrap();
+        tcp::write_message(&mut client, &edns_query("Echo.Neko-DNS.")).await.unwrap();
+        let response = tcp::read_message(&mut client).await.unwrap().unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        let facts: Vec<String> = parsed.answers.iter()
+            .map(|r| String::from_utf8_lossy(&r.rdata[1..]).into_owned())
+            .collect();
+        assert!(facts.contains(&"transport=tcp".to_string()), "{:?}", facts);
+        assert!(facts.contains(&format!("client={}", local)), "{:?}", facts);
+        assert!(facts.contains(&"flags=rd".to_string()), "{:?}", facts);
+        assert!(facts.iter().any(|f| f.starts_with("edns=v0 udp=") && f.ends_with("do=0 options=none")), "{:?}", facts);
+        assert!(parsed.answers.iter().all(|r| r.rtype == RecordType::TXT && r.ttl == 0));
+        assert!(parsed.header.qr && parsed.header.aa);
+    }
+
+    #[tokio::test]
+    async fn test_refuse_types_answer_without_resolving() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.listen.refuse_types = vec!["HTTPS".to_string()];
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        let response = engine.handle_query(&packet::build_query(0x4141, "www.example.com", RecordType::HTTPS, true)).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.id, 0x4141);
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NoError);
+        assert!(parsed.answers.is_empty());
+        assert!(parsed.authorities.iter().any(|r| r.rtype == RecordType::SOA));
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+
+        // Other types still resolve
+        let response = engine.handle_query(&packet::build_query(0x4142, "www.example.com", RecordType::A, true)).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_min_response_time_pads_cache_hits() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.listen.min_response_time_ms = 150;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let client = Some("192.0.2.10".parse().unwrap());
+        engine.handle_query_from(client, &edns_query("padded.example.com")).await.unwrap();
+
+        let start = std::time::Instant::now();
+        let response = engine.handle_query_from(client, &edns_query("padded.example.com")).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150), "answered in {:?}", start.elapsed());
+        assert_eq!(packet::parse_packet(&response).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+        assert_eq!(engine.metrics.cache_hits.load(Ordering::Relaxed), 1);
+
+        // Internal queries are not padded
+        let start = std::time::Instant::now();
+        engine.handle_query(&edns_query("padded.example.com")).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_negative_flush_lets_name_resolve() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let mut nx = packet::parse_packet(&packet::build_query(0x0d01, "new.example.org", RecordType::A, true)).unwrap();
+        nx.header.qr = true;
+        nx.header.rcode = crate::dns::types::ResponseCode::NxDomain;
+        engine.negative.insert("new.example.org", &RecordType::A, &nx.to_wire());
+        engine.negative.insert("other.example.net", &RecordType::A, &nx.to_wire());
+
+        let listed = engine.negative.list();
+        let entry = listed.iter().find(|e| e["name"] == "new.example.org").unwrap();
+        assert_eq!(entry["type"], "A");
+        assert_eq!(entry["rcode"], "NxDomain");
+        assert_eq!(entry["speculative"], false);
+        let response = engine.handle_query(&edns_query("new.example.org")).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, crate::dns::types::ResponseCode::NxDomain);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+
+        assert_eq!(engine.negative.flush(Some("Example.org.")), 1);
+        let response = engine.handle_query(&edns_query("new.example.org")).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NoError);
+        assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, 1]);
+        // Other domains stay cached
+        assert_eq!(engine.negative.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_curiosity_walk_warms_both_families() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                let (qname, qtype) = (resp.questions[0].name.clone(), resp.questions[0].qtype);
+                resp.header.qr = true;
+                resp.header.ra = true;
+                let rdata = if qtype == RecordType::AAAA { "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets().to_vec() } else { vec![192, 0, 2, 1] };
+                resp.answers.push(packet::DnsRecord::new(&qname, qtype, 60, rdata));
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+
+        engine.walk_target("walked.example.com").await;
+        assert!(engine.cache.get("walked.example.com", &RecordType::A).await.is_some());
+        assert!(engine.cache.get("walked.example.com", &RecordType::AAAA).await.is_some());
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 2);
+        // Both cached: a second walk asks nothing
+        engine.walk_target("walked.example.com").await;
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_stalled_message_closes_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.listen.tcp_idle_timeout_ms = 200;
+        let engine = Arc::new(QueryEngine::new(Arc::new(config)).await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let handler = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            engine.handle_tcp(stream, addr).await
+        });
+
+        // A length prefix promising 40 bytes, then nothing
+        let mut client = TcpStream::connect(server).await.unwrap();
+        client.write_all(&[0, 40, 0x12]).await.unwrap();
+        let start = std::time::Instant::now();
+        tokio::time::timeout(Duration::from_secs(2), handler).await.unwrap().unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0, "server should have closed the connection");
+    }
+
+    #[tokio::test]
+    async fn test_identity_hide_covers_probes_and_nsid() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let nsid_query = || {
+            let mut query = packet::build_query(0x2473, "example.com", RecordType::A, true);
+            query[11] = 1;
+            query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 4, 0, 3, 0, 0]);
+            query
+        };
+        let mut probe = packet::build_query(0x2474, "hostname.bind", RecordType::TXT, false);
+        let class_at = probe.len() - 2;
+        probe[class_at..].copy_from_slice(&3u16.to_be_bytes()); // CH
+        let nsid_of = |response: &[u8]| {
+            let parsed = packet::parse_packet(response).unwrap();
+            parsed.additionals.iter()
+                .find(|r| r.rtype == RecordType::OPT)
+                .and_then(|r| (r.rdata.len() > 4 && r.rdata[..2] == [0, 3]).then(|| r.rdata[4..].to_vec()))
+        };
+
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, "[identity]\nhostname = \"neko-1\""))).await.unwrap();
+        let parsed = packet::parse_packet(&engine.handle_query(&probe).await.unwrap()).unwrap();
+        assert_eq!(parsed.answers[0].rdata[1..], *b"neko-1");
+        assert_eq!(nsid_of(&engine.handle_query(&nsid_query()).await.unwrap()), Some(b"neko-1".to_vec()));
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+
+        let mut config = test_config(upstream, "[identity]\nhostname = \"neko-1\"\nhide = true");
+        config.edns.nsid = Some("edns-id".to_string());
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let parsed = packet::parse_packet(&engine.handle_query(&probe).await.unwrap()).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::Refused);
+        assert!(parsed.answers.is_empty());
+        assert_eq!(nsid_of(&engine.handle_query(&nsid_query()).await.unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn test_special_use_names_answered_locally() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+
+        let response = engine.handle_query(&packet::build_query(0x2475, "localhost", RecordType::A, true)).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.answers[0].rdata, vec![127, 0, 0, 1]);
+        let response = engine.handle_query(&packet::build_query(0x2476, "foo.invalid", RecordType::A, true)).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, crate::dns::types::ResponseCode::NxDomain);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+
+        // Switched off, they are forwarded like any other name
+        let mut config = test_config(upstream, "");
+        config.listen.rfc6761 = false;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let response = engine.handle_query(&packet::build_query(0x2477, "localhost", RecordType::A, true)).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_route_answered_per_resolution_default() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        // Only local zones after the cache, and the name is in none of them
+        let order = "[resolution]\norder = [\"cache\", \"local_zone\"]\n";
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, order))).await.unwrap();
+        let response = engine.handle_query(&edns_query("nowhere.example.com")).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, crate::dns::types::ResponseCode::ServFail);
+        assert!(has_ede(&response));
+
+        for (policy, rcode) in [("refused", crate::dns::types::ResponseCode::Refused), ("nxdomain", crate::dns::types::ResponseCode::NxDomain)] {
+            let engine = QueryEngine::new(Arc::new(test_config(upstream, &format!("{}default = \"{}\"\n", order, policy)))).await.unwrap();
+            let response = engine.handle_query(&edns_query("nowhere.example.com")).await.unwrap();
+            assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, rcode);
+            assert!(has_ede(&response));
+            assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_resolution_path_reported() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let check = |extra: &str, recursive_up: bool| check_resolution_paths(&test_config(upstream, extra), recursive_up);
+        assert_eq!(check("", false), ResolutionPaths::Complete);
+
+        // Neither forwarding nor recursion in the order, recursion off
+        let order = "[resolution]\norder = [\"cache\", \"local_zone\", \"recursive\"]\n";
+        let ResolutionPaths::Missing(problem) = check(order, false) else { panic!("not reported") };
+        assert!(problem.contains("recursion is disabled") && problem.contains("no \"forward\" stage"), "{}", problem);
+
+        // Recursion configured but the resolver didn't come up
+        let failed = format!("{}[recursive]\nenabled = true\n", order);
+        let ResolutionPaths::Missing(problem) = check(&failed, false) else { panic!("not reported") };
+        assert!(problem.contains("failed to start"), "{}", problem);
+        assert_eq!(check(&failed, true), ResolutionPaths::Complete);
+
+        // Local zones still resolve their own names
+        let zone = format!("{}[[local_zones]]\ndomain = \"home.arpa\"\nserver = \"192.0.2.53\"\n", order);
+        let ResolutionPaths::Partial(warning) = check(&zone, false) else { panic!("not reported") };
+        assert!(warning.starts_with("Only home.arpa can be resolved"), "{}", warning);
+        let everything = format!("{}[[local_zones]]\ndomain = \".\"\nserver = \"192.0.2.53\"\n", order);
+        assert_eq!(check(&everything, false), ResolutionPaths::Complete);
+
+        // Still starts: resolution.default answers, as configured
+        assert!(QueryEngine::new(Arc::new(test_config(upstream, order))).await.is_ok());
+    }
+
+    /// Whether any owner name, or the name in CNAME rdata, uses a compression pointer
+    fn has_compression_pointer(wire: &[u8]) -> bool {
+        let pointer_in_name = |pos: &mut usize| -> bool {
+            loop {
+                let len = wire[*pos];
+                if len & 0xC0 == 0xC0 {
+                    *pos += 2;
+                    return true;
+                }
+                *pos += 1 + len as usize;
+                if len == 0 {
+                    return false;
+                }
+            }
+        };
+        let parsed = packet::parse_packet(wire).unwrap();
+        let mut pos = 12;
+        let mut found = false;
+        for _ in 0..parsed.questions.len() {
+            found |= pointer_in_name(&mut pos);
+            pos += 4;
+        }
+        for _ in 0..parsed.answers.len() + parsed.authorities.len() + parsed.additionals.len() {
+            found |= pointer_in_name(&mut pos);
+            let rtype = u16::from_be_bytes([wire[pos], wire[pos + 1]]);
+            let rdlength = u16::from_be_bytes([wire[pos + 8], wire[pos + 9]]) as usize;
+            pos += 10;
+            if rtype == RecordType::CNAME.to_u16() {
+                let mut target = pos;
+                found |= pointer_in_name(&mut target);
+            }
+            pos += rdlength;
+        }
+        found
+    }
+
+    #[tokio::test]
+    async fn test_uncompressed_responses_have_no_pointers() {
+        // www → CNAME web, then two A records for web: plenty to compress
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                resp.header.ra = true;
+                let qname = resp.questions[0].name.clone();
+                let target = format!("web.{}", qname.trim_start_matches("www."));
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::CNAME, 60, packet::encode_name(&target)));
+                for last in [10, 11] {
+                    resp.answers.push(packet::DnsRecord::new(&target, RecordType::A, 60, vec![10, 0, 0, last]));
+                }
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+
+        let compressed = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let response = compressed.handle_query(&edns_query("www.example.com")).await.unwrap();
+        assert!(has_compression_pointer(&response));
+
+        let mut config = test_config(upstream, "");
+        config.listen.compress_responses = false;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        for _ in 0..2 {
+            // Resolved, then straight from the (compressed) cached copy
+            let response = engine.handle_query(&edns_query("www.example.com")).await.unwrap();
+            assert!(!has_compression_pointer(&response));
+            let parsed = packet::parse_packet(&response).unwrap();
+            assert_eq!(parsed.answers.len(), 3);
+            assert_eq!(parsed.answers[2].rdata, vec![10, 0, 0, 11]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_openmetrics_rendering() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = Arc::new(QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap());
+        engine.handle_query(&packet::build_query(0x2479, "example.com", RecordType::A, true)).await.unwrap();
+
+        let text = crate::metrics::render_openmetrics(&engine);
+        assert!(text.ends_with("# EOF\n"));
+        assert!(text.contains("# TYPE unbound_time_up_seconds counter\n# UNIT unbound_time_up_seconds seconds\n"));
+        assert!(text.contains("# UNIT unbound_memory_caches_bytes bytes\n"));
+        assert!(text.contains("# TYPE unbound_queries counter\n"));
+        assert!(text.contains("unbound_queries_total{thread=\"0\"} 1\n"));
+        assert!(text.contains("nekonsd_upstream_server_queries_total{name=\"stub\"} 1\n"));
+        assert!(!text.contains("\n\n"));
+        // No sample is named after a bare counter family (they all carry _total)
+        let counters: Vec<&str> = text.lines()
+            .filter_map(|l| l.strip_prefix("# TYPE ")?.strip_suffix(" counter"))
+            .collect();
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            assert!(!counters.contains(&&line[..line.find(['{', ' ']).unwrap()]), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_and_client_miss_share_one_resolution() {
+        // Answers after 200ms, so the client miss arrives while the prefetch is in flight
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let upstream = socket.local_addr().unwrap();
+        let received = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let seen = received.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                seen.fetch_add(1, Ordering::Relaxed);
+                resp.header.qr = true;
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, 1]));
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    let _ = socket.send_to(&resp.to_wire(), peer).await;
+                });
+            }
+        });
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+
+        let query = packet::build_query(0x4242, "expiring.example.com", RecordType::A, true);
+        let (prefetched, answered) = tokio::join!(
+            engine.prefetch_once("expiring.example.com", RecordType::A),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                engine.handle_query_from(Some("192.0.2.9".parse().unwrap()), &query).await
+            },
+        );
+        assert_eq!(received.load(Ordering::Relaxed), 1);
+        assert_eq!(packet::parse_packet(&prefetched.unwrap()).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+        let answered = packet::parse_packet(&answered.unwrap()).unwrap();
+        assert_eq!(answered.header.id, 0x4242);
+        assert_eq!(answered.answers[0].rdata, vec![192, 0, 2, 1]);
+        assert!(engine.resolving.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_udp_responses_counted_by_reason() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.neko_comment.enabled = true;
+        config.listen.max_udp_response_size = Some(1024);
+        let engine = Arc::new(QueryEngine::new(Arc::new(config)).await.unwrap());
+        let answer = |query: &[u8], records: u8| {
+            let mut parsed = packet::parse_packet(query).unwrap();
+            parsed.header.qr = true;
+            parsed.additionals.clear();
+            for i in 0..records {
+                parsed.answers.push(packet::DnsRecord::new("big.example.com", RecordType::A, 60, vec![192, 0, 2, i]));
+            }
+            parsed.to_wire()
+        };
+        let count = |reason| engine.metrics.truncated(reason);
+
+        // No EDNS: 512 bytes
+        let plain = packet::build_query(1, "big.example.com", RecordType::A, true);
+        let fitted = engine.fit_udp(&plain, answer(&plain, 40));
+        assert!(packet::parse_packet(&fitted).unwrap().header.tc);
+        assert_eq!(count(TruncateReason::ClientSize), 1);
+
+        // The client takes 4096, listen.max_udp_response_size only 1024
+        let edns = edns_query("big.example.com");
+        let fitted = engine.fit_udp(&edns, answer(&edns, 80));
+        assert!(packet::parse_packet(&fitted).unwrap().header.tc);
+        assert_eq!(count(TruncateReason::HardCap), 1);
+
+        // Fits once our TXT is gone: sent whole, without it
+        let mut decorated = answer(&plain, 29);
+        assert!(decorated.len() <= 512);
+        packet::append_feature_record(&mut decorated, &engine.neko_comment, &QueryFeatures::new());
+        assert!(decorated.len() > 512);
+        let fitted = packet::parse_packet(&engine.fit_udp(&plain, decorated)).unwrap();
+        assert!(!fitted.header.tc);
+        assert_eq!(fitted.answers.len(), 29);
+        assert!(fitted.additionals.is_empty());
+        assert_eq!(count(TruncateReason::FeatureTxt), 1);
+        assert_eq!(count(TruncateReason::ClientSize), 1);
+
+        let text = crate::metrics::render_metrics(&engine);
+        assert!(text.contains("nekonsd_truncated_responses_total{reason=\"hard_cap\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_authoritative_zone_file_answered_locally() {
+        let upstream_hit = Arc::new(AtomicBool::new(false));
+        let upstream = spawn_upstream(upstream_hit.clone()).await;
+        let file = std::env::temp_dir().join(format!("neko-dns-zone-{}.zone", std::process::id()));
+        std::fs::write(&file, "$TTL 300\n@ IN SOA ns1 hostmaster 1 3600 600 86400 60\n  IN NS ns1\nns1 IN A 192.168.1.2\nnas IN A 192.168.1.10\n").unwrap();
+        let mut config = test_config(upstream, &format!("[[authoritative_zone]]\ndomain = \"home.arpa\"\nfile = \"{}\"\n", file.display()));
+        // Private addresses from our own zone aren't rebinding
+        config.security.deny_private_answers = true;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        std::fs::remove_file(&file).ok();
+
+        let a = packet::parse_packet(&engine.handle_query(&packet::build_query(1, "nas.home.arpa", RecordType::A, true)).await.unwrap()).unwrap();
+        assert!(a.header.aa);
+        assert_eq!(a.answers[0].rdata, vec![192, 168, 1, 10]);
+        let nx = packet::parse_packet(&engine.handle_query(&packet::build_query(2, "gone.home.arpa", RecordType::A, true)).await.unwrap()).unwrap();
+        assert_eq!(nx.header.rcode, crate::dns::types::ResponseCode::NxDomain);
+        assert_eq!(nx.authorities[0].rtype, RecordType::SOA);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+        assert_eq!(engine.get_stats()["authoritative_zones"][0]["records"], 4);
+    }
+
+    #[tokio::test]
+    async fn test_edited_zone_file_served_after_reload() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let file = std::env::temp_dir().join(format!("neko-dns-reload-{}.zone", std::process::id()));
+        let soa = "$TTL 300\n@ IN SOA ns1 hostmaster 1 3600 600 86400 60\n";
+        std::fs::write(&file, format!("{}nas IN A 192.168.1.10\n", soa)).unwrap();
+        let config = test_config(upstream, &format!("[[authoritative_zone]]\ndomain = \"home.arpa\"\nfile = \"{}\"\n", file.display()));
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let nas = |id| {
+            let engine = &engine;
+            async move {
+                let response = engine.handle_query(&packet::build_query(id, "nas.home.arpa", RecordType::A, true)).await.unwrap();
+                packet::parse_packet(&response).unwrap().answers[0].rdata.clone()
+            }
+        };
+        assert_eq!(nas(1).await, vec![192, 168, 1, 10]);
+        assert_eq!(engine.reload_authoritative_zones(), 0);
+
+        std::fs::write(&file, format!("{}nas IN A 192.168.1.200\n", soa)).unwrap();
+        assert_eq!(engine.reload_authoritative_zones(), 1);
+        assert_eq!(nas(2).await, vec![192, 168, 1, 200]);
+
+        // A broken edit keeps the last good version
+        std::fs::write(&file, format!("{}nas IN A not-an-address\n", soa)).unwrap();
+        assert_eq!(engine.reload_authoritative_zones(), 0);
+        assert_eq!(nas(3).await, vec![192, 168, 1, 200]);
+        std::fs::remove_file(&file).ok();
+    }
+
+    #[tokio::test]
+    async fn test_any_over_udp_pushed_to_tcp() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.recursive.any_over_udp = AnyOverUdp::Tcp;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let client: SocketAddr = "192.0.2.20:40000".parse().unwrap();
+        let query = packet::build_query(9, "example.com", RecordType::ANY, true);
+
+        let udp = packet::parse_packet(&engine.handle_client_query(client, ClientTransport::Udp, &query).await.unwrap()).unwrap();
+        assert!(udp.header.tc);
+        assert!(udp.answers.is_empty());
+        assert_eq!(engine.metrics.truncated(TruncateReason::AnyOverUdp), 1);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+
+        let tcp = packet::parse_packet(&engine.handle_client_query(client, ClientTransport::Tcp, &query).await.unwrap()).unwrap();
+        assert!(!tcp.header.tc);
+        assert!(!tcp.answers.is_empty());
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_minimal_any_answers_hinfo() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.recursive.any_over_udp = AnyOverUdp::Minimal;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let query = packet::build_query(10, "example.com", RecordType::ANY, true);
+        let response = engine.handle_client_query("192.0.2.20:40000".parse().unwrap(), ClientTransport::Tcp, &query).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.answers.len(), 1);
+        assert_eq!(parsed.answers[0].rtype, RecordType::Unknown(13));
+        assert_eq!(&parsed.answers[0].rdata[1..8], b"RFC8482");
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rd0_query_gets_referral_instead_of_resolution() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let hints = std::env::temp_dir().join(format!("neko-dns-referral-{}.hints", std::process::id()));
+        std::fs::write(&hints, ".  3600000  NS  a.root-servers.net.\na.root-servers.net.  3600000  A  192.0.2.200\n").unwrap();
+        let extra = format!("[recursive]\nenabled = true\nroot_hints_path = \"{}\"\nroot_reprobe_interval_secs = 0\n", hints.display());
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, &extra))).await.unwrap();
+        std::fs::remove_file(&hints).unwrap();
+
+        let response = engine.handle_query(&packet::build_query(0x0b01, "www.uncached.test", RecordType::A, false)).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NoError);
+        assert!(!parsed.header.aa);
+        assert!(parsed.answers.is_empty());
+        assert_eq!(parsed.authorities[0].rtype, RecordType::NS);
+        assert_eq!(packet::format_record(&parsed.authorities[0], &response), "a.root-servers.net");
+        assert_eq!(parsed.additionals[0].rdata, vec![192, 0, 2, 200]);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_one_do_fetch_serves_do_and_non_do_clients() {
+        // Signs its answer only when asked with DO
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        let asked = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let asked_by_stub = asked.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                asked_by_stub.fetch_add(1, Ordering::Relaxed);
+                let dnssec_ok = resp.additionals.iter().any(|r| r.rtype == RecordType::OPT && r.ttl & 0x8000 != 0);
+                resp.header.qr = true;
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, 1]));
+                if dnssec_ok {
+                    resp.answers.push(packet::DnsRecord::new(&qname, RecordType::Unknown(46), 60, vec![0, 1, 13, 2]));
+                }
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let types = |response: &[u8]| -> Vec<RecordType> {
+            packet::parse_packet(response).unwrap().answers.iter().map(|r| r.rtype).collect()
+        };
+
+        // Plain client first: fetched with DO anyway, stripped on the way out
+        let plain = engine.handle_query(&packet::build_query(0x0d01, "signed.example.com", RecordType::A, true)).await.unwrap();
+        assert_eq!(types(&plain), vec![RecordType::A]);
+
+        // A DO client is served the signed copy from the same entry
+        let mut do_query = edns_query("signed.example.com");
+        let flags_at = do_query.len() - 4;
+        do_query[flags_at] = 0x80;
+        let signed = engine.handle_query(&do_query).await.unwrap();
+        assert_eq!(types(&signed), vec![RecordType::A, RecordType::Unknown(46)]);
+        assert_eq!(asked.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_diagnostic_version_answered_without_resolving() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let query = packet::build_query(0x77, "neko-dns.version", RecordType::TXT, true);
+        let parsed = packet::parse_packet(&engine.handle_query_from(None, &query).await.unwrap()).unwrap();
+        assert!(parsed.header.aa);
+        assert_eq!(parsed.answers[0].rdata[1..], *format!("neko-dns {}", env!("CARGO_PKG_VERSION")).as_bytes());
+        let query = packet::build_query(0x78, "neko-dns.stats", RecordType::TXT, true);
+        let parsed = packet::parse_packet(&engine.handle_query_from(None, &query).await.unwrap()).unwrap();
+        assert!(parsed.answers.iter().any(|r| r.rdata[1..].starts_with(b"cache_hit_rate=")));
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_aaaa_policy_hides_aaaa_from_ipv4_clients() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut resp = packet::parse_packet(&buf[..len]).unwrap();
+                resp.header.qr = true;
+                let qname = resp.questions[0].name.clone();
+                let v6: std::net::Ipv6Addr = "2001:db8::1".parse().unwrap();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::AAAA, 60, v6.octets().to_vec()));
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        let mut config = test_config(upstream, "");
+        config.listen.aaaa_policy = crate::config::AaaaPolicy::NodataForIpv4Clients;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let query = packet::build_query(0x66, "dual.example.com", RecordType::AAAA, true);
+
+        let v6_client: IpAddr = "2001:db8::53".parse().unwrap();
+        let answer = packet::parse_packet(&engine.handle_query_from(Some(v6_client), &query).await.unwrap()).unwrap();
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(answer.answers[0].rtype, RecordType::AAAA);
+
+        for v4_client in ["192.0.2.10", "::ffff:192.0.2.10"] {
+            let response = engine.handle_query_from(Some(v4_client.parse().unwrap()), &query).await.unwrap();
+            let answer = packet::parse_packet(&response).unwrap();
+            assert_eq!(answer.header.rcode, crate::dns::types::ResponseCode::NoError);
+            assert!(answer.answers.is_empty(), "{} got AAAA", v4_client);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_aging_entry_served_then_refreshed_in_background() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let mut config = test_config(upstream, "");
+        config.cache.prefetch_on_read_threshold = 0.2;
+        let engine = Arc::new(QueryEngine::new(Arc::new(config)).await.unwrap());
+        let name = "aging.example.com";
+        engine.handle_query(&edns_query(name)).await.unwrap();
+        let ttl = engine.cache.get(name, &RecordType::A).await.unwrap().ttl;
+        engine.cache.backdate(name, &RecordType::A, ttl as u64 - 2);
+
+        // Answered from the cache without waiting for the upstream, refresh queued
+        let response = engine.handle_query(&edns_query(name)).await.unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().answers.len(), 1);
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 1);
+        assert!(engine.read_refresh_pending.contains_key(&(name.to_string(), RecordType::A.to_u16())));
+        // A second hit meanwhile doesn't queue it again
+        engine.handle_query(&edns_query(name)).await.unwrap();
+        assert_eq!(engine.read_refresh_pending.len(), 1);
+
+        let refresher = engine.clone();
+        tokio::spawn(async move { refresher.run_read_refresher().await });
+        for _ in 0..50 {
+            if engine.read_refresh_pending.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 2);
+        assert!(engine.cache.get(name, &RecordType::A).await.unwrap().remaining_ttl > 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_verifier_detects_changed_answer() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let mut last_octet = 1;
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut resp = packet::parse_packet(&buf[..len]).unwrap();
+                resp.header.qr = true;
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 300, vec![192, 0, 2, last_octet]));
+                last_octet += 1;
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        let mut config = test_config(upstream, "");
+        config.cache.verify_sample_rate = 1.0;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let name = "drifting.example.com";
+        engine.handle_query(&edns_query(name)).await.unwrap();
+
+        let mismatches = engine.verify_cache_sample().await;
+        assert_eq!(mismatches, vec![(name.to_string(), RecordType::A)]);
+        assert_eq!(engine.metrics.cache_verifications.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.metrics.cache_verify_mismatches.load(Ordering::Relaxed), 1);
+        // The entry now holds the fresh answer
+        let cached = packet::parse_packet(&engine.cache.export_entry(name, &RecordType::A, None).unwrap()).unwrap();
+        assert_eq!(cached.answers[0].rdata, vec![192, 0, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_ecs_scoped_answer_shared_within_its_subnet() {
+        // Stub authoritative: returns the client's ECS with a /24 scope
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let seen = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                seen.fetch_add(1, Ordering::SeqCst);
+                resp.header.qr = true;
+                resp.header.ra = true;
+                for opt in resp.additionals.iter_mut().filter(|r| r.rtype == RecordType::OPT && r.rdata.len() > 7) {
+                    opt.rdata[7] = 24;
+                }
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, 1]));
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        let mut config = test_config(upstream, "");
+        config.cache.ecs_scoped = true;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        let ecs_query = |id: u16, client: [u8; 4]| {
+            let mut query = packet::parse_packet(&packet::build_query(id, "geo.example.com", RecordType::A, true)).unwrap();
+            let mut rdata = vec![0, 8, 0, 8, 0, 1, 32, 0];
+            rdata.extend_from_slice(&client);
+            let mut opt = packet::DnsRecord::new("", RecordType::OPT, 0, rdata);
+            opt.rclass = crate::dns::types::DnsClass::from(1232);
+            query.additionals.push(opt);
+            query.to_wire()
+        };
+
+        engine.handle_query(&ecs_query(1, [198, 51, 100, 10])).await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // Same /24: answered from the cache, with this client's own subnet echoed
+        let response = engine.handle_query(&ecs_query(2, [198, 51, 100, 77])).await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        let echoed = crate::edns::client_subnet(&response).unwrap();
+        assert_eq!(echoed.addr, "198.51.100.77".parse::<IpAddr>().unwrap());
+        assert_eq!(echoed.scope_prefix, 24);
+
+        // Another /24, and a client without ECS: both resolved again
+        engine.handle_query(&ecs_query(3, [203, 0, 113, 5])).await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        engine.handle_query(&packet::build_query(4, "geo.example.com", RecordType::A, true)).await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_ecs_misses_from_other_subnets_not_coalesced() {
+        // Slow stub authoritative: the answer's last octet is the client's third, /24 scope
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let upstream = socket.local_addr().unwrap();
+        let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let seen = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                seen.fetch_add(1, Ordering::SeqCst);
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    resp.header.qr = true;
+                    resp.header.ra = true;
+                    let mut octet = 0;
+                    for opt in resp.additionals.iter_mut().filter(|r| r.rtype == RecordType::OPT && r.rdata.len() > 10) {
+                        opt.rdata[7] = 24;
+                        octet = opt.rdata[10];
+                    }
+                    let qname = resp.questions[0].name.clone();
+                    resp.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 60, vec![192, 0, 2, octet]));
+                    let _ = socket.send_to(&resp.to_wire(), peer).await;
+                });
+            }
+        });
+        let mut config = test_config(upstream, "");
+        config.cache.ecs_scoped = true;
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+
+        let ecs_query = |id: u16, client: [u8; 4]| {
+            let mut query = packet::parse_packet(&packet::build_query(id, "geo.example.com", RecordType::A, true)).unwrap();
+            let mut rdata = vec![0, 8, 0, 7, 0, 1, 24, 0];
+            rdata.extend_from_slice(&client[..3]);
+            let mut opt = packet::DnsRecord::new("", RecordType::OPT, 0, rdata);
+            opt.rclass = crate::dns::types::DnsClass::from(1232);
+            query.additionals.push(opt);
+            query.to_wire()
+        };
+        let (query_a, query_b) = (ecs_query(1, [198, 51, 100, 10]), ecs_query(2, [203, 0, 113, 5]));
+        let (first, second) = tokio::join!(engine.handle_query(&query_a), engine.handle_query(&query_b));
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        for (response, octet, client) in [(first.unwrap(), 100, "198.51.100.0"), (second.unwrap(), 113, "203.0.113.0")] {
+            let parsed = packet::parse_packet(&response).unwrap();
+            assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, octet]);
+            assert_eq!(crate::edns::client_subnet(&response).unwrap().addr, client.parse::<IpAddr>().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_private_reverse_answered_locally() {
+        let upstream = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let engine = QueryEngine::new(Arc::new(test_config(upstream, ""))).await.unwrap();
+        let ptr = packet::build_query(0x2515, "5.0.0.10.in-addr.arpa", RecordType::PTR, true);
+        let parsed = packet::parse_packet(&engine.handle_query(&ptr).await.unwrap()).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NxDomain);
+        assert_eq!(parsed.authorities[0].name, "10.in-addr.arpa");
+        assert_eq!(engine.metrics.upstream_queries.load(Ordering::Relaxed), 0);
+
+        // A local zone for the reverse zone gets the query instead
+        let local = spawn_upstream(Arc::new(AtomicBool::new(false))).await;
+        let config = test_config(upstream, &format!(
+            "[[local_zones]]\ndomain = \"10.in-addr.arpa\"\nserver = \"127.0.0.1\"\nport = {}\ntimeout_ms = 500\n",
+            local.port(),
+        ));
+        let engine = QueryEngine::new(Arc::new(config)).await.unwrap();
+        let parsed = packet::parse_packet(&engine.handle_query(&ptr).await.unwrap()).unwrap();
+        assert_eq!(parsed.header.rcode, crate::dns::types::ResponseCode::NoError);
+        assert_eq!(engine.metrics.local_zone_queries.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod packet;
 pub mod engine;
 pub mod types;
+pub mod tcp;
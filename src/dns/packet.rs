@@ -1,6 +1,9 @@
 use crate::dns::types::{RecordType, DnsClass, ResponseCode};
 use crate::neko_comment::{NekoComment, QueryFeatures};
+use std::collections::HashMap;
 use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// Raw DNS packet parser - full binary level parsing per RFC 1035
 /// No external DNS library used - everything is hand-parsed from &[u8]
@@ -22,6 +25,20 @@ pub struct DnsHeader {
     pub arcount: u16,       // Additional count
 }
 
+impl DnsHeader {
+    /// Re-assemble the 16-bit flags word from the individual header fields
+    pub fn flags(&self) -> u16 {
+        ((self.qr as u16) << 15)
+            | ((self.opcode as u16 & 0xF) << 11)
+            | ((self.aa as u16) << 10)
+            | ((self.tc as u16) << 9)
+            | ((self.rd as u16) << 8)
+            | ((self.ra as u16) << 7)
+            | ((self.z as u16 & 0x7) << 4)
+            | (self.rcode.to_u16() & 0xF)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DnsQuestion {
     pub name: String,
@@ -41,6 +58,21 @@ pub struct DnsRecord {
     pub rdata_offset: usize,
 }
 
+impl DnsRecord {
+    /// Build a synthesized IN-class record (rdata must be uncompressed)
+    pub fn new(name: &str, rtype: RecordType, ttl: u32, rdata: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            rtype,
+            rclass: DnsClass::IN,
+            ttl,
+            rdlength: rdata.len() as u16,
+            rdata,
+            rdata_offset: 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -135,7 +167,7 @@ pub fn parse_packet(data: &[u8]) -> anyhow::Result<DnsPacket> {
     let nscount = u16::from_be_bytes([data[8], data[9]]);
     let arcount = u16::from_be_bytes([data[10], data[11]]);
 
-    let header = DnsHeader {
+    let mut header = DnsHeader {
         id,
         qr: (flags >> 15) & 1 == 1,
         opcode: ((flags >> 11) & 0xF) as u8,
@@ -171,6 +203,14 @@ pub fn parse_packet(data: &[u8]) -> anyhow::Result<DnsPacket> {
     let authorities = parse_records(data, &mut offset, nscount)?;
     let additionals = parse_records(data, &mut offset, arcount)?;
 
+    // Extended RCODE (RFC 6891 §6.1.3): upper 8 bits in the OPT TTL
+    if let Some(opt) = additionals.iter().find(|r| r.rtype == RecordType::OPT) {
+        let upper = (opt.ttl >> 24) as u16;
+        if upper != 0 {
+            header.rcode = ResponseCode::from_u16((upper << 4) | (flags & 0xF));
+        }
+    }
+
     Ok(DnsPacket {
         header,
         questions,
@@ -181,8 +221,27 @@ pub fn parse_packet(data: &[u8]) -> anyhow::Result<DnsPacket> {
     })
 }
 
+/// Smallest possible record: root name (1) + type, class, TTL, rdlength (10)
+const MIN_RECORD_LEN: usize = 11;
+
+/// parse.max_records_per_section, set once at startup
+static MAX_RECORDS_PER_SECTION: AtomicUsize = AtomicUsize::new(1000);
+
+pub fn set_max_records_per_section(max: usize) {
+    MAX_RECORDS_PER_SECTION.store(max, Ordering::Relaxed);
+}
+
 fn parse_records(data: &[u8], offset: &mut usize, count: u16) -> anyhow::Result<Vec<DnsRecord>> {
-    let mut records = Vec::new();
+    // Refuse counts the packet can't possibly hold before looping over them
+    let max = MAX_RECORDS_PER_SECTION.load(Ordering::Relaxed);
+    if count as usize > max {
+        return Err(anyhow::anyhow!("DNS section claims {} records (max {})", count, max));
+    }
+    let remaining = data.len().saturating_sub(*offset);
+    if count as usize * MIN_RECORD_LEN > remaining {
+        return Err(anyhow::anyhow!("DNS section claims {} records in {} bytes", count, remaining));
+    }
+    let mut records = Vec::with_capacity(count as usize);
     for _ in 0..count {
         let name = parse_name(data, offset)?;
         if *offset + 10 > data.len() {
@@ -214,23 +273,401 @@ fn parse_records(data: &[u8], offset: &mut usize, count: u16) -> anyhow::Result<
     Ok(records)
 }
 
+impl DnsRecord {
+    /// Address carried by an A/AAAA record
+    pub fn ip_addr(&self) -> Option<IpAddr> {
+        match self.rtype {
+            RecordType::A => <[u8; 4]>::try_from(self.rdata.as_slice()).ok().map(|b| IpAddr::V4(Ipv4Addr::from(b))),
+            RecordType::AAAA => <[u8; 16]>::try_from(self.rdata.as_slice()).ok().map(|b| IpAddr::V6(Ipv6Addr::from(b))),
+            _ => None,
+        }
+    }
+}
+
+impl DnsPacket {
+    /// All A/AAAA addresses in the answer section
+    pub fn answer_ips(&self) -> Vec<IpAddr> {
+        self.answers.iter().filter_map(|r| r.ip_addr()).collect()
+    }
+
+    /// (lowercased owner name, address) for every A/AAAA glue record in the additional section
+    pub fn glue_ips(&self) -> Vec<(String, IpAddr)> {
+        self.additionals
+            .iter()
+            .filter_map(|r| r.ip_addr().map(|ip| (r.name.to_lowercase(), ip)))
+            .collect()
+    }
+
+    /// Serialize back into wire format with name compression.
+    /// Section counts come from the record vectors, so callers can freely
+    /// add/remove records and the header stays consistent.
+    pub fn to_wire(&self) -> Vec<u8> {
+        self.write_wire(true)
+    }
+
+    /// Serialize with every name written out in full (listen.compress_responses = false)
+    pub fn to_wire_uncompressed(&self) -> Vec<u8> {
+        self.write_wire(false)
+    }
+
+    fn write_wire(&self, compress: bool) -> Vec<u8> {
+        // Extended RCODE (RFC 6891 §6.1.3): the upper 8 bits go in the OPT TTL,
+        // so a packet without an OPT gets one to carry them
+        let extended = (self.header.rcode.to_u16() >> 4) as u32;
+        let fresh_opt = (extended != 0 && !self.additionals.iter().any(|r| r.rtype == RecordType::OPT))
+            .then(|| DnsRecord { rclass: DnsClass::from(EXTENDED_RCODE_UDP_SIZE), ..DnsRecord::new("", RecordType::OPT, 0, Vec::new()) });
+        let mut w = WireWriter::new(compress);
+        w.buf.extend_from_slice(&self.header.id.to_be_bytes());
+        w.buf.extend_from_slice(&self.header.flags().to_be_bytes());
+        let arcount = self.additionals.len() + fresh_opt.is_some() as usize;
+        for count in [self.questions.len(), self.answers.len(), self.authorities.len(), arcount] {
+            w.buf.extend_from_slice(&(count as u16).to_be_bytes());
+        }
+        for q in &self.questions {
+            w.write_name(&q.name, true);
+            w.buf.extend_from_slice(&q.qtype.to_u16().to_be_bytes());
+            w.buf.extend_from_slice(&q.qclass.to_u16().to_be_bytes());
+        }
+        for record in self.answers.iter().chain(&self.authorities).chain(&self.additionals).chain(&fresh_opt) {
+            if record.rtype == RecordType::OPT {
+                let opt = DnsRecord { ttl: (record.ttl & 0x00FF_FFFF) | (extended << 24), ..record.clone() };
+                w.write_record(&opt, &self.raw);
+            } else {
+                w.write_record(record, &self.raw);
+            }
+        }
+        w.buf
+    }
+}
+
+/// Wire-format writer with name compression (RFC 1035 §4.1.4)
+struct WireWriter {
+    buf: Vec<u8>,
+    compress: bool,
+    /// name suffix → offset of its first occurrence
+    names: HashMap<String, u16>,
+}
+
+impl WireWriter {
+    fn new(compress: bool) -> Self {
+        Self { buf: Vec::with_capacity(512), compress, names: HashMap::new() }
+    }
+
+    fn write_name(&mut self, name: &str, compressible: bool) {
+        let name = name.trim_end_matches('.');
+        if name.is_empty() {
+            self.buf.push(0);
+            return;
+        }
+        let labels: Vec<&str> = name.split('.').collect();
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".");
+            if self.compress && compressible {
+                if let Some(&ptr) = self.names.get(&suffix) {
+                    self.buf.extend_from_slice(&(0xC000 | ptr).to_be_bytes());
+                    return;
+                }
+            }
+            // Pointers are 14 bits - names beyond that offset can't be referenced
+            if self.buf.len() < 0x3FFF {
+                self.names.entry(suffix).or_insert(self.buf.len() as u16);
+            }
+            let label = labels[i].as_bytes();
+            self.buf.push(label.len() as u8);
+            self.buf.extend_from_slice(label);
+        }
+        self.buf.push(0);
+    }
+
+    fn write_record(&mut self, record: &DnsRecord, full_packet: &[u8]) {
+        self.write_name(&record.name, true);
+        self.buf.extend_from_slice(&record.rtype.to_u16().to_be_bytes());
+        self.buf.extend_from_slice(&record.rclass.to_u16().to_be_bytes());
+        self.buf.extend_from_slice(&record.ttl.to_be_bytes());
+        let len_pos = self.buf.len();
+        self.buf.extend_from_slice(&[0, 0]);
+        self.write_rdata(record, full_packet);
+        let rdlength = (self.buf.len() - len_pos - 2) as u16;
+        self.buf[len_pos..len_pos + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+
+    /// Names inside well-known rdata are expanded so the record survives being moved
+    /// into a new layout. RFC 1035 types may be re-compressed; SRV targets must not be (RFC 2782).
+    fn write_rdata(&mut self, record: &DnsRecord, full_packet: &[u8]) {
+        let (data, base) = rdata_context(record, full_packet);
+        let rdata = &record.rdata;
+        match record.rtype {
+            RecordType::NS | RecordType::CNAME | RecordType::PTR => {
+                if let Ok((name, _)) = name_at(data, base) {
+                    self.write_name(&name, true);
+                    return;
+                }
+            }
+            RecordType::MX if rdata.len() >= 3 => {
+                if let Ok((name, _)) = name_at(data, base + 2) {
+                    self.buf.extend_from_slice(&rdata[..2]);
+                    self.write_name(&name, true);
+                    return;
+                }
+            }
+            RecordType::SOA => {
+                let names = name_at(data, base)
+                    .and_then(|(mname, pos)| name_at(data, pos).map(|(rname, end)| (mname, rname, end)));
+                if let Ok((mname, rname, end)) = names {
+                    if let Some(fixed) = data.get(end..end + 20) {
+                        self.write_name(&mname, true);
+                        self.write_name(&rname, true);
+                        self.buf.extend_from_slice(fixed);
+                        return;
+                    }
+                }
+            }
+            RecordType::SRV if rdata.len() >= 7 => {
+                if let Ok((name, _)) = name_at(data, base + 6) {
+                    self.buf.extend_from_slice(&rdata[..6]);
+                    self.write_name(&name, false);
+                    return;
+                }
+            }
+            _ => {}
+        }
+        self.buf.extend_from_slice(rdata);
+    }
+}
+
+/// Locate the buffer that compression pointers in `record.rdata` are relative to.
+/// Parsed records point into their packet; synthesized records are self-contained.
+fn rdata_context<'a>(record: &'a DnsRecord, full_packet: &'a [u8]) -> (&'a [u8], usize) {
+    let end = record.rdata_offset + record.rdata.len();
+    if full_packet.get(record.rdata_offset..end) == Some(&record.rdata[..]) {
+        (full_packet, record.rdata_offset)
+    } else {
+        (&record.rdata, 0)
+    }
+}
+
+/// Parse a name at `pos`, returning it together with the offset just past it
+fn name_at(data: &[u8], pos: usize) -> anyhow::Result<(String, usize)> {
+    let mut offset = pos;
+    let name = parse_name(data, &mut offset)?;
+    Ok((name, offset))
+}
+
+/// Largest UDP response the client can take: its EDNS payload size, or 512 without OPT (RFC 6891 §6.2.5)
+pub fn udp_payload_limit(query: &[u8]) -> usize {
+    parse_packet(query).ok()
+        .and_then(|q| q.additionals.iter().find(|r| r.rtype == RecordType::OPT).map(|opt| opt.rclass.to_u16() as usize))
+        .map(|size| size.max(512))
+        .unwrap_or(512)
+}
+
+/// Over-limit UDP responses become an empty TC=1 answer (question + OPT) so the client retries over TCP
+pub fn truncate_for_udp(response: &[u8], limit: usize) -> anyhow::Result<Vec<u8>> {
+    if response.len() <= limit {
+        return Ok(response.to_vec());
+    }
+    let mut parsed = parse_packet(response)?;
+    parsed.header.tc = true;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    parsed.additionals.retain(|r| r.rtype == RecordType::OPT);
+    Ok(parsed.to_wire())
+}
+
+/// Rewrite a response without compression pointers in owner names or in the names
+/// inside NS/CNAME/PTR/MX/SOA/SRV rdata (other rdata is copied as is)
+pub fn decompress(response: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(parse_packet(response)?.to_wire_uncompressed())
+}
+
+/// Minimal responses: drop the authority and additional sections, keeping the answer
+/// and any negotiated OPT. The SOA proof of a negative answer is kept unless the client set RD.
+pub fn minimize_response(response: &[u8], client_rd: bool) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = parse_packet(response)?;
+    let negative = parsed.answers.is_empty()
+        && matches!(parsed.header.rcode, ResponseCode::NoError | ResponseCode::NxDomain);
+    if negative && !client_rd {
+        parsed.authorities.retain(|r| r.rtype == RecordType::SOA);
+    } else {
+        parsed.authorities.clear();
+    }
+    parsed.additionals.retain(|r| r.rtype == RecordType::OPT);
+    Ok(parsed.to_wire())
+}
+
+/// Reorder the answer section conventionally: the CNAME chain starting at the
+/// question name, then the queried type at the chain's end, then everything else
+pub fn order_answers(response: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = parse_packet(response)?;
+    let Some(question) = parsed.questions.first() else { return Ok(response.to_vec()) };
+    let qtype = question.qtype;
+    let mut target = question.name.clone();
+    let mut remaining = std::mem::take(&mut parsed.answers);
+    let mut ordered = Vec::with_capacity(remaining.len());
+
+    if qtype != RecordType::CNAME {
+        // Each CNAME is taken at most once, so loops in a bad chain terminate
+        while let Some(pos) = remaining.iter()
+            .position(|r| r.rtype == RecordType::CNAME && r.name.eq_ignore_ascii_case(&target))
+        {
+            let cname = remaining.remove(pos);
+            match parse_name_at_offset(response, cname.rdata_offset) {
+                Ok(next) => target = next,
+                Err(_) => { ordered.push(cname); break; }
+            }
+            ordered.push(cname);
+        }
+    }
+    let (finals, rest): (Vec<_>, Vec<_>) = remaining.into_iter()
+        .partition(|r| r.rtype == qtype && r.name.eq_ignore_ascii_case(&target));
+    ordered.extend(finals);
+    ordered.extend(rest);
+
+    parsed.answers = ordered;
+    Ok(parsed.to_wire())
+}
+
+/// Negative-answer TTL (RFC 2308): min(SOA TTL, SOA MINIMUM) from the authority section
+pub fn soa_negative_ttl(packet: &DnsPacket) -> Option<u32> {
+    let soa = packet.authorities.iter().find(|r| r.rtype == RecordType::SOA && r.rdata.len() >= 4)?;
+    let min = u32::from_be_bytes(soa.rdata[soa.rdata.len() - 4..].try_into().ok()?);
+    Some(min.min(soa.ttl))
+}
+
+/// Minimal SOA for negative answers neko-dns synthesizes itself (RFC 2308 §3).
+/// The real zone apex isn't known, so it is owned by the parent of `qname`;
+/// both the record TTL and MINIMUM are `negative_ttl`.
+pub fn synthetic_soa(qname: &str, negative_ttl: u32) -> DnsRecord {
+    let apex = qname.trim_end_matches('.').split_once('.').map(|(_, parent)| parent).unwrap_or("");
+    let mut rdata = encode_name("neko-dns.invalid");
+    rdata.extend(encode_name("hostmaster.neko-dns.invalid"));
+    // serial, refresh, retry, expire, minimum
+    for value in [1u32, 3600, 600, 86400, negative_ttl] {
+        rdata.extend_from_slice(&value.to_be_bytes());
+    }
+    DnsRecord::new(apex, RecordType::SOA, negative_ttl, rdata)
+}
+
+/// Add a synthetic SOA to a NXDOMAIN / NODATA packet whose authority section has none
+pub fn add_negative_soa(packet: &mut DnsPacket, negative_ttl: u32) {
+    let negative = packet.header.rcode == ResponseCode::NxDomain
+        || (packet.header.rcode == ResponseCode::NoError && packet.answers.is_empty());
+    if !negative || packet.authorities.iter().any(|r| r.rtype == RecordType::SOA) {
+        return;
+    }
+    let Some(qname) = packet.questions.first().map(|q| q.name.clone()) else { return };
+    packet.authorities.push(synthetic_soa(&qname, negative_ttl));
+}
+
 /// Build a SERVFAIL response from a query packet
 pub fn build_servfail(query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    build_error_response(query, ResponseCode::ServFail)
+}
+
+/// Build a FORMERR response from a query packet
+pub fn build_formerr(query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    build_error_response(query, ResponseCode::FormErr)
+}
+
+/// Build a REFUSED response from a query packet
+pub fn build_refused(query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    build_error_response(query, ResponseCode::Refused)
+}
+
+/// Build an empty NOERROR (NODATA) response from a query packet
+pub fn build_nodata(query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    build_error_response(query, ResponseCode::NoError)
+}
+
+/// Build an empty NOERROR response with TC=1, telling the client to retry over TCP
+pub fn build_truncated(query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut response = build_error_response(query, ResponseCode::NoError)?;
+    response[2] |= 0x02;
+    Ok(response)
+}
+
+/// RFC 8482 answer to ANY: one HINFO with CPU "RFC8482" and an empty OS
+pub fn build_minimal_any(query: &[u8], ttl: u32) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = parse_packet(query)?;
+    let qname = parsed.questions.first()
+        .map(|q| q.name.clone())
+        .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+    parsed.header.qr = true;
+    parsed.header.rcode = ResponseCode::NoError;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    parsed.additionals.retain(|r| r.rtype == RecordType::OPT);
+    let mut rdata = vec![7];
+    rdata.extend_from_slice(b"RFC8482");
+    rdata.push(0);
+    parsed.answers.push(DnsRecord::new(&qname, RecordType::Unknown(13), ttl, rdata));
+    Ok(parsed.to_wire())
+}
+
+fn build_error_response(query: &[u8], rcode: ResponseCode) -> anyhow::Result<Vec<u8>> {
     if query.len() < 12 {
-        return Err(anyhow::anyhow!("Query too short for SERVFAIL"));
+        return Err(anyhow::anyhow!("Query too short for {:?}", rcode));
     }
     let mut response = query.to_vec();
-    // Set QR=1 (response), keep opcode, set RCODE=2 (SERVFAIL)
+    // Set QR=1 (response), keep opcode, set RCODE
     response[2] = (response[2] | 0x80) & 0xFB; // QR=1, TC=0
-    response[3] = (response[3] & 0xF0) | 0x02;  // RCODE=2
+    response[3] = (response[3] & 0xF0) | (rcode.to_u16() & 0xF) as u8;
+    set_response_flags(query, &mut response);
     // Zero out answer/authority/additional counts
     response[6] = 0; response[7] = 0;
     response[8] = 0; response[9] = 0;
     response[10] = 0; response[11] = 0;
+    let extended = (rcode.to_u16() >> 4) as u8;
+    if extended != 0 {
+        // The upper RCODE bits need an OPT of their own (RFC 6891 §6.1.3)
+        let mut offset = 12;
+        if u16::from_be_bytes([query[4], query[5]]) > 0 {
+            parse_name(query, &mut offset)?;
+            offset += 4;
+        }
+        if offset > response.len() {
+            return Err(anyhow::anyhow!("Query too short for {:?}", rcode));
+        }
+        response.truncate(offset);
+        response.push(0);
+        response.extend_from_slice(&RecordType::OPT.to_u16().to_be_bytes());
+        response.extend_from_slice(&EXTENDED_RCODE_UDP_SIZE.to_be_bytes());
+        response.extend_from_slice(&[extended, 0, 0, 0, 0, 0]);
+        response[11] = 1;
+    }
     // Truncate after question section
     Ok(response)
 }
 
+/// UDP payload size advertised in an OPT added only to carry an extended RCODE
+const EXTENDED_RCODE_UDP_SIZE: u16 = 1232;
+
+const FLAG_RD: u8 = 0x01;
+const FLAG_RA: u8 = 0x80;
+const FLAG_Z: u8 = 0x40;
+const FLAG_AD: u8 = 0x20;
+const FLAG_CD: u8 = 0x10;
+
+/// Header flags for anything we send back to a client, whichever path produced it:
+/// - RD copied from the client's query (RFC 1035 §4.1.1)
+/// - RA=1, we offer recursion
+/// - AD=0, neko-dns does no DNSSEC validation of its own, so it never vouches for data
+///   (RFC 4035 §3.2.3) even when an upstream set it
+/// - CD copied from the query (RFC 4035 §3.2.2); with no validator there is nothing to skip
+/// - Z=0
+pub fn set_response_flags(query: &[u8], response: &mut [u8]) {
+    if query.len() < 4 || response.len() < 4 {
+        return;
+    }
+    response[2] = (response[2] & !FLAG_RD) | (query[2] & FLAG_RD);
+    response[3] = (response[3] & !(FLAG_Z | FLAG_AD | FLAG_CD)) | FLAG_RA | (query[3] & FLAG_CD);
+}
+
+/// RCODE SERVFAIL in the header, checked without parsing the rest of the packet
+pub(crate) fn is_servfail(response: &[u8]) -> bool {
+    response.len() >= 4 && response[3] & 0x0F == ResponseCode::ServFail.to_u16() as u8
+}
+
 /// Build a response packet with modified TTLs from cached data
 pub fn build_response(query: &[u8], cached_response: &[u8], new_ttl: u32) -> anyhow::Result<Vec<u8>> {
     let mut response = cached_response.to_vec();
@@ -241,6 +678,10 @@ pub fn build_response(query: &[u8], cached_response: &[u8], new_ttl: u32) -> any
     response[0] = query[0];
     response[1] = query[1];
 
+    // A cached answer is never authoritative (RFC 1035 §4.1.1)
+    response[2] &= !0x04;
+    set_response_flags(query, &mut response);
+
     // Update TTLs in all answer records
     let parsed = parse_packet(&response)?;
     let mut offset = 12;
@@ -274,6 +715,8 @@ pub fn build_response(query: &[u8], cached_response: &[u8], new_ttl: u32) -> any
 /// Encode a DNS name into wire format
 pub fn encode_name(name: &str) -> Vec<u8> {
     let mut result = Vec::new();
+    // "." and "example.com." end in the root label, which the push(0) below adds
+    let name = name.strip_suffix('.').unwrap_or(name);
     if name.is_empty() {
         result.push(0);
         return result;
@@ -321,6 +764,14 @@ pub fn extract_query_info(data: &[u8]) -> anyhow::Result<(String, RecordType)> {
     Ok((name, qtype))
 }
 
+/// QCLASS of the first question (None if the packet is too short to have one)
+pub fn extract_query_class(data: &[u8]) -> Option<DnsClass> {
+    let mut offset = 12;
+    parse_name(data, &mut offset).ok()?;
+    let class = data.get(offset + 2..offset + 4)?;
+    Some(DnsClass::from(u16::from_be_bytes([class[0], class[1]])))
+}
+
 /// Format rdata for display based on record type
 pub fn format_rdata(rtype: &RecordType, rdata: &[u8], full_packet: &[u8]) -> String {
     match rtype {
@@ -352,7 +803,7 @@ pub fn format_rdata(rtype: &RecordType, rdata: &[u8], full_packet: &[u8]) -> Str
                 format!("{} (binary)", preference)
             }
         }
-        RecordType::TXT => {
+        RecordType::TXT | RecordType::SPF => {
             let mut result = String::new();
             let mut pos = 0;
             while pos < rdata.len() {
@@ -367,10 +818,149 @@ pub fn format_rdata(rtype: &RecordType, rdata: &[u8], full_packet: &[u8]) -> Str
             }
             format!("\"{}\"", result)
         }
+        RecordType::SSHFP if rdata.len() >= 2 => {
+            // RFC 4255: algorithm, fingerprint type, fingerprint (hex)
+            let fingerprint: String = rdata[2..].iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{} {} {}", rdata[0], rdata[1], fingerprint)
+        }
+        RecordType::TLSA if rdata.len() >= 3 => {
+            // RFC 6698: usage, selector, matching type, certificate association data (hex)
+            let data: String = rdata[3..].iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{} {} {} {}", rdata[0], rdata[1], rdata[2], data)
+        }
+        RecordType::CDS if rdata.len() >= 4 => {
+            // RFC 7344 (DS format, RFC 4034 §5.3): key tag, algorithm, digest type, digest (hex)
+            let digest: String = rdata[4..].iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{} {} {} {}", u16::from_be_bytes([rdata[0], rdata[1]]), rdata[2], rdata[3], digest)
+        }
+        RecordType::DNSKEY | RecordType::CDNSKEY if rdata.len() >= 4 => {
+            // RFC 4034 §2.2 (CDNSKEY: RFC 7344): flags, protocol, algorithm, public key (base64)
+            format!("{} {} {} {}", u16::from_be_bytes([rdata[0], rdata[1]]), rdata[2], rdata[3], base64(&rdata[4..]))
+        }
+        RecordType::URI if rdata.len() >= 4 => {
+            // RFC 7553: priority, weight, target (the rest of the rdata, not length-prefixed)
+            format!("{} {} \"{}\"",
+                u16::from_be_bytes([rdata[0], rdata[1]]), u16::from_be_bytes([rdata[2], rdata[3]]),
+                String::from_utf8_lossy(&rdata[4..]))
+        }
+        RecordType::LOC if rdata.len() == 16 && rdata[0] == 0 => {
+            // RFC 1876: version, size, horiz/vert precision, lat, long, altitude
+            let lat = u32::from_be_bytes([rdata[4], rdata[5], rdata[6], rdata[7]]);
+            let lon = u32::from_be_bytes([rdata[8], rdata[9], rdata[10], rdata[11]]);
+            let alt = u32::from_be_bytes([rdata[12], rdata[13], rdata[14], rdata[15]]);
+            // Altitude is in cm above a base of 100,000m below the WGS 84 spheroid
+            let alt_cm = alt as i64 - 10_000_000;
+            let sign = if alt_cm < 0 { "-" } else { "" };
+            let alt_cm = alt_cm.unsigned_abs();
+            format!(
+                "{} {} {}{}.{:02}m {} {} {}",
+                loc_coord(lat, 'N', 'S'),
+                loc_coord(lon, 'E', 'W'),
+                sign, alt_cm / 100, alt_cm % 100,
+                loc_size(rdata[1]),
+                loc_size(rdata[2]),
+                loc_size(rdata[3]),
+            )
+        }
         _ => format!("(binary {} bytes)", rdata.len()),
     }
 }
 
+/// An SPF policy: the obsolete SPF type, or a TXT whose text starts with "v=spf1" (RFC 7208 §4.5)
+pub fn is_spf(rtype: &RecordType, rdata: &[u8]) -> bool {
+    match rtype {
+        RecordType::SPF => true,
+        RecordType::TXT => {
+            let Some(&len) = rdata.first() else { return false };
+            let text = &rdata[1..rdata.len().min(1 + len as usize)];
+            text.len() >= 6
+                && text[..6].eq_ignore_ascii_case(b"v=spf1")
+                && text.get(6).is_none_or(|&b| b == b' ')
+        }
+        _ => false,
+    }
+}
+
+/// What the cache verifier and shadow upstreams compare: rcode and the answer records, ignoring TTLs,
+/// order and name compression. None for a SERVFAIL, which says nothing about the data.
+pub fn answer_digest(response: &[u8]) -> Option<(u8, Vec<String>)> {
+    let parsed = parse_packet(response).ok()?;
+    if parsed.header.rcode == ResponseCode::ServFail {
+        return None;
+    }
+    let mut answers: Vec<String> = parsed.answers.iter()
+        .map(|r| format!("{} {} {}", r.name.to_lowercase(), r.rtype.name(), format_record(r, response)))
+        .collect();
+    answers.sort();
+    Some((response[3] & 0x0f, answers))
+}
+
+/// Presentation form of a parsed record's rdata, resolving compression pointers
+/// against `full_packet` for name-bearing types (falls back to `format_rdata`)
+pub fn format_record(record: &DnsRecord, full_packet: &[u8]) -> String {
+    let (data, base) = rdata_context(record, full_packet);
+    let name = |pos: usize| name_at(data, pos).map(|(n, end)| (if n.is_empty() { ".".to_string() } else { n }, end));
+    let formatted = match record.rtype {
+        RecordType::NS | RecordType::CNAME | RecordType::PTR => name(base).ok().map(|(n, _)| n),
+        RecordType::MX if record.rdata.len() >= 3 => name(base + 2).ok()
+            .map(|(n, _)| format!("{} {}", u16::from_be_bytes([record.rdata[0], record.rdata[1]]), n)),
+        RecordType::SOA => name(base)
+            .and_then(|(mname, pos)| name(pos).map(|(rname, end)| (mname, rname, end)))
+            .ok()
+            .and_then(|(mname, rname, end)| {
+                let fixed = data.get(end..end + 20)?;
+                let field = |i: usize| u32::from_be_bytes([fixed[i * 4], fixed[i * 4 + 1], fixed[i * 4 + 2], fixed[i * 4 + 3]]);
+                Some(format!("{} {} {} {} {} {} {}", mname, rname, field(0), field(1), field(2), field(3), field(4)))
+            }),
+        RecordType::SRV if record.rdata.len() >= 7 => name(base + 6).ok().map(|(n, _)| {
+            let r = &record.rdata;
+            format!("{} {} {} {}",
+                u16::from_be_bytes([r[0], r[1]]), u16::from_be_bytes([r[2], r[3]]), u16::from_be_bytes([r[4], r[5]]), n)
+        }),
+        _ => None,
+    };
+    formatted.unwrap_or_else(|| format_rdata(&record.rtype, &record.rdata, full_packet))
+}
+
+/// Standard base64 with padding (RFC 4648 §4), as DNSKEY keys are presented
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (chunk.get(1).copied().unwrap_or(0) as u32) << 8
+            | chunk.get(2).copied().unwrap_or(0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// LOC latitude/longitude: thousandths of an arc second, offset by 2^31 at the equator/meridian
+fn loc_coord(raw: u32, positive: char, negative: char) -> String {
+    let value = raw as i64 - (1i64 << 31);
+    let hemisphere = if value < 0 { negative } else { positive };
+    let value = value.unsigned_abs();
+    let (degrees, rem) = (value / 3_600_000, value % 3_600_000);
+    let (minutes, rem) = (rem / 60_000, rem % 60_000);
+    format!("{} {} {}.{:03} {}", degrees, minutes, rem / 1000, rem % 1000, hemisphere)
+}
+
+/// LOC size/precision: high nibble mantissa, low nibble power of ten, in cm
+fn loc_size(encoded: u8) -> String {
+    let cm = (encoded >> 4) as u64 * 10u64.pow((encoded & 0x0F) as u32);
+    if cm.is_multiple_of(100) {
+        format!("{}m", cm / 100)
+    } else {
+        format!("{}.{:02}m", cm / 100, cm % 100)
+    }
+}
+
 /// Parse a DNS name without compression support (for standalone rdata)
 fn parse_name_standalone(data: &[u8]) -> anyhow::Result<String> {
     let mut labels = Vec::new();
@@ -416,11 +1006,44 @@ pub fn parse_name_at_offset(full_packet: &[u8], offset: usize) -> anyhow::Result
     parse_name(full_packet, &mut pos)
 }
 
+/// RRSIG (RFC 4034)
+const TYPE_RRSIG: u16 = 46;
+/// DS, NSEC, DNSKEY (RFC 4034) and NSEC3 (RFC 5155)
+const DNSSEC_TYPES: [u16; 5] = [43, TYPE_RRSIG, 47, 48, 50];
+
+/// Remove DNSSEC records (RRSIG, NSEC, NSEC3, DNSKEY, DS) for a client without DO
+/// (RFC 4035 §3.2.1). Answers of the queried type stay - a DNSKEY query still gets its DNSKEYs.
+pub fn strip_dnssec(response: &[u8], qtype: RecordType) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = parse_packet(response)?;
+    let is_dnssec = |r: &DnsRecord| DNSSEC_TYPES.contains(&r.rtype.to_u16());
+    let before = parsed.answers.len() + parsed.authorities.len() + parsed.additionals.len();
+    parsed.answers.retain(|r| !is_dnssec(r) || r.rtype == qtype);
+    parsed.authorities.retain(|r| !is_dnssec(r));
+    parsed.additionals.retain(|r| !is_dnssec(r));
+    if parsed.answers.len() + parsed.authorities.len() + parsed.additionals.len() == before {
+        return Ok(response.to_vec());
+    }
+    Ok(parsed.to_wire())
+}
+
+/// DNSSEC material in the response: AD bit set, or any RRSIG record
+pub fn is_dnssec_signed(response: &[u8]) -> bool {
+    if response.len() < 12 {
+        return false;
+    }
+    if response[3] & 0x20 != 0 {
+        return true;
+    }
+    parse_packet(response)
+        .map(|p| p.answers.iter().chain(&p.authorities).chain(&p.additionals).any(|r| r.rtype.to_u16() == TYPE_RRSIG))
+        .unwrap_or(false)
+}
+
 /// Append a neko-dns feature notification TXT record to a response.
 /// Shows which resolver features were triggered during query processing.
 /// Modifies the packet in-place: appends the record bytes and increments ARCOUNT.
 pub fn append_feature_record(response: &mut Vec<u8>, neko: &NekoComment, features: &QueryFeatures) {
-    if response.len() < 12 {
+    if response.len() < 12 || neko.skips_response(response) {
         return;
     }
     let mut added: u16 = 0;
@@ -480,6 +1103,33 @@ mod tests {
         assert!(servfail[2] & 0x80 != 0);
         // RCODE=2
         assert_eq!(servfail[3] & 0x0F, 2);
+        // RA=1, RD echoed
+        assert!(servfail[3] & 0x80 != 0);
+        assert!(servfail[2] & 0x01 != 0);
+    }
+
+    #[test]
+    fn test_response_flags() {
+        // Query: RD=1, CD=1, and AD=1 (RFC 6840 §5.7 "AD please")
+        let mut query = build_query(0x1111, "example.com", RecordType::A, true);
+        query[3] |= 0x30;
+        // Authoritative-style answer: RD=0, RA=0, AD=1, Z=1
+        let answers = vec![rr("example.com", RecordType::A, 300, &[192, 0, 2, 1])];
+        let mut response = response_with("example.com", RecordType::A, [&answers, &[], &[]]);
+        response[2] = (response[2] | 0x04) & !0x01;
+        response[3] = (response[3] & !0x80) | 0x60;
+
+        set_response_flags(&query, &mut response);
+        let parsed = parse_packet(&response).unwrap();
+        assert!(parsed.header.qr && parsed.header.aa && parsed.header.rd && parsed.header.ra);
+        assert_eq!(response[3] & 0x70, 0x10, "AD and Z cleared, CD echoed");
+
+        // RD=0 / CD=0 query
+        let plain = build_query(0x1111, "example.com", RecordType::A, false);
+        set_response_flags(&plain, &mut response);
+        let parsed = parse_packet(&response).unwrap();
+        assert!(!parsed.header.rd && parsed.header.ra);
+        assert_eq!(response[3] & 0x70, 0);
     }
 
     #[test]
@@ -490,4 +1140,358 @@ mod tests {
         assert_eq!(packet.header.qdcount, 1);
         assert_eq!(packet.questions[0].name, "example.com");
     }
+
+    fn rr(name: &str, rtype: RecordType, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut record = encode_name(name);
+        record.extend_from_slice(&rtype.to_u16().to_be_bytes());
+        record.extend_from_slice(&1u16.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    fn opt_rr() -> Vec<u8> {
+        let mut record = vec![0];
+        record.extend_from_slice(&41u16.to_be_bytes());
+        record.extend_from_slice(&4096u16.to_be_bytes());
+        record.extend_from_slice(&0u32.to_be_bytes());
+        record.extend_from_slice(&0u16.to_be_bytes());
+        record
+    }
+
+    fn soa_rdata() -> Vec<u8> {
+        let mut rdata = encode_name("ns.example.com");
+        rdata.extend(encode_name("hostmaster.example.com"));
+        for v in [2024010101u32, 3600, 600, 86400, 300] {
+            rdata.extend_from_slice(&v.to_be_bytes());
+        }
+        rdata
+    }
+
+    fn response_with(qname: &str, qtype: RecordType, sections: [&[Vec<u8>]; 3]) -> Vec<u8> {
+        let mut response = build_query(0x4242, qname, qtype, true);
+        response[2] |= 0x80;
+        response[3] |= 0x80;
+        for (i, section) in sections.iter().enumerate() {
+            response[6 + i * 2..8 + i * 2].copy_from_slice(&(section.len() as u16).to_be_bytes());
+        }
+        for record in sections.iter().flat_map(|s| s.iter()) {
+            response.extend_from_slice(record);
+        }
+        response
+    }
+
+    #[test]
+    fn test_build_response_clears_aa() {
+        let answers = vec![rr("example.com", RecordType::A, 300, &[192, 0, 2, 1])];
+        let mut cached = response_with("example.com", RecordType::A, [&answers, &[], &[]]);
+        cached[2] |= 0x04;
+        cached[3] &= !0x80;
+        assert!(parse_packet(&cached).unwrap().header.aa);
+
+        let query = build_query(0x5151, "example.com", RecordType::A, true);
+        let served = parse_packet(&build_response(&query, &cached, 42).unwrap()).unwrap();
+        assert_eq!(served.header.id, 0x5151);
+        assert!(!served.header.aa);
+        assert!(served.header.ra);
+        assert_eq!(served.answers[0].ttl, 42);
+    }
+
+    #[test]
+    fn test_answer_and_glue_ips_mixed_families() {
+        let v6 = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let answers = vec![
+            rr("example.com", RecordType::A, 300, &[192, 0, 2, 1]),
+            rr("example.com", RecordType::AAAA, 300, &v6),
+            rr("example.com", RecordType::TXT, 300, b"\x04nope"),
+        ];
+        let additionals = vec![
+            rr("NS1.example.com", RecordType::A, 300, &[192, 0, 2, 53]),
+            rr("ns1.example.com", RecordType::AAAA, 300, &v6),
+            opt_rr(),
+        ];
+        let parsed = parse_packet(&response_with("example.com", RecordType::A, [&answers, &[], &additionals])).unwrap();
+
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(parsed.answer_ips(), vec![v4, v6]);
+        assert_eq!(parsed.glue_ips(), vec![
+            ("ns1.example.com".to_string(), "192.0.2.53".parse().unwrap()),
+            ("ns1.example.com".to_string(), v6),
+        ]);
+    }
+
+    #[test]
+    fn test_implausible_record_counts_rejected_up_front() {
+        let mut response = build_query(1, "example.com", RecordType::A, false);
+        response[2] |= 0x80;
+        response[6..8].copy_from_slice(&60000u16.to_be_bytes());
+        let err = parse_packet(&response).unwrap_err().to_string();
+        assert!(err.contains("claims 60000 records"), "{}", err);
+
+        // Within the cap but more than the bytes left could hold
+        response[6..8].copy_from_slice(&3u16.to_be_bytes());
+        response.extend_from_slice(&[0; 20]);
+        let err = parse_packet(&response).unwrap_err().to_string();
+        assert!(err.contains("claims 3 records in 20 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn test_format_sshfp() {
+        let rdata = [1, 1, 0xde, 0xad, 0xbe, 0xef];
+        assert_eq!(format_rdata(&RecordType::SSHFP, &rdata, &rdata), "1 1 DEADBEEF");
+    }
+
+    #[test]
+    fn test_format_tlsa() {
+        // DANE-EE, SPKI, SHA-256
+        let rdata = [3, 1, 1, 0x0c, 0x72, 0xac, 0x70];
+        assert_eq!(format_rdata(&RecordType::TLSA, &rdata, &rdata), "3 1 1 0C72AC70");
+        assert_eq!(RecordType::from(52), RecordType::TLSA);
+        assert_eq!(RecordType::from_name("tlsa"), Some(RecordType::TLSA));
+    }
+
+    #[test]
+    fn test_format_cds_cdnskey() {
+        // RFC 4034 §5.4 example DS for dskey.example.com, published as CDS
+        let mut cds = vec![0xec, 0x45, 5, 1];
+        cds.extend_from_slice(&[0x2b, 0xb1, 0x83, 0xaf, 0x5f, 0x22, 0x58, 0x81, 0x79, 0xa5,
+            0x3b, 0x0a, 0x98, 0x63, 0x1f, 0xad, 0x1a, 0x29, 0x21, 0x18]);
+        assert_eq!(format_rdata(&RecordType::CDS, &cds, &cds), "60485 5 1 2BB183AF5F22588179A53B0A98631FAD1A292118");
+        assert_eq!(RecordType::from(59), RecordType::CDS);
+        assert_eq!(RecordType::from_name("cds"), Some(RecordType::CDS));
+
+        // KSK, protocol 3, ECDSAP256SHA256
+        let cdnskey = [0x01, 0x01, 3, 13, b'n', b'e', b'k', b'o', b'!'];
+        assert_eq!(format_rdata(&RecordType::CDNSKEY, &cdnskey, &cdnskey), "257 3 13 bmVrbyE=");
+        assert_eq!(format_rdata(&RecordType::DNSKEY, &cdnskey, &cdnskey), "257 3 13 bmVrbyE=");
+        assert_eq!(RecordType::CDNSKEY.to_u16(), 60);
+        assert_eq!(RecordType::from_name("CDNSKEY"), Some(RecordType::CDNSKEY));
+        assert_eq!(base64(b"neko"), "bmVrbw==");
+        assert_eq!(base64(b"nek"), "bmVr");
+    }
+
+    #[test]
+    fn test_spf_detection() {
+        let txt = |text: &str| {
+            let mut rdata = vec![text.len() as u8];
+            rdata.extend_from_slice(text.as_bytes());
+            rdata
+        };
+        assert!(is_spf(&RecordType::TXT, &txt("v=spf1 include:_spf.example.com -all")));
+        assert!(is_spf(&RecordType::TXT, &txt("V=SPF1")));
+        assert!(!is_spf(&RecordType::TXT, &txt("v=spf10 -all")));
+        assert!(!is_spf(&RecordType::TXT, &txt("google-site-verification=abc")));
+        assert!(!is_spf(&RecordType::A, &[118, 61, 115, 112]));
+        let spf = txt("v=spf1 -all");
+        assert!(is_spf(&RecordType::SPF, &spf));
+        assert_eq!(format_rdata(&RecordType::SPF, &spf, &spf), "\"v=spf1 -all\"");
+        assert_eq!(RecordType::from(99), RecordType::SPF);
+        assert_eq!(RecordType::from_name("spf"), Some(RecordType::SPF));
+    }
+
+    #[test]
+    fn test_format_uri() {
+        let mut rdata = vec![0, 10, 0, 1];
+        rdata.extend_from_slice(b"https://example.com/");
+        assert_eq!(format_rdata(&RecordType::URI, &rdata, &rdata), "10 1 \"https://example.com/\"");
+        assert_eq!(RecordType::from(256), RecordType::URI);
+        assert_eq!(RecordType::URI.to_u16(), 256);
+        assert_eq!(RecordType::from_name("uri"), Some(RecordType::URI));
+    }
+
+    #[test]
+    fn test_format_loc() {
+        // RFC 1876 example: cambridge-net.kei.com LOC 42 21 54 N 71 06 18 W -24m 30m
+        let mut rdata = vec![0, 0x33, 0x16, 0x13];
+        rdata.extend_from_slice(&2_299_997_648u32.to_be_bytes());
+        rdata.extend_from_slice(&1_891_505_648u32.to_be_bytes());
+        rdata.extend_from_slice(&9_997_600u32.to_be_bytes());
+        assert_eq!(
+            format_rdata(&RecordType::LOC, &rdata, &rdata),
+            "42 21 54.000 N 71 6 18.000 W -24.00m 30m 10000m 10m"
+        );
+    }
+
+    #[test]
+    fn test_to_wire_roundtrip_with_compressed_rdata() {
+        // CNAME rdata is a pointer back to the question name
+        let answers = vec![rr("www.example.com", RecordType::CNAME, 300, &[0xC0, 0x0C])];
+        let response = response_with("example.com", RecordType::A, [&answers, &[], &[]]);
+        let rebuilt = parse_packet(&parse_packet(&response).unwrap().to_wire()).unwrap();
+        let cname = &rebuilt.answers[0];
+        assert_eq!(cname.name, "www.example.com");
+        assert_eq!(parse_name_at_offset(&rebuilt.raw, cname.rdata_offset).unwrap(), "example.com");
+    }
+
+    #[test]
+    fn test_minimize_response_keeps_answer_and_opt() {
+        let answers = vec![rr("example.com", RecordType::A, 300, &[93, 184, 216, 34])];
+        let authorities = vec![rr("example.com", RecordType::NS, 300, &encode_name("ns.example.com"))];
+        let additionals = vec![rr("ns.example.com", RecordType::A, 300, &[192, 0, 2, 53]), opt_rr()];
+        let response = response_with("example.com", RecordType::A, [&answers, &authorities, &additionals]);
+
+        let minimal = parse_packet(&minimize_response(&response, true).unwrap()).unwrap();
+        assert_eq!(minimal.header.ancount, 1);
+        assert_eq!(minimal.header.nscount, 0);
+        assert_eq!(minimal.header.arcount, 1);
+        assert_eq!(minimal.answers[0].rdata, vec![93, 184, 216, 34]);
+        assert_eq!(minimal.additionals[0].rtype, RecordType::OPT);
+    }
+
+    #[test]
+    fn test_order_answers_cname_chain_then_queried_type() {
+        let answers = vec![
+            rr("edge.cdn.test", RecordType::A, 60, &[192, 0, 2, 80]),
+            rr("alias.cdn.test", RecordType::CNAME, 300, &encode_name("edge.cdn.test")),
+            rr("www.example.com", RecordType::CNAME, 300, &encode_name("alias.cdn.test")),
+        ];
+        let response = response_with("www.example.com", RecordType::A, [&answers, &[], &[opt_rr()]]);
+
+        let ordered = order_answers(&response).unwrap();
+        let parsed = parse_packet(&ordered).unwrap();
+        let names: Vec<(&str, RecordType)> = parsed.answers.iter().map(|r| (r.name.as_str(), r.rtype)).collect();
+        assert_eq!(names, vec![
+            ("www.example.com", RecordType::CNAME),
+            ("alias.cdn.test", RecordType::CNAME),
+            ("edge.cdn.test", RecordType::A),
+        ]);
+        assert_eq!(parse_name_at_offset(&ordered, parsed.answers[0].rdata_offset).unwrap(), "alias.cdn.test");
+        assert_eq!(parsed.answers[2].rdata, vec![192, 0, 2, 80]);
+        assert_eq!(parsed.additionals[0].rtype, RecordType::OPT);
+    }
+
+    #[test]
+    fn test_minimize_response_nodata_soa() {
+        let authorities = vec![rr("example.com", RecordType::SOA, 300, &soa_rdata())];
+        let response = response_with("example.com", RecordType::AAAA, [&[], &authorities, &[]]);
+
+        let kept = parse_packet(&minimize_response(&response, false).unwrap()).unwrap();
+        assert_eq!(kept.authorities.len(), 1);
+        assert_eq!(kept.authorities[0].rtype, RecordType::SOA);
+
+        let stripped = parse_packet(&minimize_response(&response, true).unwrap()).unwrap();
+        assert!(stripped.authorities.is_empty());
+    }
+
+    #[test]
+    fn test_truncate_for_udp() {
+        let plain = build_query(1, "example.com", RecordType::TXT, true);
+        assert_eq!(udp_payload_limit(&plain), 512);
+        let mut edns = plain.clone();
+        edns[11] = 1;
+        edns.extend_from_slice(&opt_rr());
+        assert_eq!(udp_payload_limit(&edns), 4096);
+
+        let txt: Vec<u8> = std::iter::once(255u8).chain(std::iter::repeat_n(b'x', 255)).collect();
+        let answers: Vec<Vec<u8>> = (0..4).map(|_| rr("example.com", RecordType::TXT, 60, &txt)).collect();
+        let response = response_with("example.com", RecordType::TXT, [&answers, &[], &[opt_rr()]]);
+        assert!(response.len() > 512);
+
+        assert_eq!(truncate_for_udp(&response, 4096).unwrap(), response);
+        let truncated = parse_packet(&truncate_for_udp(&response, 512).unwrap()).unwrap();
+        assert!(truncated.header.tc);
+        assert!(truncated.answers.is_empty());
+        assert_eq!(truncated.questions[0].name, "example.com");
+        assert_eq!(truncated.additionals.len(), 1);
+        assert_eq!(truncated.additionals[0].rtype, RecordType::OPT);
+    }
+
+    #[test]
+    fn test_feature_txt_skipped_for_signed_answers() {
+        use crate::config::NekoCommentConfig;
+        let neko = NekoComment::new(&NekoCommentConfig { enabled: true, skip_signed_answers: true, edns_metadata: false, ..NekoCommentConfig::default() });
+        let answers = vec![rr("example.com", RecordType::A, 300, &[93, 184, 216, 34])];
+        let plain = response_with("example.com", RecordType::A, [&answers, &[], &[]]);
+
+        let mut unsigned = plain.clone();
+        append_feature_record(&mut unsigned, &neko, &QueryFeatures::new());
+        assert!(parse_packet(&unsigned).unwrap().header.arcount > 0);
+
+        let mut ad = plain.clone();
+        ad[3] |= 0x20;
+        let before = ad.clone();
+        append_feature_record(&mut ad, &neko, &QueryFeatures::new());
+        assert_eq!(ad, before);
+
+        let with_rrsig = [answers[0].clone(), rr("example.com", RecordType::Unknown(46), 300, &[0; 18])];
+        let mut signed = response_with("example.com", RecordType::A, [&with_rrsig, &[], &[]]);
+        let before = signed.clone();
+        append_feature_record(&mut signed, &neko, &QueryFeatures::new());
+        assert_eq!(signed, before);
+
+        // Opt-out restores the old behaviour
+        let always = NekoComment::new(&NekoCommentConfig { enabled: true, skip_signed_answers: false, edns_metadata: false, ..NekoCommentConfig::default() });
+        append_feature_record(&mut ad, &always, &QueryFeatures::new());
+        assert!(parse_packet(&ad).unwrap().header.arcount > 0);
+    }
+
+    #[test]
+    fn test_parse_refused_notauth_and_extended_rcodes() {
+        let query = build_query(0x2439, "example.com", RecordType::A, true);
+        let refused = build_refused(&query).unwrap();
+        assert_eq!(parse_packet(&refused).unwrap().header.rcode, ResponseCode::Refused);
+
+        let mut notauth = query.clone();
+        notauth[2] |= 0x80;
+        notauth[3] = (notauth[3] & 0xF0) | 9;
+        let parsed = parse_packet(&notauth).unwrap();
+        assert_eq!(parsed.header.rcode, ResponseCode::NotAuth);
+        assert_eq!(parsed.header.rcode.label(), "NOTAUTH");
+        assert_eq!(parse_packet(&parsed.to_wire()).unwrap().header.rcode, ResponseCode::NotAuth);
+
+        // BADVERS = 16: header rcode 0, extended RCODE 1 in the OPT TTL
+        let mut badvers = query.clone();
+        badvers[2] |= 0x80;
+        badvers[11] = 1;
+        badvers.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 1, 0, 0, 0, 0, 0]);
+        let parsed = parse_packet(&badvers).unwrap();
+        assert_eq!(parsed.header.rcode, ResponseCode::BadVers);
+        assert_eq!(parse_packet(&parsed.to_wire()).unwrap().header.rcode, ResponseCode::BadVers);
+        assert_eq!(ResponseCode::from(12).label(), "RCODE12");
+    }
+
+    #[test]
+    fn test_extended_rcode_written_to_opt_ttl() {
+        let query = build_query(0x2439, "example.com", RecordType::A, true);
+        let opts = |wire: &[u8]| parse_packet(wire).unwrap().additionals.into_iter().filter(|r| r.rtype == RecordType::OPT).collect::<Vec<_>>();
+
+        // Built from scratch without an OPT: one is added to carry the upper bits
+        let mut scratch = parse_packet(&query).unwrap();
+        scratch.header.qr = true;
+        scratch.header.rcode = ResponseCode::BadVers;
+        let wire = scratch.to_wire();
+        assert_eq!(parse_packet(&wire).unwrap().header.rcode, ResponseCode::BadVers);
+        assert_eq!(opts(&wire).len(), 1);
+        assert_eq!(opts(&wire)[0].ttl >> 24, 1);
+
+        // A fresh OPT (TTL 0) gets the upper bits; its own flags are kept
+        scratch.additionals.push(DnsRecord::new("", RecordType::OPT, 0x8000, Vec::new()));
+        let wire = scratch.to_wire();
+        assert_eq!(parse_packet(&wire).unwrap().header.rcode, ResponseCode::BadVers);
+        assert_eq!(opts(&wire).len(), 1);
+        assert_eq!(opts(&wire)[0].ttl, 0x0100_8000);
+
+        let error = build_error_response(&query, ResponseCode::BadVers).unwrap();
+        assert_eq!(parse_packet(&error).unwrap().header.rcode, ResponseCode::BadVers);
+        assert_eq!(parse_packet(&build_servfail(&query).unwrap()).unwrap().header.arcount, 0);
+    }
+
+    #[test]
+    fn test_strip_dnssec_keeps_queried_type() {
+        let mut pkt = parse_packet(&build_query(1, "example.com", RecordType::DNSKEY, false)).unwrap();
+        pkt.header.qr = true;
+        pkt.answers.push(DnsRecord::new("example.com", RecordType::DNSKEY, 300, vec![1, 1, 3, 8]));
+        pkt.answers.push(DnsRecord::new("example.com", RecordType::Unknown(46), 300, vec![0; 20]));
+        pkt.additionals.push(DnsRecord::new("example.com", RecordType::Unknown(43), 300, vec![0; 8]));
+        let stripped = parse_packet(&strip_dnssec(&pkt.to_wire(), RecordType::DNSKEY).unwrap()).unwrap();
+        assert_eq!(stripped.answers.iter().map(|r| r.rtype).collect::<Vec<_>>(), vec![RecordType::DNSKEY]);
+        assert!(stripped.additionals.is_empty());
+        assert_eq!(stripped.header.ancount, 1);
+
+        // Nothing to strip: the bytes are passed through untouched
+        let plain = build_query(2, "example.com", RecordType::A, false);
+        assert_eq!(strip_dnssec(&plain, RecordType::A).unwrap(), plain);
+    }
 }
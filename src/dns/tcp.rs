@@ -0,0 +1,142 @@
+//! DNS over TCP framing (RFC 1035 §4.2.2, RFC 7766)
+//!
+//! Every message is prefixed with its 2-byte length. Messages can be up to
+//! 65535 bytes, far beyond the 4096-byte UDP buffers, and a single read may
+//! return only part of one — always read the full prefixed length.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::debug;
+
+use crate::config::StillTruncatedPolicy;
+
+/// Read one length-prefixed message. `Ok(None)` on a clean EOF between messages.
+pub async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 2];
+    match stream.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut msg = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut msg).await?;
+    Ok(Some(msg))
+}
+
+/// Write one length-prefixed message (single write so prefix and body share a segment)
+pub async fn write_message<W: AsyncWrite + Unpin>(stream: &mut W, msg: &[u8]) -> std::io::Result<()> {
+    let len = u16::try_from(msg.len()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("DNS message too large for TCP ({} bytes)", msg.len()))
+    })?;
+    let mut framed = Vec::with_capacity(msg.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(msg);
+    stream.write_all(&framed).await
+}
+
+/// One-shot query over TCP from `source` (if set); the response must carry the query's ID
+pub async fn query(query: &[u8], addr: SocketAddr, timeout: Duration, dscp: Option<u8>, source: Option<IpAddr>) -> anyhow::Result<Vec<u8>> {
+    tokio::time::timeout(timeout, async {
+        let mut stream = match source {
+            Some(_) => {
+                let socket = if addr.is_ipv6() { TcpSocket::new_v6()? } else { TcpSocket::new_v4()? };
+                socket.bind(SocketAddr::new(crate::source_addr::bind_ip(source, &addr), 0))?;
+                socket.connect(addr).await?
+            }
+            None => TcpStream::connect(addr).await?,
+        };
+        if let Some(dscp) = dscp {
+            crate::dscp::apply(&stream, dscp);
+        }
+        write_message(&mut stream, query).await?;
+
+        let response = read_message(&mut stream).await?
+            .ok_or_else(|| anyhow::anyhow!("TCP connection to {} closed without a response", addr))?;
+        if response.len() < 12 || query.len() < 2 || response[..2] != query[..2] {
+            return Err(anyhow::anyhow!("Mismatched TCP response from {}", addr));
+        }
+        Ok(response)
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("TCP timeout querying {}", addr))?
+}
+
+/// Some broken servers set TC over TCP as well. The TCP retry after a truncated UDP
+/// answer is never repeated; this decides what its still-truncated answer becomes
+/// (tcp_still_truncated) and counts them.
+pub struct StillTruncated {
+    policy: StillTruncatedPolicy,
+    count: AtomicU64,
+}
+
+impl StillTruncated {
+    pub fn new(policy: StillTruncatedPolicy) -> Self {
+        Self { policy, count: AtomicU64::new(0) }
+    }
+
+    /// Check a response received over TCP from `server`
+    pub fn settle(&self, mut response: Vec<u8>, server: SocketAddr) -> anyhow::Result<Vec<u8>> {
+        if response.len() < 3 || response[2] & 0x02 == 0 {
+            return Ok(response);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        match self.policy {
+            StillTruncatedPolicy::Accept => {
+                debug!("TCP response from {} is still truncated, using it as is", server);
+                response[2] &= !0x02;
+                Ok(response)
+            }
+            StillTruncatedPolicy::Fail => Err(anyhow::anyhow!("TCP response from {} is still truncated", server)),
+        }
+    }
+
+    /// TCP responses that came back with TC set
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::packet;
+    use crate::dns::types::RecordType;
+
+    #[tokio::test]
+    async fn test_query_reads_large_response() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let query = read_message(&mut stream).await.unwrap().unwrap();
+            // 10000-byte response, dribbled out in small chunks
+            let mut response = query.clone();
+            response[2] |= 0x80;
+            response.resize(10_000, 0xAB);
+            let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&response);
+            for chunk in framed.chunks(1000) {
+                stream.write_all(chunk).await.unwrap();
+                stream.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let q = packet::build_query(0x4321, "big.example.com", RecordType::TXT, false);
+        let response = query(&q, addr, Duration::from_secs(2), None, None).await.unwrap();
+        assert_eq!(response.len(), 10_000);
+        assert_eq!(&response[..2], &[0x43, 0x21]);
+        assert!(response[10_000 - 1] == 0xAB);
+    }
+
+    #[tokio::test]
+    async fn test_write_message_rejects_oversized() {
+        let mut sink = Vec::new();
+        assert!(write_message(&mut sink, &vec![0u8; 70_000]).await.is_err());
+        write_message(&mut sink, &[1, 2, 3]).await.unwrap();
+        assert_eq!(sink, vec![0, 3, 1, 2, 3]);
+    }
+}
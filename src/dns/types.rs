@@ -10,9 +10,19 @@ pub enum RecordType {
     MX = 15,
     TXT = 16,
     AAAA = 28,
+    LOC = 29,
     SRV = 33,
     OPT = 41,     // EDNS
+    SSHFP = 44,
+    DNSKEY = 48,
+    TLSA = 52,
+    CDS = 59,
+    CDNSKEY = 60,
+    SVCB = 64,
+    HTTPS = 65,
+    SPF = 99,     // Obsolete (RFC 7208 §3.1), SPF lives in TXT now
     ANY = 255,
+    URI = 256,
     Unknown(u16),
 }
 
@@ -27,9 +37,19 @@ impl From<u16> for RecordType {
             15 => RecordType::MX,
             16 => RecordType::TXT,
             28 => RecordType::AAAA,
+            29 => RecordType::LOC,
             33 => RecordType::SRV,
             41 => RecordType::OPT,
+            44 => RecordType::SSHFP,
+            48 => RecordType::DNSKEY,
+            52 => RecordType::TLSA,
+            59 => RecordType::CDS,
+            60 => RecordType::CDNSKEY,
+            64 => RecordType::SVCB,
+            65 => RecordType::HTTPS,
+            99 => RecordType::SPF,
             255 => RecordType::ANY,
+            256 => RecordType::URI,
             other => RecordType::Unknown(other),
         }
     }
@@ -46,9 +66,19 @@ impl RecordType {
             RecordType::MX => 15,
             RecordType::TXT => 16,
             RecordType::AAAA => 28,
+            RecordType::LOC => 29,
             RecordType::SRV => 33,
             RecordType::OPT => 41,
+            RecordType::SSHFP => 44,
+            RecordType::DNSKEY => 48,
+            RecordType::TLSA => 52,
+            RecordType::CDS => 59,
+            RecordType::CDNSKEY => 60,
+            RecordType::SVCB => 64,
+            RecordType::HTTPS => 65,
+            RecordType::SPF => 99,
             RecordType::ANY => 255,
+            RecordType::URI => 256,
             RecordType::Unknown(v) => *v,
         }
     }
@@ -63,28 +93,87 @@ impl RecordType {
             RecordType::MX => "MX".into(),
             RecordType::TXT => "TXT".into(),
             RecordType::AAAA => "AAAA".into(),
+            RecordType::LOC => "LOC".into(),
             RecordType::SRV => "SRV".into(),
             RecordType::OPT => "OPT".into(),
+            RecordType::SSHFP => "SSHFP".into(),
+            RecordType::DNSKEY => "DNSKEY".into(),
+            RecordType::TLSA => "TLSA".into(),
+            RecordType::CDS => "CDS".into(),
+            RecordType::CDNSKEY => "CDNSKEY".into(),
+            RecordType::SVCB => "SVCB".into(),
+            RecordType::HTTPS => "HTTPS".into(),
+            RecordType::SPF => "SPF".into(),
             RecordType::ANY => "ANY".into(),
+            RecordType::URI => "URI".into(),
             RecordType::Unknown(v) => format!("TYPE{}", v),
         }
     }
+
+    /// Parse a mnemonic ("AAAA", case-insensitive) or RFC 3597 "TYPEnnn" form
+    pub fn from_name(name: &str) -> Option<RecordType> {
+        let upper = name.to_ascii_uppercase();
+        if let Some(num) = upper.strip_prefix("TYPE") {
+            return num.parse::<u16>().ok().map(RecordType::from);
+        }
+        let t = match upper.as_str() {
+            "A" => RecordType::A,
+            "NS" => RecordType::NS,
+            "CNAME" => RecordType::CNAME,
+            "SOA" => RecordType::SOA,
+            "PTR" => RecordType::PTR,
+            "MX" => RecordType::MX,
+            "TXT" => RecordType::TXT,
+            "AAAA" => RecordType::AAAA,
+            "LOC" => RecordType::LOC,
+            "SRV" => RecordType::SRV,
+            "OPT" => RecordType::OPT,
+            "SSHFP" => RecordType::SSHFP,
+            "DNSKEY" => RecordType::DNSKEY,
+            "TLSA" => RecordType::TLSA,
+            "CDS" => RecordType::CDS,
+            "CDNSKEY" => RecordType::CDNSKEY,
+            "SVCB" => RecordType::SVCB,
+            "HTTPS" => RecordType::HTTPS,
+            "SPF" => RecordType::SPF,
+            "ANY" => RecordType::ANY,
+            "URI" => RecordType::URI,
+            _ => return None,
+        };
+        Some(t)
+    }
 }
 
-/// DNS response codes
+/// DNS response codes (RFC 6895 §2.3). Codes above 15 are EDNS extended
+/// RCODEs: the upper 8 bits travel in the OPT record's TTL.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
 pub enum ResponseCode {
-    NoError = 0,
-    FormErr = 1,
-    ServFail = 2,
-    NxDomain = 3,
-    NotImp = 4,
-    Refused = 5,
+    NoError,
+    FormErr,
+    ServFail,
+    NxDomain,
+    NotImp,
+    Refused,
+    YxDomain,
+    YxRrSet,
+    NxRrSet,
+    NotAuth,
+    NotZone,
+    DsoTypeNi,
+    BadVers,
+    BadCookie,
+    Unknown(u16),
 }
 
 impl From<u8> for ResponseCode {
     fn from(v: u8) -> Self {
+        ResponseCode::from_u16(v as u16)
+    }
+}
+
+impl ResponseCode {
+    /// Full 12-bit RCODE (header bits plus the OPT extension)
+    pub fn from_u16(v: u16) -> Self {
         match v {
             0 => ResponseCode::NoError,
             1 => ResponseCode::FormErr,
@@ -92,7 +181,56 @@ impl From<u8> for ResponseCode {
             3 => ResponseCode::NxDomain,
             4 => ResponseCode::NotImp,
             5 => ResponseCode::Refused,
-            _ => ResponseCode::ServFail,
+            6 => ResponseCode::YxDomain,
+            7 => ResponseCode::YxRrSet,
+            8 => ResponseCode::NxRrSet,
+            9 => ResponseCode::NotAuth,
+            10 => ResponseCode::NotZone,
+            11 => ResponseCode::DsoTypeNi,
+            16 => ResponseCode::BadVers,
+            23 => ResponseCode::BadCookie,
+            other => ResponseCode::Unknown(other),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            ResponseCode::NoError => 0,
+            ResponseCode::FormErr => 1,
+            ResponseCode::ServFail => 2,
+            ResponseCode::NxDomain => 3,
+            ResponseCode::NotImp => 4,
+            ResponseCode::Refused => 5,
+            ResponseCode::YxDomain => 6,
+            ResponseCode::YxRrSet => 7,
+            ResponseCode::NxRrSet => 8,
+            ResponseCode::NotAuth => 9,
+            ResponseCode::NotZone => 10,
+            ResponseCode::DsoTypeNi => 11,
+            ResponseCode::BadVers => 16,
+            ResponseCode::BadCookie => 23,
+            ResponseCode::Unknown(v) => v,
+        }
+    }
+
+    /// Mnemonic as used by dig and unbound ("NOERROR", "BADVERS", "RCODE12"...)
+    pub fn label(&self) -> String {
+        match self {
+            ResponseCode::NoError => "NOERROR".to_string(),
+            ResponseCode::FormErr => "FORMERR".to_string(),
+            ResponseCode::ServFail => "SERVFAIL".to_string(),
+            ResponseCode::NxDomain => "NXDOMAIN".to_string(),
+            ResponseCode::NotImp => "NOTIMP".to_string(),
+            ResponseCode::Refused => "REFUSED".to_string(),
+            ResponseCode::YxDomain => "YXDOMAIN".to_string(),
+            ResponseCode::YxRrSet => "YXRRSET".to_string(),
+            ResponseCode::NxRrSet => "NXRRSET".to_string(),
+            ResponseCode::NotAuth => "NOTAUTH".to_string(),
+            ResponseCode::NotZone => "NOTZONE".to_string(),
+            ResponseCode::DsoTypeNi => "DSOTYPENI".to_string(),
+            ResponseCode::BadVers => "BADVERS".to_string(),
+            ResponseCode::BadCookie => "BADCOOKIE".to_string(),
+            ResponseCode::Unknown(v) => format!("RCODE{}", v),
         }
     }
 }
@@ -0,0 +1,78 @@
+//! DSCP / traffic-class marking for outbound DNS sockets
+//!
+//! DSCP lives in the upper 6 bits of the IPv4 TOS byte (IP_TOS) and of the
+//! IPv6 traffic class (IPV6_TCLASS), so the codepoint is shifted left by 2.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use socket2::SockRef;
+use tracing::warn;
+
+/// Warn only once per process when the platform rejects the option
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Mark outgoing packets on `socket` (UDP or TCP) with `dscp` (0-63, e.g. 48 = CS6, 46 = EF)
+pub fn apply<'a>(socket: impl Into<SockRef<'a>>, dscp: u8) {
+    if dscp > 63 {
+        warn_once(&format!("invalid DSCP value {} (must be 0-63)", dscp));
+        return;
+    }
+    if let Err(e) = set_traffic_class(&socket.into(), (dscp as u32) << 2) {
+        warn_once(&format!("failed to set DSCP {}: {}", dscp, e));
+    }
+}
+
+fn set_traffic_class(sock: &SockRef<'_>, tos: u32) -> std::io::Result<()> {
+    if sock.local_addr()?.is_ipv4() {
+        return sock.set_tos_v4(tos);
+    }
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+    ))]
+    {
+        sock.set_tclass_v6(tos)
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+    )))]
+    {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "IPV6_TCLASS not supported on this platform"))
+    }
+}
+
+fn warn_once(msg: &str) {
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        warn!("DSCP marking disabled: {}", msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_dscp_sets_ipv4_tos() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        apply(&socket, 48);
+        assert_eq!(SockRef::from(&socket).tos_v4().unwrap(), 48 << 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dscp_sets_ipv6_tclass() {
+        // IPv6 may be unavailable in minimal containers
+        let Ok(socket) = UdpSocket::bind("[::1]:0").await else { return };
+        apply(&socket, 46);
+        assert_eq!(SockRef::from(&socket).tclass_v6().unwrap(), 46 << 2);
+    }
+}
@@ -0,0 +1,106 @@
+use std::net::SocketAddr;
+
+use crate::config::DebugConfig;
+use crate::dns::packet;
+use crate::dns::types::RecordType;
+use crate::edns::EDNS_FLAG_DO;
+
+const OPTION_NAMES: [(u16, &str); 7] = [
+    (3, "nsid"),
+    (5, "dau"),
+    (8, "ecs"),
+    (10, "cookie"),
+    (11, "keepalive"),
+    (12, "padding"),
+    (15, "ede"),
+];
+
+/// How a client query reached us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientTransport {
+    Udp,
+    Tcp,
+}
+
+impl ClientTransport {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ClientTransport::Udp => "udp",
+            ClientTransport::Tcp => "tcp",
+        }
+    }
+}
+
+/// true if `qname` is the echo name (debug.echo_name) and the echo is on
+pub fn is_echo_name(config: &DebugConfig, qname: &str) -> bool {
+    config.echo && qname.trim_end_matches('.').eq_ignore_ascii_case(config.echo_name.trim_end_matches('.'))
+}
+
+/// Echo answer: one TXT per fact about the query as received, whatever the qtype asked
+/// ("client=...", "transport=...", "id=...", "flags=...", "edns=...")
+pub fn echo_response(query: &[u8], client: SocketAddr, transport: ClientTransport) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = packet::parse_packet(query)?;
+    let qname = parsed.questions.first()
+        .map(|q| q.name.clone())
+        .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+
+    let mut flags = Vec::new();
+    for (set, name) in [
+        (parsed.header.rd, "rd"),
+        (query[3] & 0x20 != 0, "ad"),
+        (query[3] & 0x10 != 0, "cd"),
+    ] {
+        if set {
+            flags.push(name);
+        }
+    }
+    let edns = match parsed.additionals.iter().find(|r| r.rtype == RecordType::OPT) {
+        None => "edns=none".to_string(),
+        Some(opt) => {
+            let options: Vec<String> = option_codes(&opt.rdata).into_iter()
+                .map(|code| match OPTION_NAMES.iter().find(|(c, _)| *c == code) {
+                    Some((_, name)) => name.to_string(),
+                    None => code.to_string(),
+                })
+                .collect();
+            format!(
+                "edns=v{} udp={} do={} options={}",
+                (opt.ttl >> 16) & 0xff,
+                opt.rclass.to_u16(),
+                (opt.ttl & EDNS_FLAG_DO != 0) as u8,
+                if options.is_empty() { "none".to_string() } else { options.join(",") },
+            )
+        }
+    };
+    let facts = [
+        format!("client={}", client),
+        format!("transport={}", transport.label()),
+        format!("id={:#06x} opcode={}", parsed.header.id, parsed.header.opcode),
+        format!("flags={}", if flags.is_empty() { "none".to_string() } else { flags.join(" ") }),
+        edns,
+    ];
+
+    parsed.header.qr = true;
+    parsed.header.aa = true;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    parsed.additionals.clear();
+    for fact in facts {
+        let mut rdata = vec![fact.len() as u8];
+        rdata.extend_from_slice(fact.as_bytes());
+        parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::TXT, 0, rdata));
+    }
+    Ok(parsed.to_wire())
+}
+
+/// Option codes in an OPT rdata, in order
+fn option_codes(rdata: &[u8]) -> Vec<u16> {
+    let mut codes = Vec::new();
+    let mut pos = 0;
+    while pos + 4 <= rdata.len() {
+        codes.push(u16::from_be_bytes([rdata[pos], rdata[pos + 1]]));
+        let len = u16::from_be_bytes([rdata[pos + 2], rdata[pos + 3]]) as usize;
+        pos += 4 + len;
+    }
+    codes
+}
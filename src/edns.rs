@@ -1,6 +1,32 @@
 use crate::config::EdnsConfig;
+use crate::dns::packet::{self, DnsRecord};
+use crate::dns::types::{DnsClass, RecordType};
+use crate::neko_comment::QueryFeatures;
+use crate::subnet::ClientNet;
+use std::net::IpAddr;
 use tracing::debug;
 
+/// NSID option code (RFC 5001)
+const OPTION_NSID: u16 = 3;
+/// Extended DNS Error option code (RFC 8914)
+const OPTION_EDE: u16 = 15;
+/// EDE INFO-CODE 0: Other (the EXTRA-TEXT explains)
+pub const EDE_OTHER: u16 = 0;
+/// EDE INFO-CODE 3: Stale Answer
+pub const EDE_STALE_ANSWER: u16 = 3;
+/// EDE INFO-CODE 14: Not Ready
+pub const EDE_NOT_READY: u16 = 14;
+/// EDE INFO-CODE 22: No Reachable Authority
+pub const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
+/// EDNS Client Subnet option code (RFC 7871)
+pub const OPTION_ECS: u16 = 8;
+/// Resolution metadata option code (neko_comment.edns_metadata, private use)
+pub const OPTION_RESOLUTION_METADATA: u16 = 65003;
+/// Instance health option code (neko_comment.health_in_edns, private use)
+pub const OPTION_HEALTH: u16 = 65004;
+/// DO (DNSSEC OK) bit within the OPT TTL field
+pub(crate) const EDNS_FLAG_DO: u32 = 0x8000;
+
 /// EDNS Extension Handler
 ///
 /// EDNS0 OPT レコード (RFC 6891) に独自オプションコードを追加。
@@ -14,6 +40,167 @@ pub struct EdnsMeta {
     pub options: Vec<(u16, Vec<u8>)>,
 }
 
+/// Where an answer came from, first byte of the resolution metadata option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolutionSource {
+    Cache = 1,
+    Stale = 2,
+    Negative = 3,
+    Recursive = 4,
+    Forward = 5,
+    Local = 6,
+}
+
+impl ResolutionSource {
+    /// None for answers made up on the spot (chaos, root hints, REFUSED...)
+    pub fn from_features(features: &QueryFeatures) -> Option<Self> {
+        if features.serve_stale {
+            Some(Self::Stale)
+        } else if features.negative_cache_hit {
+            Some(Self::Negative)
+        } else if features.cache_hit {
+            Some(Self::Cache)
+        } else if features.local_zone {
+            Some(Self::Local)
+        } else if features.recursive {
+            Some(Self::Recursive)
+        } else if features.upstream_forward {
+            Some(Self::Forward)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(test)]
+    fn from_code(code: u8) -> Option<Self> {
+        [Self::Cache, Self::Stale, Self::Negative, Self::Recursive, Self::Forward, Self::Local]
+            .into_iter()
+            .find(|s| *s as u8 == code)
+    }
+}
+
+/// What the instance is doing, first byte of the health option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthMode {
+    Forwarding = 1,
+    Recursive = 2,
+    /// Offline mode: answering from the cache only
+    Offline = 3,
+}
+
+/// The health option: mode (1 byte), recursion circuit (1 byte, 1 = open: no root
+/// reachable, recursion bypassed), cache hit rate in tenths (1 byte, 0-10, 255 before
+/// the first query), then the version as major, minor, patch (1 byte each)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthSummary {
+    pub mode: HealthMode,
+    pub circuit_open: bool,
+    pub hit_rate_tenths: Option<u8>,
+    pub version: [u8; 3],
+}
+
+impl HealthSummary {
+    /// Bucket the hit rate and take the version from the crate
+    pub fn new(mode: HealthMode, circuit_open: bool, cache_hits: u64, cache_misses: u64) -> Self {
+        let lookups = cache_hits + cache_misses;
+        let mut version = [0u8; 3];
+        for (byte, part) in version.iter_mut().zip(env!("CARGO_PKG_VERSION").split('.')) {
+            *byte = part.parse::<u64>().map(|v| v.min(u8::MAX as u64) as u8).unwrap_or(0);
+        }
+        Self {
+            mode,
+            circuit_open,
+            hit_rate_tenths: (lookups > 0).then(|| (cache_hits * 10 / lookups) as u8),
+            version,
+        }
+    }
+
+    fn to_option_data(self) -> [u8; 6] {
+        let [major, minor, patch] = self.version;
+        [self.mode as u8, self.circuit_open as u8, self.hit_rate_tenths.unwrap_or(u8::MAX), major, minor, patch]
+    }
+
+    #[cfg(test)]
+    fn from_option_data(data: &[u8]) -> Option<Self> {
+        let [mode, circuit, hit_rate, major, minor, patch] = <[u8; 6]>::try_from(data).ok()?;
+        let mode = [HealthMode::Forwarding, HealthMode::Recursive, HealthMode::Offline]
+            .into_iter()
+            .find(|m| *m as u8 == mode)?;
+        Some(Self {
+            mode,
+            circuit_open: circuit != 0,
+            hit_rate_tenths: (hit_rate != u8::MAX).then_some(hit_rate),
+            version: [major, minor, patch],
+        })
+    }
+}
+
+/// An EDNS Client Subnet option (RFC 7871 §6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    pub addr: IpAddr,
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 {
+            return None;
+        }
+        let (source_prefix, scope_prefix, address) = (data[2], data[3], &data[4..]);
+        let addr = match u16::from_be_bytes([data[0], data[1]]) {
+            1 if source_prefix <= 32 && address.len() <= 4 => {
+                let mut octets = [0u8; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::from(octets)
+            }
+            2 if source_prefix <= 128 && address.len() <= 16 => {
+                let mut octets = [0u8; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::from(octets)
+            }
+            _ => return None,
+        };
+        Some(Self { addr, source_prefix, scope_prefix })
+    }
+
+    /// Option data: FAMILY, SOURCE and SCOPE PREFIX-LENGTH, then only the address bytes
+    /// the source prefix covers
+    fn to_option_data(self) -> Vec<u8> {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(v4) => (1u16, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (2u16, v6.octets().to_vec()),
+        };
+        let mut data = family.to_be_bytes().to_vec();
+        data.push(self.source_prefix);
+        data.push(self.scope_prefix);
+        data.extend_from_slice(&octets[..self.source_prefix.div_ceil(8) as usize]);
+        data
+    }
+
+    /// Network an answer carrying this option may be cached for: the returned scope,
+    /// never more specific than the source that was sent. None for scope 0 (any client).
+    pub fn cache_scope(&self) -> Option<ClientNet> {
+        let prefix = self.scope_prefix.min(self.source_prefix);
+        (prefix > 0).then(|| ClientNet::masked(self.addr, prefix))
+    }
+}
+
+/// The ECS option of a query or response, if it has a well-formed one
+pub fn client_subnet(packet: &[u8]) -> Option<ClientSubnet> {
+    client_options(packet)?.into_iter()
+        .find(|(code, _)| *code == OPTION_ECS)
+        .and_then(|(_, data)| ClientSubnet::parse(&data))
+}
+
+/// Hand a cached answer's ECS back as the client's own subnet, with the scope the
+/// answer was stored under (responses without ECS are left alone)
+pub fn echo_client_subnet(response: &[u8], client: &ClientSubnet) -> anyhow::Result<Vec<u8>> {
+    let Some(stored) = client_subnet(response) else { return Ok(response.to_vec()) };
+    set_option(response, OPTION_ECS, &ClientSubnet { scope_prefix: stored.scope_prefix, ..*client }.to_option_data())
+}
+
 pub struct EdnsHandler {
     config: EdnsConfig,
 }
@@ -49,38 +236,101 @@ impl EdnsHandler {
 
     /// Parse EDNS option pairs from OPT rdata
     fn parse_edns_options(&self, rdata: &[u8]) -> Vec<(u16, Vec<u8>)> {
-        let mut options = Vec::new();
-        let mut offset = 0;
-
-        while offset + 4 <= rdata.len() {
-            let code = u16::from_be_bytes([rdata[offset], rdata[offset + 1]]);
-            let length = u16::from_be_bytes([rdata[offset + 2], rdata[offset + 3]]) as usize;
-            offset += 4;
-
-            if offset + length > rdata.len() {
-                break;
+        let mut options = parse_all_options(rdata);
+        // Only collect our custom options (standard ones like COOKIE/NSID are handled separately)
+        options.retain(|(code, data)| {
+            let custom = *code >= 65001 && *code <= 65534;
+            if custom {
+                debug!("Found custom EDNS option: code={}, len={}", code, data.len());
             }
+            custom
+        });
+        options
+    }
 
-            let data = rdata[offset..offset + length].to_vec();
-            offset += length;
+    /// The client's custom options include edns.journey_option_code
+    pub fn journey_requested(&self, meta: Option<&EdnsMeta>) -> bool {
+        meta.is_some_and(|m| m.options.iter().any(|(code, _)| *code == self.config.journey_option_code))
+    }
 
-            // Only collect our custom options
-            if code >= 65001 && code <= 65534 {
-                debug!("Found custom EDNS option: code={}, len={}", code, length);
-                options.push((code, data));
-            }
+    /// True when an NSID is configured and the client's OPT carries the NSID option
+    pub fn nsid_requested(&self, query: &[u8]) -> bool {
+        if self.config.nsid.is_none() {
+            return false;
         }
+        client_options(query).is_some_and(|opts| opts.iter().any(|(code, _)| *code == OPTION_NSID))
+    }
 
-        options
+    /// The client sent an OPT record (EDNS-aware, so EDNS options may be returned)
+    pub fn client_has_opt(&self, query: &[u8]) -> bool {
+        client_options(query).is_some()
+    }
+
+    /// The client set the DO bit (RFC 3225), i.e. wants DNSSEC records
+    pub fn client_dnssec_ok(&self, query: &[u8]) -> bool {
+        packet::parse_packet(query).ok()
+            .and_then(|q| q.additionals.into_iter().find(|r| r.rtype == RecordType::OPT))
+            .is_some_and(|opt| opt.ttl & EDNS_FLAG_DO != 0)
+    }
+
+    /// Put our NSID into the response OPT, replacing any NSID an upstream left there
+    pub fn add_nsid(&self, response: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nsid = self.config.nsid.as_deref().ok_or_else(|| anyhow::anyhow!("NSID not configured"))?;
+        set_option(response, OPTION_NSID, nsid.as_bytes())
+    }
+
+    /// Attach an Extended DNS Error (RFC 8914) to the response OPT
+    pub fn add_ede(&self, response: &[u8], info_code: u16, extra_text: &str) -> anyhow::Result<Vec<u8>> {
+        let mut data = info_code.to_be_bytes().to_vec();
+        data.extend_from_slice(extra_text.as_bytes());
+        set_option(response, OPTION_EDE, &data)
+    }
+
+    /// Attach the resolution metadata option: source (1 byte), latency in ms
+    /// (u32, big endian), then the upstream name (UTF-8, rest of the option).
+    /// Ok(None) when the answer has no resolution source.
+    pub fn add_resolution_metadata(&self, response: &[u8], features: &QueryFeatures) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(source) = ResolutionSource::from_features(features) else { return Ok(None) };
+        let latency = features.latency_ms.unwrap_or(0).min(u32::MAX as u64) as u32;
+        let mut data = vec![source as u8];
+        data.extend_from_slice(&latency.to_be_bytes());
+        data.extend_from_slice(features.answered_by.as_deref().unwrap_or("").as_bytes());
+        set_option(response, OPTION_RESOLUTION_METADATA, &data).map(Some)
+    }
+
+    /// Attach the health option (layout on `HealthSummary`)
+    pub fn add_health(&self, response: &[u8], health: HealthSummary) -> anyhow::Result<Vec<u8>> {
+        set_option(response, OPTION_HEALTH, &health.to_option_data())
+    }
+
+    /// EDNS negotiation (RFC 6891): an EDNS query gets exactly one OPT back, placed last
+    /// (after the feature/journey TXT), advertising `udp_size`. The client's DO bit is
+    /// echoed and the other flags cleared; options already on the response OPT (NSID, EDE)
+    /// are kept. A query without OPT gets none (§7).
+    pub fn negotiate_opt(&self, query: &[u8], response: &[u8], udp_size: u16) -> anyhow::Result<Vec<u8>> {
+        let client_opt = packet::parse_packet(query).ok()
+            .and_then(|q| q.additionals.into_iter().find(|r| r.rtype == RecordType::OPT));
+        let mut parsed = packet::parse_packet(response)?;
+        let existing = parsed.additionals.iter().position(|r| r.rtype == RecordType::OPT)
+            .map(|i| parsed.additionals.remove(i));
+        parsed.additionals.retain(|r| r.rtype != RecordType::OPT);
+
+        if let Some(client_opt) = client_opt {
+            let mut opt = existing.unwrap_or_else(|| DnsRecord::new("", RecordType::OPT, 0, Vec::new()));
+            opt.name = String::new();
+            opt.rclass = DnsClass::from(udp_size.max(512));
+            // TTL = extended RCODE (kept) | version 0 | DO echoed | Z cleared
+            opt.ttl = (opt.ttl & 0xFF00_0000) | (client_opt.ttl & EDNS_FLAG_DO);
+            parsed.additionals.push(opt);
+        }
+        Ok(parsed.to_wire())
     }
 
     /// Build an EDNS OPT record with custom options
     pub fn build_opt_record(&self, options: &[(u16, &[u8])]) -> Vec<u8> {
         let mut rdata = Vec::new();
         for (code, data) in options {
-            rdata.extend_from_slice(&code.to_be_bytes());
-            rdata.extend_from_slice(&(data.len() as u16).to_be_bytes());
-            rdata.extend_from_slice(data);
+            push_option(&mut rdata, *code, data);
         }
 
         let mut record = Vec::new();
@@ -95,3 +345,178 @@ impl EdnsHandler {
         record
     }
 }
+
+/// (source, latency ms, upstream) from the response's resolution metadata option (tests only)
+#[cfg(test)]
+pub fn decode_resolution_metadata(response: &[u8]) -> Option<(ResolutionSource, u32, String)> {
+    let (_, data) = client_options(response)?.into_iter().find(|(code, _)| *code == OPTION_RESOLUTION_METADATA)?;
+    if data.len() < 5 {
+        return None;
+    }
+    let source = ResolutionSource::from_code(data[0])?;
+    let latency = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+    Some((source, latency, String::from_utf8(data[5..].to_vec()).ok()?))
+}
+
+/// The response's health option (tests only)
+#[cfg(test)]
+pub fn decode_health(response: &[u8]) -> Option<HealthSummary> {
+    let (_, data) = client_options(response)?.into_iter().find(|(code, _)| *code == OPTION_HEALTH)?;
+    HealthSummary::from_option_data(&data)
+}
+
+/// Options of the query's OPT record (None when the query has no OPT)
+fn client_options(query: &[u8]) -> Option<Vec<(u16, Vec<u8>)>> {
+    let parsed = packet::parse_packet(query).ok()?;
+    let opt = parsed.additionals.iter().find(|r| r.rtype == RecordType::OPT)?;
+    Some(parse_all_options(&opt.rdata))
+}
+
+/// Set option `code` in the response OPT (adding an OPT if missing), replacing any existing value
+fn set_option(response: &[u8], code: u16, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = packet::parse_packet(response)?;
+
+    let opt = match parsed.additionals.iter_mut().position(|r| r.rtype == RecordType::OPT) {
+        Some(i) => &mut parsed.additionals[i],
+        None => {
+            let mut opt = DnsRecord::new("", RecordType::OPT, 0, Vec::new());
+            opt.rclass = DnsClass::from(4096);
+            parsed.additionals.push(opt);
+            parsed.additionals.last_mut().unwrap()
+        }
+    };
+
+    let mut rdata = Vec::new();
+    for (c, d) in parse_all_options(&opt.rdata).into_iter().filter(|(c, _)| *c != code) {
+        push_option(&mut rdata, c, &d);
+    }
+    push_option(&mut rdata, code, data);
+    opt.rdlength = rdata.len() as u16;
+    opt.rdata = rdata;
+
+    Ok(parsed.to_wire())
+}
+
+/// Every (code, data) pair in OPT rdata; stops at the first malformed option
+fn parse_all_options(rdata: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut options = Vec::new();
+    let mut offset = 0;
+
+    while offset + 4 <= rdata.len() {
+        let code = u16::from_be_bytes([rdata[offset], rdata[offset + 1]]);
+        let length = u16::from_be_bytes([rdata[offset + 2], rdata[offset + 3]]) as usize;
+        offset += 4;
+
+        if offset + length > rdata.len() {
+            break;
+        }
+
+        options.push((code, rdata[offset..offset + length].to_vec()));
+        offset += length;
+    }
+
+    options
+}
+
+fn push_option(rdata: &mut Vec<u8>, code: u16, data: &[u8]) {
+    rdata.extend_from_slice(&code.to_be_bytes());
+    rdata.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(nsid: Option<&str>) -> EdnsHandler {
+        EdnsHandler::new(&EdnsConfig {
+            enabled: true,
+            custom_option_code: 65001,
+            journey_option_code: 65002,
+            nsid: nsid.map(str::to_string),
+            strip_dnssec_for_non_do: true,
+            fetch_with_do: true,
+        })
+    }
+
+    /// Query for example.com A with an OPT record carrying `options`
+    fn query_with_options(h: &EdnsHandler, options: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut query = packet::build_query(0x0707, "example.com", RecordType::A, true);
+        query[11] = 1; // ARCOUNT
+        query.extend_from_slice(&h.build_opt_record(options));
+        query
+    }
+
+    fn response_options(response: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let parsed = packet::parse_packet(response).unwrap();
+        let opt = parsed.additionals.iter().find(|r| r.rtype == RecordType::OPT).unwrap();
+        parse_all_options(&opt.rdata)
+    }
+
+    #[test]
+    fn test_nsid_request_and_response() {
+        let h = handler(Some("neko-1"));
+        let cookie: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let query = query_with_options(&h, &[(10, cookie), (OPTION_NSID, &[])]);
+        assert!(h.nsid_requested(&query));
+
+        // Upstream answered with its own OPT (and its own NSID) — ours replaces it
+        let mut response = query_with_options(&h, &[(10, cookie), (OPTION_NSID, b"upstream")]);
+        response[2] |= 0x80;
+        let options = response_options(&h.add_nsid(&response).unwrap());
+        assert_eq!(options, vec![(10, cookie.to_vec()), (OPTION_NSID, b"neko-1".to_vec())]);
+
+        // No OPT in the response at all → one is added
+        let mut bare = packet::build_query(0x0707, "example.com", RecordType::A, true);
+        bare[2] |= 0x80;
+        let with_nsid = h.add_nsid(&bare).unwrap();
+        assert_eq!(response_options(&with_nsid), vec![(OPTION_NSID, b"neko-1".to_vec())]);
+        assert_eq!(packet::parse_packet(&with_nsid).unwrap().additionals[0].rclass, DnsClass::from(4096));
+    }
+
+    #[test]
+    fn test_nsid_not_requested() {
+        let h = handler(Some("neko-1"));
+        let query = query_with_options(&h, &[(10, &[1, 2, 3, 4, 5, 6, 7, 8])]);
+        assert!(!h.nsid_requested(&query));
+        assert!(!handler(None).nsid_requested(&query_with_options(&h, &[(OPTION_NSID, &[])])));
+    }
+
+    #[test]
+    fn test_standard_options_are_not_custom_meta() {
+        let h = handler(None);
+        let query = query_with_options(&h, &[(10, &[9; 8]), (12, &[0; 4]), (65001, b"mood=curious")]);
+        let meta = h.extract_options(&query).unwrap();
+        assert_eq!(meta.options, vec![(65001, b"mood=curious".to_vec())]);
+        assert!(h.extract_options(&query_with_options(&h, &[(10, &[9; 8])])).is_none());
+    }
+
+    #[test]
+    fn test_negotiated_opt_is_last_and_advertises_our_size() {
+        let h = handler(None);
+        let mut query = query_with_options(&h, &[]);
+        // Client: 4096 bytes, DO=1 and a stray Z bit
+        let opt_at = query.len() - 11;
+        query[opt_at + 5..opt_at + 9].copy_from_slice(&0x0000_8001u32.to_be_bytes());
+
+        // Upstream answer: its own OPT (1400 bytes) followed by a TXT
+        let mut response = query_with_options(&h, &[(OPTION_NSID, b"up")]);
+        response[2] |= 0x80;
+        let mut parsed = packet::parse_packet(&response).unwrap();
+        parsed.additionals.push(DnsRecord::new("example.com", RecordType::TXT, 0, vec![3, b'c', b'a', b't']));
+        parsed.additionals[0].rclass = DnsClass::from(1400);
+
+        let negotiated = packet::parse_packet(&h.negotiate_opt(&query, &parsed.to_wire(), 1232).unwrap()).unwrap();
+        assert_eq!(negotiated.additionals.len(), 2);
+        let opt = negotiated.additionals.last().unwrap();
+        assert_eq!(opt.rtype, RecordType::OPT);
+        assert_eq!(opt.rclass, DnsClass::from(1232));
+        assert_eq!(opt.ttl, EDNS_FLAG_DO);
+        assert_eq!(parse_all_options(&opt.rdata), vec![(OPTION_NSID, b"up".to_vec())]);
+
+        // Non-EDNS query → the OPT is dropped
+        let plain = packet::build_query(0x0707, "example.com", RecordType::A, true);
+        let stripped = packet::parse_packet(&h.negotiate_opt(&plain, &parsed.to_wire(), 1232).unwrap()).unwrap();
+        assert!(stripped.additionals.iter().all(|r| r.rtype != RecordType::OPT));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::config::IdentityConfig;
+use crate::dns::packet;
+use crate::dns::types::{DnsClass, RecordType};
+
+/// What a CH-class TXT probe asks about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    /// id.server / hostname.bind
+    Hostname,
+    /// version.server / version.bind
+    Version,
+}
+
+/// The identity probe this query is, if any (CH class, TXT or ANY)
+pub fn probe(qname: &str, qclass: DnsClass, qtype: RecordType) -> Option<Probe> {
+    if qclass != DnsClass::CH || !matches!(qtype, RecordType::TXT | RecordType::ANY) {
+        return None;
+    }
+    match qname.trim_end_matches('.').to_lowercase().as_str() {
+        "id.server" | "hostname.bind" => Some(Probe::Hostname),
+        "version.server" | "version.bind" => Some(Probe::Version),
+        _ => None,
+    }
+}
+
+impl IdentityConfig {
+    /// Answer text for a probe; None when hidden or not configured
+    pub fn text(&self, probe: Probe) -> Option<String> {
+        if self.hide {
+            return None;
+        }
+        match probe {
+            Probe::Hostname => self.hostname.clone(),
+            Probe::Version => Some(self.version_string.clone()
+                .unwrap_or_else(|| format!("neko-dns {}", env!("CARGO_PKG_VERSION")))),
+        }
+    }
+
+    /// NSID (RFC 5001) to return: edns.nsid, else identity.hostname; never while hidden
+    pub fn nsid(&self, edns_nsid: Option<&str>) -> Option<String> {
+        if self.hide {
+            return None;
+        }
+        edns_nsid.map(str::to_string).or_else(|| self.hostname.clone())
+    }
+}
+
+/// CH TXT answer to an identity probe, REFUSED when there is nothing to disclose
+pub fn probe_response(config: &IdentityConfig, query: &[u8], probe: Probe) -> anyhow::Result<Vec<u8>> {
+    let Some(text) = config.text(probe) else {
+        return packet::build_refused(query);
+    };
+    let mut parsed = packet::parse_packet(query)?;
+    let qname = parsed.questions.first()
+        .map(|q| q.name.clone())
+        .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+    parsed.header.qr = true;
+    parsed.header.aa = true;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    parsed.additionals.clear();
+    let mut rdata = Vec::new();
+    for chunk in text.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    let mut record = packet::DnsRecord::new(&qname, RecordType::TXT, 0, rdata);
+    record.rclass = DnsClass::CH;
+    parsed.answers.push(record);
+    Ok(parsed.to_wire())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::types::ResponseCode;
+
+    fn ch_query(name: &str) -> Vec<u8> {
+        let mut query = packet::build_query(0x1d, name, RecordType::TXT, false);
+        let class_at = query.len() - 2;
+        query[class_at..].copy_from_slice(&3u16.to_be_bytes());
+        query
+    }
+
+    fn answer_text(response: &[u8]) -> Option<String> {
+        let parsed = packet::parse_packet(response).unwrap();
+        let record = parsed.answers.first()?;
+        assert_eq!(record.rclass, DnsClass::CH);
+        Some(String::from_utf8_lossy(&record.rdata[1..]).to_string())
+    }
+
+    #[test]
+    fn test_probe_names() {
+        assert_eq!(probe("ID.SERVER.", DnsClass::CH, RecordType::TXT), Some(Probe::Hostname));
+        assert_eq!(probe("hostname.bind", DnsClass::CH, RecordType::ANY), Some(Probe::Hostname));
+        assert_eq!(probe("version.bind", DnsClass::CH, RecordType::TXT), Some(Probe::Version));
+        assert_eq!(probe("version.server", DnsClass::CH, RecordType::TXT), Some(Probe::Version));
+        assert_eq!(probe("version.bind", DnsClass::IN, RecordType::TXT), None);
+        assert_eq!(probe("version.bind", DnsClass::CH, RecordType::A), None);
+        assert_eq!(probe("authors.bind", DnsClass::CH, RecordType::TXT), None);
+    }
+
+    #[test]
+    fn test_every_probe_respects_hide() {
+        let mut config = IdentityConfig {
+            hostname: Some("neko-1".to_string()),
+            version_string: Some("meow".to_string()),
+            hide: false,
+        };
+        for (name, expected) in [("id.server", "neko-1"), ("hostname.bind", "neko-1"), ("version.bind", "meow"), ("version.server", "meow")] {
+            let query = ch_query(name);
+            let p = probe(name, DnsClass::CH, RecordType::TXT).unwrap();
+            assert_eq!(answer_text(&probe_response(&config, &query, p).unwrap()).as_deref(), Some(expected));
+        }
+        assert_eq!(config.nsid(None).as_deref(), Some("neko-1"));
+        assert_eq!(config.nsid(Some("edns-id")).as_deref(), Some("edns-id"));
+
+        config.hide = true;
+        for name in ["id.server", "hostname.bind", "version.bind", "version.server"] {
+            let query = ch_query(name);
+            let p = probe(name, DnsClass::CH, RecordType::TXT).unwrap();
+            let response = probe_response(&config, &query, p).unwrap();
+            assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, ResponseCode::Refused);
+            assert_eq!(answer_text(&response), None);
+        }
+        assert_eq!(config.nsid(Some("edns-id")), None);
+    }
+
+    #[test]
+    fn test_unset_hostname_refused_version_defaults() {
+        let config = IdentityConfig { hostname: None, version_string: None, hide: false };
+        let response = probe_response(&config, &ch_query("id.server"), Probe::Hostname).unwrap();
+        assert_eq!(packet::parse_packet(&response).unwrap().header.rcode, ResponseCode::Refused);
+        let response = probe_response(&config, &ch_query("version.bind"), Probe::Version).unwrap();
+        assert_eq!(answer_text(&response), Some(format!("neko-dns {}", env!("CARGO_PKG_VERSION"))));
+    }
+}
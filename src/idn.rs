@@ -0,0 +1,53 @@
+/// Unicode form of a name with punycode (xn--) labels, for display (web.decode_idn).
+/// None when there is nothing to decode or it doesn't decode cleanly; the ACE form
+/// is then shown as is.
+pub fn display_name(name: &str) -> Option<String> {
+    if !name.split('.').any(|label| label.len() > 4 && label[..4].eq_ignore_ascii_case("xn--")) {
+        return None;
+    }
+    match idna::domain_to_unicode(name) {
+        (unicode, Ok(())) if unicode != name => Some(unicode),
+        _ => None,
+    }
+}
+
+/// ACE form of a search term typed in Unicode, so it matches the stored names
+pub fn search_key(term: &str) -> String {
+    if term.is_ascii() {
+        return term.to_string();
+    }
+    idna::domain_to_ascii(term).unwrap_or_else(|_| term.to_string())
+}
+
+/// Add "display_name" next to `field` in every object whose name decodes
+pub fn annotate(values: &mut [serde_json::Value], field: &str) {
+    for value in values {
+        if let Some(unicode) = value[field].as_str().and_then(display_name) {
+            value["display_name"] = serde_json::Value::String(unicode);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punycode_displayed_in_unicode() {
+        assert_eq!(display_name("xn--r8jz45g.jp").as_deref(), Some("例え.jp"));
+        assert_eq!(display_name("www.XN--R8JZ45G.jp").as_deref(), Some("www.例え.jp"));
+        assert_eq!(display_name("example.com"), None);
+        // Not valid punycode: left for the ACE form
+        assert_eq!(display_name("xn--.example"), None);
+        assert_eq!(search_key("例え.jp"), "xn--r8jz45g.jp");
+
+        let mut entries = vec![
+            serde_json::json!({ "name": "xn--r8jz45g.jp", "type": "A" }),
+            serde_json::json!({ "name": "example.com", "type": "A" }),
+        ];
+        annotate(&mut entries, "name");
+        assert_eq!(entries[0]["display_name"], "例え.jp");
+        assert_eq!(entries[0]["name"], "xn--r8jz45g.jp");
+        assert!(entries[1].get("display_name").is_none());
+    }
+}
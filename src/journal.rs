@@ -1,38 +1,77 @@
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use parking_lot::RwLock;
-use tracing::debug;
+use rand::Rng;
+use tracing::{debug, warn};
 
-use crate::config::JournalConfig;
+use crate::compress::{self, FileWriter};
+use crate::config::{Compression, JournalConfig};
 use crate::dns::types::RecordType;
 
 /// Query Journal - 全クエリ/応答をWAL的に記録
 ///
 /// 「昨日の23時にこのドメインは何に解決されてた？」が引ける。
 /// タイムトラベルデバッグに最適。
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct JournalEntry {
     pub timestamp: String,
+    /// Lowercased like cache keys, so case variants aggregate
     pub domain: String,
+    /// Name as received, only when it differed from `domain` (0x20 debugging)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_case: Option<String>,
     pub qtype: String,
     pub upstream: String,
     pub ttl: u32,
     pub latency_us: u64,
 }
 
+/// What kind of query is being journaled, for sampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalKind {
+    /// Answered by resolution (upstream, recursive, local zone, root hints)
+    Resolved,
+    /// Answered from the positive or negative cache (including stale serves)
+    CacheHit,
+    /// FORMERR / SERVFAIL / REFUSED / chaos injection
+    Error,
+}
+
 pub struct Journal {
     config: JournalConfig,
     entries: RwLock<Vec<JournalEntry>>,
     total_recorded: AtomicU64,
+    /// Queries skipped by journal.sample_rate
+    sampled_out: AtomicU64,
+    /// How `save` compresses journal.path (persist.compression)
+    compression: Compression,
 }
 
 impl Journal {
     pub fn new(config: &JournalConfig) -> anyhow::Result<Self> {
+        let entries = match config.path.as_ref() {
+            Some(path) if std::path::Path::new(path).exists() => {
+                match load_entries(path, config.retention_hours, config.max_entries) {
+                    Ok(entries) => {
+                        debug!("📓 Journal: restored {} entries from {}", entries.len(), path);
+                        entries
+                    }
+                    Err(e) => {
+                        warn!("📓 Journal: nothing restored from {}: {}", path, e);
+                        Vec::new()
+                    }
+                }
+            }
+            _ => Vec::new(),
+        };
         Ok(Self {
             config: config.clone(),
-            entries: RwLock::new(Vec::new()),
+            entries: RwLock::new(entries),
             total_recorded: AtomicU64::new(0),
+            sampled_out: AtomicU64::new(0),
+            compression: Compression::None,
         })
     }
 
@@ -44,14 +83,22 @@ impl Journal {
         upstream: &str,
         ttl: u32,
         latency: Duration,
+        kind: JournalKind,
     ) {
         if !self.config.enabled {
             return;
         }
+        if !self.sampled(kind) {
+            self.sampled_out.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
 
+        let normalized = domain.to_lowercase();
+        let original_case = (normalized != domain).then(|| domain.to_string());
         let entry = JournalEntry {
             timestamp: Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
-            domain: domain.to_string(),
+            domain: normalized,
+            original_case,
             qtype: qtype.name(),
             upstream: upstream.to_string(),
             ttl,
@@ -69,6 +116,23 @@ impl Journal {
         }
     }
 
+    /// journal.sample_rate decision. thread_rng is a CSPRNG that doesn't go to the OS per
+    /// call, and unlike an every-Nth counter it can't lock onto periodic traffic patterns.
+    fn sampled(&self, kind: JournalKind) -> bool {
+        let rate = match kind {
+            JournalKind::Error if self.config.always_log_errors => return true,
+            JournalKind::CacheHit => self.config.cache_hit_sample_rate.unwrap_or(self.config.sample_rate),
+            _ => self.config.sample_rate,
+        };
+        if rate >= 1.0 {
+            true
+        } else if rate <= 0.0 {
+            false
+        } else {
+            rand::thread_rng().gen::<f64>() < rate
+        }
+    }
+
     /// Query the journal - search by domain and optional time range
     pub fn search(
         &self,
@@ -76,12 +140,13 @@ impl Journal {
         qtype: Option<&str>,
         limit: usize,
     ) -> Vec<JournalEntry> {
+        let domain = domain.map(|d| d.to_lowercase());
         let entries = self.entries.read();
         entries.iter()
             .rev() // Most recent first
             .filter(|e| {
-                if let Some(d) = domain {
-                    if !e.domain.contains(d) {
+                if let Some(ref d) = domain {
+                    if !e.domain.contains(d.as_str()) {
                         return false;
                     }
                 }
@@ -103,6 +168,40 @@ impl Journal {
         entries.iter().rev().take(count).cloned().collect()
     }
 
+    /// The `n` zones queried most (see `history_zone`), counting only the names
+    /// `keep` accepts; for prefetch.prime_from_history
+    pub fn top_zones(&self, n: usize, keep: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut per_name: HashMap<&str, u64> = HashMap::new();
+        let entries = self.entries.read();
+        for entry in entries.iter() {
+            *per_name.entry(entry.domain.as_str()).or_default() += 1;
+        }
+        let mut per_zone: HashMap<&str, u64> = HashMap::new();
+        for (name, count) in per_name {
+            if let Some(zone) = history_zone(name).filter(|_| keep(name)) {
+                *per_zone.entry(zone).or_default() += count;
+            }
+        }
+        let mut zones: Vec<(&str, u64)> = per_zone.into_iter().collect();
+        zones.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        zones.into_iter().take(n).map(|(zone, _)| zone.to_string()).collect()
+    }
+
+    /// Compress journal.path when saving (persist.compression)
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write the entries to journal.path (if set) for the next start
+    pub fn save(&self) {
+        let Some(path) = self.config.path.as_ref() else { return };
+        match save_entries(&self.entries.read(), path, self.compression) {
+            Ok(n) => debug!("📓 Journal: saved {} entries to {}", n, path),
+            Err(e) => warn!("📓 Journal: saving to {} failed: {}", path, e),
+        }
+    }
+
     /// Get journal stats
     pub fn get_stats(&self) -> serde_json::Value {
         let entries = self.entries.read();
@@ -111,6 +210,100 @@ impl Journal {
             "current_entries": entries.len(),
             "max_entries": self.config.max_entries,
             "total_recorded": self.total_recorded.load(Ordering::Relaxed),
+            "sample_rate": self.config.sample_rate,
+            "sampled_out": self.sampled_out.load(Ordering::Relaxed),
         })
     }
 }
+
+/// Zone a journaled name counts towards: its parent, or the name itself when the
+/// parent would be a TLD. Single-label names count towards nothing.
+fn history_zone(domain: &str) -> Option<&str> {
+    let name = domain.trim_end_matches('.');
+    let (_, parent) = name.split_once('.')?;
+    Some(if parent.contains('.') { parent } else { name })
+}
+
+fn save_entries(entries: &[JournalEntry], path: &str, compression: Compression) -> anyhow::Result<usize> {
+    let tmp = format!("{}.tmp", path);
+    let mut out = FileWriter::create(&tmp, compression)?;
+    serde_json::to_writer(&mut out, entries)?;
+    out.finish()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(entries.len())
+}
+
+/// Entries saved by `save_entries`, without those older than `retention_hours`
+/// and only the newest `max_entries`
+fn load_entries(path: &str, retention_hours: u64, max_entries: usize) -> anyhow::Result<Vec<JournalEntry>> {
+    let saved: Vec<JournalEntry> = serde_json::from_reader(compress::open(path)?)?;
+    let cutoff = Utc::now() - chrono::Duration::hours(retention_hours.min(i64::MAX as u64 / 3600) as i64);
+    let mut entries: Vec<JournalEntry> = saved.into_iter()
+        .filter(|e| DateTime::parse_from_rfc3339(&e.timestamp).is_ok_and(|t| t >= cutoff))
+        .collect();
+    let excess = entries.len().saturating_sub(max_entries);
+    entries.drain(..excess);
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(sample_rate: f64, cache_hit_sample_rate: Option<f64>, always_log_errors: bool) -> JournalConfig {
+        JournalConfig { enabled: true, path: None, max_entries: 100, retention_hours: 24, sample_rate, cache_hit_sample_rate, always_log_errors }
+    }
+
+    #[tokio::test]
+    async fn test_case_variants_aggregate() {
+        let journal = Journal::new(&config(1.0, None, true)).unwrap();
+        journal.record_query("Example.COM", &RecordType::A, "stub", 60, Duration::ZERO, JournalKind::Resolved).await;
+        journal.record_query("example.com", &RecordType::A, "stub", 60, Duration::ZERO, JournalKind::Resolved).await;
+
+        let entries = journal.search(Some("EXAMPLE.com"), None, 10);
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.domain == "example.com"));
+        assert_eq!(entries[0].original_case, None);
+        assert_eq!(entries[1].original_case.as_deref(), Some("Example.COM"));
+    }
+
+    #[tokio::test]
+    async fn test_sampling_per_kind() {
+        let journal = Journal::new(&config(0.0, Some(1.0), true)).unwrap();
+        for kind in [JournalKind::Resolved, JournalKind::CacheHit, JournalKind::Error] {
+            journal.record_query("example.com", &RecordType::A, "stub", 60, Duration::ZERO, kind).await;
+        }
+        assert_eq!(journal.recent(10).len(), 2);
+        assert_eq!(journal.get_stats()["sampled_out"], 1);
+
+        let journal = Journal::new(&config(0.0, None, false)).unwrap();
+        for kind in [JournalKind::Resolved, JournalKind::CacheHit, JournalKind::Error] {
+            journal.record_query("example.com", &RecordType::A, "stub", 60, Duration::ZERO, kind).await;
+        }
+        assert!(journal.recent(10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_saved_history_restored() {
+        let path = std::env::temp_dir().join(format!("neko-dns-journal-{}.json", std::process::id()));
+        let config = JournalConfig { path: Some(path.to_string_lossy().into_owned()), ..config(1.0, None, true) };
+        let journal = Journal::new(&config).unwrap();
+        for name in ["www.example.com", "api.example.com", "www.example.com", "example.org", "localhost"] {
+            journal.record_query(name, &RecordType::A, "stub", 60, Duration::ZERO, JournalKind::Resolved).await;
+        }
+        journal.save();
+
+        // Compressed or not, the saved file loads back
+        let restored = Journal::new(&config).unwrap().with_compression(Compression::Gzip);
+        assert_eq!(restored.recent(10).len(), 5);
+        assert_eq!(restored.top_zones(10, |_| true), vec!["example.com", "example.org"]);
+        assert_eq!(restored.top_zones(10, |name| name != "example.org"), vec!["example.com"]);
+        assert_eq!(restored.top_zones(1, |_| true), vec!["example.com"]);
+
+        // Past retention_hours: dropped on load
+        restored.entries.write()[0].timestamp = "2020-01-01T00:00:00.000Z".to_string();
+        restored.save();
+        assert_eq!(Journal::new(&config).unwrap().recent(10).len(), 4);
+        std::fs::remove_file(&path).ok();
+    }
+}
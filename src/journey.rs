@@ -1,5 +1,6 @@
 use std::collections::VecDeque;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 use parking_lot::RwLock;
 use tracing::debug;
@@ -22,6 +23,25 @@ pub struct Journey {
     pub steps: Vec<JourneyStep>,
     pub started_at: Instant,
     pub total_duration: Option<Duration>,
+    /// max_steps を超えて捨てたステップの数
+    pub dropped_steps: usize,
+}
+
+/// 同時に追跡する旅路の上限 (超えたら古いものから捨てる)
+const MAX_ACTIVE_JOURNEYS: usize = 10_000;
+/// ステップのzone / detailに残す最大バイト数 (超えた分は切り詰めて "…" を付ける)
+const MAX_STEP_TEXT: usize = 256;
+
+/// `text` を MAX_STEP_TEXT バイト以内に (文字の途中では切らない)
+fn capped(text: &str) -> String {
+    if text.len() <= MAX_STEP_TEXT {
+        return text.to_string();
+    }
+    let mut end = MAX_STEP_TEXT;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…", &text[..end])
 }
 
 /// 解決ジャーニーのトラッカー
@@ -32,6 +52,12 @@ pub struct JourneyTracker {
     /// 完了したジャーニーの履歴 (Web UI用)
     history: Arc<RwLock<VecDeque<Journey>>>,
     max_history: usize,
+    /// 1つの旅路に残すステップの上限
+    max_steps: usize,
+    /// finish されないままこれより古くなった旅路は sweep_abandoned で捨てる
+    max_active_age: Duration,
+    /// 捨てた旅路の数
+    abandoned: Arc<AtomicU64>,
 }
 
 impl Clone for JourneyTracker {
@@ -41,6 +67,9 @@ impl Clone for JourneyTracker {
             active_journeys: self.active_journeys.clone(),
             history: self.history.clone(),
             max_history: self.max_history,
+            max_steps: self.max_steps,
+            max_active_age: self.max_active_age,
+            abandoned: self.abandoned.clone(),
         }
     }
 }
@@ -52,7 +81,36 @@ impl JourneyTracker {
             active_journeys: Arc::new(RwLock::new(std::collections::HashMap::new())),
             history: Arc::new(RwLock::new(VecDeque::new())),
             max_history: 100,
+            max_steps: 64,
+            max_active_age: Duration::from_secs(120),
+            abandoned: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// 終わらない旅路を捨てるまでの時間 (recursive.journey_max_age_secs)
+    pub fn with_max_active_age(mut self, max_age: Duration) -> Self {
+        self.max_active_age = max_age;
+        self
+    }
+
+    /// 履歴に残す旅路の数と1旅路あたりのステップ上限 (recursive.journey_history / journey_max_steps)
+    pub fn with_limits(mut self, max_history: usize, max_steps: usize) -> Self {
+        self.max_history = max_history;
+        self.max_steps = max_steps.max(1);
+        self
+    }
+
+    /// 中断された解決 (panic・キャンセル・タイムアウト) の旅路を捨てる。捨てた数を返す
+    pub fn sweep_abandoned(&self) -> usize {
+        let mut active = self.active_journeys.write();
+        let before = active.len();
+        active.retain(|_, j| j.started_at.elapsed() < self.max_active_age);
+        let swept = before - active.len();
+        if swept > 0 {
+            debug!("🗺️ Dropped {} abandoned journeys", swept);
+            self.abandoned.fetch_add(swept as u64, Ordering::Relaxed);
         }
+        swept
     }
 
     /// ジャーニー開始
@@ -65,7 +123,19 @@ impl JourneyTracker {
             steps: Vec::new(),
             started_at: Instant::now(),
             total_duration: None,
+            dropped_steps: 0,
         };
+        if self.active_journeys.read().len() >= MAX_ACTIVE_JOURNEYS {
+            self.sweep_abandoned();
+            let mut active = self.active_journeys.write();
+            if active.len() >= MAX_ACTIVE_JOURNEYS {
+                let oldest = active.iter().min_by_key(|(_, j)| j.started_at).map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    active.remove(&oldest);
+                    self.abandoned.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
         self.active_journeys
             .write()
             .insert(qname.to_lowercase(), journey);
@@ -79,12 +149,21 @@ impl JourneyTracker {
         let key = qname.to_lowercase();
         if let Some(journey) = self.active_journeys.write().get_mut(&key) {
             let elapsed = journey.started_at.elapsed().as_millis() as u64;
-            journey.steps.push(JourneyStep {
-                zone: zone.to_string(),
+            let step = JourneyStep {
+                zone: capped(zone),
                 action: action.to_string(),
-                detail: detail.to_string(),
+                detail: capped(detail),
                 timestamp_ms: elapsed,
-            });
+            };
+            // 上限に達したら最後の枠を上書き: 途中は捨てても結末 (ANSWER等) は残る
+            if journey.steps.len() >= self.max_steps {
+                journey.dropped_steps += 1;
+                if let Some(last) = journey.steps.last_mut() {
+                    *last = step;
+                }
+            } else {
+                journey.steps.push(step);
+            }
         }
     }
 
@@ -103,7 +182,10 @@ impl JourneyTracker {
                 total
             );
             let mut history = self.history.write();
-            if history.len() >= self.max_history {
+            if self.max_history == 0 {
+                return;
+            }
+            while history.len() >= self.max_history {
                 history.pop_front();
             }
             history.push_back(journey);
@@ -209,6 +291,7 @@ impl JourneyTracker {
                     "steps": steps,
                     "total_ms": j.total_duration.map(|d| d.as_millis() as u64),
                     "step_count": j.steps.len(),
+                    "dropped_steps": j.dropped_steps,
                 })
             })
             .collect()
@@ -236,8 +319,61 @@ impl JourneyTracker {
         serde_json::json!({
             "total_journeys": history.len(),
             "active_journeys": self.active_journeys.read().len(),
+            "abandoned_journeys": self.abandoned.load(Ordering::Relaxed),
             "avg_steps": format!("{:.1}", avg_steps),
             "avg_duration_ms": format!("{:.1}", avg_duration),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abandoned_journey_swept() {
+        let tracker = JourneyTracker::new(true).with_max_active_age(Duration::from_millis(50));
+        tracker.start("aborted.example.com");
+        std::thread::sleep(Duration::from_millis(80));
+        tracker.start("running.example.com");
+
+        assert_eq!(tracker.sweep_abandoned(), 1);
+        let stats = tracker.get_stats();
+        assert_eq!(stats["active_journeys"], 1);
+        assert_eq!(stats["abandoned_journeys"], 1);
+        // The one still in flight finishes normally
+        tracker.add_step("running.example.com", ".", "ROOT", "");
+        tracker.finish("running.example.com", Duration::from_millis(1));
+        assert!(tracker.get_latest("running.example.com").is_some());
+        assert!(tracker.get_latest("aborted.example.com").is_none());
+    }
+
+    #[test]
+    fn test_long_journey_truncated_to_caps() {
+        let tracker = JourneyTracker::new(true).with_limits(2, 8);
+        tracker.start("deep.example.com");
+        for i in 0..500 {
+            tracker.add_step("deep.example.com", &format!("z{}.example", i), "REFERRAL", &"x".repeat(10_000));
+        }
+        tracker.add_step("deep.example.com", "deep.example.com", "ANSWER", "é".repeat(1000).as_str());
+        tracker.finish("deep.example.com", Duration::from_millis(5));
+
+        let journey = tracker.get_latest("deep.example.com").unwrap();
+        assert_eq!(journey.steps.len(), 8);
+        assert_eq!(journey.dropped_steps, 493);
+        assert_eq!(journey.steps[0].zone, "z0.example");
+        // The outcome survives in the last slot
+        assert_eq!(journey.steps[7].action, "ANSWER");
+        assert!(journey.steps.iter().all(|s| s.detail.len() <= MAX_STEP_TEXT + "…".len()));
+        assert_eq!(tracker.get_history(10)[0]["dropped_steps"], 493);
+
+        // History keeps only the configured number of journeys
+        for name in ["a.example", "b.example", "c.example"] {
+            tracker.start(name);
+            tracker.add_step(name, ".", "ROOT", "");
+            tracker.finish(name, Duration::from_millis(1));
+        }
+        assert_eq!(tracker.get_history(10).len(), 2);
+        assert!(tracker.get_latest("deep.example.com").is_none());
+    }
+}
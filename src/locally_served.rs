@@ -0,0 +1,109 @@
+use crate::dns::packet::{self, DnsRecord};
+use crate::dns::types::{RecordType, ResponseCode};
+
+/// Negative TTL of the answers (and the SOA MINIMUM), as RFC 6303 §3 suggests
+const LOCAL_SOA_TTL: u32 = 10800;
+
+/// Reverse zones RFC 6303 §4 says every resolver should serve itself (listen.rfc6303):
+/// private, link-local, loopback, documentation and otherwise unroutable addresses.
+/// Nobody on the public internet can answer for them.
+const ZONES: &[&str] = &[
+    // RFC 1918
+    "10.in-addr.arpa",
+    "16.172.in-addr.arpa", "17.172.in-addr.arpa", "18.172.in-addr.arpa", "19.172.in-addr.arpa",
+    "20.172.in-addr.arpa", "21.172.in-addr.arpa", "22.172.in-addr.arpa", "23.172.in-addr.arpa",
+    "24.172.in-addr.arpa", "25.172.in-addr.arpa", "26.172.in-addr.arpa", "27.172.in-addr.arpa",
+    "28.172.in-addr.arpa", "29.172.in-addr.arpa", "30.172.in-addr.arpa", "31.172.in-addr.arpa",
+    "168.192.in-addr.arpa",
+    // RFC 5735: this network, loopback, link-local, documentation, broadcast
+    "0.in-addr.arpa",
+    "127.in-addr.arpa",
+    "254.169.in-addr.arpa",
+    "2.0.192.in-addr.arpa",
+    "100.51.198.in-addr.arpa",
+    "113.0.203.in-addr.arpa",
+    "255.255.255.255.in-addr.arpa",
+    // RFC 4291 unspecified and loopback, RFC 4193 ULA, link-local, RFC 3849 documentation
+    "0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+    "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+    "d.f.ip6.arpa",
+    "8.e.f.ip6.arpa", "9.e.f.ip6.arpa", "a.e.f.ip6.arpa", "b.e.f.ip6.arpa",
+    "8.b.d.0.1.0.0.2.ip6.arpa",
+];
+
+/// The locally served zone `qname` is in, if any
+pub fn zone_of(qname: &str) -> Option<&'static str> {
+    let name = qname.trim_end_matches('.').to_lowercase();
+    ZONES.iter().copied().find(|zone| name == *zone || name.ends_with(&format!(".{}", zone)))
+}
+
+/// The zone's SOA (RFC 6303 §3): the zone name as MNAME, nobody.invalid as RNAME
+fn zone_soa(zone: &str) -> DnsRecord {
+    let mut rdata = packet::encode_name(zone);
+    rdata.extend(packet::encode_name("nobody.invalid"));
+    // serial, refresh, retry, expire, minimum
+    for value in [1u32, 604800, 86400, 2419200, LOCAL_SOA_TTL] {
+        rdata.extend_from_slice(&value.to_be_bytes());
+    }
+    DnsRecord::new(zone, RecordType::SOA, LOCAL_SOA_TTL, rdata)
+}
+
+/// Answer from the empty zone: its SOA / NS at the apex, NODATA for other apex
+/// types, NXDOMAIN below it
+pub fn local_response(query: &[u8], qtype: RecordType, zone: &str) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = packet::parse_packet(query)?;
+    let qname = parsed.questions.first()
+        .map(|q| q.name.clone())
+        .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+    parsed.header.qr = true;
+    parsed.header.aa = true;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    let apex = qname.trim_end_matches('.').eq_ignore_ascii_case(zone);
+    match (apex, qtype) {
+        (true, RecordType::SOA) => parsed.answers.push(zone_soa(zone)),
+        (true, RecordType::NS) => {
+            parsed.answers.push(DnsRecord::new(zone, RecordType::NS, LOCAL_SOA_TTL, packet::encode_name(zone)));
+        }
+        (true, _) => parsed.authorities.push(zone_soa(zone)),
+        (false, _) => {
+            parsed.header.rcode = ResponseCode::NxDomain;
+            parsed.authorities.push(zone_soa(zone));
+        }
+    }
+    Ok(parsed.to_wire())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zone_of() {
+        assert_eq!(zone_of("5.0.0.10.in-addr.arpa."), Some("10.in-addr.arpa"));
+        assert_eq!(zone_of("1.1.20.172.IN-ADDR.ARPA"), Some("20.172.in-addr.arpa"));
+        assert_eq!(zone_of("1.1.32.172.in-addr.arpa"), None);
+        assert_eq!(zone_of("1.1.168.192.in-addr.arpa"), Some("168.192.in-addr.arpa"));
+        assert_eq!(zone_of("8.8.8.8.in-addr.arpa"), None);
+        assert_eq!(zone_of("1.0.0.0.d.f.ip6.arpa"), Some("d.f.ip6.arpa"));
+        assert_eq!(zone_of("110.in-addr.arpa"), None);
+    }
+
+    #[test]
+    fn test_local_responses() {
+        let answer = |name: &str, qtype: RecordType| {
+            let query = packet::build_query(9, name, qtype, true);
+            packet::parse_packet(&local_response(&query, qtype, zone_of(name).unwrap()).unwrap()).unwrap()
+        };
+        let ptr = answer("5.0.0.10.in-addr.arpa", RecordType::PTR);
+        assert_eq!(ptr.header.rcode, ResponseCode::NxDomain);
+        assert_eq!(ptr.authorities[0].name, "10.in-addr.arpa");
+        assert_eq!(ptr.authorities[0].rtype, RecordType::SOA);
+        let soa = answer("10.in-addr.arpa", RecordType::SOA);
+        assert_eq!(soa.header.rcode, ResponseCode::NoError);
+        assert_eq!(soa.answers[0].rtype, RecordType::SOA);
+        let apex_a = answer("10.in-addr.arpa", RecordType::A);
+        assert!(apex_a.answers.is_empty());
+        assert_eq!(apex_a.header.rcode, ResponseCode::NoError);
+    }
+}
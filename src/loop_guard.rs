@@ -0,0 +1,105 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use dashmap::DashMap;
+use tracing::error;
+
+/// Query loop guard
+///
+/// An upstream or local zone pointing back at our own listen address makes
+/// every forwarded query come back in as a new one. Such targets are dropped
+/// from the config at startup (`is_self_target`); at runtime the source ports
+/// of our outgoing queries are tracked, and a query arriving from one of them
+/// on a local address is our own and gets no answer, which ends the loop.
+pub struct LoopGuard {
+    /// Local ports of forwarded queries in flight → how many use it
+    ports: DashMap<u16, usize>,
+    detected: AtomicU64,
+}
+
+/// Keeps a port registered for as long as its query is in flight
+pub struct TrackedPort<'a> {
+    guard: &'a LoopGuard,
+    port: u16,
+}
+
+impl Drop for TrackedPort<'_> {
+    fn drop(&mut self) {
+        self.guard.ports.remove_if_mut(&self.port, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+impl LoopGuard {
+    pub fn new() -> Self {
+        Self { ports: DashMap::new(), detected: AtomicU64::new(0) }
+    }
+
+    /// Register the socket a forwarded query goes out from
+    pub fn track(&self, local: SocketAddr) -> TrackedPort<'_> {
+        *self.ports.entry(local.port()).or_insert(0) += 1;
+        TrackedPort { guard: self, port: local.port() }
+    }
+
+    /// true if `client` is one of our own forwarding sockets - the query looped back to us
+    pub fn is_own_query(&self, client: SocketAddr) -> bool {
+        if !self.ports.contains_key(&client.port()) || !is_local_ip(client.ip()) {
+            return false;
+        }
+        self.detected.fetch_add(1, Ordering::Relaxed);
+        error!("🔁 Query loop: received our own forwarded query from {} - an upstream or local zone points back at neko-dns", client);
+        true
+    }
+
+    pub fn detected(&self) -> u64 {
+        self.detected.load(Ordering::Relaxed)
+    }
+}
+
+/// true if a query sent to `target` would arrive at our own `listen` socket
+pub fn is_self_target(listen: SocketAddr, target: SocketAddr) -> bool {
+    if target.port() != listen.port() {
+        return false;
+    }
+    target.ip() == listen.ip()
+        || target.ip().is_unspecified()
+        || (listen.ip().is_unspecified() && is_local_ip(target.ip()))
+}
+
+/// Loopback, or an address of one of this host's interfaces (binding to it works)
+fn is_local_ip(ip: IpAddr) -> bool {
+    ip.is_loopback() || std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_targets() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(is_self_target(addr("127.0.0.1:53"), addr("127.0.0.1:53")));
+        assert!(is_self_target(addr("0.0.0.0:53"), addr("127.0.0.1:53")));
+        assert!(is_self_target(addr("0.0.0.0:5353"), addr("0.0.0.0:5353")));
+        assert!(!is_self_target(addr("127.0.0.1:5353"), addr("127.0.0.1:53")));
+        assert!(!is_self_target(addr("0.0.0.0:53"), addr("192.0.2.1:53")));
+    }
+
+    #[test]
+    fn test_tracked_ports_released() {
+        let guard = LoopGuard::new();
+        let local = "0.0.0.0:50000".parse().unwrap();
+        let client: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let first = guard.track(local);
+        let second = guard.track(local);
+        assert!(guard.is_own_query(client));
+        // Same port on a remote host is someone else's
+        assert!(!guard.is_own_query("192.0.2.1:50000".parse().unwrap()));
+        drop(first);
+        assert!(guard.is_own_query(client));
+        drop(second);
+        assert!(!guard.is_own_query(client));
+        assert_eq!(guard.detected(), 2);
+    }
+}
@@ -15,34 +15,58 @@ mod recursive;
 mod journey;
 mod curiosity;
 mod metrics;
+mod dscp;
+mod source_addr;
+mod tap;
+mod ring;
+mod subnet;
+mod rebind;
+mod alerting;
+mod maintenance;
+mod spoof;
+mod loop_guard;
+mod echo;
+mod diagnostic;
+mod idn;
+mod identity;
+mod special_use;
+mod locally_served;
+mod authoritative;
+mod compress;
+#[cfg(feature = "redis")]
+mod redis_cache;
 
 use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tracing::{info, error, warn};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{debug, info, error, warn};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
 
-use crate::config::Config;
+use crate::config::{Config, LogFormat, LoggingConfig};
 use crate::dns::engine::QueryEngine;
 use crate::web::server::WebServer;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "neko_dns=info".into()),
-        )
-        .init();
-
-    info!("🐱 neko-dns v{} starting...", env!("CARGO_PKG_VERSION"));
-
-    // Load config
     let config_path = std::env::args()
         .nth(1)
         .unwrap_or_else(|| "neko-dns.toml".to_string());
 
+    // Initialize tracing (logging.format, or NEKO_DNS_LOG_FORMAT)
+    let log_format = std::env::var("NEKO_DNS_LOG_FORMAT").ok()
+        .and_then(|f| LogFormat::from_name(&f))
+        .unwrap_or_else(|| LoggingConfig::peek(&config_path).format);
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "neko_dns=info".into());
+    log_subscriber(log_format, filter, std::io::stdout).init();
+
+    info!("🐱 neko-dns v{} starting...", env!("CARGO_PKG_VERSION"));
+
+    // Load config
     let config = Config::load(&config_path)?;
     info!("Config loaded from {}", config_path);
+    dns::packet::set_max_records_per_section(config.parse.max_records_per_section);
 
     let config = Arc::new(config);
 
@@ -55,18 +79,82 @@ async fn main() -> anyhow::Result<()> {
         prefetch_engine.run_prefetch_loop().await;
     });
 
+    // Re-resolve a sample of the cache to catch divergent answers (cache.verify_sample_rate)
+    let verify_engine = engine.clone();
+    tokio::spawn(async move {
+        verify_engine.run_cache_verifier().await;
+    });
+
+    // Refresh aging entries clients read (cache.prefetch_on_read_threshold)
+    let read_refresh_engine = engine.clone();
+    tokio::spawn(async move {
+        read_refresh_engine.run_read_refresher().await;
+    });
+
     // Start trust scorer
     let trust_engine = engine.clone();
     tokio::spawn(async move {
         trust_engine.run_trust_scorer().await;
     });
 
+    // Start alert monitor (alerting.webhook_url only)
+    let alert_engine = engine.clone();
+    tokio::spawn(async move {
+        alert_engine.run_alert_monitor().await;
+    });
+
     // Start curiosity walk loop (recursive mode only)
     let curiosity_engine = engine.clone();
     tokio::spawn(async move {
         curiosity_engine.run_curiosity_walk_loop().await;
     });
 
+    // Sweep journeys of aborted resolutions (recursive mode only)
+    let journey_engine = engine.clone();
+    tokio::spawn(async move {
+        journey_engine.run_journey_sweeper().await;
+    });
+
+    // Reload edited authoritative zone files
+    let zone_engine = engine.clone();
+    tokio::spawn(async move {
+        zone_engine.run_zone_watcher().await;
+    });
+
+    // Prime common TLD delegations (after root warmup)
+    let priming_engine = engine.clone();
+    tokio::spawn(async move {
+        priming_engine.run_tld_priming().await;
+    });
+
+    // Save the infra cache and the answer cache periodically, and them and the journal
+    // on Ctrl-C / SIGTERM
+    let persist_infra = config.recursive.enabled && config.recursive.persist_infra_cache;
+    if persist_infra || config.persist.enabled || config.journal.path.is_some() {
+        let saver_engine = engine.clone();
+        tokio::spawn(async move {
+            saver_engine.run_infra_cache_saver().await;
+        });
+        let snapshot_engine = engine.clone();
+        tokio::spawn(async move {
+            snapshot_engine.run_cache_snapshotter().await;
+        });
+        let shutdown_engine = engine.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            info!("🐱 Shutting down, saving state...");
+            shutdown_engine.snapshot_cache().await;
+            shutdown_engine.save_state();
+            std::process::exit(0);
+        });
+    }
+
+    // Startup self-test (after root warmup)
+    let selftest_engine = engine.clone();
+    tokio::spawn(async move {
+        selftest_engine.run_selftest().await;
+    });
+
     // Start Web UI
     let web_engine = engine.clone();
     let web_config = config.clone();
@@ -77,45 +165,58 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    // Bind UDP socket
-    let bind_addr = format!("{}:{}", config.listen.address, config.listen.port);
-    let udp_socket = UdpSocket::bind(&bind_addr).await?;
-    info!("🐱 neko-dns listening on {} (UDP)", bind_addr);
-
-    // Bind TCP listener
-    let tcp_listener = tokio::net::TcpListener::bind(&bind_addr).await?;
-    info!("🐱 neko-dns listening on {} (TCP)", bind_addr);
+    // Bind UDP socket and TCP listener
+    let (udp_socket, tcp_listener) = bind_listeners(&config.listen).await?;
 
     // TCP handler
-    let tcp_engine = engine.clone();
-    tokio::spawn(async move {
-        loop {
-            match tcp_listener.accept().await {
-                Ok((stream, addr)) => {
-                    let eng = tcp_engine.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = eng.handle_tcp(stream, addr).await {
-                            warn!("TCP handler error from {}: {}", addr, e);
-                        }
-                    });
+    if let Some(tcp_listener) = tcp_listener {
+        let tcp_engine = engine.clone();
+        tokio::spawn(async move {
+            loop {
+                match tcp_listener.accept().await {
+                    Ok((stream, addr)) => {
+                        // Saturated → close the connection right away
+                        let Some(permit) = tcp_engine.try_acquire_query_slot() else {
+                            debug!("Dropping TCP connection from {}: too many queries in flight", addr);
+                            continue;
+                        };
+                        let eng = tcp_engine.clone();
+                        tokio::spawn(async move {
+                            let _permit = permit;
+                            if let Err(e) = eng.handle_tcp(stream, addr).await {
+                                warn!("TCP handler error from {}: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("TCP accept error: {}", e),
                 }
-                Err(e) => error!("TCP accept error: {}", e),
             }
-        }
-    });
+        });
+    }
 
-    // Main UDP loop
+    // Main UDP loop (TCP only: the listener task does all the work)
+    let Some(udp_socket) = udp_socket else {
+        return std::future::pending().await;
+    };
     let udp_socket = std::sync::Arc::new(udp_socket);
     let mut buf = vec![0u8; 4096];
     loop {
         match udp_socket.recv_from(&mut buf).await {
             Ok((len, addr)) => {
+                // Saturated → drop the datagram; the client will retransmit
+                let Some(permit) = engine.try_acquire_query_slot() else {
+                    continue;
+                };
                 let packet = buf[..len].to_vec();
                 let socket = udp_socket.clone();
                 let eng = engine.clone();
                 tokio::spawn(async move {
-                    match eng.handle_query(&packet).await {
-                        Ok(response) => {
+                    let _permit = permit;
+                    match eng.handle_udp_query(addr, &packet).await {
+                        Ok(None) => {} // retransmit of an in-flight query
+                        Ok(Some(response)) => {
+                            // Too big for the client's UDP buffer → TC=1, client retries over TCP
+                            let response = eng.fit_udp(&packet, response);
                             if let Err(e) = socket.send_to(&response, addr).await {
                                 warn!("Failed to send response to {}: {}", addr, e);
                             }
@@ -134,3 +235,105 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 }
+
+/// Log subscriber writing `format` lines to `writer`
+fn log_subscriber<W>(format: LogFormat, filter: EnvFilter, writer: W) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().flatten_event(true).finish()),
+    }
+}
+
+/// Bind the transports enabled in [listen] (listen.udp / listen.tcp)
+async fn bind_listeners(listen: &config::ListenConfig) -> anyhow::Result<(Option<UdpSocket>, Option<TcpListener>)> {
+    let bind_addr = format!("{}:{}", listen.address, listen.port);
+    let udp_socket = if listen.udp {
+        let socket = UdpSocket::bind(&bind_addr).await?;
+        info!("🐱 neko-dns listening on {} (UDP)", bind_addr);
+        Some(socket)
+    } else {
+        None
+    };
+    let tcp_listener = if listen.tcp {
+        let listener = TcpListener::bind(&bind_addr).await?;
+        info!("🐱 neko-dns listening on {} (TCP)", bind_addr);
+        Some(listener)
+    } else {
+        None
+    };
+    Ok((udp_socket, tcp_listener))
+}
+
+/// Ctrl-C, or SIGTERM (systemd / docker stop) on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => { let _ = tokio::signal::ctrl_c().await; }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_only_enabled_transports_bound() {
+        let listen = |extra: &str| -> config::ListenConfig {
+            toml::from_str(&format!("address = \"127.0.0.1\"\nport = 0\n{}", extra)).unwrap()
+        };
+        let (udp, tcp) = bind_listeners(&listen("tcp = false")).await.unwrap();
+        assert!(udp.is_some() && tcp.is_none());
+        let (udp, tcp) = bind_listeners(&listen("udp = false")).await.unwrap();
+        assert!(udp.is_none() && tcp.is_some());
+        let (udp, tcp) = bind_listeners(&listen("")).await.unwrap();
+        assert!(udp.is_some() && tcp.is_some());
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<parking_lot::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_log_lines_parse() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = log_subscriber(LogFormat::Json, EnvFilter::new("info"), move || writer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            info!(qname = "example.com", qtype = "A", latency_ms = 3u64, "Answered");
+            info!("🐱 plain message");
+        });
+        let output = String::from_utf8(captured.0.lock().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["qname"], "example.com");
+        assert_eq!(lines[0]["latency_ms"], 3);
+        assert_eq!(lines[0]["message"], "Answered");
+        assert_eq!(lines[1]["level"], "INFO");
+    }
+}
@@ -0,0 +1,189 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::Utc;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::dns::packet;
+use crate::dns::types::RecordType;
+
+/// TTL of the records handed out by "redirect <ip>"
+const REDIRECT_TTL: u32 = 30;
+
+/// What maintenance mode does with a matched query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceMode {
+    /// SERVFAIL everything
+    ServfailAll,
+    /// Answer from the cache (stale entries included), never resolve
+    ServeCacheOnly,
+    /// A / AAAA answers point at this address, other types get NODATA
+    Redirect(IpAddr),
+}
+
+impl MaintenanceMode {
+    /// "servfail_all", "serve_cache_only" or "redirect <ip>"
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        match s {
+            "servfail_all" => Ok(Self::ServfailAll),
+            "serve_cache_only" => Ok(Self::ServeCacheOnly),
+            _ => {
+                let ip = s.strip_prefix("redirect")
+                    .map(str::trim)
+                    .filter(|ip| !ip.is_empty())
+                    .ok_or_else(|| anyhow::anyhow!("unknown mode {:?} (servfail_all, serve_cache_only, redirect <ip>)", s))?;
+                Ok(Self::Redirect(ip.parse().map_err(|_| anyhow::anyhow!("invalid redirect address {:?}", ip))?))
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Self::ServfailAll => "servfail_all".to_string(),
+            Self::ServeCacheOnly => "serve_cache_only".to_string(),
+            Self::Redirect(ip) => format!("redirect {}", ip),
+        }
+    }
+}
+
+/// Body of POST /api/maintenance
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceRequest {
+    pub enabled: bool,
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Domain suffixes to apply to (empty = every query)
+    #[serde(default, rename = "match")]
+    pub matches: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Active {
+    mode: MaintenanceMode,
+    /// Lowercased, without the trailing dot
+    matches: Vec<String>,
+    since: String,
+}
+
+/// Maintenance switch - toggled at runtime through the API, in memory only
+/// (a restart always comes back in normal operation)
+pub struct Maintenance {
+    active: RwLock<Option<Active>>,
+    matched: AtomicU64,
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Self { active: RwLock::new(None), matched: AtomicU64::new(0) }
+    }
+
+    /// Apply an API request; returns the resulting status
+    pub fn apply(&self, request: &MaintenanceRequest) -> anyhow::Result<serde_json::Value> {
+        if !request.enabled {
+            if self.active.write().take().is_some() {
+                info!("🚧 Maintenance mode off");
+            }
+            return Ok(self.status());
+        }
+        let mode = MaintenanceMode::parse(request.mode.as_deref().unwrap_or("servfail_all"))?;
+        let matches: Vec<String> = request.matches.iter()
+            .map(|m| m.trim().trim_end_matches('.').to_lowercase())
+            .filter(|m| !m.is_empty())
+            .collect();
+        info!("🚧 Maintenance mode on: {} for {}", mode.name(),
+            if matches.is_empty() { "all queries".to_string() } else { matches.join(", ") });
+        *self.active.write() = Some(Active { mode, matches, since: Utc::now().to_rfc3339() });
+        Ok(self.status())
+    }
+
+    /// The mode to apply to this query, if maintenance is on and it matches
+    pub fn check(&self, qname: &str) -> Option<MaintenanceMode> {
+        let active = self.active.read();
+        let active = active.as_ref()?;
+        if !active.matches.is_empty() {
+            let qname = qname.trim_end_matches('.').to_lowercase();
+            let hit = active.matches.iter().any(|zone| {
+                qname == *zone || (qname.len() > zone.len() && qname.ends_with(zone.as_str())
+                    && qname.as_bytes()[qname.len() - zone.len() - 1] == b'.')
+            });
+            if !hit {
+                return None;
+            }
+        }
+        self.matched.fetch_add(1, Ordering::Relaxed);
+        Some(active.mode)
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let active = self.active.read();
+        match active.as_ref() {
+            Some(a) => serde_json::json!({
+                "enabled": true,
+                "mode": a.mode.name(),
+                "match": a.matches,
+                "since": a.since,
+                "matched_queries": self.matched.load(Ordering::Relaxed),
+            }),
+            None => serde_json::json!({
+                "enabled": false,
+                "matched_queries": self.matched.load(Ordering::Relaxed),
+            }),
+        }
+    }
+}
+
+/// Answer for "redirect <ip>": the address when the family fits the qtype, NODATA otherwise
+pub fn redirect_response(query: &[u8], qtype: RecordType, ip: IpAddr) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = packet::parse_packet(query)?;
+    let qname = parsed.questions.first()
+        .map(|q| q.name.clone())
+        .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+    parsed.header.qr = true;
+    parsed.header.ra = true;
+    parsed.header.aa = false;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    match (qtype, ip) {
+        (RecordType::A, IpAddr::V4(v4)) => {
+            parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::A, REDIRECT_TTL, v4.octets().to_vec()));
+        }
+        (RecordType::AAAA, IpAddr::V6(v6)) => {
+            parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::AAAA, REDIRECT_TTL, v6.octets().to_vec()));
+        }
+        _ => packet::add_negative_soa(&mut parsed, REDIRECT_TTL),
+    }
+    Ok(parsed.to_wire())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_parsing() {
+        assert_eq!(MaintenanceMode::parse("servfail_all").unwrap(), MaintenanceMode::ServfailAll);
+        assert_eq!(MaintenanceMode::parse("serve_cache_only").unwrap(), MaintenanceMode::ServeCacheOnly);
+        assert_eq!(MaintenanceMode::parse("redirect 192.0.2.80").unwrap(), MaintenanceMode::Redirect("192.0.2.80".parse().unwrap()));
+        assert!(MaintenanceMode::parse("redirect").is_err());
+        assert!(MaintenanceMode::parse("redirect example.com").is_err());
+        assert!(MaintenanceMode::parse("nap").is_err());
+    }
+
+    #[test]
+    fn test_match_scoping() {
+        let maintenance = Maintenance::new();
+        let request: MaintenanceRequest = serde_json::from_value(serde_json::json!({
+            "enabled": true, "mode": "servfail_all", "match": ["Example.com."],
+        })).unwrap();
+        maintenance.apply(&request).unwrap();
+        assert_eq!(maintenance.check("www.example.com"), Some(MaintenanceMode::ServfailAll));
+        assert_eq!(maintenance.check("example.com."), Some(MaintenanceMode::ServfailAll));
+        assert_eq!(maintenance.check("notexample.com"), None);
+
+        maintenance.apply(&serde_json::from_value(serde_json::json!({"enabled": false})).unwrap()).unwrap();
+        assert_eq!(maintenance.check("www.example.com"), None);
+        assert_eq!(maintenance.status()["matched_queries"], 2);
+    }
+}
@@ -4,16 +4,51 @@
 //! (https://github.com/letsencrypt/unbound_exporter) for compatibility
 //! with existing Prometheus/Grafana dashboards.
 //!
-//! Endpoint: GET /metrics (on the web UI port, default 8053)
+//! Endpoint: GET /metrics (on the web UI port, default 8053); clients sending
+//! `Accept: application/openmetrics-text` get OpenMetrics 1.0 instead
 
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Write;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
+use parking_lot::Mutex;
 
 use crate::dns::engine::QueryEngine;
+use crate::dns::types::ResponseCode;
 
 /// Global metrics counters that are atomically updated from query processing
+/// Why a UDP response didn't go out whole
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TruncateReason {
+    /// Bigger than the client's EDNS payload size (512 without EDNS): sent with TC=1
+    ClientSize,
+    /// Bigger than listen.max_udp_response_size: sent with TC=1
+    HardCap,
+    /// Only our feature / journey TXT didn't fit: sent whole without them
+    FeatureTxt,
+    /// ANY over UDP with recursive.any_over_udp = "tcp": sent empty with TC=1
+    AnyOverUdp,
+}
+
+impl TruncateReason {
+    pub const ALL: [TruncateReason; 4] = [
+        TruncateReason::ClientSize,
+        TruncateReason::HardCap,
+        TruncateReason::FeatureTxt,
+        TruncateReason::AnyOverUdp,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TruncateReason::ClientSize => "client_size",
+            TruncateReason::HardCap => "hard_cap",
+            TruncateReason::FeatureTxt => "feature_txt",
+            TruncateReason::AnyOverUdp => "any_over_udp",
+        }
+    }
+}
+
 pub struct MetricsCounters {
     /// Total queries received
     pub queries_total: AtomicU64,
@@ -37,14 +72,24 @@ pub struct MetricsCounters {
     pub prefetches: AtomicU64,
     /// Total stale serves
     pub stale_serves: AtomicU64,
+    /// Cached entries re-resolved by the verifier (cache.verify_sample_rate)
+    pub cache_verifications: AtomicU64,
+    /// ...whose fresh answer differed from the cached one
+    pub cache_verify_mismatches: AtomicU64,
     /// Total TCP queries
     pub tcp_queries: AtomicU64,
+    /// Queries dropped because max_concurrent_queries were already in flight
+    pub queries_dropped_saturated: AtomicU64,
+    /// UDP responses that had to be cut down to fit, by TruncateReason
+    pub truncated_responses: [AtomicU64; 4],
     /// Total SERVFAIL responses
     pub servfail_total: AtomicU64,
     /// Total NXDOMAIN responses
     pub nxdomain_total: AtomicU64,
     /// Total NOERROR responses
     pub noerror_total: AtomicU64,
+    /// Responses with any other rcode (REFUSED, NOTAUTH, BADVERS...), by full rcode value
+    pub other_rcodes: Mutex<BTreeMap<u16, u64>>,
     /// Query type counters
     pub query_type_a: AtomicU64,
     pub query_type_aaaa: AtomicU64,
@@ -57,6 +102,10 @@ pub struct MetricsCounters {
     pub query_type_txt: AtomicU64,
     pub query_type_any: AtomicU64,
     pub query_type_https: AtomicU64,
+    pub query_type_tlsa: AtomicU64,
+    pub query_type_uri: AtomicU64,
+    pub query_type_cds: AtomicU64,
+    pub query_type_cdnskey: AtomicU64,
     pub query_type_other: AtomicU64,
     /// Server start time
     pub start_time: Instant,
@@ -80,10 +129,15 @@ impl MetricsCounters {
             negative_cache_hits: AtomicU64::new(0),
             prefetches: AtomicU64::new(0),
             stale_serves: AtomicU64::new(0),
+            cache_verifications: AtomicU64::new(0),
+            cache_verify_mismatches: AtomicU64::new(0),
             tcp_queries: AtomicU64::new(0),
+            queries_dropped_saturated: AtomicU64::new(0),
+            truncated_responses: Default::default(),
             servfail_total: AtomicU64::new(0),
             nxdomain_total: AtomicU64::new(0),
             noerror_total: AtomicU64::new(0),
+            other_rcodes: Mutex::new(BTreeMap::new()),
             query_type_a: AtomicU64::new(0),
             query_type_aaaa: AtomicU64::new(0),
             query_type_cname: AtomicU64::new(0),
@@ -95,6 +149,10 @@ impl MetricsCounters {
             query_type_txt: AtomicU64::new(0),
             query_type_any: AtomicU64::new(0),
             query_type_https: AtomicU64::new(0),
+            query_type_tlsa: AtomicU64::new(0),
+            query_type_uri: AtomicU64::new(0),
+            query_type_cds: AtomicU64::new(0),
+            query_type_cdnskey: AtomicU64::new(0),
             query_type_other: AtomicU64::new(0),
             start_time: Instant::now(),
             recursive_latency_sum_us: AtomicU64::new(0),
@@ -116,20 +174,104 @@ impl MetricsCounters {
             "TXT" => self.query_type_txt.fetch_add(1, Ordering::Relaxed),
             "ANY" | "*" => self.query_type_any.fetch_add(1, Ordering::Relaxed),
             "HTTPS" | "TYPE65" => self.query_type_https.fetch_add(1, Ordering::Relaxed),
+            "TLSA" => self.query_type_tlsa.fetch_add(1, Ordering::Relaxed),
+            "URI" => self.query_type_uri.fetch_add(1, Ordering::Relaxed),
+            "CDS" => self.query_type_cds.fetch_add(1, Ordering::Relaxed),
+            "CDNSKEY" => self.query_type_cdnskey.fetch_add(1, Ordering::Relaxed),
             _ => self.query_type_other.fetch_add(1, Ordering::Relaxed),
         };
     }
 
+    /// Count an answer under its rcode
+    pub fn inc_answer_rcode(&self, rcode: ResponseCode) {
+        let counter = match rcode {
+            ResponseCode::NoError => &self.noerror_total,
+            ResponseCode::ServFail => &self.servfail_total,
+            ResponseCode::NxDomain => &self.nxdomain_total,
+            other => {
+                *self.other_rcodes.lock().entry(other.to_u16()).or_insert(0) += 1;
+                return;
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_truncated(&self, reason: TruncateReason) {
+        self.truncated_responses[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn truncated(&self, reason: TruncateReason) -> u64 {
+        self.truncated_responses[reason as usize].load(Ordering::Relaxed)
+    }
+
     pub fn record_recursive_latency(&self, latency_us: u64) {
         self.recursive_latency_sum_us.fetch_add(latency_us, Ordering::Relaxed);
         self.recursive_latency_count.fetch_add(1, Ordering::Relaxed);
     }
+
+    /// Read the core counters once, for a view where derived values match
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            queries_total: self.queries_total.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            upstream_queries: self.upstream_queries.load(Ordering::Relaxed),
+            recursive_queries: self.recursive_queries.load(Ordering::Relaxed),
+            stale_serves: self.stale_serves.load(Ordering::Relaxed),
+            noerror_total: self.noerror_total.load(Ordering::Relaxed),
+            nxdomain_total: self.nxdomain_total.load(Ordering::Relaxed),
+            servfail_total: self.servfail_total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One read of the core counters
+///
+/// Every counter only grows, but they are loaded one after another while
+/// queries keep coming in, so relations across them (hits + misses vs.
+/// queries) can be off by the queries in flight. Ratios are computed from
+/// the snapshot itself and always agree with the numbers reported next to them.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub queries_total: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub upstream_queries: u64,
+    pub recursive_queries: u64,
+    pub stale_serves: u64,
+    pub noerror_total: u64,
+    pub nxdomain_total: u64,
+    pub servfail_total: u64,
+}
+
+impl MetricsSnapshot {
+    /// cache_hits / (cache_hits + cache_misses), 0.0 before any lookup
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.cache_hits + self.cache_misses;
+        if total > 0 { self.cache_hits as f64 / total as f64 } else { 0.0 }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "total": self.queries_total,
+            "cache_hits": self.cache_hits,
+            "cache_misses": self.cache_misses,
+            "hit_rate_percent": format!("{:.1}", self.hit_ratio() * 100.0),
+            "upstream": self.upstream_queries,
+            "recursive": self.recursive_queries,
+            "stale_serves": self.stale_serves,
+            "noerror": self.noerror_total,
+            "nxdomain": self.nxdomain_total,
+            "servfail": self.servfail_total,
+        })
+    }
 }
 
 /// Generate Prometheus-format metrics text
 pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     let mut out = String::with_capacity(8192);
     let c = &engine.metrics;
+    let snapshot = c.snapshot();
 
     // ──────────────────────────────────────────────
     // Server info (unbound-compatible)
@@ -152,15 +294,15 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     // ──────────────────────────────────────────────
     // Query totals (unbound: thread0.num.queries)
     // ──────────────────────────────────────────────
-    let queries_total = c.queries_total.load(Ordering::Relaxed);
+    let queries_total = snapshot.queries_total;
     write_help_type(&mut out, "unbound_queries_total", "Total number of queries received.", "counter");
     writeln!(out, "unbound_queries_total{{thread=\"0\"}} {}", queries_total).ok();
 
     // ──────────────────────────────────────────────
     // Cache hits/misses (unbound: thread0.num.cachehits / cachemiss)
     // ──────────────────────────────────────────────
-    let cache_hits = c.cache_hits.load(Ordering::Relaxed);
-    let cache_misses = c.cache_misses.load(Ordering::Relaxed);
+    let cache_hits = snapshot.cache_hits;
+    let cache_misses = snapshot.cache_misses;
     write_help_type(&mut out, "unbound_cache_hits_total", "Total number of queries that were successfully answered using a cache lookup.", "counter");
     writeln!(out, "unbound_cache_hits_total{{thread=\"0\"}} {}", cache_hits).ok();
 
@@ -177,14 +319,14 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     // ──────────────────────────────────────────────
     // Expired / stale serves (unbound: thread0.num.expired)
     // ──────────────────────────────────────────────
-    let stale_serves = c.stale_serves.load(Ordering::Relaxed);
+    let stale_serves = snapshot.stale_serves;
     write_help_type(&mut out, "unbound_expired_total", "Total number of expired entries served.", "counter");
     writeln!(out, "unbound_expired_total{{thread=\"0\"}} {}", stale_serves).ok();
 
     // ──────────────────────────────────────────────
     // Recursive replies (unbound: thread0.num.recursivereplies)
     // ──────────────────────────────────────────────
-    let recursive_queries = c.recursive_queries.load(Ordering::Relaxed);
+    let recursive_queries = snapshot.recursive_queries;
     write_help_type(&mut out, "unbound_recursive_replies_total", "Total number of replies sent to queries that needed recursive processing.", "counter");
     writeln!(out, "unbound_recursive_replies_total{{thread=\"0\"}} {}", recursive_queries).ok();
 
@@ -208,16 +350,34 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     write_help_type(&mut out, "unbound_query_tcp_total", "Total number of queries that were made using TCP.", "counter");
     writeln!(out, "unbound_query_tcp_total {}", tcp_queries).ok();
 
+    // ──────────────────────────────────────────────
+    // Dropped under load (unbound: num.requestlist.exceeded)
+    // ──────────────────────────────────────────────
+    let dropped = c.queries_dropped_saturated.load(Ordering::Relaxed);
+    write_help_type(&mut out, "unbound_request_list_exceeded_total", "Number of queries that were dropped because the request list was full.", "counter");
+    writeln!(out, "unbound_request_list_exceeded_total {}", dropped).ok();
+
+    // ──────────────────────────────────────────────
+    // UDP responses cut down to size (unbound: num.answer.truncated, split by reason)
+    // ──────────────────────────────────────────────
+    write_help_type(&mut out, "nekonsd_truncated_responses_total", "UDP responses cut down to fit: truncated to the client's size or listen.max_udp_response_size, or sent without the feature TXT.", "counter");
+    for reason in TruncateReason::ALL {
+        writeln!(out, "nekonsd_truncated_responses_total{{reason=\"{}\"}} {}", reason.label(), c.truncated(reason)).ok();
+    }
+
     // ──────────────────────────────────────────────
     // Answer rcodes (unbound: num.answer.rcode.*)
     // ──────────────────────────────────────────────
-    let noerror = c.noerror_total.load(Ordering::Relaxed);
-    let servfail = c.servfail_total.load(Ordering::Relaxed);
-    let nxdomain = c.nxdomain_total.load(Ordering::Relaxed);
+    let noerror = snapshot.noerror_total;
+    let servfail = snapshot.servfail_total;
+    let nxdomain = snapshot.nxdomain_total;
     write_help_type(&mut out, "unbound_answer_rcodes_total", "Total number of answers to queries, from cache or from recursion, by response code.", "counter");
     writeln!(out, "unbound_answer_rcodes_total{{rcode=\"NOERROR\"}} {}", noerror).ok();
     writeln!(out, "unbound_answer_rcodes_total{{rcode=\"SERVFAIL\"}} {}", servfail).ok();
     writeln!(out, "unbound_answer_rcodes_total{{rcode=\"NXDOMAIN\"}} {}", nxdomain).ok();
+    for (rcode, count) in c.other_rcodes.lock().iter() {
+        writeln!(out, "unbound_answer_rcodes_total{{rcode=\"{}\"}} {}", ResponseCode::from_u16(*rcode).label(), count).ok();
+    }
 
     // ──────────────────────────────────────────────
     // Query types (unbound: num.query.type.*)
@@ -233,6 +393,10 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "SRV", c.query_type_srv.load(Ordering::Relaxed));
     write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "TXT", c.query_type_txt.load(Ordering::Relaxed));
     write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "HTTPS", c.query_type_https.load(Ordering::Relaxed));
+    write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "TLSA", c.query_type_tlsa.load(Ordering::Relaxed));
+    write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "URI", c.query_type_uri.load(Ordering::Relaxed));
+    write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "CDS", c.query_type_cds.load(Ordering::Relaxed));
+    write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "CDNSKEY", c.query_type_cdnskey.load(Ordering::Relaxed));
     write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "ANY", c.query_type_any.load(Ordering::Relaxed));
     write_counter_if_nonzero(&mut out, "unbound_query_types_total", "type", "other", c.query_type_other.load(Ordering::Relaxed));
 
@@ -250,11 +414,21 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     write_help_type(&mut out, "unbound_msg_cache_max_size", "Maximum number of cache entries.", "gauge");
     writeln!(out, "unbound_msg_cache_max_size {}", cache_max).ok();
 
+    // Cache persistence (persist.enabled)
+    if engine.config.persist.enabled {
+        let snapshot = &cache_stats["snapshot"];
+        let duration = snapshot["last_duration_ms"].as_u64().unwrap_or(0) as f64 / 1000.0;
+        write_help_type(&mut out, "nekonsd_cache_snapshot_duration_seconds", "How long the last cache snapshot took.", "gauge");
+        writeln!(out, "nekonsd_cache_snapshot_duration_seconds {:.3}", duration).ok();
+        write_help_type(&mut out, "nekonsd_cache_snapshot_entries", "Entries written by the last cache snapshot.", "gauge");
+        writeln!(out, "nekonsd_cache_snapshot_entries {}", snapshot["last_entries"].as_u64().unwrap_or(0)).ok();
+    }
+
     // ──────────────────────────────────────────────
     // Memory (unbound: mem.cache.*)
-    // Approximate: each entry ≈ 512 bytes
+    // Message cache: measured from the stored entries; negative cache ≈ 256 bytes/entry
     // ──────────────────────────────────────────────
-    let cache_mem = cache_entries * 512;
+    let cache_mem = cache_stats["memory_bytes"].as_u64().unwrap_or(0);
     write_help_type(&mut out, "unbound_memory_caches_bytes", "Memory in bytes in use by caches.", "gauge");
     writeln!(out, "unbound_memory_caches_bytes{{cache=\"message\"}} {}", cache_mem).ok();
 
@@ -284,6 +458,8 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     write_help_type(&mut out, "nekonsd_upstream_failures", "Total failures per upstream server.", "counter");
     write_help_type(&mut out, "nekonsd_upstream_avg_latency_ms", "Average latency in ms per upstream server.", "gauge");
     write_help_type(&mut out, "nekonsd_upstream_trust_score", "Trust score per upstream server (0.0-1.0).", "gauge");
+    write_help_type(&mut out, "nekonsd_upstream_shadow_agreements", "Shadow upstream answers matching the one returned to the client.", "counter");
+    write_help_type(&mut out, "nekonsd_upstream_shadow_disagreements", "Shadow upstream answers differing from the one returned to the client.", "counter");
 
     if let Some(arr) = upstream_stats.as_array() {
         for u in arr {
@@ -296,6 +472,12 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
             writeln!(out, "nekonsd_upstream_failures{{name=\"{}\"}} {}", name, tf).ok();
             writeln!(out, "nekonsd_upstream_avg_latency_ms{{name=\"{}\"}} {:.1}", name, lat).ok();
             writeln!(out, "nekonsd_upstream_trust_score{{name=\"{}\"}} {:.3}", name, trust).ok();
+            if u["shadow"].as_bool() == Some(true) {
+                let agreements = u["agreements"].as_u64().unwrap_or(0);
+                let disagreements = u["disagreements"].as_u64().unwrap_or(0);
+                writeln!(out, "nekonsd_upstream_shadow_agreements{{name=\"{}\"}} {}", name, agreements).ok();
+                writeln!(out, "nekonsd_upstream_shadow_disagreements{{name=\"{}\"}} {}", name, disagreements).ok();
+            }
         }
     }
 
@@ -317,6 +499,9 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
         write_help_type(&mut out, "nekonsd_glue_cache_count", "Total number of glue cache entries.", "gauge");
         writeln!(out, "nekonsd_glue_cache_count {}", glue_cache).ok();
 
+        write_help_type(&mut out, "nekonsd_root_unreachable", "Whether the root warmup reached no root server (1) or not (0).", "gauge");
+        writeln!(out, "nekonsd_root_unreachable {}", recursive.roots_unreachable() as u8).ok();
+
         let rsuc = c.recursive_successes.load(Ordering::Relaxed);
         let rfail = c.recursive_failures.load(Ordering::Relaxed);
         write_help_type(&mut out, "nekonsd_recursive_successes_total", "Total successful recursive resolutions.", "counter");
@@ -324,7 +509,37 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
 
         write_help_type(&mut out, "nekonsd_recursive_failures_total", "Total failed recursive resolutions.", "counter");
         writeln!(out, "nekonsd_recursive_failures_total {}", rfail).ok();
+
+        write_help_type(&mut out, "nekonsd_recursive_busy_total", "Recursive resolutions refused because recursive.max_concurrent_resolutions were in progress.", "counter");
+        writeln!(out, "nekonsd_recursive_busy_total {}", recursive.busy_rejections()).ok();
+
+        let depth = recursive.depth_stats();
+        let buckets = depth.cumulative();
+        let count = buckets.last().map(|b| b.1).unwrap_or(0);
+        write_help_type(&mut out, "nekonsd_recursion_depth", "Delegation depth at which recursive resolutions ended.", "histogram");
+        for (le, n) in &buckets {
+            writeln!(out, "nekonsd_recursion_depth_bucket{{le=\"{}\"}} {}", le, n).ok();
+        }
+        writeln!(out, "nekonsd_recursion_depth_bucket{{le=\"+Inf\"}} {}", count).ok();
+        writeln!(out, "nekonsd_recursion_depth_sum {}", depth.depth_sum()).ok();
+        writeln!(out, "nekonsd_recursion_depth_count {}", count).ok();
+
+        write_help_type(&mut out, "nekonsd_recursion_max_depth_reached_total", "Recursive resolutions stopped by recursive.max_depth.", "counter");
+        writeln!(out, "nekonsd_recursion_max_depth_reached_total {}", depth.max_depth_reached()).ok();
+
+        write_help_type(&mut out, "nekonsd_recursion_outbound_queries_total", "Queries sent to authoritative servers by recursive resolutions.", "counter");
+        writeln!(out, "nekonsd_recursion_outbound_queries_total {}", depth.outbound_queries()).ok();
+    }
+
+    // ──────────────────────────────────────────────
+    // Rejected responses (unbound: num.answer.unwanted, split by reason)
+    // ──────────────────────────────────────────────
+    write_help_type(&mut out, "nekonsd_spoofed_responses_total", "Responses from upstreams / authoritatives rejected as possibly spoofed, by reason.", "counter");
+    for reason in crate::spoof::SpoofReason::ALL {
+        writeln!(out, "nekonsd_spoofed_responses_total{{reason=\"{}\"}} {}", reason.label(), engine.spoof.count(reason)).ok();
     }
+    write_help_type(&mut out, "nekonsd_tcp_still_truncated_total", "TCP retries of truncated UDP answers that came back truncated again.", "counter");
+    writeln!(out, "nekonsd_tcp_still_truncated_total {}", engine.still_truncated.count()).ok();
 
     // ──────────────────────────────────────────────
     // Local zone queries (neko-dns specific)
@@ -339,11 +554,18 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     write_help_type(&mut out, "nekonsd_cache_evictions_total", "Total number of cache entries evicted.", "counter");
     writeln!(out, "nekonsd_cache_evictions_total {}", cache_evictions).ok();
 
+    // ──────────────────────────────────────────────
+    // Cache verification (cache.verify_sample_rate)
+    // ──────────────────────────────────────────────
+    write_help_type(&mut out, "nekonsd_cache_verifications_total", "Cached entries re-resolved to check them.", "counter");
+    writeln!(out, "nekonsd_cache_verifications_total {}", c.cache_verifications.load(Ordering::Relaxed)).ok();
+    write_help_type(&mut out, "nekonsd_cache_verify_mismatches_total", "Re-resolved entries whose answer differed from the cached one.", "counter");
+    writeln!(out, "nekonsd_cache_verify_mismatches_total {}", c.cache_verify_mismatches.load(Ordering::Relaxed)).ok();
+
     // ──────────────────────────────────────────────
     // Cache hit rate (convenience gauge)
     // ──────────────────────────────────────────────
-    let total = cache_hits + cache_misses;
-    let hit_rate = if total > 0 { cache_hits as f64 / total as f64 } else { 0.0 };
+    let hit_rate = snapshot.hit_ratio();
     write_help_type(&mut out, "nekonsd_cache_hit_ratio", "Cache hit ratio (0.0-1.0).", "gauge");
     writeln!(out, "nekonsd_cache_hit_ratio {:.4}", hit_rate).ok();
 
@@ -391,6 +613,59 @@ pub fn render_metrics(engine: &Arc<QueryEngine>) -> String {
     out
 }
 
+/// Per-upstream counters exported without `_total`: OpenMetrics counter samples need
+/// the suffix, and nekonsd_upstream_queries_total is already the overall count
+const OPENMETRICS_RENAMES: [(&str, &str); 2] = [
+    ("nekonsd_upstream_queries", "nekonsd_upstream_server_queries"),
+    ("nekonsd_upstream_failures", "nekonsd_upstream_server_failures"),
+];
+
+/// `render_metrics` in OpenMetrics 1.0 text format: counter families named without
+/// `_total` (their samples keep it), `# UNIT` for _seconds / _bytes families, `# EOF`
+pub fn render_openmetrics(engine: &Arc<QueryEngine>) -> String {
+    to_openmetrics(&render_metrics(engine))
+}
+
+fn to_openmetrics(text: &str) -> String {
+    let types: HashMap<&str, &str> = text.lines()
+        .filter_map(|l| l.strip_prefix("# TYPE "))
+        .filter_map(|l| l.split_once(' '))
+        .collect();
+    let family = |name: &str| -> String {
+        if let Some((_, renamed)) = OPENMETRICS_RENAMES.iter().find(|(old, _)| *old == name) {
+            return renamed.to_string();
+        }
+        match types.get(name) {
+            Some(&"counter") => name.strip_suffix("_total").unwrap_or(name).to_string(),
+            _ => name.to_string(),
+        }
+    };
+
+    let mut out = String::with_capacity(text.len() + 1024);
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            let (name, help) = rest.split_once(' ').unwrap_or((rest, ""));
+            writeln!(out, "# HELP {} {}", family(name), help).ok();
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, metric_type) = rest.split_once(' ').unwrap_or((rest, "unknown"));
+            let family = family(name);
+            writeln!(out, "# TYPE {} {}", family, metric_type).ok();
+            if let Some(unit) = ["seconds", "bytes"].into_iter().find(|u| family.ends_with(&format!("_{}", u))) {
+                writeln!(out, "# UNIT {} {}", family, unit).ok();
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let end = line.find(['{', ' ']).unwrap_or(line.len());
+            let name = &line[..end];
+            match types.get(name) {
+                Some(&"counter") => writeln!(out, "{}_total{}", family(name), &line[end..]).ok(),
+                _ => writeln!(out, "{}", line).ok(),
+            };
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
 // ── helpers ─────────────────────────────────────────
 
 fn write_help_type(out: &mut String, name: &str, help: &str, metric_type: &str) {
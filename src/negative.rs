@@ -1,11 +1,16 @@
-use std::time::Instant;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use dashmap::DashMap;
+use parking_lot::Mutex;
 use tracing::debug;
 
 use crate::config::NegativeCacheConfig;
-use crate::dns::types::RecordType;
+use crate::dns::types::{RecordType, ResponseCode};
 use crate::dns::packet;
 
+/// Keyboard rows for adjacent-key typos (negative.speculative_keyboard)
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
 /// Negative Cache - RFC 2308 の魔改造版
 ///
 /// NXDOMAIN をただキャッシュするだけでなく、
@@ -30,6 +35,10 @@ struct NegCacheEntry {
 pub struct NegativeCache {
     config: NegativeCacheConfig,
     entries: DashMap<NegCacheKey, NegCacheEntry>,
+    /// Start of the current minute and speculative entries added in it
+    speculative_window: Mutex<(Instant, u32)>,
+    /// Variants not cached because speculative_max_per_minute was used up
+    speculative_rate_limited: AtomicU64,
 }
 
 impl NegativeCache {
@@ -37,6 +46,8 @@ impl NegativeCache {
         Self {
             config: config.clone(),
             entries: DashMap::new(),
+            speculative_window: Mutex::new((Instant::now(), 0)),
+            speculative_rate_limited: AtomicU64::new(0),
         }
     }
 
@@ -64,7 +75,14 @@ impl NegativeCache {
         None
     }
 
-    /// Insert an NXDOMAIN response into the negative cache
+    /// Seconds left on a cached entry (tests only)
+    #[cfg(test)]
+    pub fn remaining_ttl(&self, name: &str, qtype: &RecordType) -> Option<u32> {
+        let key = NegCacheKey { name: name.to_lowercase(), qtype: qtype.to_u16() };
+        self.entries.get(&key).map(|e| e.ttl.saturating_sub(e.inserted_at.elapsed().as_secs() as u32))
+    }
+
+    /// Insert an NXDOMAIN or NODATA response into the negative cache
     pub fn insert(&self, name: &str, qtype: &RecordType, response: &[u8]) {
         if !self.config.enabled {
             return;
@@ -76,7 +94,11 @@ impl NegativeCache {
         };
 
         // Extract SOA minimum TTL from authority section (per RFC 2308)
-        let ttl = self.extract_neg_ttl(response).unwrap_or(self.config.default_ttl);
+        let parsed = packet::parse_packet(response).ok();
+        let ttl = parsed.as_ref().and_then(packet::soa_negative_ttl).unwrap_or(self.config.default_ttl);
+        let ttl = self.config.clamp_ttl(ttl);
+        // NODATA means the name exists, so its typo variants say nothing
+        let nxdomain = parsed.is_none_or(|p| p.header.rcode == ResponseCode::NxDomain);
 
         self.entries.insert(key, NegCacheEntry {
             raw_response: response.to_vec(),
@@ -86,17 +108,20 @@ impl NegativeCache {
         });
 
         // Speculative negative caching
-        if self.config.speculative {
+        if self.config.speculative && nxdomain {
             self.insert_speculative(name, qtype, response, ttl);
         }
     }
 
     /// Generate typo variants and add to negative cache
     fn insert_speculative(&self, name: &str, qtype: &RecordType, response: &[u8], ttl: u32) {
+        if !self.config.speculates_on(qtype) {
+            return;
+        }
         let variants = self.generate_typo_variants(name);
-        let short_ttl = ttl.min(60); // Speculative entries get short TTL
+        let short_ttl = ttl.min(60); // Speculative entries get short TTL, whatever min_ttl says
 
-        for variant in variants {
+        for (i, variant) in variants.iter().enumerate() {
             let key = NegCacheKey {
                 name: variant.to_lowercase(),
                 qtype: qtype.to_u16(),
@@ -104,6 +129,11 @@ impl NegativeCache {
 
             // Don't overwrite non-speculative entries
             if !self.entries.contains_key(&key) {
+                if !self.take_speculative_slot() {
+                    self.speculative_rate_limited.fetch_add((variants.len() - i) as u64, Ordering::Relaxed);
+                    debug!("Speculative negative cache: per-minute limit reached, {} variants of {} skipped", variants.len() - i, name);
+                    return;
+                }
                 debug!("Speculative negative cache: {} (from {})", variant, name);
                 self.entries.insert(key, NegCacheEntry {
                     raw_response: response.to_vec(),
@@ -115,6 +145,23 @@ impl NegativeCache {
         }
     }
 
+    /// Count one speculative insertion against negative.speculative_max_per_minute
+    fn take_speculative_slot(&self) -> bool {
+        let limit = self.config.speculative_max_per_minute;
+        if limit == 0 {
+            return true;
+        }
+        let mut window = self.speculative_window.lock();
+        if window.0.elapsed() >= Duration::from_secs(60) {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= limit {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+
     /// Generate common typo variants of a domain name
     fn generate_typo_variants(&self, name: &str) -> Vec<String> {
         let mut variants = Vec::new();
@@ -148,29 +195,53 @@ impl NegativeCache {
             }
         }
 
+        // Neighbouring key: one character replaced by the key beside it
+        if self.config.speculative_keyboard {
+            for (i, c) in chars.iter().enumerate() {
+                for neighbour in keyboard_neighbours(c.to_ascii_lowercase()) {
+                    let mut replaced = chars.clone();
+                    replaced[i] = neighbour;
+                    variants.push(format!("{}.{}", replaced.into_iter().collect::<String>(), rest));
+                }
+            }
+        }
+
         // Limit to prevent explosion
-        variants.truncate(10);
+        variants.truncate(self.config.speculative_max_variants);
         variants
     }
 
-    /// Extract negative TTL from SOA record in authority section
-    fn extract_neg_ttl(&self, response: &[u8]) -> Option<u32> {
-        let parsed = packet::parse_packet(response).ok()?;
-        for record in &parsed.authorities {
-            if record.rtype == RecordType::SOA {
-                // SOA minimum TTL is the last 4 bytes of rdata
-                if record.rdata.len() >= 4 {
-                    let min_ttl = u32::from_be_bytes([
-                        record.rdata[record.rdata.len() - 4],
-                        record.rdata[record.rdata.len() - 3],
-                        record.rdata[record.rdata.len() - 2],
-                        record.rdata[record.rdata.len() - 1],
-                    ]);
-                    return Some(min_ttl.min(record.ttl));
-                }
-            }
+    /// Live entries for GET /api/negative
+    pub fn list(&self) -> Vec<serde_json::Value> {
+        self.entries.iter()
+            .filter_map(|entry| {
+                let remaining = entry.ttl.checked_sub(entry.inserted_at.elapsed().as_secs() as u32).filter(|r| *r > 0)?;
+                let rcode = packet::parse_packet(&entry.raw_response)
+                    .map(|p| format!("{:?}", p.header.rcode))
+                    .unwrap_or_default();
+                Some(serde_json::json!({
+                    "name": entry.key().name,
+                    "type": RecordType::from(entry.key().qtype).name(),
+                    "rcode": rcode,
+                    "remaining_ttl": remaining,
+                    "speculative": entry.speculative,
+                }))
+            })
+            .collect()
+    }
+
+    /// Drop the entries for `domain` and the names under it (every entry when None);
+    /// returns how many were removed
+    pub fn flush(&self, domain: Option<&str>) -> usize {
+        let before = self.entries.len();
+        match domain.map(|d| d.trim_end_matches('.').to_lowercase()) {
+            None => self.entries.clear(),
+            Some(domain) => self.entries.retain(|key, _| {
+                let name = key.name.trim_end_matches('.');
+                !(name == domain || name.strip_suffix(domain.as_str()).is_some_and(|rest| rest.ends_with('.')))
+            }),
         }
-        None
+        before.saturating_sub(self.entries.len())
     }
 
     /// Get stats
@@ -183,6 +254,104 @@ impl NegativeCache {
             "total_entries": total,
             "speculative_entries": speculative,
             "real_entries": total - speculative,
+            "speculative_rate_limited": self.speculative_rate_limited.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Keys left and right of `c` on its keyboard row
+fn keyboard_neighbours(c: char) -> Vec<char> {
+    KEYBOARD_ROWS.iter()
+        .find_map(|row| {
+            let keys: Vec<char> = row.chars().collect();
+            let pos = keys.iter().position(|k| *k == c)?;
+            Some([pos.checked_sub(1), Some(pos + 1)].into_iter()
+                .flatten()
+                .filter_map(|p| keys.get(p).copied())
+                .collect())
         })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> NegativeCacheConfig {
+        NegativeCacheConfig {
+            enabled: true,
+            speculative: true,
+            speculative_max_variants: 10,
+            speculative_max_per_minute: 0,
+            speculative_keyboard: false,
+            speculative_types: vec!["A".to_string(), "AAAA".to_string()],
+            default_ttl: 300,
+            min_ttl: 0,
+            max_ttl: 86400,
+            synthetic_soa: true,
+            synthetic_soa_ttl: 300,
+        }
+    }
+
+    fn nxdomain(name: &str) -> Vec<u8> {
+        let mut parsed = packet::parse_packet(&packet::build_query(1, name, RecordType::A, true)).unwrap();
+        parsed.header.qr = true;
+        parsed.header.rcode = ResponseCode::NxDomain;
+        parsed.to_wire()
+    }
+
+    #[test]
+    fn test_speculative_variants_only_for_listed_types() {
+        let cache = NegativeCache::new(&config());
+        cache.insert("4.3.2.1.in-addr.arpa", &RecordType::PTR, &nxdomain("4.3.2.1.in-addr.arpa"));
+        assert_eq!(cache.get_stats()["speculative_entries"], 0);
+        cache.insert("gogle.com", &RecordType::A, &nxdomain("gogle.com"));
+        assert!(cache.get_stats()["speculative_entries"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_variant_cap_and_keyboard_typos() {
+        let cache = NegativeCache::new(&NegativeCacheConfig { speculative_max_variants: 3, ..config() });
+        cache.insert("gogle.com", &RecordType::A, &nxdomain("gogle.com"));
+        assert_eq!(cache.get_stats()["speculative_entries"], 3);
+
+        let cache = NegativeCache::new(&NegativeCacheConfig { speculative_max_variants: 100, speculative_keyboard: true, ..config() });
+        let variants = cache.generate_typo_variants("cat.example");
+        // c → x/v, a → s, t → r/y
+        for typo in ["xat.example", "vat.example", "cst.example", "car.example", "cay.example"] {
+            assert!(variants.iter().any(|v| v == typo), "{} missing", typo);
+        }
+        assert_eq!(keyboard_neighbours('q'), vec!['w']);
+        assert!(keyboard_neighbours('-').is_empty());
+    }
+
+    #[test]
+    fn test_speculative_insertions_rate_limited() {
+        let cache = NegativeCache::new(&NegativeCacheConfig { speculative_max_per_minute: 15, ..config() });
+        for name in ["firstname.example", "secondname.example"] {
+            cache.insert(name, &RecordType::A, &nxdomain(name));
+        }
+        let stats = cache.get_stats();
+        assert_eq!(stats["speculative_entries"], 15);
+        assert_eq!(stats["speculative_rate_limited"], 5);
+        // The real NXDOMAINs are cached regardless
+        assert_eq!(stats["real_entries"], 2);
+        assert!(cache.check("secondname.example", &RecordType::A).is_some());
+    }
+
+    #[test]
+    fn test_negative_ttl_floor_and_cap() {
+        let cache = NegativeCache::new(&NegativeCacheConfig { min_ttl: 120, max_ttl: 3600, ..config() });
+        let with_soa = |name: &str, minimum: u32| {
+            let mut parsed = packet::parse_packet(&nxdomain(name)).unwrap();
+            parsed.authorities.push(packet::synthetic_soa(name, minimum));
+            parsed.to_wire()
+        };
+        cache.insert("gogle.com", &RecordType::A, &with_soa("gogle.com", 1));
+        assert_eq!(cache.remaining_ttl("gogle.com", &RecordType::A), Some(120));
+        // Typo guesses stay short even under the floor
+        assert!(cache.entries.iter().filter(|e| e.speculative).all(|e| e.ttl == 60));
+        cache.insert("missing.example", &RecordType::AAAA, &with_soa("missing.example", 604800));
+        assert_eq!(cache.remaining_ttl("missing.example", &RecordType::AAAA), Some(3600));
     }
 }
@@ -1,4 +1,6 @@
-use crate::config::NekoCommentConfig;
+use crate::config::{NekoCommentConfig, NekoVerbosity};
+use crate::dns::packet;
+use crate::dns::types::RecordType;
 use rand::seq::SliceRandom;
 
 /// 🐱 neko-dns feature notifier + random cat messages
@@ -30,8 +32,15 @@ const NEKO_MESSAGES: &[&str] = &[
     "*headbonk* here's your A record!",
 ];
 
+/// Longest feature TXT text; verbose details that don't fit are left out
+const MAX_SUMMARY_LEN: usize = 500;
+
 pub struct NekoComment {
     enabled: bool,
+    skip_signed_answers: bool,
+    verbosity: NekoVerbosity,
+    /// Wire-format owner name of the feature TXT
+    record_name: Vec<u8>,
 }
 
 /// Tracks which features were triggered during a single query processing
@@ -54,8 +63,15 @@ pub struct QueryFeatures {
     pub local_zone: bool,
     /// Which upstream won the race (if forwarding mode)
     pub upstream_winner: Option<String>,
+    /// Where the answer came from, cached answers included ("recursive",
+    /// "local-zone" or an upstream name) - for the EDNS metadata, not the TXT
+    pub answered_by: Option<String>,
     /// Resolution latency in ms
     pub latency_ms: Option<u64>,
+    /// Time spent resolving (upstream race or recursion) in ms, excluding our own processing
+    pub resolve_latency_ms: Option<u64>,
+    /// TTL left on the cache entry that answered
+    pub cache_ttl_remaining: Option<u32>,
 }
 
 impl QueryFeatures {
@@ -93,12 +109,41 @@ impl QueryFeatures {
 
         parts.join(" ")
     }
+
+    /// `to_summary` plus per-feature detail, as much of it as fits in MAX_SUMMARY_LEN
+    pub fn to_verbose(&self) -> String {
+        let mut summary = self.to_summary();
+        let mut details = Vec::new();
+        if let Some(ref name) = self.answered_by {
+            details.push(format!("upstream:{}", name));
+        }
+        if let Some(ms) = self.resolve_latency_ms {
+            details.push(format!("resolve:{}ms", ms));
+        }
+        if let Some(ms) = self.latency_ms {
+            details.push(format!("total:{}ms", ms));
+        }
+        if let Some(ttl) = self.cache_ttl_remaining {
+            details.push(format!("ttl_left:{}s", ttl));
+        }
+        for detail in details {
+            if summary.len() + 1 + detail.len() > MAX_SUMMARY_LEN {
+                continue;
+            }
+            summary.push(' ');
+            summary.push_str(&detail);
+        }
+        summary
+    }
 }
 
 impl NekoComment {
     pub fn new(config: &NekoCommentConfig) -> Self {
         Self {
             enabled: config.enabled,
+            skip_signed_answers: config.skip_signed_answers,
+            verbosity: config.verbosity,
+            record_name: packet::encode_name(&config.record_name.to_lowercase()),
         }
     }
 
@@ -106,30 +151,43 @@ impl NekoComment {
         self.enabled
     }
 
+    /// true if unsigned TXT must not be added to this response
+    pub fn skips_response(&self, response: &[u8]) -> bool {
+        self.skip_signed_answers && crate::dns::packet::is_dnssec_signed(response)
+    }
+
+    /// Drop the ADDITIONAL TXT records neko-dns added (features, cat message, journey)
+    pub fn strip_own_records(&self, response: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut parsed = packet::parse_packet(response)?;
+        parsed.additionals.retain(|r| {
+            let ours = packet::encode_name(&r.name.to_lowercase()) == self.record_name
+                || ["neko-dns.comment", "neko-dns.journey"].iter().any(|n| r.name.eq_ignore_ascii_case(n));
+            r.rtype != RecordType::TXT || !ours
+        });
+        Ok(parsed.to_wire())
+    }
+
     /// Build an ADDITIONAL TXT record from triggered query features.
-    /// name: "neko-dns.features." TXT record, class CH, TTL 0
+    /// name: neko_comment.record_name ("neko-dns.features." by default) TXT record, class IN, TTL 0
     /// All content is pure ASCII - no encoding issues with any DNS client.
     pub fn build_feature_txt(&self, features: &QueryFeatures) -> Option<Vec<u8>> {
         if !self.enabled {
             return None;
         }
 
-        let summary = features.to_summary();
+        let summary = match self.verbosity {
+            NekoVerbosity::Off => return None,
+            NekoVerbosity::Compact => features.to_summary(),
+            NekoVerbosity::Verbose => features.to_verbose(),
+        };
         let summary_bytes = summary.as_bytes();
 
         // Sanity: TXT RDATA must fit reasonably in a DNS packet
-        if summary_bytes.len() > 500 {
+        if summary_bytes.len() > MAX_SUMMARY_LEN {
             return None;
         }
 
-        let mut record = Vec::new();
-
-        // Name: "neko-dns.features." encoded as DNS labels
-        record.push(8);
-        record.extend_from_slice(b"neko-dns");
-        record.push(8);
-        record.extend_from_slice(b"features");
-        record.push(0); // root label
+        let mut record = self.record_name.clone();
 
         // Type: TXT (16)
         record.extend_from_slice(&16u16.to_be_bytes());
@@ -196,3 +254,78 @@ impl NekoComment {
         Some(record)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neko(verbosity: NekoVerbosity) -> NekoComment {
+        NekoComment::new(&NekoCommentConfig { verbosity, ..NekoCommentConfig::default() })
+    }
+
+    /// (owner name, TXT text) of a record built by build_feature_txt
+    fn decode(record: &[u8]) -> (String, String) {
+        let mut pos = 0;
+        let mut labels = Vec::new();
+        while record[pos] != 0 {
+            let len = record[pos] as usize;
+            labels.push(String::from_utf8_lossy(&record[pos + 1..pos + 1 + len]).to_string());
+            pos += 1 + len;
+        }
+        pos += 11; // root label, type, class, TTL, RDLENGTH
+        let mut text = String::new();
+        while pos < record.len() {
+            let len = record[pos] as usize;
+            text.push_str(&String::from_utf8_lossy(&record[pos + 1..pos + 1 + len]));
+            pos += 1 + len;
+        }
+        (labels.join("."), text)
+    }
+
+    fn features() -> QueryFeatures {
+        QueryFeatures {
+            cache_hit: true,
+            answered_by: Some("cloudflare".to_string()),
+            resolve_latency_ms: Some(12),
+            latency_ms: Some(15),
+            cache_ttl_remaining: Some(240),
+            ..QueryFeatures::new()
+        }
+    }
+
+    #[test]
+    fn test_verbosity_levels() {
+        assert!(neko(NekoVerbosity::Off).build_feature_txt(&features()).is_none());
+
+        let (name, compact) = decode(&neko(NekoVerbosity::Compact).build_feature_txt(&features()).unwrap());
+        assert_eq!(name, "neko-dns.features");
+        assert_eq!(compact, "neko-dns [CACHE_HIT] 15ms");
+
+        let (_, verbose) = decode(&neko(NekoVerbosity::Verbose).build_feature_txt(&features()).unwrap());
+        assert_eq!(verbose, "neko-dns [CACHE_HIT] 15ms upstream:cloudflare resolve:12ms total:15ms ttl_left:240s");
+    }
+
+    #[test]
+    fn test_verbose_stays_under_cap() {
+        let long = QueryFeatures { answered_by: Some("u".repeat(480)), ..features() };
+        let (_, text) = decode(&neko(NekoVerbosity::Verbose).build_feature_txt(&long).unwrap());
+        assert!(text.len() <= MAX_SUMMARY_LEN);
+        // The upstream detail didn't fit, the shorter ones after it still did
+        assert!(!text.contains("upstream:"));
+        assert!(text.ends_with("ttl_left:240s"));
+
+        // Longer than one character-string: split at 255
+        let chunked = QueryFeatures { answered_by: Some("u".repeat(300)), ..features() };
+        let record = neko(NekoVerbosity::Verbose).build_feature_txt(&chunked).unwrap();
+        let rdata = &record[19 + 10..]; // owner name, type, class, TTL, RDLENGTH
+        assert_eq!(rdata[0], 255);
+        assert_eq!(rdata[256] as usize, rdata.len() - 257);
+        assert!(decode(&record).1.contains(&"u".repeat(300)));
+    }
+
+    #[test]
+    fn test_record_name_override() {
+        let neko = NekoComment::new(&NekoCommentConfig { record_name: "Diag.Example.".to_string(), ..NekoCommentConfig::default() });
+        assert_eq!(decode(&neko.build_feature_txt(&features()).unwrap()).0, "diag.example");
+    }
+}
@@ -13,6 +13,58 @@
 use std::collections::HashMap;
 use chrono::{Utc, Timelike};
 use parking_lot::RwLock;
+use tracing::warn;
+
+use crate::config::PrefetchConfig;
+use crate::dns::types::RecordType;
+
+/// Per-type prefetch priorities (prefetch.type_priority) and the round budget
+pub struct PrefetchPlan {
+    /// Type code → priority
+    priorities: HashMap<u16, i32>,
+    max_per_round: usize,
+}
+
+impl PrefetchPlan {
+    pub fn new(config: &PrefetchConfig) -> Self {
+        let priorities = config.type_priority.iter()
+            .filter_map(|(name, priority)| {
+                let parsed = RecordType::from_name(name);
+                if parsed.is_none() { warn!("Unknown record type in prefetch.type_priority: {}", name); }
+                parsed.map(|t| (t.to_u16(), *priority))
+            })
+            .collect();
+        Self { priorities, max_per_round: config.max_per_round }
+    }
+
+    fn priority(&self, qtype: RecordType) -> i32 {
+        self.priorities.get(&qtype.to_u16()).copied().unwrap_or(0)
+    }
+
+    /// Candidates (soonest expiry first) reordered by type priority, cut to the budget
+    pub fn select(&self, mut candidates: Vec<(String, RecordType)>) -> Vec<(String, RecordType)> {
+        // Stable: equal priorities keep their expiry order
+        candidates.sort_by_key(|(_, qtype)| std::cmp::Reverse(self.priority(*qtype)));
+        if self.max_per_round > 0 {
+            candidates.truncate(self.max_per_round);
+        }
+        candidates
+    }
+
+    /// Effective ordering, highest priority first
+    pub fn get_stats(&self) -> serde_json::Value {
+        let mut order: Vec<(u16, i32)> = self.priorities.iter().map(|(t, p)| (*t, *p)).collect();
+        order.sort_by_key(|(t, p)| (std::cmp::Reverse(*p), *t));
+        let order: Vec<serde_json::Value> = order.into_iter()
+            .map(|(t, p)| serde_json::json!({ "type": RecordType::from(t).name(), "priority": p }))
+            .collect();
+        serde_json::json!({
+            "max_per_round": self.max_per_round,
+            "type_order": order,
+            "unlisted_priority": 0,
+        })
+    }
+}
 
 /// Time-of-day pattern learner
 /// Records which domains are queried at which hours
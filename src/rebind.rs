@@ -0,0 +1,138 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+use crate::config::{LocalZoneConfig, SecurityConfig};
+use crate::dns::packet;
+use crate::dns::types::RecordType;
+
+/// DNS rebinding guard (security.deny_private_answers)
+///
+/// A public name that suddenly resolves to 127.0.0.1 or 192.168.x.x lets a web page
+/// reach services on the LAN. For names outside the local zones and the exception
+/// list, private addresses are stripped from the answer; if no address survives the
+/// client gets NODATA.
+pub struct RebindGuard {
+    enabled: bool,
+    /// Lowercased suffixes allowed to answer with private addresses
+    allowed: Vec<String>,
+    /// Negative TTL of the SOA added to NODATA answers (negative.synthetic_soa)
+    soa_ttl: Option<u32>,
+    filtered: AtomicU64,
+}
+
+impl RebindGuard {
+    pub fn new(config: &SecurityConfig, local_zones: &[LocalZoneConfig]) -> Self {
+        let allowed = local_zones.iter()
+            .map(|z| z.domain.as_str())
+            .chain(config.private_answer_exceptions.iter().map(String::as_str))
+            .map(|d| d.trim_end_matches('.').to_lowercase())
+            .collect();
+        Self {
+            enabled: config.deny_private_answers,
+            allowed,
+            soa_ttl: None,
+            filtered: AtomicU64::new(0),
+        }
+    }
+
+    /// Also let names under these domains (authoritative zones) answer with private addresses
+    pub fn with_allowed_zones<'a>(mut self, zones: impl IntoIterator<Item = &'a str>) -> Self {
+        self.allowed.extend(zones.into_iter().map(|d| d.trim_end_matches('.').to_lowercase()));
+        self
+    }
+
+    /// Give the NODATA answers this guard makes a synthetic SOA with this negative TTL
+    pub fn with_negative_soa(mut self, ttl: Option<u32>) -> Self {
+        self.soa_ttl = ttl;
+        self
+    }
+
+    fn is_allowed(&self, qname: &str) -> bool {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        self.allowed.iter().any(|zone| {
+            qname == *zone || (qname.len() > zone.len() && qname.ends_with(zone.as_str())
+                && qname.as_bytes()[qname.len() - zone.len() - 1] == b'.')
+        })
+    }
+
+    /// Rewritten response when private addresses had to go, None when it passes as is
+    pub fn filter(&self, response: &[u8]) -> Option<Vec<u8>> {
+        if !self.enabled {
+            return None;
+        }
+        let mut parsed = packet::parse_packet(response).ok()?;
+        let qname = parsed.questions.first()?.name.clone();
+        if self.is_allowed(&qname) {
+            return None;
+        }
+
+        let before = parsed.answers.len() + parsed.additionals.len();
+        parsed.answers.retain(|r| !record_is_private(r));
+        parsed.additionals.retain(|r| !record_is_private(r));
+        let stripped = before - parsed.answers.len() - parsed.additionals.len();
+        if stripped == 0 {
+            return None;
+        }
+
+        // Nothing left to connect to → NODATA rather than a dangling CNAME chain
+        if !parsed.answers.iter().any(|r| matches!(r.rtype, RecordType::A | RecordType::AAAA)) {
+            parsed.answers.clear();
+            if let Some(ttl) = self.soa_ttl {
+                packet::add_negative_soa(&mut parsed, ttl);
+            }
+        }
+        self.filtered.fetch_add(1, Ordering::Relaxed);
+        warn!("🛡️ Rebinding guard: stripped {} private address(es) from the answer for {}", stripped, qname);
+        Some(parsed.to_wire())
+    }
+
+    pub fn get_stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "deny_private_answers": self.enabled,
+            "exceptions": self.allowed,
+            "filtered_responses": self.filtered.load(Ordering::Relaxed),
+        })
+    }
+}
+
+fn record_is_private(record: &packet::DnsRecord) -> bool {
+    record.ip_addr().is_some_and(is_private)
+}
+
+/// Loopback, RFC 1918, link-local, CGNAT, "this network" and their IPv6 counterparts
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_private(IpAddr::V4(v4));
+            }
+            let first = v6.segments()[0];
+            v6.is_loopback() || v6.is_unspecified()
+                || (first & 0xfe00) == 0xfc00   // fc00::/7 unique local
+                || (first & 0xffc0) == 0xfe80   // fe80::/10 link-local
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_private_ranges() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "172.31.255.255", "192.168.1.10", "169.254.1.1",
+                   "0.0.0.0", "100.64.0.1", "::1", "fe80::1", "fd00::1", "::ffff:192.168.0.1"] {
+            assert!(is_private(ip.parse().unwrap()), "{} should be private", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "192.0.2.1", "100.128.0.1", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!is_private(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+}
@@ -1,19 +1,27 @@
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use dashmap::DashMap;
-use parking_lot::RwLock;
 use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
 use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
 use tracing::{debug, info, warn};
 
-use crate::config::RecursiveConfig;
+use crate::compress::{self, FileWriter};
+use crate::config::{Compression, RecursiveConfig};
+use crate::dns::engine::random_query_id;
 use crate::dns::packet::{self};
+use crate::dns::tcp::{self, StillTruncated};
 use crate::dns::types::{RecordType, ResponseCode};
 use crate::curiosity::CuriosityCache;
 use crate::journey::JourneyTracker;
+use crate::spoof::{self, SpoofMonitor, SpoofReason};
+use crate::tap::QueryTap;
+use crate::upstream::UpstreamManager;
 
 // ============================================================
 // Unbound-inspired constants (proven in production)
@@ -34,10 +42,35 @@ const RTT_BAND_MS: i32 = 400;
 const TIMEOUT_PENALTY: i32 = 10_000;
 /// Max consecutive timeouts before heavy penalty
 const MAX_TIMEOUT_COUNT: u32 = 3;
-/// Delegation cache default TTL (seconds)
-const DELEG_CACHE_TTL_SECS: u64 = 1800;
 /// Socket pool size
 const SOCKET_POOL_SIZE: usize = 48;
+/// Source ports used unless recursive.source_port_min / max say otherwise
+const DEFAULT_SOURCE_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+/// Narrower source port ranges get a warning (RFC 5452 wants as many as possible)
+const MIN_SOURCE_PORT_RANGE: usize = 4096;
+/// Random ports tried before giving up on creating a socket
+const SOURCE_PORT_BIND_ATTEMPTS: u32 = 8;
+/// Root/infra probe timeout (ms), capped at recursive.query_timeout_ms
+const PROBE_TIMEOUT_MS: u64 = 1500;
+/// Probe attempts per server (first try + jittered retries)
+const PROBE_MAX_ATTEMPTS: u32 = 3;
+/// Probe retry backoff base / cap (ms)
+const PROBE_BACKOFF_BASE_MS: u64 = 200;
+const PROBE_BACKOFF_CAP_MS: u64 = 2000;
+/// Max non-root servers re-probed per round
+const REPROBE_MAX_ZONE_SERVERS: usize = 64;
+/// TTL for ". NS" answers synthesized from root hints (root zone NS TTL)
+const ROOT_HINTS_TTL: u32 = 518_400;
+/// Max TLDs tracked in per-zone resolution stats (LRU-evicted beyond this)
+const ZONE_STATS_MAX: usize = 1024;
+/// TLDs listed in get_stats()
+const ZONE_STATS_TOP_N: usize = 10;
+/// Max NS names kept in the resolver glue cache
+const GLUE_CACHE_MAX: usize = 10_000;
+/// Candidates listed per SELECT journey step (recursive.trace_selection)
+const TRACE_SELECTION_MAX_CANDIDATES: usize = 16;
+/// CNAMEs followed out of their zone per resolution (each one restarts the walk)
+const MAX_CNAME_RESTARTS: usize = 8;
 
 // ============================================================
 // Jacobson/Karels RTT Estimator (RFC 6298, adapted for DNS)
@@ -55,13 +88,17 @@ struct RttInfo {
     rttvar: i32,
     rto: i32,
     timeout_count: u32,
+    /// Last time this server was contacted (success or timeout)
+    last_seen: Instant,
+    /// Skipped by selection until then (recursive.quarantine_secs)
+    quarantined_until: Option<Instant>,
 }
 
 impl RttInfo {
     fn new() -> Self {
         let rttvar = UNKNOWN_SERVER_NICENESS / 4; // 94ms
         let rto = Self::calc_rto(0, rttvar);
-        Self { srtt: 0, rttvar, rto, timeout_count: 0 }
+        Self { srtt: 0, rttvar, rto, timeout_count: 0, last_seen: Instant::now(), quarantined_until: None }
     }
 
     fn calc_rto(srtt: i32, rttvar: i32) -> i32 {
@@ -83,10 +120,13 @@ impl RttInfo {
         }
         self.rto = Self::calc_rto(self.srtt, self.rttvar);
         self.timeout_count = 0;
+        self.last_seen = Instant::now();
+        self.quarantined_until = None;
     }
 
     /// Record a timeout — exponential backoff (RFC 6298 §5.5)
     fn lost(&mut self, orig_rto: i32) {
+        self.last_seen = Instant::now();
         if self.rto < orig_rto { return; }
         let doubled = (orig_rto * 2).min(RTT_MAX_TIMEOUT_MS);
         if self.rto <= doubled {
@@ -95,6 +135,12 @@ impl RttInfo {
         self.timeout_count += 1;
     }
 
+    /// Still inside its quarantine. Once it ends the server gets one query: an answer
+    /// clears it, another timeout sends it straight back (timeout_count is still over).
+    fn is_quarantined(&self) -> bool {
+        self.quarantined_until.is_some_and(|until| Instant::now() < until)
+    }
+
     /// Score for server selection (lower = better)
     fn selection_score(&self) -> i32 {
         if self.timeout_count >= MAX_TIMEOUT_COUNT {
@@ -108,6 +154,80 @@ impl RttInfo {
     }
 }
 
+// ============================================================
+// Infra cache persistence (recursive.persist_infra_cache)
+// ============================================================
+
+/// On-disk form of the infra cache
+#[derive(Debug, Serialize, Deserialize)]
+struct InfraSnapshot {
+    saved_at: u64,
+    servers: Vec<SavedRtt>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedRtt {
+    ip: IpAddr,
+    srtt: i32,
+    rttvar: i32,
+    rto: i32,
+    timeout_count: u32,
+    /// Unix seconds (Instant doesn't survive a restart)
+    last_seen: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Write the infra cache to `path` (via a temp file, so a crash never leaves half a file)
+fn save_infra(infra: &DashMap<IpAddr, RttInfo>, path: &str, compression: Compression) -> anyhow::Result<usize> {
+    let now = unix_now();
+    let servers: Vec<SavedRtt> = infra.iter()
+        .map(|e| {
+            let r = e.value();
+            SavedRtt {
+                ip: *e.key(),
+                srtt: r.srtt,
+                rttvar: r.rttvar,
+                rto: r.rto,
+                timeout_count: r.timeout_count,
+                last_seen: now.saturating_sub(r.last_seen.elapsed().as_secs()),
+            }
+        })
+        .collect();
+    let count = servers.len();
+    let tmp = format!("{}.tmp", path);
+    let mut out = FileWriter::create(&tmp, compression)?;
+    serde_json::to_writer(&mut out, &InfraSnapshot { saved_at: now, servers })?;
+    out.finish()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(count)
+}
+
+/// Restore entries saved by `save_infra`, skipping those idle for longer than `max_age`
+fn load_infra(infra: &DashMap<IpAddr, RttInfo>, path: &str, max_age: Duration) -> anyhow::Result<usize> {
+    let snapshot: InfraSnapshot = serde_json::from_reader(compress::open(path)?)?;
+    let now = unix_now();
+    let mut restored = 0;
+    for saved in snapshot.servers {
+        let age = Duration::from_secs(now.saturating_sub(saved.last_seen));
+        if age > max_age {
+            continue;
+        }
+        infra.insert(saved.ip, RttInfo {
+            srtt: saved.srtt,
+            rttvar: saved.rttvar,
+            rto: saved.rto.clamp(RTT_MIN_TIMEOUT_MS, RTT_MAX_TIMEOUT_MS),
+            timeout_count: saved.timeout_count,
+            last_seen: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+            quarantined_until: None,
+        });
+        restored += 1;
+    }
+    Ok(restored)
+}
+
 // ============================================================
 // Delegation Cache — skip root/TLD for known zones
 // ============================================================
@@ -140,45 +260,306 @@ impl DelegEntry {
     }
 }
 
+// ============================================================
+// Glue Cache — addresses resolved for glue-less NS names
+// ============================================================
+
+#[derive(Debug, Clone)]
+struct GlueEntry {
+    ips: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// NS name → addresses, expired by TTL and bounded to `max` names
+/// (the entries closest to expiry go first once full)
+struct GlueCache {
+    entries: DashMap<String, GlueEntry>,
+    /// (expiry, name) of every entry, soonest first, so a full cache evicts without a scan
+    by_expiry: parking_lot::Mutex<BTreeSet<(Instant, String)>>,
+    max: usize,
+    /// Used when the address records' TTL isn't known
+    default_ttl: Duration,
+    min_ttl: Duration,
+    max_ttl: Duration,
+}
+
+impl GlueCache {
+    fn new(config: &RecursiveConfig, max: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            by_expiry: parking_lot::Mutex::new(BTreeSet::new()),
+            max,
+            default_ttl: Duration::from_secs(config.glue_ttl_secs),
+            min_ttl: Duration::from_secs(config.deleg_min_ttl_secs),
+            max_ttl: Duration::from_secs(config.deleg_max_ttl_secs),
+        }
+    }
+
+    fn get(&self, ns_name: &str) -> Option<Vec<IpAddr>> {
+        let key = ns_name.to_lowercase();
+        let entry = self.entries.get(&key)?;
+        if entry.expires > Instant::now() {
+            return Some(entry.ips.clone());
+        }
+        drop(entry);
+        if let Some((key, expired)) = self.entries.remove_if(&key, |_, e| e.expires <= Instant::now()) {
+            self.by_expiry.lock().remove(&(expired.expires, key));
+        }
+        None
+    }
+
+    /// `ttl` is the address records' TTL when known, clamped like delegations
+    fn insert(&self, ns_name: &str, ips: &[IpAddr], ttl: Option<u32>) {
+        let ttl = match ttl {
+            Some(secs) => Duration::from_secs(secs as u64).clamp(self.min_ttl, self.max_ttl.max(self.min_ttl)),
+            None => self.default_ttl,
+        };
+        if ttl.is_zero() || ips.is_empty() || self.max == 0 { return; }
+
+        let key = ns_name.to_lowercase();
+        let expires = Instant::now() + ttl;
+        let mut by_expiry = self.by_expiry.lock();
+        let previous = self.entries.get(&key).map(|e| e.expires);
+        match previous {
+            Some(previous) => { by_expiry.remove(&(previous, key.clone())); }
+            // Full: the soonest to expire goes (already expired ones first)
+            None => while self.entries.len() >= self.max {
+                let Some((soonest, name)) = by_expiry.pop_first() else { break };
+                self.entries.remove_if(&name, |_, e| e.expires == soonest);
+            },
+        }
+        by_expiry.insert((expires, key.clone()));
+        self.entries.insert(key, GlueEntry { ips: ips.to_vec(), expires });
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Smallest TTL among the answer's address records
+fn answer_ttl(parsed: &packet::DnsPacket) -> Option<u32> {
+    parsed.answers.iter()
+        .filter(|r| r.ip_addr().is_some())
+        .map(|r| r.ttl)
+        .min()
+}
+
+// ============================================================
+// Per-zone Resolution Stats — which TLDs are slow / busy
+// ============================================================
+
+#[derive(Debug, Clone)]
+struct ZoneStats {
+    resolutions: u64,
+    failures: u64,
+    total_depth: u64,
+    total_latency: Duration,
+    last_seen: Instant,
+}
+
+impl ZoneStats {
+    fn to_json(&self, zone: &str) -> serde_json::Value {
+        let n = self.resolutions.max(1) as f64;
+        serde_json::json!({
+            "zone": zone,
+            "resolutions": self.resolutions,
+            "failures": self.failures,
+            "avg_depth": format!("{:.1}", self.total_depth as f64 / n),
+            "avg_latency_ms": format!("{:.1}", self.total_latency.as_secs_f64() * 1000.0 / n),
+        })
+    }
+}
+
+/// Depth reached and queries sent per resolve(), for tuning max_depth
+/// (nekonsd_recursion_depth histogram)
+pub struct DepthStats {
+    /// Resolutions that ended at each depth, 0..=max_depth
+    by_depth: Vec<AtomicU64>,
+    max_depth_reached: AtomicU64,
+    outbound_queries: AtomicU64,
+}
+
+impl DepthStats {
+    fn new(max_depth: u32) -> Self {
+        Self {
+            by_depth: (0..=max_depth).map(|_| AtomicU64::new(0)).collect(),
+            max_depth_reached: AtomicU64::new(0),
+            outbound_queries: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, depth: u32, queries: u64, hit_max: bool) {
+        let bucket = (depth as usize).min(self.by_depth.len() - 1);
+        self.by_depth[bucket].fetch_add(1, Ordering::Relaxed);
+        self.outbound_queries.fetch_add(queries, Ordering::Relaxed);
+        if hit_max {
+            self.max_depth_reached.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// (depth, resolutions that ended at or below it), one per depth 0..=max_depth
+    pub fn cumulative(&self) -> Vec<(u32, u64)> {
+        let mut total = 0;
+        self.by_depth.iter().enumerate()
+            .map(|(depth, n)| {
+                total += n.load(Ordering::Relaxed);
+                (depth as u32, total)
+            })
+            .collect()
+    }
+
+    /// Sum of the depths of every recorded resolution
+    pub fn depth_sum(&self) -> u64 {
+        self.by_depth.iter().enumerate()
+            .map(|(depth, n)| depth as u64 * n.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn max_depth_reached(&self) -> u64 {
+        self.max_depth_reached.load(Ordering::Relaxed)
+    }
+
+    pub fn outbound_queries(&self) -> u64 {
+        self.outbound_queries.load(Ordering::Relaxed)
+    }
+}
+
+/// Aggregation key for a qname: its TLD ("." for the root itself)
+fn stats_zone(qname: &str) -> String {
+    qname.trim_end_matches('.').rsplit('.').next()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_lowercase())
+        .unwrap_or_else(|| ".".to_string())
+}
+
 // ============================================================
 // Socket Pool — pre-bound UDP sockets to eliminate syscall overhead
 // ============================================================
 
 struct SocketPool {
     available: tokio::sync::Mutex<Vec<UdpSocket>>,
+    /// IPv6 sockets for AAAA-glue servers (kept apart so each family reuses its own)
+    available_v6: tokio::sync::Mutex<Vec<UdpSocket>>,
     pool_size: usize,
+    dscp: Option<u8>,
+    /// Query types sent over TCP straight away (predictably large answers)
+    tcp_first_types: Vec<RecordType>,
+    /// Source ports picked from (recursive.source_port_min / max)
+    ports: std::ops::RangeInclusive<u16>,
+    /// connect() each socket to the server queried, so the kernel drops
+    /// datagrams from any other source (those never reach the spoof monitor)
+    connect: bool,
+    /// Local addresses to send from (None = OS choice)
+    source_v4: Option<IpAddr>,
+    source_v6: Option<IpAddr>,
+    /// Resolver tap (debug.query_tap), attached after construction
+    tap: std::sync::OnceLock<Arc<QueryTap>>,
+    /// Rejected-response counters, attached after construction
+    spoof: std::sync::OnceLock<Arc<SpoofMonitor>>,
+    /// What a TCP answer still carrying TC becomes (tcp_still_truncated); used as is when unset
+    still_truncated: std::sync::OnceLock<Arc<StillTruncated>>,
 }
 
 impl SocketPool {
-    fn new(pool_size: usize) -> Self {
+    fn new(pool_size: usize, dscp: Option<u8>, tcp_first_types: Vec<RecordType>) -> Self {
         // Lazy init — sockets allocated on first acquire, returned to pool after use
         Self {
             available: tokio::sync::Mutex::new(Vec::with_capacity(pool_size)),
+            available_v6: tokio::sync::Mutex::new(Vec::new()),
             pool_size,
+            dscp,
+            tcp_first_types,
+            ports: DEFAULT_SOURCE_PORTS,
+            connect: false,
+            source_v4: None,
+            source_v6: None,
+            tap: std::sync::OnceLock::new(),
+            spoof: std::sync::OnceLock::new(),
+            still_truncated: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// Send from these local addresses; ones that can't be bound are dropped with a warning
+    fn with_source(mut self, v4: Option<Ipv4Addr>, v6: Option<Ipv6Addr>) -> Self {
+        self.source_v4 = crate::source_addr::checked(v4.map(IpAddr::V4), "recursive");
+        self.source_v6 = crate::source_addr::checked(v6.map(IpAddr::V6), "recursive (IPv6)");
+        self
+    }
+
+    /// connect() sockets to the queried server (recursive.connect_udp_sockets)
+    /// Pick source ports from `min..=max` only (firewalled hosts); warns when the range
+    /// is too narrow to make source ports hard to guess (RFC 5452 §10)
+    fn with_port_range(mut self, min: u16, max: u16) -> Self {
+        let width = usize::from(max.saturating_sub(min)) + 1;
+        if width < MIN_SOURCE_PORT_RANGE {
+            warn!(
+                "🌲 Source port range {}-{} has only {} ports; fewer than {} makes spoofed answers easier to land",
+                min, max, width, MIN_SOURCE_PORT_RANGE
+            );
         }
+        self.ports = min..=max;
+        self
     }
 
-    async fn acquire_or_create(&self) -> anyhow::Result<(UdpSocket, bool)> {
+    fn with_connected_sockets(mut self, connect: bool) -> Self {
+        self.connect = connect;
+        self
+    }
+
+    fn source_for(&self, dest: &SocketAddr) -> Option<IpAddr> {
+        if dest.is_ipv6() { self.source_v6 } else { self.source_v4 }
+    }
+
+    fn family_pool(&self, ipv6: bool) -> &tokio::sync::Mutex<Vec<UdpSocket>> {
+        if ipv6 { &self.available_v6 } else { &self.available }
+    }
+
+    async fn acquire_or_create(&self, dest: &SocketAddr) -> anyhow::Result<(UdpSocket, bool)> {
         {
-            let mut pool = self.available.lock().await;
+            let mut pool = self.family_pool(dest.is_ipv6()).lock().await;
             if let Some(s) = pool.pop() {
                 return Ok((s, true));
             }
         }
-        // Pool empty/exhausted — create with CSPRNG port (RFC 5452)
+        // Pool empty/exhausted — create with CSPRNG port (RFC 5452), retrying
+        // collisions within the range rather than falling back to an OS-picked port
         use rand::rngs::OsRng;
         use rand::Rng;
-        let src_port: u16 = OsRng.gen_range(49152..=65535);
-        let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), src_port);
-        let socket = match UdpSocket::bind(bind_addr).await {
-            Ok(s) => s,
-            Err(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        let local = crate::source_addr::bind_ip(self.source_for(dest), dest);
+        let mut attempt = 0;
+        let socket = loop {
+            let src_port: u16 = OsRng.gen_range(self.ports.clone());
+            match UdpSocket::bind(SocketAddr::new(local, src_port)).await {
+                Ok(s) => break s,
+                Err(e) if attempt + 1 >= SOURCE_PORT_BIND_ATTEMPTS => {
+                    return Err(anyhow::anyhow!(
+                        "No free source port in {}-{}: {}", self.ports.start(), self.ports.end(), e
+                    ));
+                }
+                Err(_) => attempt += 1,
+            }
         };
+        if let Some(dscp) = self.dscp {
+            crate::dscp::apply(&socket, dscp);
+        }
         Ok((socket, false))
     }
 
+    /// Throw away datagrams that arrived after their query was given up on, so a
+    /// reused socket starts empty and its backlog can't eat the next query's read budget
+    fn drain_stale(socket: &UdpSocket) -> usize {
+        let mut buf = [0u8; 512];
+        let mut drained = 0;
+        while socket.try_recv_from(&mut buf).is_ok() {
+            drained += 1;
+        }
+        drained
+    }
+
     async fn release(&self, socket: UdpSocket) {
-        let mut pool = self.available.lock().await;
+        let ipv6 = socket.local_addr().map(|a| a.is_ipv6()).unwrap_or(false);
+        let mut pool = self.family_pool(ipv6).lock().await;
         if pool.len() < self.pool_size {
             pool.push(socket);
         }
@@ -186,6 +567,75 @@ impl SocketPool {
     }
 }
 
+// ============================================================
+// Prober — bounded-concurrency infra probes
+// ============================================================
+
+/// What warmup, re-probe and TLD priming share: the socket pool, the probe
+/// timeout and one semaphore capping how many probes are in flight at once
+/// (recursive.probe_concurrency)
+#[derive(Clone)]
+struct Prober {
+    pool: Arc<SocketPool>,
+    slots: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl Prober {
+    fn new(pool: Arc<SocketPool>, concurrency: usize, timeout: Duration) -> Self {
+        Self { pool, slots: Arc::new(Semaphore::new(concurrency.max(1))), timeout }
+    }
+
+    /// One query, sent once a probe slot is free
+    async fn query(&self, qname: &str, qtype: RecordType, server: SocketAddr, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+        let _slot = self.slots.acquire().await?;
+        RecursiveResolver::send_query_pooled(&self.pool, qname, qtype, server, timeout).await
+    }
+}
+
+// ============================================================
+// Resolution slots — recursive.max_concurrent_resolutions
+// ============================================================
+
+/// A resolve() turned away because max_concurrent_resolutions were already running
+#[derive(Debug)]
+pub struct ResolverBusy;
+
+impl std::fmt::Display for ResolverBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many recursive resolutions in progress")
+    }
+}
+
+impl std::error::Error for ResolverBusy {}
+
+/// One permit per resolve() in progress; past the cap a resolution fails fast
+/// instead of queueing behind the others (no semaphore when unlimited)
+struct ResolutionSlots {
+    slots: Option<Arc<Semaphore>>,
+    max: usize,
+    rejected: AtomicU64,
+}
+
+impl ResolutionSlots {
+    fn new(max: usize) -> Self {
+        Self { slots: (max > 0).then(|| Arc::new(Semaphore::new(max))), max, rejected: AtomicU64::new(0) }
+    }
+
+    /// A slot for one resolution, held until the permit is dropped
+    fn try_acquire(&self) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, ResolverBusy> {
+        let Some(slots) = &self.slots else { return Ok(None) };
+        slots.clone().try_acquire_owned().map(Some).map_err(|_| {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            ResolverBusy
+        })
+    }
+
+    fn in_flight(&self) -> usize {
+        self.slots.as_ref().map_or(0, |s| self.max - s.available_permits())
+    }
+}
+
 // ============================================================
 // Root Server Info
 // ============================================================
@@ -200,23 +650,55 @@ pub struct RootServer {
 // Recursive Resolver — the core engine
 // ============================================================
 
+#[derive(Clone)]
 pub struct RecursiveResolver {
     root_servers: Vec<RootServer>,
     config: RecursiveConfig,
-    glue_cache: Arc<RwLock<HashMap<String, Vec<IpAddr>>>>,
+    /// Addresses resolved for glue-less NS names (TTL-expired, bounded)
+    glue_cache: Arc<GlueCache>,
     /// Jacobson/Karels RTT tracking per authority server IP
     infra_cache: Arc<DashMap<IpAddr, RttInfo>>,
     /// Zone delegation cache (skip root/TLD for known zones)
     deleg_cache: Arc<DashMap<String, DelegEntry>>,
     /// Pre-allocated UDP socket pool
     socket_pool: Arc<SocketPool>,
+    /// Warmup / re-probe / priming queries (shares socket_pool)
+    prober: Prober,
+    /// Forwarders used for glue-less NS names (ns_resolution_via_upstream)
+    upstream: Arc<UpstreamManager>,
+    /// Per-TLD resolution counts / depth / latency
+    zone_stats: Arc<DashMap<String, ZoneStats>>,
+    /// Depth histogram and outbound query count over every resolve()
+    depth_stats: Arc<DepthStats>,
+    /// Set once the root warmup got an answer from at least one root server
+    ready: Arc<AtomicBool>,
+    /// Set when the warmup finished without any root answering (cleared by a successful re-probe)
+    roots_unreachable: Arc<AtomicBool>,
+    /// Offline mode: warmup and re-probes send nothing while set
+    offline: Arc<AtomicBool>,
+    /// Caps concurrent resolve() calls (max_concurrent_resolutions)
+    resolutions: Arc<ResolutionSlots>,
+    /// How save_infra_cache compresses the file (persist.compression)
+    compression: Compression,
 }
 
 impl RecursiveResolver {
-    pub fn new(config: &RecursiveConfig) -> anyhow::Result<Self> {
+    pub fn new(config: &RecursiveConfig, upstream: Arc<UpstreamManager>) -> anyhow::Result<Self> {
         let root_servers = Self::load_root_hints(&config.root_hints_path)?;
 
-        let pool = SocketPool::new(SOCKET_POOL_SIZE);
+        let tcp_first_types = config.tcp_first_types.iter()
+            .filter_map(|t| {
+                let parsed = RecordType::from_name(t);
+                if parsed.is_none() { warn!("🌲 Unknown record type in tcp_first_types: {}", t); }
+                parsed
+            })
+            .collect();
+        let pool = Arc::new(SocketPool::new(SOCKET_POOL_SIZE, config.dscp, tcp_first_types)
+            .with_source(config.source_address, config.source_address_v6)
+            .with_port_range(config.source_port_min, config.source_port_max)
+            .with_connected_sockets(config.connect_udp_sockets));
+        let probe_timeout = Duration::from_millis(PROBE_TIMEOUT_MS.min(config.query_timeout_ms));
+        let prober = Prober::new(pool.clone(), config.probe_concurrency, probe_timeout);
 
         info!(
             "🌲 Recursive resolver: {} roots, Jacobson/Karels RTT, delegation cache, lazy socket pool (max {})",
@@ -227,53 +709,319 @@ impl RecursiveResolver {
         let resolver = Self {
             root_servers,
             config: config.clone(),
-            glue_cache: Arc::new(RwLock::new(HashMap::new())),
+            glue_cache: Arc::new(GlueCache::new(config, GLUE_CACHE_MAX)),
             infra_cache: Arc::new(DashMap::new()),
             deleg_cache: Arc::new(DashMap::new()),
-            socket_pool: Arc::new(pool),
+            socket_pool: pool,
+            prober,
+            upstream,
+            zone_stats: Arc::new(DashMap::new()),
+            depth_stats: Arc::new(DepthStats::new(config.max_depth)),
+            ready: Arc::new(AtomicBool::new(false)),
+            roots_unreachable: Arc::new(AtomicBool::new(false)),
+            offline: Arc::new(AtomicBool::new(false)),
+            resolutions: Arc::new(ResolutionSlots::new(config.max_concurrent_resolutions)),
+            compression: Compression::None,
         };
 
-        // Schedule root server RTT warm-up (runs in background)
-        let infra = resolver.infra_cache.clone();
-        let roots: Vec<SocketAddr> = resolver.root_servers.iter()
+        if config.persist_infra_cache {
+            let max_age = Duration::from_secs(config.infra_cache_max_age_secs);
+            match load_infra(&resolver.infra_cache, &config.infra_cache_path, max_age) {
+                Ok(n) => info!("🌲 Infra cache: restored {} server RTTs from {}", n, config.infra_cache_path),
+                Err(e) => warn!("🌲 Infra cache: nothing restored from {}: {}", config.infra_cache_path, e),
+            }
+        }
+
+        Ok(resolver)
+    }
+
+    /// Schedule the root server RTT warm-up and the re-probe loop (runs in background).
+    /// Both hold off while offline mode is on.
+    pub fn start_root_warmup(&self) {
+        let infra = self.infra_cache.clone();
+        let roots: Vec<SocketAddr> = self.root_servers.iter()
             .filter_map(|s| s.ipv4.map(|ip| SocketAddr::new(IpAddr::V4(ip), 53)))
             .collect();
-        let sp = resolver.socket_pool.clone();
+        let prober = self.prober.clone();
+        let ready = self.ready.clone();
+        let unreachable = self.roots_unreachable.clone();
+        let offline = self.offline.clone();
+        let reprobe_interval = Duration::from_secs(self.config.root_reprobe_interval_secs);
         tokio::spawn(async move {
-            Self::warmup_root_rtts(infra, roots, sp).await;
+            while offline.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            if Self::warmup_root_rtts(&infra, &roots, &prober).await > 0 {
+                ready.store(true, Ordering::Relaxed);
+            } else {
+                unreachable.store(true, Ordering::Relaxed);
+            }
+            if !reprobe_interval.is_zero() {
+                Self::reprobe_loop(&infra, &roots, &prober, reprobe_interval, &ready, &unreachable, &offline).await;
+            }
         });
-
-        Ok(resolver)
     }
 
     /// Probe all root servers in parallel to learn RTTs before first real query.
-    /// Sends a minimal ". NS" query to each root.
+    /// Returns how many roots answered.
     async fn warmup_root_rtts(
-        infra: Arc<DashMap<IpAddr, RttInfo>>,
-        roots: Vec<SocketAddr>,
-        pool: Arc<SocketPool>,
+        infra: &DashMap<IpAddr, RttInfo>,
+        roots: &[SocketAddr],
+        prober: &Prober,
+    ) -> u32 {
+        let probed = Self::probe_servers(infra, roots, prober).await;
+        if probed > 0 {
+            info!("🌲 Root warmup: {}/{} servers probed, recursion ready", probed, roots.len());
+        } else {
+            warn!("🌲 Root warmup: no root server answered, all roots unreachable until one does");
+        }
+        probed
+    }
+
+    /// Record every query sent to authoritatives in the resolver tap
+    pub fn with_tap(self, tap: Arc<QueryTap>) -> Self {
+        let _ = self.socket_pool.tap.set(tap);
+        self
+    }
+
+    /// Count responses rejected during the exchange (wrong source / ID / question, bad referral)
+    pub fn with_spoof_monitor(self, spoof: Arc<SpoofMonitor>) -> Self {
+        let _ = self.socket_pool.spoof.set(spoof);
+        self
+    }
+
+    /// Settle TCP answers that are still truncated here (shared with the upstream manager)
+    pub fn with_still_truncated(self, still_truncated: Arc<StillTruncated>) -> Self {
+        let _ = self.socket_pool.still_truncated.set(still_truncated);
+        self
+    }
+
+    /// Compress the saved infra cache (persist.compression)
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Share the engine's offline switch (resolution.offline, /api/offline)
+    pub fn with_offline(mut self, offline: Arc<AtomicBool>) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// true once at least one root server has answered a probe.
+    /// Until then the engine forwards instead of recursing.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Skip the root warmup (tests only)
+    #[cfg(test)]
+    pub fn force_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    /// Delegate `zone` to a single server, e.g. a stub on a test port (tests only)
+    #[cfg(test)]
+    pub fn seed_delegation(&self, zone: &str, server: SocketAddr) {
+        self.deleg_cache.insert(zone.to_string(), DelegEntry {
+            ns_addrs: vec![server],
+            ns_names: vec![],
+            glue_ips: HashMap::new(),
+            created: Instant::now(),
+            ttl_secs: 3600,
+        });
+    }
+
+    /// true when the warmup gave up on every root (port 53 blocked, no route...).
+    /// The engine then skips recursion entirely (recursive.roots_unreachable).
+    pub fn roots_unreachable(&self) -> bool {
+        self.roots_unreachable.load(Ordering::Relaxed)
+    }
+
+    /// Save the infra cache to recursive.infra_cache_path (no-op unless persist_infra_cache)
+    pub fn save_infra_cache(&self) {
+        if !self.config.persist_infra_cache {
+            return;
+        }
+        match save_infra(&self.infra_cache, &self.config.infra_cache_path, self.compression) {
+            Ok(n) => debug!("🌲 Infra cache: saved {} server RTTs to {}", n, self.config.infra_cache_path),
+            Err(e) => warn!("🌲 Infra cache: saving to {} failed: {}", self.config.infra_cache_path, e),
+        }
+    }
+
+    /// Save the infra cache every infra_cache_save_interval_secs
+    pub async fn run_infra_cache_saver(&self) {
+        if !self.config.persist_infra_cache || self.config.infra_cache_save_interval_secs == 0 {
+            return;
+        }
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.infra_cache_save_interval_secs));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.save_infra_cache();
+        }
+    }
+
+    /// Periodically re-probe roots and zone servers not contacted recently,
+    /// so RTTs don't go stale and servers unreachable at boot can recover
+    /// (which also flips `ready` if the warmup found no root).
+    async fn reprobe_loop(
+        infra: &DashMap<IpAddr, RttInfo>,
+        roots: &[SocketAddr],
+        prober: &Prober,
+        interval: Duration,
+        ready: &AtomicBool,
+        unreachable: &AtomicBool,
+        offline: &AtomicBool,
     ) {
+        loop {
+            // ±10% jitter so a fleet doesn't re-probe in lockstep
+            let jitter = { use rand::rngs::OsRng; use rand::Rng; OsRng.gen_range(0.9..1.1) };
+            tokio::time::sleep(interval.mul_f64(jitter)).await;
+            if offline.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            let targets = Self::reprobe_targets(infra, roots, interval);
+            let probed = Self::probe_servers(infra, &targets, prober).await;
+            debug!("🌲 Infra re-probe: {}/{} servers answered", probed, targets.len());
+            if probed > 0 && !ready.swap(true, Ordering::Relaxed) {
+                unreachable.store(false, Ordering::Relaxed);
+                info!("🌲 Root servers reachable, recursion ready");
+            }
+        }
+    }
+
+    /// Roots always, plus known servers idle for longer than `stale_after`
+    fn reprobe_targets(infra: &DashMap<IpAddr, RttInfo>, roots: &[SocketAddr], stale_after: Duration) -> Vec<SocketAddr> {
+        let mut targets = roots.to_vec();
+        let stale: Vec<SocketAddr> = infra.iter()
+            .filter(|e| e.value().last_seen.elapsed() >= stale_after)
+            .map(|e| SocketAddr::new(*e.key(), 53))
+            .filter(|addr| !roots.contains(addr))
+            .take(REPROBE_MAX_ZONE_SERVERS)
+            .collect();
+        targets.extend(stale);
+        targets
+    }
+
+    /// Send a minimal ". NS" probe to each server in parallel (at most
+    /// probe_concurrency in flight), retrying failures with capped, jittered
+    /// exponential backoff. Returns how many servers answered.
+    async fn probe_servers(
+        infra: &DashMap<IpAddr, RttInfo>,
+        servers: &[SocketAddr],
+        prober: &Prober,
+    ) -> u32 {
         let mut set = JoinSet::new();
-        for addr in roots.iter().copied() {
-            let pl = pool.clone();
+        for addr in servers.iter().copied() {
+            let prober = prober.clone();
             set.spawn(async move {
-                let start = Instant::now();
-                let probe_timeout = Duration::from_millis(1500);
-                let result = Self::send_query_pooled(&pl, ".", RecordType::NS, addr, probe_timeout).await;
-                let latency = start.elapsed();
-                (addr, result.is_ok(), latency)
+                for attempt in 0..PROBE_MAX_ATTEMPTS {
+                    let start = Instant::now();
+                    if prober.query(".", RecordType::NS, addr, prober.timeout).await.is_ok() {
+                        return (addr, Some(start.elapsed()));
+                    }
+                    if attempt + 1 < PROBE_MAX_ATTEMPTS {
+                        tokio::time::sleep(Self::probe_backoff(attempt)).await;
+                    }
+                }
+                (addr, None)
             });
         }
         let mut probed = 0u32;
-        while let Some(Ok((addr, ok, latency))) = set.join_next().await {
-            if ok {
-                infra.entry(addr.ip()).or_insert_with(RttInfo::new).update(latency.as_millis() as i32);
-                probed += 1;
-            } else {
-                infra.entry(addr.ip()).or_insert_with(RttInfo::new).lost(UNKNOWN_SERVER_NICENESS);
+        while let Some(Ok((addr, latency))) = set.join_next().await {
+            match latency {
+                Some(latency) => {
+                    infra.entry(addr.ip()).or_insert_with(RttInfo::new).update(latency.as_millis() as i32);
+                    probed += 1;
+                }
+                None => {
+                    let orig_rto = infra.get(&addr.ip()).map(|r| r.rto).unwrap_or(UNKNOWN_SERVER_NICENESS);
+                    infra.entry(addr.ip()).or_insert_with(RttInfo::new).lost(orig_rto);
+                }
             }
         }
-        info!("🌲 Root warmup: {}/{} servers probed", probed, roots.len());
+        probed
+    }
+
+    /// base·2^attempt plus up to 50% jitter, capped
+    fn probe_backoff(attempt: u32) -> Duration {
+        use rand::rngs::OsRng;
+        use rand::Rng;
+        let exp = PROBE_BACKOFF_BASE_MS.saturating_mul(1 << attempt.min(16)).min(PROBE_BACKOFF_CAP_MS);
+        let jitter = OsRng.gen_range(0..=exp / 2);
+        Duration::from_millis((exp + jitter).min(PROBE_BACKOFF_CAP_MS))
+    }
+
+    /// Answer a root priming query (". NS") directly from the loaded root hints
+    pub fn root_hints_response(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Self::build_root_hints_response(query, &self.root_servers)
+    }
+
+    /// Referral to the closest delegation of `qname` we have cached, with its glue
+    /// (for RD=0 clients: recursive.iterative_referrals). Nothing is resolved; with no
+    /// cached delegation the root hints are the referral.
+    pub fn referral_response(&self, query: &[u8], qname: &str) -> anyhow::Result<Vec<u8>> {
+        let name = qname.trim_end_matches('.').to_lowercase();
+        let labels: Vec<&str> = name.split('.').collect();
+        for i in 0..labels.len() {
+            let zone = labels[i..].join(".");
+            let Some(entry) = self.deleg_cache.get(&zone) else { continue };
+            if entry.is_expired() || entry.ns_names.is_empty() {
+                continue;
+            }
+            let ttl = entry.ttl_secs.saturating_sub(entry.created.elapsed().as_secs()).max(1) as u32;
+            let mut response = packet::parse_packet(query)?;
+            response.header.qr = true;
+            response.header.aa = false;
+            response.header.tc = false;
+            response.header.rcode = ResponseCode::NoError;
+            response.answers.clear();
+            response.authorities.clear();
+            response.additionals.retain(|r| r.rtype == RecordType::OPT);
+            let mut glue_records = Vec::new();
+            for ns in &entry.ns_names {
+                let ns = ns.trim_end_matches('.').to_lowercase();
+                response.authorities.push(packet::DnsRecord::new(&zone, RecordType::NS, ttl, packet::encode_name(&ns)));
+                let glue = entry.glue_ips.get(&ns).cloned().or_else(|| self.glue_cache.get(&ns)).unwrap_or_default();
+                for ip in glue {
+                    let (rtype, rdata) = match ip {
+                        IpAddr::V4(v4) => (RecordType::A, v4.octets().to_vec()),
+                        IpAddr::V6(v6) => (RecordType::AAAA, v6.octets().to_vec()),
+                    };
+                    glue_records.push(packet::DnsRecord::new(&ns, rtype, ttl, rdata));
+                }
+            }
+            response.additionals.splice(0..0, glue_records);
+            debug!("🗺️ Referral for {} from cached delegation {}", qname, zone);
+            return Ok(response.to_wire());
+        }
+        let mut parsed = packet::parse_packet(&Self::build_root_hints_response(query, &self.root_servers)?)?;
+        parsed.authorities = std::mem::take(&mut parsed.answers);
+        Ok(parsed.to_wire())
+    }
+
+    fn build_root_hints_response(query: &[u8], roots: &[RootServer]) -> anyhow::Result<Vec<u8>> {
+        let mut response = packet::parse_packet(query)?;
+        response.header.qr = true;
+        response.header.aa = false;
+        response.header.tc = false;
+        response.header.ra = true;
+        response.header.rcode = ResponseCode::NoError;
+        response.answers.clear();
+        response.authorities.clear();
+        response.additionals.clear();
+
+        let mut roots: Vec<&RootServer> = roots.iter().collect();
+        roots.sort_by(|a, b| a.name.cmp(&b.name));
+        for root in roots {
+            response.answers.push(packet::DnsRecord::new("", RecordType::NS, ROOT_HINTS_TTL, packet::encode_name(&root.name)));
+            if let Some(ip) = root.ipv4 {
+                response.additionals.push(packet::DnsRecord::new(&root.name, RecordType::A, ROOT_HINTS_TTL, ip.octets().to_vec()));
+            }
+        }
+        Ok(response.to_wire())
     }
 
     fn load_root_hints(path: &str) -> anyhow::Result<Vec<RootServer>> {
@@ -332,15 +1080,72 @@ impl RecursiveResolver {
             }
         }
 
-        let root_addrs: Vec<SocketAddr> = self.root_servers.iter()
+        (self.root_addrs(), ".".to_string(), 0)
+    }
+
+    fn root_addrs(&self) -> Vec<SocketAddr> {
+        self.root_servers.iter()
             .filter_map(|s| s.ipv4.map(|ip| SocketAddr::new(IpAddr::V4(ip), 53)))
-            .collect();
-        (root_addrs, ".".to_string(), 0)
+            .collect()
+    }
+
+    /// Fetch the root → TLD delegation for each of `prime_tlds` up front, so the
+    /// first query under a common TLD skips the root round trip. Returns how many were primed.
+    pub async fn prime_tlds(&self) -> usize {
+        let roots = self.select_servers_by_rtt(&self.root_addrs(), 3);
+        self.prime_tlds_from(&roots).await
     }
 
-    fn store_delegation(&self, zone: &str, ns_names: &[String], ns_addrs: &[SocketAddr], glue_records: &[(String, Vec<IpAddr>)]) {
+    async fn prime_tlds_from(&self, servers: &[SocketAddr]) -> usize {
+        let timeout = Duration::from_millis(self.config.query_timeout_ms);
+        let mut primed = 0;
+        for tld in &self.config.prime_tlds {
+            let tld = tld.trim_matches('.').to_lowercase();
+            if tld.is_empty() { continue; }
+            // First root that hands back the referral wins
+            for server in servers {
+                let response = match self.prober.query(&tld, RecordType::NS, *server, timeout).await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        debug!("🗺️ Priming {} via {} failed: {}", tld, server, e);
+                        continue;
+                    }
+                };
+                if let DfsResult::Referral { ns_names, ns_addrs, zone, glue_records, ttl } =
+                    Self::classify_response(&response, &tld, RecordType::NS)
+                {
+                    if zone.trim_end_matches('.').eq_ignore_ascii_case(&tld) && !ns_addrs.is_empty() {
+                        self.store_delegation(&zone, &ns_names, &ns_addrs, &glue_records, ttl);
+                        primed += 1;
+                        break;
+                    }
+                }
+            }
+        }
+        info!("🗺️ Primed {}/{} TLD delegations", primed, self.config.prime_tlds.len());
+        primed
+    }
+
+    /// Resolve the NS of each zone, one at a time, which caches its delegation and
+    /// glue (and the ones above) on the way (prefetch.prime_from_history). Returns how
+    /// many resolved.
+    pub async fn prime_zones(&self, zones: &[String], curiosity: &CuriosityCache, journey: &JourneyTracker) -> usize {
+        let mut primed = 0;
+        for zone in zones {
+            match self.resolve(zone, RecordType::NS, curiosity, journey).await {
+                Ok(_) => primed += 1,
+                Err(e) => debug!("🗺️ Priming {} failed: {}", zone, e),
+            }
+        }
+        info!("🗺️ Primed {}/{} zones from the query history", primed, zones.len());
+        primed
+    }
+
+    /// Cache a referral for its NS/glue TTL, clamped to deleg_min/max_ttl_secs (0 = don't cache)
+    fn store_delegation(&self, zone: &str, ns_names: &[String], ns_addrs: &[SocketAddr], glue_records: &[(String, Vec<IpAddr>)], ttl: u32) {
         let zone_key = zone.trim_end_matches('.').to_lowercase();
-        if zone_key.is_empty() { return; }
+        let ttl_secs = (ttl as u64).min(self.config.deleg_max_ttl_secs).max(self.config.deleg_min_ttl_secs);
+        if zone_key.is_empty() || ttl_secs == 0 { return; }
 
         let mut glue_ips = HashMap::new();
         for (name, ips) in glue_records {
@@ -352,10 +1157,21 @@ impl RecursiveResolver {
             ns_names: ns_names.to_vec(),
             glue_ips,
             created: Instant::now(),
-            ttl_secs: DELEG_CACHE_TTL_SECS,
+            ttl_secs,
         });
     }
 
+    /// Remember NS addresses resolved for a glue-less delegation, so the next
+    /// name under that zone starts there instead of re-walking from the root
+    fn add_delegation_addrs(&self, zone: &str, addrs: &[SocketAddr]) {
+        let zone_key = zone.trim_end_matches('.').to_lowercase();
+        if let Some(mut entry) = self.deleg_cache.get_mut(&zone_key) {
+            for addr in addrs {
+                if !entry.ns_addrs.contains(addr) { entry.ns_addrs.push(*addr); }
+            }
+        }
+    }
+
     // ============================================================
     // RTT-Band Server Selection (Unbound's algorithm)
     // ============================================================
@@ -364,34 +1180,90 @@ impl RecursiveResolver {
     /// 1. Score all servers by Jacobson/Karels RTT (lower = faster)
     /// 2. Find minimum score
     /// 3. All servers within min + RTT_BAND are candidates
-    /// 4. Random select from candidates
+    /// 4. Random select from candidates (a uniquely best server always stays in)
     fn select_servers_by_rtt(&self, servers: &[SocketAddr], max_count: usize) -> Vec<SocketAddr> {
-        if servers.is_empty() { return vec![]; }
+        use rand::rngs::OsRng;
+        Self::select_from_band(self.score_servers(servers), max_count, &mut OsRng)
+    }
 
-        let mut scored: Vec<(SocketAddr, i32)> = servers.iter()
+    /// select_servers_by_rtt for a hop of `qname`; with recursive.trace_selection the
+    /// decision goes into the journey as a SELECT step
+    fn select_servers_traced(&self, servers: &[SocketAddr], max_count: usize, qname: &str, zone: &str, journey: &JourneyTracker) -> Vec<SocketAddr> {
+        if !self.config.trace_selection {
+            return self.select_servers_by_rtt(servers, max_count);
+        }
+        use rand::rngs::OsRng;
+        let scored = self.score_servers(servers);
+        let chosen = Self::select_from_band(scored.clone(), max_count, &mut OsRng);
+        journey.add_step(qname, zone, "SELECT", &Self::describe_selection(&scored, &chosen));
+        chosen
+    }
+
+    /// Quarantined servers are left out, unless that would leave none
+    fn score_servers(&self, servers: &[SocketAddr]) -> Vec<(SocketAddr, i32)> {
+        let usable: Vec<SocketAddr> = servers.iter()
+            .copied()
+            .filter(|addr| !self.infra_cache.get(&addr.ip()).is_some_and(|r| r.is_quarantined()))
+            .collect();
+        let servers = if usable.is_empty() { servers } else { &usable };
+        servers.iter()
             .map(|&addr| {
                 let score = self.infra_cache.get(&addr.ip())
                     .map(|r| r.selection_score())
                     .unwrap_or(UNKNOWN_SERVER_NICENESS);
                 (addr, score)
             })
+            .collect()
+    }
+
+    /// "band<=LIMIT: addr=score(chosen|band|out) ...", best score first
+    fn describe_selection(scored: &[(SocketAddr, i32)], chosen: &[SocketAddr]) -> String {
+        let mut scored = scored.to_vec();
+        scored.sort_by_key(|&(_, s)| s);
+        let Some(&(_, min_score)) = scored.first() else { return "no servers".to_string() };
+        let limit = Self::band_limit(min_score);
+        let mut candidates: Vec<String> = scored.iter()
+            .take(TRACE_SELECTION_MAX_CANDIDATES)
+            .map(|&(addr, score)| {
+                let verdict = if chosen.contains(&addr) { "chosen" } else if score <= limit { "band" } else { "out" };
+                format!("{}={}({})", addr, score, verdict)
+            })
             .collect();
+        if scored.len() > TRACE_SELECTION_MAX_CANDIDATES {
+            candidates.push(format!("+{} more", scored.len() - TRACE_SELECTION_MAX_CANDIDATES));
+        }
+        format!("band<={}: {}", limit, candidates.join(" "))
+    }
+
+    /// Highest score still inside the band: narrow for known-fast, wide for unknown
+    fn band_limit(min_score: i32) -> i32 {
+        min_score + if min_score < 100 { 200 } else { RTT_BAND_MS }
+    }
 
+    /// Band selection over pre-scored servers; tests pass a seeded RNG to make it reproducible
+    fn select_from_band(mut scored: Vec<(SocketAddr, i32)>, max_count: usize, rng: &mut impl rand::Rng) -> Vec<SocketAddr> {
+        if scored.is_empty() || max_count == 0 { return vec![]; }
+
+        // Stable: equal scores keep their input order
         scored.sort_by_key(|&(_, s)| s);
 
         let min_score = scored[0].1;
-        // Adaptive band: narrow for known-fast, wide for unknown
-        let band = if min_score < 100 { 200 } else { RTT_BAND_MS };
-        let band_limit = min_score + band;
+        let band_limit = Self::band_limit(min_score);
 
         let mut candidates: Vec<SocketAddr> = scored.iter()
             .filter(|&&(_, s)| s <= band_limit)
             .map(|&(addr, _)| addr)
             .collect();
 
-        {
-            use rand::rngs::OsRng;
-            candidates.shuffle(&mut OsRng);
+        candidates.shuffle(rng);
+        // A server strictly faster than the rest must not be shuffled out by truncate
+        let clearly_best = scored.get(1).is_none_or(|&(_, second)| second > min_score);
+        if clearly_best && candidates.len() > max_count {
+            let best = scored[0].0;
+            if let Some(pos) = candidates.iter().position(|&a| a == best).filter(|&pos| pos >= max_count) {
+                let slot = rng.gen_range(0..max_count);
+                candidates.swap(slot, pos);
+            }
         }
         candidates.truncate(max_count);
         candidates
@@ -403,7 +1275,12 @@ impl RecursiveResolver {
 
     fn record_timeout(&self, addr: &SocketAddr) {
         let orig_rto = self.infra_cache.get(&addr.ip()).map(|r| r.rto).unwrap_or(UNKNOWN_SERVER_NICENESS);
-        self.infra_cache.entry(addr.ip()).or_insert_with(RttInfo::new).lost(orig_rto);
+        let mut rtt = self.infra_cache.entry(addr.ip()).or_insert_with(RttInfo::new);
+        rtt.lost(orig_rto);
+        if self.config.quarantine_secs > 0 && rtt.timeout_count >= MAX_TIMEOUT_COUNT && !rtt.is_quarantined() {
+            debug!("🚑 Quarantining {} for {}s after {} timeouts", addr.ip(), self.config.quarantine_secs, rtt.timeout_count);
+            rtt.quarantined_until = Some(Instant::now() + Duration::from_secs(self.config.quarantine_secs));
+        }
     }
 
     // ============================================================
@@ -417,8 +1294,11 @@ impl RecursiveResolver {
         curiosity: &CuriosityCache,
         journey: &JourneyTracker,
     ) -> anyhow::Result<Vec<u8>> {
+        let _slot = self.resolutions.try_acquire().inspect_err(|_| {
+            debug!("🌲 Not resolving {} {}: max_concurrent_resolutions reached", qname, qtype.name());
+        })?;
         let start = Instant::now();
-        let query_id: u16 = { use rand::rngs::OsRng; use rand::Rng; OsRng.gen() };
+        let query_id = random_query_id();
 
         info!("🌲 Recursive resolve: {} {} (DFS mode)", qname, qtype.name());
         journey.start(qname);
@@ -427,11 +1307,13 @@ impl RecursiveResolver {
         let (initial_servers, initial_zone, levels_skipped) = self.find_closest_delegation(qname);
 
         // === RTT-band server selection (not random!) ===
-        let mut current_servers = self.select_servers_by_rtt(&initial_servers, 6);
+        let mut current_servers = self.select_servers_traced(&initial_servers, 6, qname, &initial_zone, journey);
         if current_servers.is_empty() { current_servers = initial_servers; }
 
         let mut zone = initial_zone;
         let start_depth = if levels_skipped > 0 { 1 } else { 0 };
+        // A cached delegation whose NS may have gone stale: refetched from the parent once
+        let mut cached_zone = (levels_skipped > 0 && self.config.refetch_failed_delegations).then(|| zone.clone());
 
         journey.add_step(qname, &zone,
             if levels_skipped > 0 { "DELEG_CACHE" } else { "ROOT" },
@@ -442,11 +1324,18 @@ impl RecursiveResolver {
         let mut depth = start_depth;
         let max_depth = self.config.max_depth;
         let mut final_response: Option<Vec<u8>> = None;
+        let mut queries_sent = 0u64;
+        let mut hit_max_depth = false;
+        // Name being asked about: qname, or where its CNAMEs led (their records in `chain`)
+        let mut target = qname.to_string();
+        let mut chain: Vec<packet::DnsRecord> = Vec::new();
+        let mut restarts = 0;
 
         loop {
             if depth >= max_depth {
                 warn!("🌲 Max depth {} for {}", max_depth, qname);
                 journey.add_step(qname, &zone, "MAX_DEPTH", "depth limit");
+                hit_max_depth = true;
                 break;
             }
 
@@ -467,7 +1356,8 @@ impl RecursiveResolver {
 
             debug!("🌲 Depth {}: {} servers for {} (zone: {})", depth, servers_to_try.len(), qname, zone);
 
-            let results = self.parallel_dfs_query(qname, qtype, &servers_to_try, depth).await;
+            queries_sent += servers_to_try.len() as u64;
+            let results = self.parallel_dfs_query(&target, qtype, &servers_to_try, depth).await;
 
             let mut best_result: Option<(DfsResult, f64)> = None;
 
@@ -488,8 +1378,8 @@ impl RecursiveResolver {
                             &format!("→ {} ({} NS, {:.1}ms)", new_zone, ns_names.len(), latency.as_millis()));
                         for (name, ips) in glue_records { curiosity.store_glue(name, ips); }
                         // Cache delegation for future queries
-                        if let DfsResult::Referral { ns_names: n, ns_addrs: a, zone: z, glue_records: g } = result {
-                            self.store_delegation(z, n, a, g);
+                        if let DfsResult::Referral { ns_names: n, ns_addrs: a, zone: z, glue_records: g, ttl } = result {
+                            self.store_delegation(z, n, a, g, *ttl);
                         }
                         if best_result.is_none() { best_result = Some((result.clone(), score)); }
                     }
@@ -503,17 +1393,35 @@ impl RecursiveResolver {
             }
 
             match best_result {
-                Some((DfsResult::Answer(response), _)) => { final_response = Some(response); break; }
+                Some((DfsResult::Answer(response), _)) => {
+                    let Some((cnames, next)) = Self::dangling_cname(&response, &target, qtype).filter(|_| restarts < MAX_CNAME_RESTARTS) else {
+                        final_response = Some(response);
+                        break;
+                    };
+                    // The CNAME target may be in another TLD altogether: its walk starts
+                    // over from the closest delegation we have for it, not from this zone
+                    restarts += 1;
+                    chain.extend(cnames);
+                    let (next_servers, next_zone, skipped) = self.find_closest_delegation(&next);
+                    journey.add_step(qname, &next_zone, "CNAME",
+                        &format!("{} → {}, restarting at {}", target, next, next_zone));
+                    current_servers = self.select_servers_traced(&next_servers, 6, qname, &next_zone, journey);
+                    if current_servers.is_empty() { current_servers = next_servers; }
+                    zone = next_zone;
+                    cached_zone = (skipped > 0 && self.config.refetch_failed_delegations).then(|| zone.clone());
+                    target = next;
+                    depth += 1;
+                }
                 Some((DfsResult::NxDomain(response), _)) => { final_response = Some(response); break; }
-                Some((DfsResult::Referral { ns_names, ns_addrs, zone: new_zone, glue_records }, _)) => {
+                Some((DfsResult::Referral { ns_names, ns_addrs, zone: new_zone, glue_records, .. }, _)) => {
                     zone = new_zone;
                     let mut next_servers = ns_addrs.clone();
 
                     // Resolve missing NS IPs from caches
                     for ns_name in &ns_names {
                         let ns_lower = ns_name.to_lowercase();
-                        if let Some(ips) = self.glue_cache.read().get(&ns_lower) {
-                            for ip in ips {
+                        if let Some(ips) = self.glue_cache.get(&ns_lower) {
+                            for ip in &ips {
                                 let addr = SocketAddr::new(*ip, 53);
                                 if !next_servers.contains(&addr) { next_servers.push(addr); }
                             }
@@ -526,35 +1434,8 @@ impl RecursiveResolver {
                     }
 
                     // Parallel NS resolution if needed
-                    if next_servers.is_empty() && depth + 1 < max_depth {
-                        let ns_to_resolve: Vec<&String> = ns_names.iter().take(3).collect();
-                        match ns_to_resolve.len() {
-                            3 => {
-                                let (r1, r2, r3) = tokio::join!(
-                                    self.resolve_ns_address(ns_to_resolve[0], curiosity, journey),
-                                    self.resolve_ns_address(ns_to_resolve[1], curiosity, journey),
-                                    self.resolve_ns_address(ns_to_resolve[2], curiosity, journey),
-                                );
-                                for r in [r1, r2, r3] {
-                                    if let Ok(ips) = r { for ip in ips { next_servers.push(SocketAddr::new(ip, 53)); } }
-                                }
-                            }
-                            2 => {
-                                let (r1, r2) = tokio::join!(
-                                    self.resolve_ns_address(ns_to_resolve[0], curiosity, journey),
-                                    self.resolve_ns_address(ns_to_resolve[1], curiosity, journey),
-                                );
-                                for r in [r1, r2] {
-                                    if let Ok(ips) = r { for ip in ips { next_servers.push(SocketAddr::new(ip, 53)); } }
-                                }
-                            }
-                            1 => {
-                                if let Ok(ips) = self.resolve_ns_address(ns_to_resolve[0], curiosity, journey).await {
-                                    for ip in ips { next_servers.push(SocketAddr::new(ip, 53)); }
-                                }
-                            }
-                            _ => {}
-                        }
+                    if next_servers.is_empty() && depth + 1 < max_depth {
+                        next_servers = self.resolve_ns_names(&ns_names, curiosity, journey).await;
                     }
 
                     if next_servers.is_empty() {
@@ -562,9 +1443,12 @@ impl RecursiveResolver {
                         journey.add_step(qname, &zone, "DEAD_END", "NS resolution failed");
                         break;
                     }
+                    if ns_addrs.is_empty() {
+                        self.add_delegation_addrs(&zone, &next_servers);
+                    }
 
                     // RTT-band selection for next round
-                    current_servers = self.select_servers_by_rtt(&next_servers, 6);
+                    current_servers = self.select_servers_traced(&next_servers, 6, qname, &zone, journey);
                     if current_servers.is_empty() {
                         use rand::rngs::OsRng;
                         current_servers = next_servers;
@@ -580,6 +1464,19 @@ impl RecursiveResolver {
                     }
                 }
                 _ => {
+                    if let Some(stale) = cached_zone.take().filter(|z| *z == zone) {
+                        // Evict it and ask the parent again; its referral refills the cache
+                        self.deleg_cache.remove(&stale);
+                        let (parent_servers, parent_zone, parent_skipped) = self.find_closest_delegation(&stale);
+                        warn!("🌲 All cached NS for {} failed, re-fetching the delegation from {}", stale, parent_zone);
+                        journey.add_step(qname, &parent_zone, "DELEG_REFETCH",
+                            &format!("cached NS for {} unreachable, asking the parent", stale));
+                        current_servers = self.select_servers_traced(&parent_servers, 6, qname, &parent_zone, journey);
+                        if current_servers.is_empty() { current_servers = parent_servers; }
+                        zone = parent_zone;
+                        depth = if parent_skipped > 0 { 1 } else { 0 };
+                        continue;
+                    }
                     warn!("🌲 All branches failed for {} at depth {}", qname, depth);
                     journey.add_step(qname, &zone, "ALL_FAILED", "all branches failed");
                     break;
@@ -589,6 +1486,12 @@ impl RecursiveResolver {
 
         let elapsed = start.elapsed();
         journey.finish(qname, elapsed);
+        Self::record_zone_stats(&self.zone_stats, qname, depth, elapsed, final_response.is_some());
+        self.depth_stats.record(depth, queries_sent, hit_max_depth);
+
+        if let (Some(response), false) = (&final_response, chain.is_empty()) {
+            final_response = Some(Self::prepend_chain(response, qname, chain)?);
+        }
 
         match final_response {
             Some(response) => {
@@ -630,7 +1533,7 @@ impl RecursiveResolver {
             match Self::send_query_pooled(&self.socket_pool, qname, qtype, addr, timeout).await {
                 Ok(response) => {
                     let latency = start.elapsed();
-                    let result = Self::classify_response(&response, qname);
+                    let result = Self::classify_response(&response, qname, qtype);
                     if !matches!(result, DfsResult::Error(_)) {
                         self.record_rtt(&addr, latency.as_millis() as i32);
                     }
@@ -666,7 +1569,7 @@ impl RecursiveResolver {
                 match Self::send_query_pooled(&pl, &name, qt, addr, timeout).await {
                     Ok(response) => {
                         let latency = start.elapsed();
-                        let result = Self::classify_response(&response, &name);
+                        let result = Self::classify_response(&response, &name, qt);
                         (result, latency, addr)
                     }
                     Err(e) => {
@@ -720,31 +1623,75 @@ impl RecursiveResolver {
         addr: SocketAddr,
         timeout: Duration,
     ) -> anyhow::Result<Vec<u8>> {
-        use rand::rngs::OsRng;
-        use rand::Rng;
+        let start = Instant::now();
+        let result = Self::exchange_pooled(pool, qname, qtype, addr, timeout).await;
+        if let Some(tap) = pool.tap.get() {
+            tap.record("recursive", addr, qname, qtype, &result, start.elapsed());
+        }
+        result
+    }
 
-        let query_id: u16 = OsRng.gen();
+    async fn exchange_pooled(
+        pool: &SocketPool,
+        qname: &str,
+        qtype: RecordType,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<u8>> {
+        let query_id = random_query_id();
         let query = packet::build_query(query_id, qname, qtype, false);
 
-        let (socket, from_pool) = pool.acquire_or_create().await?;
+        if pool.tcp_first_types.contains(&qtype) {
+            debug!("🌲 {} {} → TCP first ({})", qname, qtype.name(), addr);
+            return Self::exchange_tcp(pool, &query, addr, timeout).await;
+        }
+
+        let (socket, from_pool) = pool.acquire_or_create(&addr).await?;
+        if pool.connect {
+            // Re-targets a pooled socket too; whatever is already queued still needs the drain
+            socket.connect(addr).await?;
+        }
+        if from_pool {
+            let drained = SocketPool::drain_stale(&socket);
+            if drained > 0 {
+                debug!("🌲 Dropped {} stale datagram(s) queued on a pooled socket", drained);
+            }
+        }
 
         let result = async {
-            socket.send_to(&query, addr).await?;
+            if pool.connect {
+                socket.send(&query).await?;
+            } else {
+                socket.send_to(&query, addr).await?;
+            }
+            let deadline = tokio::time::Instant::now() + timeout;
             let mut buf = vec![0u8; 4096];
-            // Try up to 3 reads to handle stale data from pooled sockets
-            for _attempt in 0..3 {
-                let len = tokio::time::timeout(timeout, socket.recv(&mut buf))
-                    .await
-                    .map_err(|_| anyhow::anyhow!("Timeout querying {}", addr))??;
-                if len >= 2 {
-                    let resp_id = u16::from_be_bytes([buf[0], buf[1]]);
-                    if resp_id == query_id {
-                        return Ok(buf[..len].to_vec());
+            let mut rejected = None;
+            // Skip late replies that raced the drain and anything spoofed, until the real answer
+            for _attempt in 0..spoof::MAX_REJECTED_DATAGRAMS {
+                let (len, peer) = match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                    Ok(received) => received?,
+                    Err(_) => break,
+                };
+                let reason = if peer != addr {
+                    SpoofReason::WrongSource
+                } else if len < 12 || u16::from_be_bytes([buf[0], buf[1]]) != query_id {
+                    SpoofReason::WrongId
+                } else {
+                    match spoof::check_response(&buf[..len], qname, qtype) {
+                        Ok(()) => return Ok(buf[..len].to_vec()),
+                        Err(reason) => reason,
                     }
-                    // Stale response from previous query — try again
+                };
+                if let Some(monitor) = pool.spoof.get() {
+                    monitor.record(reason, peer);
                 }
+                rejected = Some(reason);
+            }
+            match rejected {
+                Some(reason) => Err(anyhow::anyhow!("No valid response from {} ({})", addr, reason.describe())),
+                None => Err(anyhow::anyhow!("Timeout querying {}", addr)),
             }
-            Err(anyhow::anyhow!("No matching response from {}", addr))
         }.await;
 
         // Return socket to pool (even on error — socket itself is fine)
@@ -755,19 +1702,47 @@ impl RecursiveResolver {
             pool.release(socket).await;
         }
 
-        result
+        // TC=1 → the full answer only fits over TCP (RFC 7766)
+        match result {
+            Ok(response) if response.len() >= 3 && response[2] & 0x02 != 0 => {
+                debug!("🌲 Truncated UDP response for {} from {}, retrying over TCP", qname, addr);
+                Self::exchange_tcp(pool, &query, addr, timeout).await
+            }
+            other => other,
+        }
+    }
+
+    /// One TCP attempt, never repeated: a response still carrying TC is settled
+    /// by tcp_still_truncated instead of looping back
+    async fn exchange_tcp(pool: &SocketPool, query: &[u8], addr: SocketAddr, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+        let response = tcp::query(query, addr, timeout, pool.dscp, pool.source_for(&addr)).await?;
+        match pool.still_truncated.get() {
+            Some(still_truncated) => still_truncated.settle(response, addr),
+            None => Ok(response),
+        }
     }
 
     // ============================================================
     // Response Classification
     // ============================================================
 
-    fn classify_response(response: &[u8], qname: &str) -> DfsResult {
+    fn classify_response(response: &[u8], qname: &str, qtype: RecordType) -> DfsResult {
         let parsed = match packet::parse_packet(response) {
             Ok(p) => p,
             Err(e) => return DfsResult::Error(format!("Parse error: {}", e)),
         };
 
+        // An answer to some other question is useless at best, spoofed at worst
+        let question_matches = parsed.questions.len() == 1
+            && parsed.questions[0].qtype == qtype
+            && parsed.questions[0].name.trim_end_matches('.').eq_ignore_ascii_case(qname.trim_end_matches('.'));
+        if !question_matches {
+            let got = parsed.questions.first()
+                .map(|q| format!("{} {}", q.name, q.qtype.name()))
+                .unwrap_or_else(|| "no question".to_string());
+            return DfsResult::Error(format!("Question mismatch: asked {} {}, got {}", qname, qtype.name(), got));
+        }
+
         if parsed.header.rcode == ResponseCode::NxDomain {
             return DfsResult::NxDomain(response.to_vec());
         }
@@ -782,10 +1757,12 @@ impl RecursiveResolver {
             let mut glue_records: Vec<(String, Vec<IpAddr>)> = Vec::new();
             let mut new_zone = String::new();
             let mut has_soa = false;
+            let mut ns_ttl = u32::MAX;
 
             for record in &parsed.authorities {
                 if record.rtype == RecordType::NS {
                     if new_zone.is_empty() { new_zone = record.name.clone(); }
+                    ns_ttl = ns_ttl.min(record.ttl);
                     if let Ok(ns_name) = packet::parse_name_at_offset(response, record.rdata_offset) {
                         ns_names.push(ns_name);
                     } else if let Ok(ns_name) = packet::parse_name_from_rdata(&record.rdata, response) {
@@ -802,30 +1779,82 @@ impl RecursiveResolver {
             if ns_names.is_empty() && has_soa {
                 return DfsResult::Answer(response.to_vec());
             }
+            // Neither NODATA nor a referral (e.g. only NSEC in the authority section)
+            if ns_names.is_empty() {
+                return Self::classify_empty(&parsed, response);
+            }
 
             let mut glue_map: HashMap<String, Vec<IpAddr>> = HashMap::new();
+            for (name, ip) in parsed.glue_ips() {
+                if ns_names.iter().any(|n| n.to_lowercase() == name) {
+                    ns_addrs.push(SocketAddr::new(ip, 53));
+                }
+                glue_map.entry(name).or_default().push(ip);
+            }
             for record in &parsed.additionals {
-                if record.rtype == RecordType::A && record.rdata.len() == 4 {
-                    let ip = IpAddr::V4(Ipv4Addr::new(
-                        record.rdata[0], record.rdata[1], record.rdata[2], record.rdata[3],
-                    ));
-                    let name = record.name.to_lowercase();
-                    if ns_names.iter().any(|n| n.to_lowercase() == name) {
-                        ns_addrs.push(SocketAddr::new(ip, 53));
-                    }
-                    glue_map.entry(name).or_default().push(ip);
+                if matches!(record.rtype, RecordType::A | RecordType::AAAA)
+                    && ns_names.iter().any(|n| n.eq_ignore_ascii_case(&record.name))
+                {
+                    ns_ttl = ns_ttl.min(record.ttl);
                 }
             }
 
             for (name, ips) in glue_map { glue_records.push((name, ips)); }
             if new_zone.is_empty() { new_zone = qname.to_string(); }
 
-            return DfsResult::Referral { ns_names, ns_addrs, zone: new_zone, glue_records };
+            return DfsResult::Referral { ns_names, ns_addrs, zone: new_zone, glue_records, ttl: ns_ttl };
         }
 
+        Self::classify_empty(&parsed, response)
+    }
+
+    /// A response with no answer, SOA or NS. From the authoritative server with NOERROR
+    /// this is an empty non-terminal (a name that only exists as the ancestor of others,
+    /// e.g. _tcp.example.com), answered by a server that leaves out the SOA: NODATA, the
+    /// name exists. Anything else is a broken or lame response.
+    fn classify_empty(parsed: &packet::DnsPacket, response: &[u8]) -> DfsResult {
+        if parsed.header.aa && parsed.header.rcode == ResponseCode::NoError {
+            debug!("Empty non-terminal: NOERROR with no records, taken as NODATA");
+            return DfsResult::Answer(response.to_vec());
+        }
         DfsResult::Error("Empty response".into())
     }
 
+    /// CNAMEs an answer leads `qname` through without an answer of `qtype` at the end,
+    /// re-encoded to stand on their own, and the name left to resolve
+    fn dangling_cname(response: &[u8], qname: &str, qtype: RecordType) -> Option<(Vec<packet::DnsRecord>, String)> {
+        if qtype == RecordType::CNAME {
+            return None;
+        }
+        let parsed = packet::parse_packet(response).ok()?;
+        let mut target = qname.trim_end_matches('.').to_string();
+        let mut cnames = Vec::new();
+        while let Some(cname) = parsed.answers.iter()
+            .find(|r| r.rtype == RecordType::CNAME && r.name.trim_end_matches('.').eq_ignore_ascii_case(&target))
+        {
+            // Each CNAME is taken once, so a loop in the chain ends it
+            if cnames.len() >= parsed.answers.len() {
+                return None;
+            }
+            let next = packet::parse_name_at_offset(response, cname.rdata_offset).ok()?;
+            cnames.push(packet::DnsRecord::new(&cname.name, RecordType::CNAME, cname.ttl, packet::encode_name(&next)));
+            target = next.trim_end_matches('.').to_string();
+        }
+        let answered = parsed.answers.iter()
+            .any(|r| r.rtype == qtype && r.name.trim_end_matches('.').eq_ignore_ascii_case(&target));
+        (!cnames.is_empty() && !answered).then_some((cnames, target))
+    }
+
+    /// The answer for the end of a CNAME chain, given back as the answer to `qname`
+    fn prepend_chain(response: &[u8], qname: &str, chain: Vec<packet::DnsRecord>) -> anyhow::Result<Vec<u8>> {
+        let mut parsed = packet::parse_packet(response)?;
+        if let Some(question) = parsed.questions.first_mut() {
+            question.name = qname.to_string();
+        }
+        parsed.answers.splice(0..0, chain);
+        Ok(parsed.to_wire())
+    }
+
     fn calculate_path_score(&self, result: &DfsResult, latency: Duration, depth: u32) -> f64 {
         let latency_score = 1.0 / (1.0 + latency.as_millis() as f64 / 100.0);
         let depth_penalty = 1.0 / (1.0 + depth as f64 * 0.1);
@@ -842,6 +1871,34 @@ impl RecursiveResolver {
     // NS Address Resolution (with delegation cache + RTT)
     // ============================================================
 
+    /// Resolve up to ns_resolution_parallelism glue-less NS names at once and return
+    /// the addresses of the first one that resolves. The other lookups are left to
+    /// finish in the background, so their addresses still land in the glue cache.
+    async fn resolve_ns_names(
+        &self,
+        ns_names: &[String],
+        curiosity: &CuriosityCache,
+        journey: &JourneyTracker,
+    ) -> Vec<SocketAddr> {
+        let mut set = JoinSet::new();
+        for ns_name in ns_names.iter().take(self.config.ns_resolution_parallelism.max(1)) {
+            let resolver = self.clone();
+            let ns_name = ns_name.clone();
+            let curiosity = curiosity.clone();
+            let journey = journey.clone();
+            set.spawn(async move { resolver.resolve_ns_address(&ns_name, &curiosity, &journey).await });
+        }
+        while let Some(joined) = set.join_next().await {
+            if let Ok(Ok(ips)) = joined {
+                if !ips.is_empty() {
+                    set.detach_all();
+                    return ips.into_iter().map(|ip| SocketAddr::new(ip, 53)).collect();
+                }
+            }
+        }
+        Vec::new()
+    }
+
     async fn resolve_ns_address(
         &self,
         ns_name: &str,
@@ -850,6 +1907,14 @@ impl RecursiveResolver {
     ) -> anyhow::Result<Vec<IpAddr>> {
         debug!("🌲 Resolving NS: {}", ns_name);
 
+        // Hybrid mode: glue-less NS names go to the forwarders, recursion is the fallback
+        if self.config.ns_resolution_via_upstream {
+            if let Some((ips, ttl)) = self.resolve_ns_via_upstream(ns_name).await {
+                self.store_ns_ips(ns_name, &ips, ttl, curiosity);
+                return Ok(ips);
+            }
+        }
+
         // Use delegation cache to find closest zone
         let (initial_servers, _, _) = self.find_closest_delegation(ns_name);
         let mut current_servers = self.select_servers_by_rtt(&initial_servers, 4);
@@ -891,26 +1956,18 @@ impl RecursiveResolver {
             let mut got_referral = false;
             for qr in query_results {
                 if let Ok(response) = qr {
-                    let result = Self::classify_response(&response, ns_name);
+                    let result = Self::classify_response(&response, ns_name, RecordType::A);
                     match result {
                         DfsResult::Answer(data) => {
                             let parsed = packet::parse_packet(&data)?;
-                            let mut ips = Vec::new();
-                            for answer in &parsed.answers {
-                                if answer.rtype == RecordType::A && answer.rdata.len() == 4 {
-                                    ips.push(IpAddr::V4(Ipv4Addr::new(
-                                        answer.rdata[0], answer.rdata[1], answer.rdata[2], answer.rdata[3],
-                                    )));
-                                }
-                            }
+                            let ips = parsed.answer_ips();
                             if !ips.is_empty() {
-                                self.glue_cache.write().insert(ns_name.to_lowercase(), ips.clone());
-                                curiosity.store_glue(ns_name, &ips);
+                                self.store_ns_ips(ns_name, &ips, answer_ttl(&parsed), curiosity);
                                 return Ok(ips);
                             }
                         }
-                        DfsResult::Referral { ns_addrs, ns_names, zone, glue_records } => {
-                            self.store_delegation(&zone, &ns_names, &ns_addrs, &glue_records);
+                        DfsResult::Referral { ns_addrs, ns_names, zone, glue_records, ttl } => {
+                            self.store_delegation(&zone, &ns_names, &ns_addrs, &glue_records, ttl);
                             for (name, ips) in &glue_records { curiosity.store_glue(name, ips); }
 
                             // First try using glue addresses directly
@@ -926,8 +1983,8 @@ impl RecursiveResolver {
 
                                 for n in &ns_names {
                                     let nl = n.to_lowercase();
-                                    if let Some(ips) = self.glue_cache.read().get(&nl) {
-                                        for ip in ips { resolved_addrs.push(SocketAddr::new(*ip, 53)); }
+                                    if let Some(ips) = self.glue_cache.get(&nl) {
+                                        for ip in &ips { resolved_addrs.push(SocketAddr::new(*ip, 53)); }
                                     } else if let Some(ips) = curiosity.get_glue(n) {
                                         for ip in &ips { resolved_addrs.push(SocketAddr::new(*ip, 53)); }
                                     }
@@ -949,25 +2006,20 @@ impl RecursiveResolver {
 
                                         for srv in &try_list {
                                             if let Ok(resp) = Self::send_query_pooled(pool, ns, RecordType::A, *srv, ns_timeout).await {
-                                                let classified = Self::classify_response(&resp, ns);
+                                                let classified = Self::classify_response(&resp, ns, RecordType::A);
                                                 match classified {
                                                     DfsResult::Answer(data) => {
                                                         if let Ok(parsed) = packet::parse_packet(&data) {
-                                                            for ans in &parsed.answers {
-                                                                if ans.rtype == RecordType::A && ans.rdata.len() == 4 {
-                                                                    let ip = IpAddr::V4(Ipv4Addr::new(
-                                                                        ans.rdata[0], ans.rdata[1], ans.rdata[2], ans.rdata[3],
-                                                                    ));
-                                                                    resolved_addrs.push(SocketAddr::new(ip, 53));
-                                                                    self.glue_cache.write().insert(ns.to_lowercase(), vec![ip]);
-                                                                    curiosity.store_glue(ns, &[ip]);
-                                                                }
+                                                            let ips = parsed.answer_ips();
+                                                            if !ips.is_empty() {
+                                                                resolved_addrs.extend(ips.iter().map(|ip| SocketAddr::new(*ip, 53)));
+                                                                self.store_ns_ips(ns, &ips, answer_ttl(&parsed), curiosity);
                                                             }
                                                         }
                                                         if !resolved_addrs.is_empty() { break; }
                                                     }
-                                                    DfsResult::Referral { ns_addrs: ref_addrs, ns_names: ref_ns, zone: ref_zone, glue_records: ref_glue } => {
-                                                        self.store_delegation(&ref_zone, &ref_ns, &ref_addrs, &ref_glue);
+                                                    DfsResult::Referral { ns_addrs: ref_addrs, ns_names: ref_ns, zone: ref_zone, glue_records: ref_glue, ttl: ref_ttl } => {
+                                                        self.store_delegation(&ref_zone, &ref_ns, &ref_addrs, &ref_glue, ref_ttl);
                                                         for (gn, gips) in &ref_glue { curiosity.store_glue(gn, gips); }
                                                         // Follow one level of referral for NS resolution
                                                         let follow_servers = if !ref_addrs.is_empty() {
@@ -976,8 +2028,8 @@ impl RecursiveResolver {
                                                             // Try glue from the referral
                                                             let mut gs = Vec::new();
                                                             for rn in &ref_ns {
-                                                                if let Some(ips) = self.glue_cache.read().get(&rn.to_lowercase()) {
-                                                                    for ip in ips { gs.push(SocketAddr::new(*ip, 53)); }
+                                                                if let Some(ips) = self.glue_cache.get(rn) {
+                                                                    for ip in &ips { gs.push(SocketAddr::new(*ip, 53)); }
                                                                 } else if let Some(ips) = curiosity.get_glue(rn) {
                                                                     for ip in &ips { gs.push(SocketAddr::new(*ip, 53)); }
                                                                 }
@@ -986,17 +2038,12 @@ impl RecursiveResolver {
                                                         };
                                                         for fsrv in follow_servers.iter().take(2) {
                                                             if let Ok(resp2) = Self::send_query_pooled(pool, ns, RecordType::A, *fsrv, ns_timeout).await {
-                                                                if let DfsResult::Answer(data2) = Self::classify_response(&resp2, ns) {
+                                                                if let DfsResult::Answer(data2) = Self::classify_response(&resp2, ns, RecordType::A) {
                                                                     if let Ok(parsed2) = packet::parse_packet(&data2) {
-                                                                        for ans in &parsed2.answers {
-                                                                            if ans.rtype == RecordType::A && ans.rdata.len() == 4 {
-                                                                                let ip = IpAddr::V4(Ipv4Addr::new(
-                                                                                    ans.rdata[0], ans.rdata[1], ans.rdata[2], ans.rdata[3],
-                                                                                ));
-                                                                                resolved_addrs.push(SocketAddr::new(ip, 53));
-                                                                                self.glue_cache.write().insert(ns.to_lowercase(), vec![ip]);
-                                                                                curiosity.store_glue(ns, &[ip]);
-                                                                            }
+                                                                        let ips = parsed2.answer_ips();
+                                                                        if !ips.is_empty() {
+                                                                            resolved_addrs.extend(ips.iter().map(|ip| SocketAddr::new(*ip, 53)));
+                                                                            self.store_ns_ips(ns, &ips, answer_ttl(&parsed2), curiosity);
                                                                         }
                                                                     }
                                                                 }
@@ -1030,10 +2077,80 @@ impl RecursiveResolver {
         Err(anyhow::anyhow!("Failed to resolve NS: {}", ns_name))
     }
 
+    /// Look up an NS name's A records through the configured upstreams
+    async fn resolve_ns_via_upstream(&self, ns_name: &str) -> Option<(Vec<IpAddr>, Option<u32>)> {
+        let query = packet::build_query(rand::random(), ns_name, RecordType::A, true);
+        match self.upstream.race_query(&query).await {
+            Ok(result) => {
+                let parsed = packet::parse_packet(&result.response).ok()?;
+                let ips = parsed.answer_ips();
+                if ips.is_empty() {
+                    debug!("🌲 Upstream {} had no address for NS {}, recursing", result.upstream_name, ns_name);
+                    return None;
+                }
+                debug!("🌲 NS {} resolved via upstream {}: {:?}", ns_name, result.upstream_name, ips);
+                Some((ips, answer_ttl(&parsed)))
+            }
+            Err(e) => {
+                debug!("🌲 Upstream NS resolution failed for {}: {}, recursing", ns_name, e);
+                None
+            }
+        }
+    }
+
+    /// Fold one resolution into its TLD's aggregate, evicting the least-recently-seen TLD when full
+    fn record_zone_stats(stats: &DashMap<String, ZoneStats>, qname: &str, depth: u32, elapsed: Duration, ok: bool) {
+        let zone = stats_zone(qname);
+        if !stats.contains_key(&zone) && stats.len() >= ZONE_STATS_MAX {
+            let oldest = stats.iter()
+                .min_by_key(|e| e.value().last_seen)
+                .map(|e| e.key().clone());
+            if let Some(k) = oldest { stats.remove(&k); }
+        }
+        let mut entry = stats.entry(zone).or_insert_with(|| ZoneStats {
+            resolutions: 0,
+            failures: 0,
+            total_depth: 0,
+            total_latency: Duration::ZERO,
+            last_seen: Instant::now(),
+        });
+        entry.resolutions += 1;
+        if !ok { entry.failures += 1; }
+        entry.total_depth += depth as u64;
+        entry.total_latency += elapsed;
+        entry.last_seen = Instant::now();
+    }
+
+    /// Busiest TLDs first
+    fn top_zone_stats(stats: &DashMap<String, ZoneStats>, n: usize) -> Vec<serde_json::Value> {
+        let mut zones: Vec<(String, ZoneStats)> = stats.iter()
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+        zones.sort_by(|a, b| b.1.resolutions.cmp(&a.1.resolutions).then_with(|| a.0.cmp(&b.0)));
+        zones.truncate(n);
+        zones.iter().map(|(z, s)| s.to_json(z)).collect()
+    }
+
+    /// Remember resolved NS addresses in both the resolver glue cache and curiosity cache
+    /// (`ttl` = the address records' TTL when known)
+    fn store_ns_ips(&self, ns_name: &str, ips: &[IpAddr], ttl: Option<u32>, curiosity: &CuriosityCache) {
+        self.glue_cache.insert(ns_name, ips, ttl);
+        curiosity.store_glue(ns_name, ips);
+    }
+
     // ============================================================
     // Stats (Web UI)
     // ============================================================
 
+    /// Resolutions turned away by max_concurrent_resolutions
+    pub fn busy_rejections(&self) -> u64 {
+        self.resolutions.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn depth_stats(&self) -> &DepthStats {
+        &self.depth_stats
+    }
+
     pub fn get_stats(&self) -> serde_json::Value {
         let mut server_rtts: Vec<(String, i32, i32, u32)> = self.infra_cache.iter()
             .map(|e| (e.key().to_string(), e.value().srtt, e.value().rto, e.value().timeout_count))
@@ -1046,16 +2163,23 @@ impl RecursiveResolver {
             .collect();
 
         serde_json::json!({
+            "ready": self.is_ready(),
+            "roots_unreachable": self.roots_unreachable(),
             "root_servers": self.root_servers.len(),
-            "glue_cache_size": self.glue_cache.read().len(),
+            "glue_cache_size": self.glue_cache.len(),
             "parallel_branches": self.config.parallel_branches,
+            "max_concurrent_resolutions": self.config.max_concurrent_resolutions,
+            "resolutions_in_flight": self.resolutions.in_flight(),
+            "resolutions_rejected_busy": self.busy_rejections(),
             "max_depth": self.config.max_depth,
             "curiosity_walk": self.config.curiosity_walk,
             "infra_cache_size": self.infra_cache.len(),
+            "quarantined_servers": self.infra_cache.iter().filter(|e| e.value().is_quarantined()).count(),
             "deleg_cache_size": self.deleg_cache.len(),
             "rtt_algorithm": "Jacobson/Karels (RFC 6298)",
             "server_selection": format!("RTT-band ({}ms band)", RTT_BAND_MS),
             "top_servers": top_servers,
+            "top_zones": Self::top_zone_stats(&self.zone_stats, ZONE_STATS_TOP_N),
         })
     }
 }
@@ -1072,6 +2196,8 @@ enum DfsResult {
         ns_addrs: Vec<SocketAddr>,
         zone: String,
         glue_records: Vec<(String, Vec<IpAddr>)>,
+        /// Lowest TTL of the NS RRset and its glue
+        ttl: u32,
     },
     NxDomain(Vec<u8>),
     Error(String),
@@ -1087,3 +2213,1002 @@ impl DfsResult {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UpstreamConfig;
+
+    use std::sync::atomic::AtomicUsize;
+
+    /// Upstream for tests whose resolution never leaves the recursive resolver
+    fn unused_upstream() -> UpstreamConfig {
+        UpstreamConfig {
+            name: "unused".to_string(),
+            address: "127.0.0.1".to_string(),
+            port: 9,
+            timeout_ms: 100,
+            ..Default::default()
+        }
+    }
+
+    /// Delegation to `ns_addrs` with nothing left to resolve
+    fn delegation(ns_addrs: Vec<SocketAddr>) -> DelegEntry {
+        DelegEntry {
+            ns_addrs,
+            ns_names: vec![],
+            glue_ips: HashMap::new(),
+            created: Instant::now(),
+            ttl_secs: 300,
+        }
+    }
+
+    /// Minimal authority stub: echoes every query back with QR set
+    async fn spawn_echo_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                buf[2] |= 0x80;
+                let _ = socket.send_to(&buf[..len], peer).await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_root_ns_answered_from_hints() {
+        let roots = RecursiveResolver::load_root_hints("root.hints").unwrap();
+        let query = packet::build_query(0x0101, "", RecordType::NS, false);
+        let response = RecursiveResolver::build_root_hints_response(&query, &roots).unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+
+        assert_eq!(parsed.header.id, 0x0101);
+        assert!(parsed.header.qr);
+        assert_eq!(parsed.answers.len(), roots.len());
+        assert!(parsed.answers.iter().all(|r| r.rtype == RecordType::NS && r.name.is_empty()));
+        let first_ns = packet::parse_name_at_offset(&response, parsed.answers[0].rdata_offset).unwrap();
+        assert_eq!(first_ns, "a.root-servers.net");
+        assert_eq!(parsed.additionals[0].name, "a.root-servers.net");
+        assert_eq!(parsed.additionals[0].rdata, vec![198, 41, 0, 4]);
+    }
+
+    /// Upstream stub answering every query with a single A record (192.0.2.53)
+    async fn spawn_a_responder() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut resp = buf[..len].to_vec();
+                resp[2] |= 0x80;
+                resp[3] |= 0x80;
+                resp[6..8].copy_from_slice(&1u16.to_be_bytes());
+                resp.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4, 192, 0, 2, 53]);
+                let _ = socket.send_to(&resp, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_glueless_ns_resolved_via_upstream() {
+        let stub = spawn_a_responder().await;
+        let upstream = Arc::new(UpstreamManager::new(&[UpstreamConfig {
+            name: "stub".to_string(),
+            address: stub.ip().to_string(),
+            port: stub.port(),
+            timeout_ms: 1000,
+            ..Default::default()
+        }]).await.unwrap());
+        let config = RecursiveConfig {
+            ns_resolution_via_upstream: true,
+            root_reprobe_interval_secs: 0,
+            ..RecursiveConfig::default()
+        };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        let curiosity = CuriosityCache::new(60);
+        let journey = JourneyTracker::new(false);
+
+        let ips = resolver.resolve_ns_address("ns1.glueless.test", &curiosity, &journey).await.unwrap();
+
+        let expected: IpAddr = "192.0.2.53".parse().unwrap();
+        assert_eq!(ips, vec![expected]);
+        assert_eq!(resolver.glue_cache.get("ns1.glueless.test"), Some(vec![expected]));
+    }
+
+    #[test]
+    fn test_zone_stats_aggregate_per_tld() {
+        let stats: DashMap<String, ZoneStats> = DashMap::new();
+        RecursiveResolver::record_zone_stats(&stats, "a.example.xyz", 2, Duration::from_millis(100), true);
+        RecursiveResolver::record_zone_stats(&stats, "b.other.XYZ", 4, Duration::from_millis(300), true);
+        RecursiveResolver::record_zone_stats(&stats, "c.example.xyz", 3, Duration::from_millis(200), false);
+        RecursiveResolver::record_zone_stats(&stats, "example.com", 2, Duration::from_millis(50), true);
+
+        let xyz = stats.get("xyz").unwrap();
+        assert_eq!(xyz.resolutions, 3);
+        assert_eq!(xyz.failures, 1);
+        assert_eq!(xyz.total_depth, 9);
+        assert_eq!(xyz.total_latency, Duration::from_millis(600));
+        drop(xyz);
+
+        let top = RecursiveResolver::top_zone_stats(&stats, 1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0]["zone"], "xyz");
+        assert_eq!(top[0]["avg_depth"], "3.0");
+        assert_eq!(top[0]["avg_latency_ms"], "200.0");
+    }
+
+    #[test]
+    fn test_zone_stats_bounded() {
+        let stats: DashMap<String, ZoneStats> = DashMap::new();
+        for i in 0..ZONE_STATS_MAX + 5 {
+            RecursiveResolver::record_zone_stats(&stats, &format!("x.tld{}", i), 1, Duration::ZERO, true);
+        }
+        assert_eq!(stats.len(), ZONE_STATS_MAX);
+        assert!(stats.contains_key(&format!("tld{}", ZONE_STATS_MAX + 4)));
+    }
+
+    /// TCP authority stub: echoes each length-prefixed query back with QR set
+    async fn spawn_tcp_echo_server() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let Ok(Some(mut msg)) = tcp::read_message(&mut stream).await else { return };
+                    msg[2] |= 0x80;
+                    let _ = tcp::write_message(&mut stream, &msg).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_tcp_first_type_skips_udp() {
+        // Only a TCP listener exists at this address, so a UDP attempt could not succeed
+        let server = spawn_tcp_echo_server().await;
+        let pool = SocketPool::new(4, None, vec![RecordType::DNSKEY]);
+
+        let response = RecursiveResolver::send_query_pooled(&pool, "example.com", RecordType::DNSKEY, server, Duration::from_millis(500))
+            .await
+            .unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert!(parsed.header.qr);
+        assert_eq!(parsed.questions[0].qtype, RecordType::DNSKEY);
+        assert!(pool.available.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reprobe_refreshes_timed_out_root() {
+        let root = spawn_echo_server().await;
+        let infra: DashMap<IpAddr, RttInfo> = DashMap::new();
+        let mut timed_out = RttInfo::new();
+        for _ in 0..MAX_TIMEOUT_COUNT {
+            let rto = timed_out.rto;
+            timed_out.lost(rto);
+        }
+        assert!(timed_out.selection_score() >= TIMEOUT_PENALTY);
+        infra.insert(root.ip(), timed_out);
+
+        let pool = Arc::new(SocketPool::new(4, None, Vec::new()));
+        let prober = Prober::new(pool, 4, Duration::from_millis(PROBE_TIMEOUT_MS));
+        let probed = RecursiveResolver::probe_servers(&infra, &[root], &prober).await;
+
+        assert_eq!(probed, 1);
+        let refreshed = infra.get(&root.ip()).unwrap();
+        assert_eq!(refreshed.timeout_count, 0);
+        assert!(refreshed.selection_score() < TIMEOUT_PENALTY);
+    }
+
+    /// Echo stub that holds each answer for a while and records the peak
+    /// number of queries it had in flight
+    async fn spawn_slow_counting_server(peak: Arc<AtomicUsize>) -> SocketAddr {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut resp = buf[..len].to_vec();
+                resp[2] |= 0x80;
+                let (socket, in_flight, peak) = (socket.clone(), in_flight.clone(), peak.clone());
+                tokio::spawn(async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    let _ = socket.send_to(&resp, peer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_probe_concurrency_is_capped() {
+        let peak = Arc::new(AtomicUsize::new(0));
+        let server = spawn_slow_counting_server(peak.clone()).await;
+        let infra: DashMap<IpAddr, RttInfo> = DashMap::new();
+        let targets = vec![server; 40];
+
+        let pool = Arc::new(SocketPool::new(16, None, Vec::new()));
+        let prober = Prober::new(pool, 4, Duration::from_millis(PROBE_TIMEOUT_MS));
+        let probed = RecursiveResolver::probe_servers(&infra, &targets, &prober).await;
+
+        assert_eq!(probed, 40);
+        let peak = peak.load(Ordering::SeqCst);
+        assert!(peak <= 4, "{} probes in flight", peak);
+        assert!(peak > 1, "probes should still overlap");
+    }
+
+    /// Authority stub that answers every query for a different name
+    async fn spawn_mismatched_responder() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                resp.header.aa = true;
+                resp.questions[0].name = "evil.example".to_string();
+                resp.answers.push(packet::DnsRecord::new("evil.example", RecordType::A, 3600, vec![203, 0, 113, 66]));
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_question_rejected() {
+        let liar = spawn_mismatched_responder().await;
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+
+        let results = resolver.parallel_dfs_query("victim.example", RecordType::A, &[liar], 0).await;
+        assert_eq!(results.len(), 1);
+        assert!(matches!(&results[0].0, DfsResult::Error(e) if e.contains("mismatch")));
+
+        // Same name in a different case is still the same question
+        let query = packet::build_query(1, "Victim.Example", RecordType::A, false);
+        let mut echoed = packet::parse_packet(&query).unwrap();
+        echoed.header.qr = true;
+        echoed.answers.push(packet::DnsRecord::new("Victim.Example", RecordType::A, 60, vec![192, 0, 2, 1]));
+        let response = echoed.to_wire();
+        assert!(matches!(RecursiveResolver::classify_response(&response, "victim.example", RecordType::A), DfsResult::Answer(_)));
+        assert!(matches!(RecursiveResolver::classify_response(&response, "victim.example", RecordType::AAAA), DfsResult::Error(_)));
+    }
+
+    #[test]
+    fn test_empty_non_terminal_is_nodata() {
+        // _tcp.example.com has only descendants: NOERROR, no records at all
+        let query = packet::build_query(1, "_tcp.example.com", RecordType::A, false);
+        let mut ent = packet::parse_packet(&query).unwrap();
+        ent.header.qr = true;
+        ent.header.aa = true;
+        let response = ent.to_wire();
+        assert!(matches!(RecursiveResolver::classify_response(&response, "_tcp.example.com", RecordType::A), DfsResult::Answer(_)));
+
+        // Same, with an NSEC (type 47) but still no SOA in the authority section
+        ent.authorities.push(packet::DnsRecord::new("_tcp.example.com", RecordType::Unknown(47), 300, vec![0, 0, 6, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03]));
+        let response = ent.to_wire();
+        assert!(matches!(RecursiveResolver::classify_response(&response, "_tcp.example.com", RecordType::A), DfsResult::Answer(_)));
+
+        // Not authoritative: a lame server, not a statement about the name
+        ent.header.aa = false;
+        ent.authorities.clear();
+        let response = ent.to_wire();
+        assert!(matches!(RecursiveResolver::classify_response(&response, "_tcp.example.com", RecordType::A), DfsResult::Error(_)));
+    }
+
+    /// Referral for `zone` with one NS RRset at `ttl`, plus glue if `glue` is given
+    fn referral(qname: &str, zone: &str, ns: &str, ttl: u32, glue: Option<[u8; 4]>) -> Vec<u8> {
+        let query = packet::build_query(7, qname, RecordType::A, false);
+        let mut pkt = packet::parse_packet(&query).unwrap();
+        pkt.header.qr = true;
+        pkt.authorities.push(packet::DnsRecord::new(zone, RecordType::NS, ttl, packet::encode_name(ns)));
+        if let Some(ip) = glue {
+            pkt.additionals.push(packet::DnsRecord::new(ns, RecordType::A, ttl, ip.to_vec()));
+        }
+        pkt.to_wire()
+    }
+
+    fn store_referral(resolver: &RecursiveResolver, response: &[u8], qname: &str) {
+        let DfsResult::Referral { ns_names, ns_addrs, zone, glue_records, ttl } =
+            RecursiveResolver::classify_response(response, qname, RecordType::A)
+        else { panic!("expected referral") };
+        resolver.store_delegation(&zone, &ns_names, &ns_addrs, &glue_records, ttl);
+    }
+
+    #[tokio::test]
+    async fn test_quarantined_server_skipped_during_cooldown() {
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, quarantine_secs: 60, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        let dead: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let alive: SocketAddr = "192.0.2.2:53".parse().unwrap();
+        for _ in 0..MAX_TIMEOUT_COUNT {
+            resolver.record_timeout(&dead);
+        }
+        resolver.record_rtt(&alive, 20_000);
+
+        // Slower than the dead server's TIMEOUT_PENALTY would be, still the only pick
+        for _ in 0..20 {
+            assert_eq!(resolver.select_servers_by_rtt(&[dead, alive], 2), vec![alive]);
+        }
+        // Nothing else to ask: the quarantined server is used anyway
+        assert_eq!(resolver.select_servers_by_rtt(&[dead], 2), vec![dead]);
+        assert_eq!(resolver.get_stats()["quarantined_servers"], 1);
+
+        // Cool-down over: one more try, and a timeout puts it straight back
+        resolver.infra_cache.get_mut(&dead.ip()).unwrap().quarantined_until = Some(Instant::now() - Duration::from_secs(1));
+        assert!(resolver.select_servers_by_rtt(&[dead, alive], 2).contains(&dead));
+        resolver.record_timeout(&dead);
+        assert_eq!(resolver.select_servers_by_rtt(&[dead, alive], 2), vec![alive]);
+        // An answer lifts it
+        resolver.record_rtt(&dead, 10);
+        assert!(resolver.select_servers_by_rtt(&[dead, alive], 2).contains(&dead));
+    }
+
+    #[tokio::test]
+    async fn test_referral_response_from_cached_delegation() {
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        store_referral(&resolver, &referral("a.example.test", "example.test", "ns1.example.test", 120, Some([192, 0, 2, 7])), "a.example.test");
+
+        let query = packet::build_query(8, "deep.b.example.test", RecordType::A, false);
+        let response = resolver.referral_response(&query, "deep.b.example.test").unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert!(parsed.header.qr && !parsed.header.aa);
+        assert!(parsed.answers.is_empty());
+        assert_eq!(parsed.authorities.len(), 1);
+        assert_eq!(parsed.authorities[0].name, "example.test");
+        assert_eq!(packet::format_record(&parsed.authorities[0], &response), "ns1.example.test");
+        assert!(parsed.authorities[0].ttl <= 120);
+        assert_eq!(parsed.additionals[0].name, "ns1.example.test");
+        assert_eq!(parsed.additionals[0].rdata, vec![192, 0, 2, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_sibling_resolution_starts_at_cached_zone() {
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+
+        // Cold: the walk for a.example.test starts at the root
+        assert_eq!(resolver.find_closest_delegation("a.example.test").2, 0);
+        store_referral(&resolver, &referral("a.example.test", "example.test", "ns1.example.test", 120, Some([192, 0, 2, 7])), "a.example.test");
+
+        // The sibling skips root and TLD - two fewer referral round trips
+        let (servers, zone, skipped) = resolver.find_closest_delegation("b.example.test");
+        assert_eq!(zone, "example.test");
+        assert_eq!(skipped, 1);
+        assert_eq!(servers, vec!["192.0.2.7:53".parse::<SocketAddr>().unwrap()]);
+        // Expiry follows the NS TTL instead of a fixed lifetime
+        assert_eq!(resolver.deleg_cache.get("example.test").unwrap().ttl_secs, 120);
+
+        // TTL 0 referrals aren't cached at all
+        store_referral(&resolver, &referral("x.zero.test", "zero.test", "ns1.zero.test", 0, Some([192, 0, 2, 8])), "x.zero.test");
+        assert!(resolver.deleg_cache.get("zero.test").is_none());
+
+        // Glue-less delegation becomes usable once its NS addresses were resolved
+        store_referral(&resolver, &referral("a.glueless.test", "glueless.test", "ns.elsewhere.net", 300, None), "a.glueless.test");
+        assert_eq!(resolver.find_closest_delegation("b.glueless.test").2, 0);
+        resolver.add_delegation_addrs("glueless.test", &["192.0.2.9:53".parse().unwrap()]);
+        assert_eq!(resolver.find_closest_delegation("b.glueless.test").1, "glueless.test");
+    }
+
+    #[tokio::test]
+    async fn test_delegation_expires_with_ns_ttl() {
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream.clone()).unwrap();
+
+        store_referral(&resolver, &referral("www.ttl.test", "ttl.test", "ns1.ttl.test", 300, Some([192, 0, 2, 30])), "www.ttl.test");
+        assert_eq!(resolver.deleg_cache.get("ttl.test").unwrap().ttl_secs, 300);
+        resolver.deleg_cache.get_mut("ttl.test").unwrap().created -= Duration::from_secs(299);
+        assert_eq!(resolver.find_closest_delegation("www.ttl.test").1, "ttl.test");
+        resolver.deleg_cache.get_mut("ttl.test").unwrap().created -= Duration::from_secs(2);
+        assert_eq!(resolver.find_closest_delegation("www.ttl.test").1, ".");
+
+        // Configured bounds win over what the referral says
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, deleg_min_ttl_secs: 60, deleg_max_ttl_secs: 100, ..RecursiveConfig::default() };
+        let clamped = RecursiveResolver::new(&config, upstream).unwrap();
+        store_referral(&clamped, &referral("www.ttl.test", "ttl.test", "ns1.ttl.test", 300, Some([192, 0, 2, 30])), "www.ttl.test");
+        store_referral(&clamped, &referral("www.zero.test", "zero.test", "ns1.zero.test", 0, Some([192, 0, 2, 31])), "www.zero.test");
+        assert_eq!(clamped.deleg_cache.get("ttl.test").unwrap().ttl_secs, 100);
+        assert_eq!(clamped.deleg_cache.get("zero.test").unwrap().ttl_secs, 60);
+    }
+
+    #[test]
+    fn test_referral_ttl_includes_glue() {
+        let query = packet::build_query(7, "www.glue.test", RecordType::A, false);
+        let mut pkt = packet::parse_packet(&query).unwrap();
+        pkt.header.qr = true;
+        pkt.authorities.push(packet::DnsRecord::new("glue.test", RecordType::NS, 3600, packet::encode_name("ns1.glue.test")));
+        pkt.additionals.push(packet::DnsRecord::new("ns1.glue.test", RecordType::A, 300, vec![192, 0, 2, 40]));
+        pkt.additionals.push(packet::DnsRecord::new("unrelated.test", RecordType::A, 5, vec![192, 0, 2, 41]));
+        let DfsResult::Referral { ttl, .. } = RecursiveResolver::classify_response(&pkt.to_wire(), "www.glue.test", RecordType::A)
+        else { panic!("expected referral") };
+        assert_eq!(ttl, 300);
+    }
+
+    #[tokio::test]
+    async fn test_pool_ports_within_configured_range() {
+        let dest: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let pool = SocketPool::new(4, None, Vec::new()).with_port_range(50500, 50563);
+        let mut sockets = Vec::new();
+        for _ in 0..8 {
+            let (socket, from_pool) = pool.acquire_or_create(&dest).await.unwrap();
+            assert!(!from_pool);
+            let port = socket.local_addr().unwrap().port();
+            assert!((50500..=50563).contains(&port), "port {} outside the range", port);
+            // Held on to, so later binds have to find other free ports in the range
+            sockets.push(socket);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pool_binds_configured_source() {
+        let dest: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let pinned = SocketPool::new(4, None, Vec::new()).with_source(Some(Ipv4Addr::LOCALHOST), None);
+        let (socket, _) = pinned.acquire_or_create(&dest).await.unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        // Not a local address → warning and OS choice
+        let unbindable = SocketPool::new(4, None, Vec::new()).with_source(Some(Ipv4Addr::new(192, 0, 2, 1)), None);
+        assert_eq!(unbindable.source_v4, None);
+        let (socket, _) = unbindable.acquire_or_create(&dest).await.unwrap();
+        assert!(socket.local_addr().unwrap().ip().is_unspecified());
+    }
+
+    #[tokio::test]
+    async fn test_tap_records_outbound_query() {
+        let server = spawn_echo_server().await;
+        let tap = Arc::new(QueryTap::new(&crate::config::DebugConfig { query_tap: true, query_tap_size: Some(10), ..Default::default() }));
+        let pool = SocketPool::new(4, None, Vec::new());
+        pool.tap.set(tap.clone()).ok().unwrap();
+
+        RecursiveResolver::send_query_pooled(&pool, "tap.example.com", RecordType::AAAA, server, Duration::from_millis(500))
+            .await
+            .unwrap();
+        let recent = tap.recent();
+        let queries = recent["queries"].as_array().unwrap();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0]["via"], "recursive");
+        assert_eq!(queries[0]["server"], server.to_string());
+        assert_eq!(queries[0]["name"], "tap.example.com");
+        assert_eq!(queries[0]["type"], "AAAA");
+        assert_eq!(queries[0]["outcome"], "NoError");
+    }
+
+    /// Root stub: answers every "<tld> NS" with a referral to ns.<tld> plus glue
+    async fn spawn_root_stub() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                let tld = pkt.questions[0].name.clone();
+                let ns = format!("ns.{}", tld);
+                pkt.header.qr = true;
+                pkt.authorities.push(packet::DnsRecord::new(&tld, RecordType::NS, 172800, packet::encode_name(&ns)));
+                pkt.additionals.push(packet::DnsRecord::new(&ns, RecordType::A, 172800, vec![192, 0, 2, 53]));
+                let _ = socket.send_to(&pkt.to_wire(), peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_primed_tlds_in_delegation_cache() {
+        let root = spawn_root_stub().await;
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig {
+            root_reprobe_interval_secs: 0,
+            prime_tlds: vec!["com".to_string(), ".JP.".to_string()],
+            ..RecursiveConfig::default()
+        };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+
+        assert_eq!(resolver.prime_tlds_from(&[root]).await, 2);
+        let (servers, zone, skipped) = resolver.find_closest_delegation("www.example.com");
+        assert_eq!(zone, "com");
+        assert_eq!(skipped, 2);
+        assert_eq!(servers, vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53)), 53)]);
+        assert_eq!(resolver.find_closest_delegation("example.jp").1, "jp");
+        assert_eq!(resolver.find_closest_delegation("example.net").1, ".");
+    }
+
+    #[test]
+    fn test_best_server_always_selected() {
+        use rand::SeedableRng;
+        let addr = |i: u8| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)), 53);
+        // One clearly best server plus nine in the same band
+        let scored: Vec<(SocketAddr, i32)> = std::iter::once((addr(10), 150))
+            .chain((1..=9).map(|i| (addr(i), 300)))
+            .collect();
+
+        for seed in 0..200 {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let selected = RecursiveResolver::select_from_band(scored.clone(), 2, &mut rng);
+            assert_eq!(selected.len(), 2);
+            assert!(selected.contains(&addr(10)), "seed {} picked {:?}", seed, selected);
+        }
+
+        // Same seed → same pick
+        let pick = |seed| RecursiveResolver::select_from_band(scored.clone(), 3, &mut rand::rngs::StdRng::seed_from_u64(seed));
+        assert_eq!(pick(7), pick(7));
+        // Out-of-band servers never make it in
+        let far = vec![(addr(1), 20), (addr(2), 5000)];
+        assert_eq!(RecursiveResolver::select_from_band(far, 2, &mut rand::rngs::StdRng::seed_from_u64(1)), vec![addr(1)]);
+    }
+
+    #[test]
+    fn test_glue_cache_expires_and_stays_bounded() {
+        let config = RecursiveConfig { deleg_min_ttl_secs: 0, deleg_max_ttl_secs: 3600, ..RecursiveConfig::default() };
+        let cache = GlueCache::new(&config, 100);
+        let ip: IpAddr = "192.0.2.53".parse().unwrap();
+
+        cache.insert("NS1.example.net", &[ip], Some(300));
+        assert_eq!(cache.get("ns1.example.net"), Some(vec![ip]));
+        // TTL 0 glue is never kept
+        cache.insert("ns0.example.net", &[ip], Some(0));
+        assert_eq!(cache.get("ns0.example.net"), None);
+
+        // Expired entries are not returned and get dropped
+        cache.entries.get_mut("ns1.example.net").unwrap().expires = Instant::now() - Duration::from_secs(1);
+        assert_eq!(cache.get("ns1.example.net"), None);
+        assert_eq!(cache.len(), 0);
+
+        for i in 0..1000 {
+            cache.insert(&format!("ns{}.example.org", i), &[ip], Some(60 + i));
+        }
+        assert_eq!(cache.len(), 100);
+        // Re-inserting a name replaces its place in the expiry order
+        cache.insert("ns999.example.org", &[ip], Some(3000));
+        assert_eq!(cache.by_expiry.lock().len(), 100);
+        // The longest-lived entries survive
+        assert_eq!(cache.get("ns999.example.org"), Some(vec![ip]));
+        assert_eq!(cache.get("ns0.example.org"), None);
+    }
+
+    #[tokio::test]
+    async fn test_infra_cache_restored_across_restart() {
+        let path = std::env::temp_dir().join(format!("neko-dns-infra-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let fast: SocketAddr = "198.51.100.1:53".parse().unwrap();
+        let slow: SocketAddr = "198.51.100.2:53".parse().unwrap();
+        let old: SocketAddr = "198.51.100.3:53".parse().unwrap();
+
+        let infra: DashMap<IpAddr, RttInfo> = DashMap::new();
+        for (addr, ms) in [(fast, 15), (slow, 900)] {
+            let mut rtt = RttInfo::new();
+            rtt.update(ms);
+            infra.insert(addr.ip(), rtt);
+        }
+        assert_eq!(save_infra(&infra, &path, Compression::None).unwrap(), 2);
+        // An entry last seen two days ago ages out on load
+        let mut snapshot: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        snapshot["servers"].as_array_mut().unwrap().push(serde_json::json!({
+            "ip": old.ip(), "srtt": 5, "rttvar": 2, "rto": 50, "timeout_count": 0,
+            "last_seen": unix_now() - 2 * 86400,
+        }));
+        std::fs::write(&path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig {
+            persist_infra_cache: true,
+            infra_cache_path: path.clone(),
+            root_reprobe_interval_secs: 0,
+            ..RecursiveConfig::default()
+        };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(resolver.infra_cache.get(&fast.ip()).unwrap().srtt, 15);
+        assert!(resolver.infra_cache.get(&slow.ip()).is_some());
+        assert!(resolver.infra_cache.get(&old.ip()).is_none());
+        // The restored RTTs steer selection right away: the slow server is outside the band
+        for _ in 0..20 {
+            assert_eq!(resolver.select_servers_by_rtt(&[slow, fast], 2), vec![fast]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pooled_socket_with_stale_backlog_still_resolves() {
+        let server = spawn_a_responder().await;
+        let pool = SocketPool::new(4, None, vec![]);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = socket.local_addr().unwrap();
+        pool.release(socket).await;
+
+        // Late replies from earlier, abandoned queries - more than the read budget
+        let late = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for i in 0..(spoof::MAX_REJECTED_DATAGRAMS as u16 * 2) {
+            let mut stale = packet::build_query(i, "old.example.com", RecordType::A, false);
+            stale[2] |= 0x80;
+            late.send_to(&stale, local).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let response = RecursiveResolver::send_query_pooled(&pool, "example.com", RecordType::A, server, Duration::from_millis(500))
+            .await
+            .unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.questions[0].name, "example.com");
+        assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, 53]);
+        // The same socket went back to the pool
+        assert_eq!(pool.available.lock().await[0].local_addr().unwrap(), local);
+    }
+
+    /// A responder whose answer is preceded by a forged one sent from another socket
+    async fn spawn_raced_responder() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut resp = buf[..len].to_vec();
+                resp[2] |= 0x80;
+                resp[6..8].copy_from_slice(&1u16.to_be_bytes());
+                resp.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4]);
+                let mut forged = resp.clone();
+                forged.extend_from_slice(&[203, 0, 113, 66]);
+                resp.extend_from_slice(&[192, 0, 2, 53]);
+                let _ = forger.send_to(&forged, peer).await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let _ = socket.send_to(&resp, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_connected_socket_ignores_other_sources() {
+        let timeout = Duration::from_millis(500);
+        for connect in [false, true] {
+            let server = spawn_raced_responder().await;
+            let monitor = Arc::new(SpoofMonitor::new());
+            let pool = SocketPool::new(4, None, vec![]).with_connected_sockets(connect);
+            let _ = pool.spoof.set(monitor.clone());
+
+            let response = RecursiveResolver::send_query_pooled(&pool, "example.com", RecordType::A, server, timeout)
+                .await
+                .unwrap();
+            assert_eq!(packet::parse_packet(&response).unwrap().answers[0].rdata, vec![192, 0, 2, 53]);
+            // Unconnected, the forgery is read and rejected; connected, the kernel drops it
+            assert_eq!(monitor.count(SpoofReason::WrongSource), if connect { 0 } else { 1 });
+
+            // The pooled socket is re-connected to the next server it queries
+            let other = spawn_raced_responder().await;
+            let local = pool.available.lock().await[0].local_addr().unwrap();
+            let response = RecursiveResolver::send_query_pooled(&pool, "example.com", RecordType::A, other, timeout)
+                .await
+                .unwrap();
+            assert_eq!(packet::parse_packet(&response).unwrap().answers[0].rdata, vec![192, 0, 2, 53]);
+            assert_eq!(pool.available.lock().await[0].local_addr().unwrap(), local);
+            assert_eq!(monitor.count(SpoofReason::WrongSource), if connect { 0 } else { 2 });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dead_cached_delegation_refetched_from_parent() {
+        // Parent of example.test: answers a.* itself, refers everything else to fresh NS
+        let parent_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let parent = parent_socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = parent_socket.recv_from(&mut buf).await {
+                let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                let qname = pkt.questions[0].name.clone();
+                pkt.header.qr = true;
+                if qname.starts_with("a.") {
+                    pkt.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 300, vec![192, 0, 2, 53]));
+                } else {
+                    pkt.authorities.push(packet::DnsRecord::new("example.test", RecordType::NS, 300, packet::encode_name("ns2.example.test")));
+                    pkt.additionals.push(packet::DnsRecord::new("ns2.example.test", RecordType::A, 300, vec![192, 0, 2, 77]));
+                }
+                let _ = parent_socket.send_to(&pkt.to_wire(), peer).await;
+            }
+        });
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, query_timeout_ms: 100, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        resolver.deleg_cache.insert("test".to_string(), delegation(vec![parent]));
+        resolver.deleg_cache.insert("example.test".to_string(), delegation(vec![dead]));
+        let curiosity = CuriosityCache::new(60);
+        let journey = JourneyTracker::new(false);
+
+        let response = resolver.resolve("a.example.test", RecordType::A, &curiosity, &journey).await.unwrap();
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.header.rcode, ResponseCode::NoError);
+        assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, 53]);
+        // The dead delegation is gone, so the next name goes straight to the parent
+        assert_eq!(resolver.find_closest_delegation("c.example.test").1, "test");
+
+        // A parent referral replaces the dead NS in the delegation cache
+        resolver.deleg_cache.insert("example.test".to_string(), delegation(vec![dead]));
+        resolver.resolve("b.example.test", RecordType::A, &curiosity, &journey).await.unwrap();
+        assert_eq!(resolver.deleg_cache.get("example.test").unwrap().all_addrs(), vec!["192.0.2.77:53".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_glueless_ns_names_resolved_concurrently() {
+        // Authoritative for nsz.test: every A answer takes 300ms
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let auth = socket.local_addr().unwrap();
+        let seen = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let log = seen.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                let qname = pkt.questions[0].name.clone();
+                log.lock().push(qname.clone());
+                pkt.header.qr = true;
+                pkt.header.aa = true;
+                let last = qname.as_bytes()[2] - b'0';
+                pkt.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 300, vec![192, 0, 2, last]));
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    let _ = socket.send_to(&pkt.to_wire(), peer).await;
+                });
+            }
+        });
+
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, query_timeout_ms: 1000, ns_resolution_parallelism: 4, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        resolver.seed_delegation("nsz.test", auth);
+        let curiosity = CuriosityCache::new(60);
+        let journey = JourneyTracker::new(false);
+        let names: Vec<String> = (1..=4).map(|i| format!("ns{}.nsz.test", i)).collect();
+
+        let start = Instant::now();
+        let addrs = resolver.resolve_ns_names(&names, &curiosity, &journey).await;
+        // One 300ms round, not four in a row
+        assert!(start.elapsed() < Duration::from_millis(600), "took {:?}", start.elapsed());
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(seen.lock().len(), 4);
+
+        // The lookups still running finish in the background and fill the glue cache
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(resolver.glue_cache.get(name), Some(vec![IpAddr::from([192, 0, 2, i as u8 + 1])]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_selection_records_candidate_scores() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let auth = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                pkt.header.qr = true;
+                pkt.header.aa = true;
+                let qname = pkt.questions[0].name.clone();
+                pkt.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 300, vec![192, 0, 2, 7]));
+                let _ = socket.send_to(&pkt.to_wire(), peer).await;
+            }
+        });
+        let slow: SocketAddr = "192.0.2.99:53".parse().unwrap();
+
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, query_timeout_ms: 500, trace_selection: true, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        resolver.deleg_cache.insert("trace.test".to_string(), delegation(vec![slow, auth]));
+        resolver.record_rtt(&auth, 30);
+        resolver.record_rtt(&slow, 900);
+        let curiosity = CuriosityCache::new(60);
+        let journey = JourneyTracker::new(true);
+
+        resolver.resolve("www.trace.test", RecordType::A, &curiosity, &journey).await.unwrap();
+        let trace = journey.get_latest("www.trace.test").unwrap();
+        let select = trace.steps.iter().find(|s| s.action == "SELECT").unwrap();
+        assert_eq!(select.zone, "trace.test");
+        assert_eq!(select.detail, format!("band<=290: {}=90(chosen) {}=2700(out)", auth, slow));
+    }
+
+    #[tokio::test]
+    async fn test_depth_histogram_buckets() {
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let auth = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                pkt.header.qr = true;
+                pkt.header.aa = true;
+                let qname = pkt.questions[0].name.clone();
+                pkt.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 300, vec![192, 0, 2, 7]));
+                let _ = socket.send_to(&pkt.to_wire(), peer).await;
+            }
+        });
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, query_timeout_ms: 500, max_depth: 3, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream.clone()).unwrap();
+        resolver.deleg_cache.insert("depth.test".to_string(), delegation(vec![auth]));
+        let curiosity = CuriosityCache::new(60);
+        let journey = JourneyTracker::new(false);
+
+        // Starts one level down at the cached zone and gets its answer there: depth 1
+        resolver.resolve("www.depth.test", RecordType::A, &curiosity, &journey).await.unwrap();
+        let stats = resolver.depth_stats();
+        assert_eq!(stats.cumulative(), vec![(0, 0), (1, 1), (2, 1), (3, 1)]);
+        assert_eq!(stats.depth_sum(), 1);
+        assert_eq!(stats.outbound_queries(), 1);
+        assert_eq!(stats.max_depth_reached(), 0);
+
+        // max_depth 1 stops before any query and counts as a hit limit
+        let config = RecursiveConfig { max_depth: 1, ..config };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        resolver.deleg_cache.insert("depth.test".to_string(), delegation(vec![auth]));
+        let _ = resolver.resolve("www.depth.test", RecordType::A, &curiosity, &journey).await;
+        assert_eq!(resolver.depth_stats().cumulative(), vec![(0, 0), (1, 1)]);
+        assert_eq!(resolver.depth_stats().max_depth_reached(), 1);
+        assert_eq!(resolver.depth_stats().outbound_queries(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cross_zone_cname_restarts_at_target_delegation() {
+        // Stub authoritative server logging the names it was asked
+        async fn auth(answer: fn(&str) -> packet::DnsRecord) -> (SocketAddr, Arc<parking_lot::Mutex<Vec<String>>>) {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let asked = Arc::new(parking_lot::Mutex::new(Vec::new()));
+            let log = asked.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 512];
+                while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                    let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                    pkt.header.qr = true;
+                    pkt.header.aa = true;
+                    let qname = pkt.questions[0].name.clone();
+                    log.lock().push(qname.to_lowercase());
+                    pkt.answers.push(answer(&qname));
+                    let _ = socket.send_to(&pkt.to_wire(), peer).await;
+                }
+            });
+            (addr, asked)
+        }
+        let (example_com, example_asked) = auth(|qname| {
+            packet::DnsRecord::new(qname, RecordType::CNAME, 300, packet::encode_name("www.target.org"))
+        }).await;
+        let (org, org_asked) = auth(|qname| packet::DnsRecord::new(qname, RecordType::A, 300, vec![192, 0, 2, 8])).await;
+
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, query_timeout_ms: 500, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        resolver.seed_delegation("example.com", example_com);
+        resolver.seed_delegation("org", org);
+        let curiosity = CuriosityCache::new(60);
+        let journey = JourneyTracker::new(true);
+
+        let response = resolver.resolve("www.example.com", RecordType::A, &curiosity, &journey).await.unwrap();
+        assert_eq!(*example_asked.lock(), vec!["www.example.com".to_string()]);
+        assert_eq!(*org_asked.lock(), vec!["www.target.org".to_string()]);
+
+        let parsed = packet::parse_packet(&response).unwrap();
+        assert_eq!(parsed.questions[0].name, "www.example.com");
+        assert_eq!(parsed.answers.len(), 2);
+        assert_eq!(packet::format_record(&parsed.answers[0], &response), "www.target.org");
+        assert_eq!(parsed.answers[1].name, "www.target.org");
+        assert_eq!(parsed.answers[1].rdata, vec![192, 0, 2, 8]);
+        let trace = journey.get_latest("www.example.com").unwrap();
+        let restart = trace.steps.iter().find(|s| s.action == "CNAME").unwrap();
+        assert_eq!(restart.zone, "org");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_resolutions_capped() {
+        // Authoritative for test: answers after 100ms, counting queries in flight
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let auth = socket.local_addr().unwrap();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        {
+            let (in_flight, peak) = (in_flight.clone(), peak.clone());
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 512];
+                while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                    let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                    let (socket, in_flight, peak) = (socket.clone(), in_flight.clone(), peak.clone());
+                    tokio::spawn(async move {
+                        let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        pkt.header.qr = true;
+                        pkt.header.aa = true;
+                        let qname = pkt.questions[0].name.clone();
+                        pkt.answers.push(packet::DnsRecord::new(&qname, RecordType::A, 300, vec![192, 0, 2, 9]));
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        let _ = socket.send_to(&pkt.to_wire(), peer).await;
+                    });
+                }
+            });
+        }
+
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, max_concurrent_resolutions: 2, ..RecursiveConfig::default() };
+        let resolver = Arc::new(RecursiveResolver::new(&config, upstream).unwrap());
+        resolver.deleg_cache.insert("test".to_string(), delegation(vec![auth]));
+        let curiosity = Arc::new(CuriosityCache::new(60));
+        let journey = Arc::new(JourneyTracker::new(false));
+
+        let mut resolutions = JoinSet::new();
+        for i in 0..6 {
+            let (resolver, curiosity, journey) = (resolver.clone(), curiosity.clone(), journey.clone());
+            resolutions.spawn(async move {
+                resolver.resolve(&format!("host{}.busy.test", i), RecordType::A, &curiosity, &journey).await
+            });
+        }
+        let (mut answered, mut busy) = (0, 0);
+        while let Some(result) = resolutions.join_next().await {
+            match result.unwrap() {
+                Ok(_) => answered += 1,
+                Err(e) if e.is::<ResolverBusy>() => busy += 1,
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!((answered, busy), (2, 4));
+        assert!(peak.load(Ordering::SeqCst) <= 2, "{} resolutions reached the authoritative at once", peak.load(Ordering::SeqCst));
+        assert_eq!(resolver.busy_rejections(), 4);
+        assert_eq!(resolver.get_stats()["resolutions_in_flight"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_history_zones_primed() {
+        // Parent of every *.test zone: refers each to ns1.<zone>
+        let parent_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let parent = parent_socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = parent_socket.recv_from(&mut buf).await {
+                let mut pkt = packet::parse_packet(&buf[..len]).unwrap();
+                let zone = pkt.questions[0].name.trim_end_matches('.').to_string();
+                let ns = format!("ns1.{}", zone);
+                pkt.header.qr = true;
+                pkt.authorities.push(packet::DnsRecord::new(&zone, RecordType::NS, 300, packet::encode_name(&ns)));
+                pkt.additionals.push(packet::DnsRecord::new(&ns, RecordType::A, 300, vec![192, 0, 2, zone.len() as u8]));
+                let _ = parent_socket.send_to(&pkt.to_wire(), peer).await;
+            }
+        });
+
+        let journal = crate::journal::Journal::new(&crate::config::JournalConfig {
+            enabled: true, path: None, max_entries: 100, retention_hours: 24, sample_rate: 1.0, cache_hit_sample_rate: None, always_log_errors: true,
+        }).unwrap();
+        for name in ["www.example.test", "api.example.test", "www.example.test", "mail.other.test", "once.rare.test"] {
+            journal.record_query(name, &RecordType::A, "recursive", 60, Duration::ZERO, crate::journal::JournalKind::Resolved).await;
+        }
+
+        let upstream = Arc::new(UpstreamManager::new(&[unused_upstream()]).await.unwrap());
+        let config = RecursiveConfig { root_reprobe_interval_secs: 0, query_timeout_ms: 100, ..RecursiveConfig::default() };
+        let resolver = RecursiveResolver::new(&config, upstream).unwrap();
+        resolver.deleg_cache.insert("test".to_string(), delegation(vec![parent]));
+        let curiosity = CuriosityCache::new(60);
+        let journey = JourneyTracker::new(false);
+
+        let zones = journal.top_zones(2, |_| true);
+        assert_eq!(zones, vec!["example.test", "other.test"]);
+        resolver.prime_zones(&zones, &curiosity, &journey).await;
+        // The next query under a primed zone goes straight to its servers
+        assert_eq!(resolver.find_closest_delegation("new.example.test").1, "example.test");
+        assert_eq!(resolver.deleg_cache.get("example.test").unwrap().all_addrs(), vec!["192.0.2.12:53".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(resolver.find_closest_delegation("mail.other.test").1, "other.test");
+        // Past the top N: left alone
+        assert_eq!(resolver.find_closest_delegation("once.rare.test").1, "test");
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use tracing::{debug, info, warn};
+
+use crate::cache::{self, Cache, CacheLookup, Transport};
+use crate::config::{CacheConfig, TtlAlchemyConfig};
+use crate::dns::types::RecordType;
+use crate::subnet::ClientNet;
+use crate::ttl_alchemy::TtlAlchemy;
+
+/// Redis-backed cache, shared by every neko-dns instance pointed at the same server.
+///
+/// Each entry is one hash at `{prefix}{name}:{qtype}` holding the raw response and
+/// its TTL bookkeeping. Redis expires the key once both the TTL and the stale
+/// window are over, so nothing here has to evict. Redis errors are logged and
+/// treated as misses — a cache outage must never fail resolution.
+pub struct RedisCache {
+    conn: ConnectionManager,
+    config: CacheConfig,
+    alchemy: TtlAlchemy,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Fields of one stored entry
+struct StoredEntry {
+    raw_response: Vec<u8>,
+    upstream_name: String,
+    alchemized_ttl: u64,
+    inserted_at: u64,
+}
+
+impl RedisCache {
+    pub async fn connect(config: &CacheConfig, alchemy: &TtlAlchemyConfig) -> anyhow::Result<Self> {
+        let client = redis::Client::open(config.redis.url.as_str())?;
+        let conn = client.get_connection_manager().await?;
+        info!("🐱 Cache backend: redis ({})", client.get_connection_info().addr);
+        Ok(Self {
+            conn,
+            config: config.clone(),
+            alchemy: TtlAlchemy::new(alchemy),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        })
+    }
+
+    fn key(&self, name: &str, qtype: &RecordType) -> String {
+        format!("{}{}:{}", self.config.redis.prefix, name.to_lowercase(), qtype.to_u16())
+    }
+
+    fn log_error(&self, what: &str, e: redis::RedisError) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        warn!("Redis cache {} failed: {}", what, e);
+    }
+
+    async fn load(&self, name: &str, qtype: &RecordType) -> Option<StoredEntry> {
+        let fields: HashMap<String, Vec<u8>> = match redis::cmd("HGETALL")
+            .arg(self.key(name, qtype))
+            .query_async(&mut self.conn.clone())
+            .await
+        {
+            Ok(fields) => fields,
+            Err(e) => {
+                self.log_error("lookup", e);
+                return None;
+            }
+        };
+        let number = |field: &str| -> Option<u64> {
+            std::str::from_utf8(fields.get(field)?).ok()?.parse().ok()
+        };
+        Some(StoredEntry {
+            alchemized_ttl: number("ttl")?,
+            inserted_at: number("inserted")?,
+            upstream_name: String::from_utf8_lossy(fields.get("upstream")?).into_owned(),
+            raw_response: fields.get("raw")?.clone(),
+        })
+    }
+}
+
+fn unix_now() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
+}
+
+#[async_trait]
+impl Cache for RedisCache {
+    async fn get(&self, name: &str, qtype: &RecordType) -> Option<CacheLookup> {
+        if let Some(entry) = self.load(name, qtype).await {
+            let elapsed = unix_now().saturating_sub(entry.inserted_at);
+            if elapsed < entry.alchemized_ttl {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(CacheLookup {
+                    raw_response: entry.raw_response,
+                    remaining_ttl: self.config.jittered_ttl((entry.alchemized_ttl - elapsed) as u32),
+                    ttl: entry.alchemized_ttl as u32,
+                    upstream_name: entry.upstream_name,
+                    scope: None,
+                });
+            }
+            let stale_elapsed = elapsed - entry.alchemized_ttl;
+            if self.config.serves_stale(name) && stale_elapsed < self.config.stale_ttl_secs {
+                debug!("Serving stale entry for {} {} (stale for {}s)", name, qtype.name(), stale_elapsed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(CacheLookup {
+                    raw_response: entry.raw_response,
+                    remaining_ttl: cache::stale_answer_ttl(&self.config, stale_elapsed),
+                    ttl: entry.alchemized_ttl as u32,
+                    upstream_name: format!("{} (stale)", entry.upstream_name),
+                    scope: None,
+                });
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    async fn get_stale(&self, name: &str, qtype: &RecordType) -> Option<CacheLookup> {
+        let entry = self.load(name, qtype).await?;
+        let elapsed = unix_now().saturating_sub(entry.inserted_at);
+        if elapsed < entry.alchemized_ttl || elapsed - entry.alchemized_ttl >= self.config.stale_ttl_secs {
+            return None;
+        }
+        debug!("Stale-on-error entry for {} {} (stale for {}s)", name, qtype.name(), elapsed - entry.alchemized_ttl);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(CacheLookup {
+            raw_response: entry.raw_response,
+            remaining_ttl: cache::stale_answer_ttl(&self.config, elapsed - entry.alchemized_ttl),
+            ttl: entry.alchemized_ttl as u32,
+            upstream_name: format!("{} (stale)", entry.upstream_name),
+            scope: None,
+        })
+    }
+
+    async fn insert(&self, name: &str, qtype: &RecordType, response: &[u8], upstream_name: &str, transport: Transport) {
+        if self.config.never_caches(qtype) {
+            return;
+        }
+        let sanitized;
+        let response = if self.config.strict_validation {
+            match cache::sanitize_for_cache(name, qtype, response) {
+                Some(cleaned) => {
+                    sanitized = cleaned;
+                    &sanitized[..]
+                }
+                None => return,
+            }
+        } else {
+            response
+        };
+        if response.len() > self.config.max_entry_bytes {
+            debug!("Redis cache: not storing {} {} ({} bytes > max_entry_bytes)", name, qtype.name(), response.len());
+            return;
+        }
+        let original_ttl = cache::extract_min_ttl(response).unwrap_or(300);
+        let key = self.key(name, qtype);
+        let mut conn = self.conn.clone();
+
+        // Carry hit count and volatility over from the entry being replaced
+        let previous: (Option<u64>, Option<u64>, Option<u32>) = match redis::cmd("HMGET")
+            .arg(&key).arg("hits").arg("rdata_hash").arg("rdata_changes")
+            .query_async(&mut conn)
+            .await
+        {
+            Ok(previous) => previous,
+            Err(e) => {
+                self.log_error("insert", e);
+                return;
+            }
+        };
+        let rdata_hash = cache::hash_rdata(response);
+        let hit_count = previous.0.unwrap_or(0);
+        let rdata_changes = match (previous.1, previous.2) {
+            (Some(old), changes) if old != rdata_hash => changes.unwrap_or(0) + 1,
+            (_, changes) => changes.unwrap_or(0),
+        };
+        let alchemized_ttl = cache::stored_ttl(&self.config, &self.alchemy, qtype, original_ttl, hit_count, rdata_changes);
+
+        let result: redis::RedisResult<()> = redis::pipe()
+            .cmd("HSET").arg(&key)
+                .arg("raw").arg(response)
+                .arg("upstream").arg(upstream_name)
+                .arg("transport").arg(transport.label())
+                .arg("original_ttl").arg(original_ttl)
+                .arg("ttl").arg(alchemized_ttl)
+                .arg("inserted").arg(unix_now())
+                .arg("hits").arg(hit_count)
+                .arg("rdata_hash").arg(rdata_hash)
+                .arg("rdata_changes").arg(rdata_changes)
+                .ignore()
+            .cmd("EXPIRE").arg(&key).arg(alchemized_ttl as u64 + self.config.stale_ttl_secs.max(1))
+                .ignore()
+            .query_async(&mut conn)
+            .await;
+        if let Err(e) = result {
+            self.log_error("insert", e);
+        }
+    }
+
+    async fn record_hit(&self, name: &str, qtype: &RecordType, _scope: Option<ClientNet>) {
+        let result: redis::RedisResult<i64> = redis::cmd("HINCRBY")
+            .arg(self.key(name, qtype)).arg("hits").arg(1)
+            .query_async(&mut self.conn.clone())
+            .await;
+        if let Err(e) = result {
+            self.log_error("hit count", e);
+        }
+    }
+
+    async fn flush(&self) {
+        let mut conn = self.conn.clone();
+        let pattern = format!("{}*", self.config.redis.prefix);
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = match redis::cmd("SCAN")
+                .arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(500)
+                .query_async(&mut conn)
+                .await
+            {
+                Ok(page) => page,
+                Err(e) => return self.log_error("flush", e),
+            };
+            if !keys.is_empty() {
+                if let Err(e) = redis::cmd("DEL").arg(&keys).query_async::<()>(&mut conn).await {
+                    return self.log_error("flush", e);
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+    }
+
+    fn get_stats(&self) -> serde_json::Value {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let total = hits + misses;
+        let hit_rate = if total > 0 { hits as f64 / total as f64 * 100.0 } else { 0.0 };
+
+        serde_json::json!({
+            "backend": "redis",
+            "hits": hits,
+            "misses": misses,
+            "hit_rate_percent": format!("{:.1}", hit_rate),
+            "errors": self.errors.load(Ordering::Relaxed),
+            "serve_stale": self.config.serve_stale,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheBackend;
+    use crate::dns::packet::{self, DnsRecord};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    type Store = std::sync::Arc<parking_lot::Mutex<HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>>>;
+
+    fn bulk(out: &mut Vec<u8>, v: Option<&[u8]>) {
+        match v {
+            Some(v) => {
+                out.extend_from_slice(format!("${}\r\n", v.len()).as_bytes());
+                out.extend_from_slice(v);
+                out.extend_from_slice(b"\r\n");
+            }
+            None => out.extend_from_slice(b"$-1\r\n"),
+        }
+    }
+
+    /// Just enough of the Redis protocol for RedisCache
+    fn execute(store: &Store, args: &[Vec<u8>]) -> Vec<u8> {
+        let mut db = store.lock();
+        let cmd = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let mut out = Vec::new();
+        match cmd.as_str() {
+            "HSET" => {
+                let hash = db.entry(args[1].clone()).or_default();
+                for pair in args[2..].chunks(2) {
+                    hash.insert(pair[0].clone(), pair[1].clone());
+                }
+                out.extend_from_slice(format!(":{}\r\n", args[2..].len() / 2).as_bytes());
+            }
+            "HGETALL" => {
+                let hash = db.get(&args[1]).cloned().unwrap_or_default();
+                out.extend_from_slice(format!("*{}\r\n", hash.len() * 2).as_bytes());
+                for (f, v) in &hash {
+                    bulk(&mut out, Some(f));
+                    bulk(&mut out, Some(v));
+                }
+            }
+            "HMGET" => {
+                let hash = db.get(&args[1]);
+                out.extend_from_slice(format!("*{}\r\n", args.len() - 2).as_bytes());
+                for f in &args[2..] {
+                    bulk(&mut out, hash.and_then(|h| h.get(f)).map(|v| &v[..]));
+                }
+            }
+            "HINCRBY" => {
+                let hash = db.entry(args[1].clone()).or_default();
+                let by: i64 = String::from_utf8_lossy(&args[3]).parse().unwrap();
+                let now = hash.get(&args[2]).map(|v| String::from_utf8_lossy(v).parse::<i64>().unwrap()).unwrap_or(0) + by;
+                hash.insert(args[2].clone(), now.to_string().into_bytes());
+                out.extend_from_slice(format!(":{}\r\n", now).as_bytes());
+            }
+            "EXPIRE" => out.extend_from_slice(b":1\r\n"),
+            "DEL" => {
+                let removed = args[1..].iter().filter(|k| db.remove(*k).is_some()).count();
+                out.extend_from_slice(format!(":{}\r\n", removed).as_bytes());
+            }
+            "SCAN" => {
+                let prefix = args[3].strip_suffix(b"*").unwrap().to_vec();
+                let keys: Vec<&Vec<u8>> = db.keys().filter(|k| k.starts_with(&prefix)).collect();
+                out.extend_from_slice(b"*2\r\n");
+                bulk(&mut out, Some(b"0"));
+                out.extend_from_slice(format!("*{}\r\n", keys.len()).as_bytes());
+                for k in keys {
+                    bulk(&mut out, Some(k));
+                }
+            }
+            "CLIENT" => out.extend_from_slice(b"+OK\r\n"),
+            _ => out.extend_from_slice(format!("-ERR unknown command '{}'\r\n", cmd).as_bytes()),
+        }
+        out
+    }
+
+    async fn spawn_mock_redis(store: Store) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut read = BufReader::new(read);
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        if read.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let count: usize = line.trim_end()[1..].parse().unwrap();
+                        let mut args = Vec::with_capacity(count);
+                        for _ in 0..count {
+                            line.clear();
+                            read.read_line(&mut line).await.unwrap();
+                            let len: usize = line.trim_end()[1..].parse().unwrap();
+                            let mut arg = vec![0u8; len + 2];
+                            read.read_exact(&mut arg).await.unwrap();
+                            arg.truncate(len);
+                            args.push(arg);
+                        }
+                        let reply = execute(&store, &args);
+                        if write.write_all(&reply).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_redis_cache_roundtrip() {
+        let store = Store::default();
+        let addr = spawn_mock_redis(store.clone()).await;
+        let config: CacheConfig = toml::from_str(&format!(
+            "max_entries = 100\nserve_stale = false\nserve_stale_domains = [\"example.com\"]\nstale_ttl_secs = 60\nbackend = \"redis\"\n[redis]\nurl = \"redis://{}/\"\nprefix = \"test:\"", addr
+        )).unwrap();
+        assert_eq!(config.backend, CacheBackend::Redis);
+        let cache: std::sync::Arc<dyn Cache> = cache::build(&config, &cache::alchemy(), 500).await.unwrap();
+
+        let mut parsed = packet::parse_packet(&packet::build_query(0x1234, "www.example.com", RecordType::A, true)).unwrap();
+        parsed.header.qr = true;
+        parsed.answers = vec![DnsRecord::new("www.example.com", RecordType::A, 300, vec![192, 0, 2, 1])];
+        let response = parsed.to_wire();
+
+        assert!(cache.get("www.example.com", &RecordType::A).await.is_none());
+        cache.insert("WWW.example.com", &RecordType::A, &response, "test", Transport::Udp).await;
+        let hit = cache.get("www.example.com", &RecordType::A).await.unwrap();
+        assert_eq!(hit.raw_response, response);
+        assert!(hit.remaining_ttl <= 300 && hit.remaining_ttl >= 299);
+        assert_eq!(hit.upstream_name, "test");
+
+        cache.record_hit("www.example.com", &RecordType::A, None).await;
+        let key = format!("test:www.example.com:{}", RecordType::A.to_u16()).into_bytes();
+        assert_eq!(store.lock()[&key][&b"hits".to_vec()], b"1");
+
+        let stats = cache.get_stats();
+        assert_eq!(stats["backend"], "redis");
+        assert_eq!(stats["hits"], 1);
+        assert_eq!(stats["misses"], 1);
+
+        // 10s past the TTL: served stale, since the name is under serve_stale_domains
+        let inserted = String::from_utf8(store.lock()[&key][&b"inserted".to_vec()].clone()).unwrap();
+        let backdated = (inserted.parse::<u64>().unwrap() - 310).to_string().into_bytes();
+        store.lock().get_mut(&key).unwrap().insert(b"inserted".to_vec(), backdated);
+        let stale = cache.get("www.example.com", &RecordType::A).await.unwrap();
+        assert_eq!(stale.upstream_name, "test (stale)");
+
+        store.lock().insert(b"other:key".to_vec(), HashMap::new());
+        cache.flush().await;
+        assert!(cache.get("www.example.com", &RecordType::A).await.is_none());
+        assert!(store.lock().contains_key(&b"other:key".to_vec()), "flush must stay inside the prefix");
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+/// Fixed-capacity buffer for the diagnostic logs (tap, evictions), sized by
+/// debug.ring_buffer_size. Once full, each push drops the oldest entry.
+pub struct RingBuffer<T> {
+    capacity: usize,
+    /// Newest first
+    entries: Mutex<VecDeque<T>>,
+    /// Entries pushed out to make room
+    dropped: AtomicU64,
+}
+
+impl<T: Clone> RingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Add an entry, dropping the oldest when full (a zero capacity keeps nothing)
+    pub fn push(&self, entry: T) {
+        if self.capacity == 0 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity {
+            entries.pop_back();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        entries.push_front(entry);
+    }
+
+    /// Copy of the entries, newest first
+    pub fn recent(&self) -> Vec<T> {
+        self.entries.lock().iter().cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    /// Current size, capacity and drops, for /api/stats
+    pub fn get_stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "entries": self.len(),
+            "capacity": self.capacity,
+            "dropped": self.dropped.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_dropped_past_capacity() {
+        let ring = RingBuffer::new(3);
+        for i in 1..=5 {
+            ring.push(i);
+        }
+        assert_eq!(ring.recent(), vec![5, 4, 3]);
+        assert_eq!(ring.len(), 3);
+        let stats = ring.get_stats();
+        assert_eq!(stats["capacity"], 3);
+        assert_eq!(stats["dropped"], 2);
+
+        let empty = RingBuffer::new(0);
+        empty.push("x");
+        assert_eq!(empty.len(), 0);
+    }
+}
@@ -0,0 +1,55 @@
+//! Source-address selection for outbound DNS sockets
+//!
+//! On multi-homed hosts the OS may pick an egress IP that authoritatives or
+//! upstreams don't expect, so operators can pin the local address queries
+//! are sent from. Addresses are checked once at startup; one that can't be
+//! bound is dropped with a warning and the OS choice is used instead.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tracing::warn;
+
+/// `source` if it can be bound on this host, otherwise None (with a warning)
+pub fn checked(source: Option<IpAddr>, what: &str) -> Option<IpAddr> {
+    let ip = source?;
+    match std::net::UdpSocket::bind(SocketAddr::new(ip, 0)) {
+        Ok(_) => Some(ip),
+        Err(e) => {
+            warn!("{} source address {} is not bindable ({}), letting the OS choose", what, ip, e);
+            None
+        }
+    }
+}
+
+/// Local IP to bind when talking to `dest`: the configured source if it is
+/// of the same family, otherwise the unspecified address of that family
+pub fn bind_ip(source: Option<IpAddr>, dest: &SocketAddr) -> IpAddr {
+    match source {
+        Some(ip) if ip.is_ipv6() == dest.is_ipv6() => ip,
+        _ if dest.is_ipv6() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unbindable_source_falls_back() {
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(checked(Some(loopback), "test"), Some(loopback));
+        // TEST-NET-1 is never assigned to a local interface
+        assert_eq!(checked(Some("192.0.2.1".parse().unwrap()), "test"), None);
+        assert_eq!(checked(None, "test"), None);
+    }
+
+    #[test]
+    fn test_bind_ip_matches_destination_family() {
+        let v4_source: IpAddr = "127.0.0.1".parse().unwrap();
+        let v4_dest: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let v6_dest: SocketAddr = "[2001:db8::53]:53".parse().unwrap();
+        assert_eq!(bind_ip(Some(v4_source), &v4_dest), v4_source);
+        assert_eq!(bind_ip(Some(v4_source), &v6_dest), IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        assert_eq!(bind_ip(None, &v4_dest), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    }
+}
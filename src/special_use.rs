@@ -0,0 +1,127 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::dns::packet;
+use crate::dns::types::{RecordType, ResponseCode};
+
+/// TTL of synthesized localhost answers and of the SOA on negative ones
+const SPECIAL_TTL: u32 = 300;
+
+/// Special-use names (RFC 6761) answered without resolving (listen.rfc6761)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialName {
+    /// localhost and *.localhost: loopback addresses
+    Localhost,
+    /// A loopback address under in-addr.arpa / ip6.arpa: PTR localhost
+    LoopbackReverse,
+    /// invalid and *.invalid: always NXDOMAIN
+    Invalid,
+    /// *.local belongs to mDNS (RFC 6762): REFUSED, with listen.rfc6761_refuse_local
+    Local,
+}
+
+impl SpecialName {
+    /// Journal label
+    pub fn label(&self) -> &'static str {
+        match self {
+            SpecialName::Localhost | SpecialName::LoopbackReverse => "RFC6761_LOCALHOST",
+            SpecialName::Invalid => "RFC6761_INVALID",
+            SpecialName::Local => "RFC6761_LOCAL",
+        }
+    }
+}
+
+/// The special-use name `qname` falls under, if any
+pub fn classify(qname: &str, refuse_local: bool) -> Option<SpecialName> {
+    let name = qname.trim_end_matches('.').to_lowercase();
+    let under = |zone: &str| name == zone || name.ends_with(&format!(".{}", zone));
+    if under("localhost") {
+        Some(SpecialName::Localhost)
+    } else if under("invalid") {
+        Some(SpecialName::Invalid)
+    } else if is_loopback_reverse(&name) {
+        Some(SpecialName::LoopbackReverse)
+    } else if refuse_local && under("local") {
+        Some(SpecialName::Local)
+    } else {
+        None
+    }
+}
+
+/// d.c.b.127.in-addr.arpa, or the 32 nibbles of ::1 under ip6.arpa
+fn is_loopback_reverse(name: &str) -> bool {
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let octets: Vec<&str> = labels.split('.').collect();
+        return octets.len() == 4 && octets[3] == "127" && octets.iter().all(|o| o.parse::<u8>().is_ok());
+    }
+    if let Some(labels) = name.strip_suffix(".ip6.arpa") {
+        let nibbles: Vec<&str> = labels.split('.').collect();
+        return nibbles.len() == 32 && nibbles[0] == "1" && nibbles[1..].iter().all(|n| *n == "0");
+    }
+    false
+}
+
+/// Synthesized answer for a special-use name
+pub fn special_response(query: &[u8], qtype: RecordType, special: SpecialName) -> anyhow::Result<Vec<u8>> {
+    let mut parsed = packet::parse_packet(query)?;
+    let qname = parsed.questions.first()
+        .map(|q| q.name.clone())
+        .ok_or_else(|| anyhow::anyhow!("query without a question"))?;
+    parsed.header.qr = true;
+    parsed.header.aa = true;
+    parsed.answers.clear();
+    parsed.authorities.clear();
+    match (special, qtype) {
+        (SpecialName::Local, _) => return packet::build_refused(query),
+        (SpecialName::Invalid, _) => parsed.header.rcode = ResponseCode::NxDomain,
+        (SpecialName::Localhost, RecordType::A) => {
+            parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::A, SPECIAL_TTL, Ipv4Addr::LOCALHOST.octets().to_vec()));
+        }
+        (SpecialName::Localhost, RecordType::AAAA) => {
+            parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::AAAA, SPECIAL_TTL, Ipv6Addr::LOCALHOST.octets().to_vec()));
+        }
+        (SpecialName::LoopbackReverse, RecordType::PTR) => {
+            parsed.answers.push(packet::DnsRecord::new(&qname, RecordType::PTR, SPECIAL_TTL, packet::encode_name("localhost")));
+        }
+        _ => {}
+    }
+    packet::add_negative_soa(&mut parsed, SPECIAL_TTL);
+    Ok(parsed.to_wire())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("localhost.", false), Some(SpecialName::Localhost));
+        assert_eq!(classify("app.LOCALHOST", false), Some(SpecialName::Localhost));
+        assert_eq!(classify("notlocalhost", false), None);
+        assert_eq!(classify("foo.invalid", false), Some(SpecialName::Invalid));
+        assert_eq!(classify("1.0.0.127.in-addr.arpa", false), Some(SpecialName::LoopbackReverse));
+        assert_eq!(classify("1.2.0.192.in-addr.arpa", false), None);
+        let v6 = format!("1{}.ip6.arpa", ".0".repeat(31));
+        assert_eq!(classify(&v6, false), Some(SpecialName::LoopbackReverse));
+        assert_eq!(classify("printer.local", false), None);
+        assert_eq!(classify("printer.local", true), Some(SpecialName::Local));
+        assert_eq!(classify("example.com", true), None);
+    }
+
+    #[test]
+    fn test_special_responses() {
+        let answer = |name: &str, qtype: RecordType| {
+            let query = packet::build_query(7, name, qtype, true);
+            let special = classify(name, true).unwrap();
+            packet::parse_packet(&special_response(&query, qtype, special).unwrap()).unwrap()
+        };
+        assert_eq!(answer("localhost", RecordType::AAAA).answers[0].rdata, Ipv6Addr::LOCALHOST.octets().to_vec());
+        let ptr = answer("1.0.0.127.in-addr.arpa", RecordType::PTR);
+        assert_eq!(ptr.answers[0].rdata, packet::encode_name("localhost"));
+        // Other types of localhost are NODATA
+        let mx = answer("localhost", RecordType::MX);
+        assert_eq!(mx.header.rcode, ResponseCode::NoError);
+        assert!(mx.answers.is_empty());
+        assert_eq!(mx.authorities[0].rtype, RecordType::SOA);
+        assert_eq!(answer("printer.local", RecordType::A).header.rcode, ResponseCode::Refused);
+    }
+}
@@ -0,0 +1,199 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use crate::dns::packet;
+use crate::dns::types::RecordType;
+
+/// Rejections within one window before the poisoning warning is logged
+const WARN_THRESHOLD: u64 = 10;
+/// Window for WARN_THRESHOLD (and at most one warning per window)
+const WARN_WINDOW: Duration = Duration::from_secs(60);
+/// Datagrams read while waiting for the real answer before giving up
+pub const MAX_REJECTED_DATAGRAMS: usize = 8;
+
+/// Why a received response was thrown away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpoofReason {
+    /// Transaction ID differs from the query (also late replies to an earlier query)
+    WrongId,
+    /// Came from an address/port other than the one queried
+    WrongSource,
+    /// Answers a different name or type
+    WrongQuestion,
+    /// Referral to a zone that doesn't contain the queried name
+    OutOfBailiwick,
+}
+
+impl SpoofReason {
+    pub const ALL: [SpoofReason; 4] = [
+        SpoofReason::WrongId,
+        SpoofReason::WrongSource,
+        SpoofReason::WrongQuestion,
+        SpoofReason::OutOfBailiwick,
+    ];
+
+    /// Metric label
+    pub fn label(&self) -> &'static str {
+        match self {
+            SpoofReason::WrongId => "wrong_id",
+            SpoofReason::WrongSource => "wrong_source",
+            SpoofReason::WrongQuestion => "wrong_question",
+            SpoofReason::OutOfBailiwick => "out_of_bailiwick",
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            SpoofReason::WrongId => "transaction ID mismatch",
+            SpoofReason::WrongSource => "response from unexpected address",
+            SpoofReason::WrongQuestion => "question mismatch",
+            SpoofReason::OutOfBailiwick => "out-of-bailiwick referral",
+        }
+    }
+}
+
+/// Spoofed-response monitor
+///
+/// Counts responses from authoritatives and upstreams rejected during the
+/// exchange (nekonsd_spoofed_responses_total{reason}). A burst is logged as a
+/// possible cache poisoning attempt, at most once per minute.
+pub struct SpoofMonitor {
+    /// Indexed like SpoofReason::ALL
+    counts: [AtomicU64; 4],
+    /// (window start, rejections in it, warned yet)
+    window: Mutex<(Instant, u64, bool)>,
+}
+
+impl SpoofMonitor {
+    pub fn new() -> Self {
+        Self {
+            counts: Default::default(),
+            window: Mutex::new((Instant::now(), 0, false)),
+        }
+    }
+
+    pub fn record(&self, reason: SpoofReason, from: SocketAddr) {
+        self.counts[Self::index(reason)].fetch_add(1, Ordering::Relaxed);
+        debug!("🛡️ Rejected response from {}: {}", from, reason.describe());
+
+        let mut window = self.window.lock();
+        if window.0.elapsed() >= WARN_WINDOW {
+            *window = (Instant::now(), 0, false);
+        }
+        window.1 += 1;
+        if window.1 >= WARN_THRESHOLD && !window.2 {
+            window.2 = true;
+            warn!(
+                "🛡️ {} responses rejected in the last {}s (latest from {}: {}) - possible cache poisoning attempt",
+                window.1, WARN_WINDOW.as_secs(), from, reason.describe(),
+            );
+        }
+    }
+
+    pub fn count(&self, reason: SpoofReason) -> u64 {
+        self.counts[Self::index(reason)].load(Ordering::Relaxed)
+    }
+
+    fn index(reason: SpoofReason) -> usize {
+        SpoofReason::ALL.iter().position(|r| *r == reason).unwrap_or(0)
+    }
+
+    pub fn get_stats(&self) -> serde_json::Value {
+        let by_reason: serde_json::Map<String, serde_json::Value> = SpoofReason::ALL.iter()
+            .map(|r| (r.label().to_string(), self.count(*r).into()))
+            .collect();
+        serde_json::json!({
+            "total": SpoofReason::ALL.iter().map(|r| self.count(*r)).sum::<u64>(),
+            "by_reason": by_reason,
+        })
+    }
+}
+
+/// Check a response whose ID and source already matched against the question
+/// asked. Unparseable responses pass; the caller's own parsing rejects those.
+pub fn check_response(response: &[u8], qname: &str, qtype: RecordType) -> Result<(), SpoofReason> {
+    let Ok(parsed) = packet::parse_packet(response) else { return Ok(()) };
+    let qname = qname.trim_end_matches('.');
+    let question_matches = parsed.questions.len() == 1
+        && parsed.questions[0].qtype == qtype
+        && parsed.questions[0].name.trim_end_matches('.').eq_ignore_ascii_case(qname);
+    if !question_matches {
+        return Err(SpoofReason::WrongQuestion);
+    }
+
+    // A referral may only point at the queried name or one of its ancestors
+    if parsed.answers.is_empty() {
+        let qname = qname.to_lowercase();
+        let foreign = parsed.authorities.iter()
+            .filter(|r| r.rtype == RecordType::NS)
+            .any(|r| !is_ancestor(&r.name.trim_end_matches('.').to_lowercase(), &qname));
+        if foreign {
+            return Err(SpoofReason::OutOfBailiwick);
+        }
+    }
+    Ok(())
+}
+
+/// The question of a query we built ourselves, for `check_response`
+pub fn query_question(query: &[u8]) -> Option<(String, RecordType)> {
+    let parsed = packet::parse_packet(query).ok()?;
+    parsed.questions.first().map(|q| (q.name.clone(), q.qtype))
+}
+
+fn is_ancestor(zone: &str, name: &str) -> bool {
+    zone.is_empty()
+        || name == zone
+        || (name.len() > zone.len() && name.ends_with(zone) && name.as_bytes()[name.len() - zone.len() - 1] == b'.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(qname: &str, qtype: RecordType, edit: impl FnOnce(&mut packet::DnsPacket)) -> Vec<u8> {
+        let mut pkt = packet::parse_packet(&packet::build_query(1, qname, qtype, false)).unwrap();
+        pkt.header.qr = true;
+        edit(&mut pkt);
+        pkt.to_wire()
+    }
+
+    #[test]
+    fn test_check_response_reasons() {
+        let ok = response("www.example.com", RecordType::A, |p| {
+            p.answers.push(packet::DnsRecord::new("www.example.com", RecordType::A, 300, vec![192, 0, 2, 1]));
+        });
+        assert_eq!(check_response(&ok, "WWW.Example.com.", RecordType::A), Ok(()));
+        assert_eq!(check_response(&ok, "www.example.com", RecordType::AAAA), Err(SpoofReason::WrongQuestion));
+        assert_eq!(check_response(&ok, "evil.example", RecordType::A), Err(SpoofReason::WrongQuestion));
+
+        let referral = response("www.example.com", RecordType::A, |p| {
+            p.authorities.push(packet::DnsRecord::new("example.com", RecordType::NS, 3600, packet::encode_name("ns1.example.com")));
+        });
+        assert_eq!(check_response(&referral, "www.example.com", RecordType::A), Ok(()));
+        let hijack = response("www.example.com", RecordType::A, |p| {
+            p.authorities.push(packet::DnsRecord::new("evil.example", RecordType::NS, 3600, packet::encode_name("ns1.evil.example")));
+        });
+        assert_eq!(check_response(&hijack, "www.example.com", RecordType::A), Err(SpoofReason::OutOfBailiwick));
+    }
+
+    #[test]
+    fn test_every_reason_is_counted() {
+        let monitor = SpoofMonitor::new();
+        let from: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        for (i, reason) in SpoofReason::ALL.iter().enumerate() {
+            for _ in 0..=i {
+                monitor.record(*reason, from);
+            }
+        }
+        for (i, reason) in SpoofReason::ALL.iter().enumerate() {
+            assert_eq!(monitor.count(*reason), i as u64 + 1);
+            assert_eq!(monitor.get_stats()["by_reason"][reason.label()], i as u64 + 1);
+        }
+        assert_eq!(monitor.get_stats()["total"], 10);
+        // Ten rejections in one window → warned once
+        assert!(monitor.window.lock().2);
+    }
+}
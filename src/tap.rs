@@ -0,0 +1,118 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use chrono::Utc;
+use tracing::info;
+
+use crate::config::DebugConfig;
+use crate::dns::types::{RecordType, ResponseCode};
+use crate::ring::RingBuffer;
+
+/// One outbound query as seen by the tap
+#[derive(Clone, Debug)]
+struct TapRecord {
+    /// "recursive" (send_query_pooled) or "upstream" (query_upstream)
+    via: &'static str,
+    server: SocketAddr,
+    qname: String,
+    qtype: u16,
+    /// Response rcode, or the error when no response came back
+    outcome: String,
+    latency_ms: f64,
+    sent_at: String,
+}
+
+/// Resolver tap — records every query neko-dns sends to authoritatives / upstreams.
+/// When disabled `record` returns immediately, so the hot path only pays a bool check.
+pub struct QueryTap {
+    enabled: bool,
+    log: RingBuffer<TapRecord>,
+}
+
+impl QueryTap {
+    pub fn new(config: &DebugConfig) -> Self {
+        if config.query_tap {
+            info!("🔎 Query tap enabled (keeping last {} outbound queries)", config.tap_size());
+        }
+        Self {
+            enabled: config.query_tap,
+            log: RingBuffer::new(config.tap_size()),
+        }
+    }
+
+    /// Record a query sent to `server` and what came back
+    pub fn record(
+        &self,
+        via: &'static str,
+        server: SocketAddr,
+        qname: &str,
+        qtype: RecordType,
+        result: &anyhow::Result<Vec<u8>>,
+        latency: Duration,
+    ) {
+        if !self.enabled {
+            return;
+        }
+        let outcome = match result {
+            Ok(response) if response.len() >= 4 => format!("{:?}", ResponseCode::from(response[3] & 0x0F)),
+            Ok(_) => "short response".to_string(),
+            Err(e) => format!("error: {}", e),
+        };
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        info!("🔎 tap [{}] {} {} → {}: {} ({:.1}ms)", via, qname, qtype.name(), server, outcome, latency_ms);
+
+        self.log.push(TapRecord {
+            via,
+            server,
+            qname: qname.to_string(),
+            qtype: qtype.to_u16(),
+            outcome,
+            latency_ms,
+            sent_at: Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        });
+    }
+
+    /// Same as `record`, taking the question from a raw query packet
+    pub fn record_packet(
+        &self,
+        via: &'static str,
+        server: SocketAddr,
+        query: &[u8],
+        result: &anyhow::Result<Vec<u8>>,
+        latency: Duration,
+    ) {
+        if !self.enabled {
+            return;
+        }
+        let question = crate::dns::packet::parse_packet(query).ok()
+            .and_then(|p| p.questions.into_iter().next());
+        match question {
+            Some(q) => self.record(via, server, &q.name, q.qtype, result, latency),
+            None => self.record(via, server, "?", RecordType::Unknown(0), result, latency),
+        }
+    }
+
+    /// Ring buffer size, for /api/stats
+    pub fn get_stats(&self) -> serde_json::Value {
+        self.log.get_stats()
+    }
+
+    /// Recent outbound queries for the Web UI, newest first
+    pub fn recent(&self) -> serde_json::Value {
+        let queries: Vec<serde_json::Value> = self.log.recent().iter()
+            .map(|r| serde_json::json!({
+                "via": r.via,
+                "server": r.server.to_string(),
+                "name": r.qname,
+                "type": RecordType::from(r.qtype).name(),
+                "outcome": r.outcome,
+                "latency_ms": r.latency_ms,
+                "sent_at": r.sent_at,
+            }))
+            .collect();
+        serde_json::json!({
+            "enabled": self.enabled,
+            "buffer": self.log.get_stats(),
+            "queries": queries,
+        })
+    }
+}
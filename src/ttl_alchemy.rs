@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+use tracing::warn;
+
 use crate::config::TtlAlchemyConfig;
+use crate::dns::types::RecordType;
 
 /// TTL Alchemy Engine
 /// RFC 2308 + 独自拡張: クエリ頻度と応答の変動率から動的にTTLを再計算する
@@ -8,12 +12,22 @@ use crate::config::TtlAlchemyConfig;
 /// - 時間帯による変動なし → 安定ドメインとしてTTL大幅延長
 pub struct TtlAlchemy {
     config: TtlAlchemyConfig,
+    /// type_max_ttl keyed by type code
+    type_caps: HashMap<u16, u32>,
 }
 
 impl TtlAlchemy {
     pub fn new(config: &TtlAlchemyConfig) -> Self {
+        let type_caps = config.type_max_ttl.iter()
+            .filter_map(|(name, cap)| {
+                let parsed = RecordType::from_name(name);
+                if parsed.is_none() { warn!("⚗️ Unknown record type in type_max_ttl: {}", name); }
+                parsed.map(|t| (t.to_u16(), *cap))
+            })
+            .collect();
         Self {
             config: config.clone(),
+            type_caps,
         }
     }
 
@@ -23,10 +37,23 @@ impl TtlAlchemy {
     ///   frequency_factor = log2(1 + hit_count) * frequency_weight
     ///   volatility_factor = rdata_changes * volatility_weight
     ///   alchemized_ttl = original_ttl * (1 + frequency_factor) / (1 + volatility_factor)
-    ///   result = clamp(alchemized_ttl, min_ttl, max_ttl)
-    pub fn calculate_ttl(&self, original_ttl: u32, hit_count: u64, rdata_changes: u32) -> u32 {
+    ///   result = clamp(alchemized_ttl, min_ttl, max_ttl).min(type_max_ttl[qtype])
+    pub fn calculate_ttl(&self, qtype: &RecordType, original_ttl: u32, hit_count: u64, rdata_changes: u32) -> u32 {
+        let ttl = self.alchemize(original_ttl, hit_count, rdata_changes);
+        match self.type_caps.get(&qtype.to_u16()) {
+            Some(cap) => ttl.min(*cap),
+            None => ttl,
+        }
+    }
+
+    /// The TTL kept within min_ttl / max_ttl, nothing else (cache.ttl_mode = "clamp")
+    pub fn clamp_ttl(&self, original_ttl: u32) -> u32 {
+        original_ttl.clamp(self.config.min_ttl, self.config.max_ttl)
+    }
+
+    fn alchemize(&self, original_ttl: u32, hit_count: u64, rdata_changes: u32) -> u32 {
         if !self.config.enabled {
-            return original_ttl.clamp(self.config.min_ttl, self.config.max_ttl);
+            return self.clamp_ttl(original_ttl);
         }
 
         let freq_factor = (1.0 + hit_count as f64).log2() * self.config.frequency_weight;
@@ -50,6 +77,7 @@ mod tests {
             max_ttl: 86400,
             frequency_weight: 0.3,
             volatility_weight: 0.5,
+            type_max_ttl: HashMap::new(),
         }
     }
 
@@ -57,7 +85,7 @@ mod tests {
     fn test_no_hits_no_changes() {
         let alchemy = TtlAlchemy::new(&test_config());
         // With 0 hits and 0 changes, TTL should be close to original
-        let result = alchemy.calculate_ttl(300, 0, 0);
+        let result = alchemy.calculate_ttl(&RecordType::A, 300, 0, 0);
         assert_eq!(result, 300);
     }
 
@@ -65,7 +93,7 @@ mod tests {
     fn test_high_frequency_extends_ttl() {
         let alchemy = TtlAlchemy::new(&test_config());
         // 1000 hits should significantly extend TTL
-        let result = alchemy.calculate_ttl(300, 1000, 0);
+        let result = alchemy.calculate_ttl(&RecordType::A, 300, 1000, 0);
         assert!(result > 300, "TTL should be extended: got {}", result);
     }
 
@@ -73,7 +101,7 @@ mod tests {
     fn test_high_volatility_shortens_ttl() {
         let alchemy = TtlAlchemy::new(&test_config());
         // 10 rdata changes should shorten TTL
-        let result = alchemy.calculate_ttl(300, 0, 10);
+        let result = alchemy.calculate_ttl(&RecordType::A, 300, 0, 10);
         assert!(result < 300, "TTL should be shortened: got {}", result);
     }
 
@@ -81,11 +109,11 @@ mod tests {
     fn test_ttl_clamped() {
         let alchemy = TtlAlchemy::new(&test_config());
         // Very high volatility shouldn't go below min
-        let result = alchemy.calculate_ttl(300, 0, 1000);
+        let result = alchemy.calculate_ttl(&RecordType::A, 300, 0, 1000);
         assert!(result >= 30, "TTL should not go below min_ttl: got {}", result);
         
         // Very high frequency shouldn't go above max
-        let result = alchemy.calculate_ttl(86400, 1_000_000, 0);
+        let result = alchemy.calculate_ttl(&RecordType::A, 86400, 1_000_000, 0);
         assert!(result <= 86400, "TTL should not exceed max_ttl: got {}", result);
     }
 
@@ -94,7 +122,22 @@ mod tests {
         let mut config = test_config();
         config.enabled = false;
         let alchemy = TtlAlchemy::new(&config);
-        let result = alchemy.calculate_ttl(300, 1000, 0);
+        let result = alchemy.calculate_ttl(&RecordType::A, 300, 1000, 0);
         assert_eq!(result, 300);
     }
+
+    #[test]
+    fn test_type_max_ttl_caps_alchemy_result() {
+        let mut config = test_config();
+        config.type_max_ttl = HashMap::from([("txt".to_string(), 60), ("SOA".to_string(), 300)]);
+        let alchemy = TtlAlchemy::new(&config);
+        // Frequency would stretch this well past an hour; TXT still stops at 60
+        assert_eq!(alchemy.calculate_ttl(&RecordType::TXT, 3600, 1000, 0), 60);
+        // Below min_ttl too: the type cap wins
+        let mut low = config.clone();
+        low.type_max_ttl = HashMap::from([("TXT".to_string(), 10)]);
+        assert_eq!(TtlAlchemy::new(&low).calculate_ttl(&RecordType::TXT, 300, 0, 0), 10);
+        assert_eq!(alchemy.calculate_ttl(&RecordType::SOA, 30, 0, 0), 30);
+        assert!(alchemy.calculate_ttl(&RecordType::A, 3600, 1000, 0) > 3600);
+    }
 }
@@ -1,13 +1,21 @@
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 use parking_lot::RwLock;
 use tokio::net::UdpSocket;
 use tracing::{debug, info, warn};
 
-use crate::config::UpstreamConfig;
+use crate::cache::Transport;
+use crate::config::{UpstreamConfig, UpstreamStrategy};
 use crate::dns::packet;
+use crate::dns::tcp::StillTruncated;
+use crate::dns::types::{DnsClass, RecordType, ResponseCode};
+use crate::edns::EDNS_FLAG_DO;
+use crate::loop_guard::LoopGuard;
+use crate::spoof::{self, SpoofMonitor, SpoofReason};
+use crate::tap::QueryTap;
 
 /// Result of a successful upstream query
 pub struct UpstreamResult {
@@ -15,20 +23,215 @@ pub struct UpstreamResult {
     pub upstream_name: String,
     pub latency: Duration,
     pub original_ttl: u32,
+    /// UDP, or TCP when the UDP answer was truncated
+    pub transport: Transport,
 }
 
 /// Per-upstream statistics and trust data
-struct UpstreamState {
+pub struct UpstreamState {
     config: UpstreamConfig,
     total_queries: AtomicU64,
     total_failures: AtomicU64,
     latency_history: RwLock<Vec<Duration>>, // Recent latencies
     trust_score: RwLock<f64>,               // 0.0 - 1.0
     disabled: RwLock<bool>,                 // Disabled by trust scorer
+    source: Option<IpAddr>,                 // Validated config.source_address
+    slow_start: (u64, f64),                 // (successes to full trust, starting trust)
+    agreements: AtomicU64,                  // Shadow only: answers matching the returned one
+    disagreements: AtomicU64,               // Shadow only: answers that differed
+    suspected_filtering: AtomicU64,         // Empty A/AAAA NOERROR answers held back (detect_filtering)
+}
+
+impl UpstreamState {
+    fn new(config: &UpstreamConfig) -> Self {
+        Self {
+            config: config.clone(),
+            total_queries: AtomicU64::new(0),
+            total_failures: AtomicU64::new(0),
+            latency_history: RwLock::new(Vec::new()),
+            trust_score: RwLock::new(1.0),
+            disabled: RwLock::new(false),
+            source: crate::source_addr::checked(config.source_address, &format!("Upstream {}", config.name)),
+            slow_start: (0, 1.0),
+            agreements: AtomicU64::new(0),
+            disagreements: AtomicU64::new(0),
+            suspected_filtering: AtomicU64::new(0),
+        }
+    }
+
+    fn push_latency(&self, latency: Duration) {
+        let mut history = self.latency_history.write();
+        history.push(latency);
+        // Keep last 100 entries
+        if history.len() > 100 {
+            let drain_to = history.len() - 100;
+            history.drain(..drain_to);
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Mean of the recent latency history (None until something was measured)
+    pub fn avg_latency_ms(&self) -> Option<f64> {
+        let history = self.latency_history.read();
+        if history.is_empty() {
+            None
+        } else {
+            Some(history.iter().map(|d| d.as_millis() as f64).sum::<f64>() / history.len() as f64)
+        }
+    }
+
+    /// Trust as used for selection: during slow-start it is capped by a ramp from the
+    /// starting trust to 1.0 over the first successful queries (trust.slow_start_queries)
+    pub fn trust_score(&self) -> f64 {
+        let score = *self.trust_score.read();
+        let (ramp_queries, initial) = self.slow_start;
+        let successes = self.total_queries.load(Ordering::Relaxed)
+            .saturating_sub(self.total_failures.load(Ordering::Relaxed));
+        if successes >= ramp_queries {
+            return score;
+        }
+        score.min(initial + (1.0 - initial) * successes as f64 / ramp_queries as f64)
+    }
+
+    /// How long to wait for this upstream when another one can still be tried:
+    /// mean + 4·stddev of the latency history (an RTO, like the recursive side's),
+    /// clamped to [min_timeout_ms, timeout_ms]. The static timeout until enough samples exist.
+    pub fn effective_timeout(&self) -> Duration {
+        let ceiling = Duration::from_millis(self.config.timeout_ms);
+        if !self.config.adaptive_timeout {
+            return ceiling;
+        }
+        let history = self.latency_history.read();
+        if history.len() < ADAPTIVE_TIMEOUT_MIN_SAMPLES {
+            return ceiling;
+        }
+        let n = history.len() as f64;
+        let mean = history.iter().map(|d| d.as_secs_f64()).sum::<f64>() / n;
+        let variance = history.iter().map(|d| (d.as_secs_f64() - mean).powi(2)).sum::<f64>() / n;
+        let rto = Duration::from_secs_f64(mean + 4.0 * variance.sqrt());
+        let floor = Duration::from_millis(self.config.min_timeout_ms).min(ceiling);
+        rto.clamp(floor, ceiling)
+    }
+}
+
+/// Latency samples needed before the adaptive timeout kicks in
+const ADAPTIVE_TIMEOUT_MIN_SAMPLES: usize = 5;
+/// Payload size of an OPT added only to set the DO bit
+const DEFAULT_EDNS_SIZE: u16 = 1232;
+
+// ============================================================
+// Upstream selection strategies
+// ============================================================
+
+/// Decides which upstreams a query goes to, and in what order.
+/// `upstreams` are the currently enabled ones in configuration order.
+pub trait UpstreamSelector: Send + Sync {
+    fn select<'a>(&self, upstreams: &[&'a UpstreamState], query: &[u8]) -> Vec<&'a UpstreamState>;
+
+    /// true: query all selected upstreams concurrently, first answer wins.
+    /// false: try them one at a time in the returned order.
+    fn races(&self) -> bool {
+        false
+    }
+}
+
+/// Everyone at once (the original neko-dns behaviour)
+pub struct RaceAll;
+
+impl UpstreamSelector for RaceAll {
+    fn select<'a>(&self, upstreams: &[&'a UpstreamState], _query: &[u8]) -> Vec<&'a UpstreamState> {
+        upstreams.to_vec()
+    }
+
+    fn races(&self) -> bool {
+        true
+    }
+}
+
+/// Lowest average latency first; unmeasured upstreams go first so they get measured
+pub struct Fastest;
+
+impl UpstreamSelector for Fastest {
+    fn select<'a>(&self, upstreams: &[&'a UpstreamState], _query: &[u8]) -> Vec<&'a UpstreamState> {
+        let mut sorted = upstreams.to_vec();
+        sorted.sort_by(|a, b| {
+            let (la, lb) = (a.avg_latency_ms().unwrap_or(0.0), b.avg_latency_ms().unwrap_or(0.0));
+            la.partial_cmp(&lb).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        sorted
+    }
+}
+
+/// Trust-score weighted random order (weighted sampling without replacement)
+pub struct Weighted;
+
+impl UpstreamSelector for Weighted {
+    fn select<'a>(&self, upstreams: &[&'a UpstreamState], _query: &[u8]) -> Vec<&'a UpstreamState> {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let mut pool: Vec<(&'a UpstreamState, f64)> = upstreams.iter()
+            .map(|u| (*u, u.trust_score().max(0.01)))
+            .collect();
+        let mut ordered = Vec::with_capacity(pool.len());
+        while !pool.is_empty() {
+            let total: f64 = pool.iter().map(|(_, w)| w).sum();
+            let mut pick = rng.gen::<f64>() * total;
+            let idx = pool.iter()
+                .position(|(_, w)| { pick -= w; pick <= 0.0 })
+                .unwrap_or(pool.len() - 1);
+            ordered.push(pool.remove(idx).0);
+        }
+        ordered
+    }
+}
+
+/// Strictly in configuration order (primary / secondary)
+pub struct Sequential;
+
+impl UpstreamSelector for Sequential {
+    fn select<'a>(&self, upstreams: &[&'a UpstreamState], _query: &[u8]) -> Vec<&'a UpstreamState> {
+        upstreams.to_vec()
+    }
+}
+
+pub fn selector_for(strategy: UpstreamStrategy) -> Box<dyn UpstreamSelector> {
+    match strategy {
+        UpstreamStrategy::RaceAll => Box::new(RaceAll),
+        UpstreamStrategy::Fastest => Box::new(Fastest),
+        UpstreamStrategy::Weighted => Box::new(Weighted),
+        UpstreamStrategy::Sequential => Box::new(Sequential),
+    }
 }
 
 pub struct UpstreamManager {
     upstreams: Vec<UpstreamState>,
+    /// upstreams with shadow = true: never selected, only compared
+    shadows: Vec<Arc<UpstreamState>>,
+    selector: Box<dyn UpstreamSelector>,
+    tap: Option<Arc<QueryTap>>,
+    spoof: Arc<SpoofMonitor>,
+    loops: Arc<LoopGuard>,
+    still_truncated: Arc<StillTruncated>,
+    retry_servfail: bool,
+    /// Hold back empty A/AAAA NOERROR answers without an SOA, like SERVFAILs
+    detect_filtering: bool,
+    force_do: bool,
+}
+
+/// A shadow upstream and its in-flight query
+type ShadowQuery = (Arc<UpstreamState>, tokio::task::JoinHandle<anyhow::Result<Vec<u8>>>);
+
+/// NOERROR without answers or an SOA to an A/AAAA query: what a silently filtering
+/// upstream sends. A real NODATA carries the zone's SOA (RFC 2308 §2.2), so it never matches.
+pub fn looks_filtered(response: &[u8]) -> bool {
+    let Ok(parsed) = packet::parse_packet(response) else { return false };
+    parsed.header.rcode == ResponseCode::NoError
+        && parsed.questions.first().is_some_and(|q| matches!(q.qtype, RecordType::A | RecordType::AAAA))
+        && parsed.answers.is_empty()
+        && !parsed.authorities.iter().any(|r| r.rtype == RecordType::SOA)
 }
 
 impl UpstreamManager {
@@ -37,25 +240,129 @@ impl UpstreamManager {
             return Err(anyhow::anyhow!("At least one upstream server is required"));
         }
 
-        let upstreams = configs
-            .iter()
-            .map(|c| UpstreamState {
-                config: c.clone(),
-                total_queries: AtomicU64::new(0),
-                total_failures: AtomicU64::new(0),
-                latency_history: RwLock::new(Vec::new()),
-                trust_score: RwLock::new(1.0),
-                disabled: RwLock::new(false),
-            })
-            .collect();
+        let (shadows, primaries): (Vec<&UpstreamConfig>, Vec<&UpstreamConfig>) = configs.iter().partition(|c| c.shadow);
+        if primaries.is_empty() {
+            return Err(anyhow::anyhow!("At least one upstream server that is not a shadow is required"));
+        }
+        let upstreams = primaries.into_iter().map(UpstreamState::new).collect();
+        let shadows: Vec<Arc<UpstreamState>> = shadows.into_iter().map(|c| Arc::new(UpstreamState::new(c))).collect();
+
+        if shadows.is_empty() {
+            info!("Upstream manager initialized with {} upstreams", configs.len());
+        } else {
+            info!("Upstream manager initialized with {} upstreams ({} shadow)", configs.len(), shadows.len());
+        }
+        Ok(Self {
+            upstreams,
+            shadows,
+            selector: Box::new(RaceAll),
+            tap: None,
+            spoof: Arc::new(SpoofMonitor::new()),
+            loops: Arc::new(LoopGuard::new()),
+            still_truncated: Arc::new(StillTruncated::new(Default::default())),
+            retry_servfail: true,
+            detect_filtering: false,
+            force_do: false,
+        })
+    }
+
+    /// Replace the upstream selection strategy (default: race all)
+    pub fn with_selector(mut self, selector: Box<dyn UpstreamSelector>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// Record every query sent to the upstreams in the resolver tap
+    pub fn with_tap(mut self, tap: Arc<QueryTap>) -> Self {
+        self.tap = Some(tap);
+        self
+    }
+
+    /// Count rejected upstream responses here (shared with the recursive resolver)
+    pub fn with_spoof_monitor(mut self, spoof: Arc<SpoofMonitor>) -> Self {
+        self.spoof = spoof;
+        self
+    }
 
-        info!("Upstream manager initialized with {} upstreams", configs.len());
-        Ok(Self { upstreams })
+    /// Register outgoing source ports here, so a query that loops back to us is recognized
+    pub fn with_loop_guard(mut self, loops: Arc<LoopGuard>) -> Self {
+        self.loops = loops;
+        self
     }
 
-    /// Race all enabled upstreams - first response wins
+    /// Settle TCP answers that are still truncated here (tcp_still_truncated, shared with the recursive resolver)
+    pub fn with_still_truncated(mut self, still_truncated: Arc<StillTruncated>) -> Self {
+        self.still_truncated = still_truncated;
+        self
+    }
+
+    /// Start every upstream at `initial` trust, reaching its real score after `queries` successes
+    pub fn with_slow_start(mut self, queries: u64, initial: f64) -> Self {
+        for upstream in &mut self.upstreams {
+            upstream.slow_start = (queries, initial.clamp(0.0, 1.0));
+        }
+        self
+    }
+
+    /// Set the DO bit on every query, as if each upstream had set_do
+    pub fn with_forced_do(mut self, force: bool) -> Self {
+        self.force_do = force;
+        self
+    }
+
+    /// Hold on to a SERVFAIL while another upstream may still answer (default: on)
+    pub fn with_servfail_retry(mut self, retry: bool) -> Self {
+        self.retry_servfail = retry;
+        self
+    }
+
+    /// Hold on to empty A/AAAA answers that look filtered while another upstream may
+    /// still answer (upstream_detect_filtering)
+    pub fn with_filtering_detection(mut self, detect: bool) -> Self {
+        self.detect_filtering = detect;
+        self
+    }
+
+    /// An answer worth giving the other upstreams a chance to beat: a SERVFAIL
+    /// (retry_servfail) or a suspected filtered answer (detect_filtering)
+    fn worth_retrying(&self, result: &UpstreamResult) -> bool {
+        if self.retry_servfail && packet::is_servfail(&result.response) {
+            return true;
+        }
+        if self.detect_filtering && looks_filtered(&result.response) {
+            if let Some(u) = self.upstreams.iter().find(|u| u.config.name == result.upstream_name) {
+                u.suspected_filtering.fetch_add(1, Ordering::Relaxed);
+            }
+            debug!("Upstream {} gave an empty answer without SOA, possibly filtered", result.upstream_name);
+            return true;
+        }
+        false
+    }
+
+    /// Which of two held answers to return if nobody does better: an empty
+    /// NOERROR still beats a SERVFAIL
+    fn keep_held(held: Option<UpstreamResult>, result: UpstreamResult) -> Option<UpstreamResult> {
+        match held {
+            Some(previous) if !packet::is_servfail(&previous.response) && packet::is_servfail(&result.response) => Some(previous),
+            _ => Some(result),
+        }
+    }
+
+    /// Send a query to the upstreams picked by the selector - races them or
+    /// walks them in order depending on the strategy. Shadow upstreams are asked
+    /// at the same time; their answers are compared with the returned one in the background.
     pub async fn race_query(&self, query: &[u8]) -> anyhow::Result<UpstreamResult> {
-        let enabled: Vec<&UpstreamState> = self.upstreams
+        let shadows = self.start_shadow_queries(query);
+        let result = self.race_selected(query).await;
+        match &result {
+            Ok(primary) if !shadows.is_empty() => Self::compare_shadow_answers(shadows, query, primary),
+            _ => shadows.iter().for_each(|(_, task)| task.abort()),
+        }
+        result
+    }
+
+    async fn race_selected(&self, query: &[u8]) -> anyhow::Result<UpstreamResult> {
+        let mut enabled: Vec<&UpstreamState> = self.upstreams
             .iter()
             .filter(|u| !*u.disabled.read())
             .collect();
@@ -66,28 +373,77 @@ impl UpstreamManager {
             for u in &self.upstreams {
                 *u.disabled.write() = false;
             }
-            return self.race_query_inner(&self.upstreams.iter().collect::<Vec<_>>(), query).await;
+            enabled = self.upstreams.iter().collect();
         }
 
-        self.race_query_inner(&enabled, query).await
-    }
+        let selected = self.selector.select(&enabled, query);
+        if selected.is_empty() {
+            return Err(anyhow::anyhow!("Upstream selector returned no upstreams"));
+        }
+        if self.selector.races() {
+            // Everyone is asked at once, so there is nothing to fail over to
+            return self.race_query_inner(&selected, query, false).await;
+        }
 
-    async fn race_query_inner(&self, upstreams: &[&UpstreamState], query: &[u8]) -> anyhow::Result<UpstreamResult> {
-        use tokio::select;
+        // Walking the list: cut slow attempts short while a fallback remains,
+        // the last upstream gets its full timeout_ms
+        let mut last_err = None;
+        let mut held = None;
+        let last = selected.len() - 1;
+        for (i, upstream) in selected.into_iter().enumerate() {
+            match self.race_query_inner(&[upstream], query, i < last).await {
+                Ok(result) if i < last && self.worth_retrying(&result) => {
+                    debug!("Upstream {} answered {:?}, trying next", upstream.name(), ResponseCode::from(result.response[3] & 0x0F));
+                    held = Self::keep_held(held, result);
+                }
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    debug!("Upstream {} failed, trying next: {}", upstream.name(), e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        if let Some(result) = held {
+            return Ok(result);
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("All upstreams failed")))
+    }
 
+    async fn race_query_inner(&self, upstreams: &[&UpstreamState], query: &[u8], adaptive: bool) -> anyhow::Result<UpstreamResult> {
         // Spawn all upstream queries simultaneously
-        let mut tasks = Vec::new();
+        let mut tasks = tokio::task::JoinSet::new();
         for upstream in upstreams {
-            let query_data = query.to_vec();
+            let query_data = Self::upstream_query(query, &upstream.config, self.force_do);
             let addr: SocketAddr = format!("{}:{}", upstream.config.address, upstream.config.port)
                 .parse()
                 .map_err(|e| anyhow::anyhow!("Invalid upstream address: {}", e))?;
-            let timeout = Duration::from_millis(upstream.config.timeout_ms);
+            let timeout = if adaptive {
+                upstream.effective_timeout()
+            } else {
+                Duration::from_millis(upstream.config.timeout_ms)
+            };
             let name = upstream.config.name.clone();
+            let dscp = upstream.config.dscp;
+            let source = upstream.source;
+            let tap = self.tap.clone();
+            let spoof = self.spoof.clone();
+            let loops = self.loops.clone();
+            let still_truncated = self.still_truncated.clone();
 
-            tasks.push(tokio::spawn(async move {
+            tasks.spawn(async move {
                 let start = Instant::now();
-                match Self::query_upstream(&query_data, addr, timeout).await {
+                let (result, transport) = match Self::query_upstream(&query_data, addr, timeout, dscp, source, &spoof, &loops).await {
+                    Ok((response, transport)) => (Ok(response), transport),
+                    Err(e) => (Err(e), Transport::Udp),
+                };
+                if let Some(tap) = &tap {
+                    tap.record_packet("upstream", addr, &query_data, &result, start.elapsed());
+                }
+                let result = match transport {
+                    Transport::Tcp => result.and_then(|response| still_truncated.settle(response, addr)),
+                    _ => result,
+                };
+                match result.and_then(Self::check_usable) {
                     Ok(response) => {
                         let latency = start.elapsed();
                         let original_ttl = Self::extract_ttl(&response).unwrap_or(0);
@@ -96,80 +452,249 @@ impl UpstreamManager {
                             upstream_name: name,
                             latency,
                             original_ttl,
+                            transport,
                         })
                     }
                     Err(e) => Err((name, e)),
                 }
-            }));
+            });
         }
 
-        // Wait for first successful response
-        let (result, _remaining) = futures_select_first(tasks).await;
-
-        match result {
-            Ok(Ok(upstream_result)) => {
-                // Record success
-                if let Some(u) = self.upstreams.iter().find(|u| u.config.name == upstream_result.upstream_name) {
-                    u.total_queries.fetch_add(1, Ordering::Relaxed);
+        // First usable response wins; a failed upstream doesn't end the race for the others,
+        // and neither does a SERVFAIL or a suspected filtered answer while someone else
+        // may still answer (retry_servfail, detect_filtering).
+        // Dropping the JoinSet aborts whoever is still waiting.
+        let mut last_err = None;
+        let mut held = None;
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(Ok(upstream_result)) => {
+                    // Record success
+                    if let Some(u) = self.upstreams.iter().find(|u| u.config.name == upstream_result.upstream_name) {
+                        u.total_queries.fetch_add(1, Ordering::Relaxed);
+                    }
+                    if !tasks.is_empty() && self.worth_retrying(&upstream_result) {
+                        debug!("Upstream {} answered {:?}, waiting for the others", upstream_result.upstream_name, ResponseCode::from(upstream_result.response[3] & 0x0F));
+                        held = Self::keep_held(held, upstream_result);
+                        continue;
+                    }
+                    return Ok(upstream_result);
                 }
-                Ok(upstream_result)
+                Ok(Err((name, e))) => {
+                    // Record failure
+                    if let Some(u) = self.upstreams.iter().find(|u| u.config.name == name) {
+                        u.total_failures.fetch_add(1, Ordering::Relaxed);
+                        u.total_queries.fetch_add(1, Ordering::Relaxed);
+                    }
+                    debug!("Upstream {} failed while racing: {}", name, e);
+                    last_err = Some(anyhow::anyhow!("Upstream {} failed: {}", name, e));
+                }
+                Err(e) => last_err = Some(anyhow::anyhow!("Upstream task failed: {}", e)),
             }
-            Ok(Err((name, e))) => {
-                // Record failure
-                if let Some(u) = self.upstreams.iter().find(|u| u.config.name == name) {
-                    u.total_failures.fetch_add(1, Ordering::Relaxed);
-                    u.total_queries.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(result) = held {
+            return Ok(result);
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("All upstreams failed")))
+    }
+
+    /// Ask every shadow upstream, each with its full timeout_ms
+    fn start_shadow_queries(&self, query: &[u8]) -> Vec<ShadowQuery> {
+        let mut started = Vec::with_capacity(self.shadows.len());
+        for shadow in &self.shadows {
+            let addr: SocketAddr = match format!("{}:{}", shadow.config.address, shadow.config.port).parse() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!("Shadow upstream {} has an invalid address: {}", shadow.name(), e);
+                    continue;
+                }
+            };
+            let query_data = Self::upstream_query(query, &shadow.config, self.force_do);
+            let state = shadow.clone();
+            let tap = self.tap.clone();
+            let spoof = self.spoof.clone();
+            let loops = self.loops.clone();
+            let still_truncated = self.still_truncated.clone();
+            let task = tokio::spawn(async move {
+                let start = Instant::now();
+                let timeout = Duration::from_millis(state.config.timeout_ms);
+                let (result, transport) = match Self::query_upstream(&query_data, addr, timeout, state.config.dscp, state.source, &spoof, &loops).await {
+                    Ok((response, transport)) => (Ok(response), transport),
+                    Err(e) => (Err(e), Transport::Udp),
+                };
+                if let Some(tap) = &tap {
+                    tap.record_packet("upstream", addr, &query_data, &result, start.elapsed());
+                }
+                let result = match transport {
+                    Transport::Tcp => result.and_then(|response| still_truncated.settle(response, addr)),
+                    _ => result,
+                };
+                let result = result.and_then(Self::check_usable);
+                state.total_queries.fetch_add(1, Ordering::Relaxed);
+                match &result {
+                    Ok(_) => state.push_latency(start.elapsed()),
+                    Err(_) => { state.total_failures.fetch_add(1, Ordering::Relaxed); }
+                }
+                result
+            });
+            started.push((shadow.clone(), task));
+        }
+        started
+    }
+
+    /// Count each shadow answer as agreeing or not with the one the client got
+    /// (rcode and answer records, see packet::answer_digest). Failed shadow queries
+    /// and SERVFAILs on either side aren't compared.
+    fn compare_shadow_answers(
+        shadows: Vec<ShadowQuery>,
+        query: &[u8],
+        primary: &UpstreamResult,
+    ) {
+        let Some(expected) = packet::answer_digest(&primary.response) else {
+            shadows.iter().for_each(|(_, task)| task.abort());
+            return;
+        };
+        let primary_name = primary.upstream_name.clone();
+        let question = spoof::query_question(query)
+            .map(|(qname, qtype)| format!("{} {}", qname, qtype.name()))
+            .unwrap_or_default();
+        tokio::spawn(async move {
+            for (shadow, task) in shadows {
+                let Ok(Ok(response)) = task.await else { continue };
+                let Some(answer) = packet::answer_digest(&response) else { continue };
+                if answer == expected {
+                    shadow.agreements.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    shadow.disagreements.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Shadow upstream {} disagrees with {} on {}: {:?} vs {:?}",
+                        shadow.name(), primary_name, question, answer, expected
+                    );
                 }
-                Err(anyhow::anyhow!("Upstream {} failed: {}", name, e))
             }
-            Err(e) => Err(anyhow::anyhow!("All upstreams failed: {}", e)),
+        });
+    }
+
+    /// The query as sent to `config`'s upstream: DO bit and/or EDNS payload size
+    /// forced when set_do (or `force_do`) / edns_size ask for it (an OPT is added if missing)
+    fn upstream_query(query: &[u8], config: &UpstreamConfig, force_do: bool) -> Vec<u8> {
+        let set_do = config.set_do || force_do;
+        if !set_do && config.edns_size.is_none() {
+            return query.to_vec();
+        }
+        let Ok(mut parsed) = packet::parse_packet(query) else { return query.to_vec() };
+        if !parsed.additionals.iter().any(|r| r.rtype == RecordType::OPT) {
+            let mut opt = packet::DnsRecord::new("", RecordType::OPT, 0, Vec::new());
+            opt.rclass = DnsClass::from(DEFAULT_EDNS_SIZE);
+            parsed.additionals.push(opt);
         }
+        for opt in parsed.additionals.iter_mut().filter(|r| r.rtype == RecordType::OPT) {
+            if let Some(size) = config.edns_size {
+                opt.rclass = DnsClass::from(size.max(512));
+            }
+            if set_do {
+                opt.ttl |= EDNS_FLAG_DO;
+            }
+        }
+        parsed.to_wire()
+    }
+
+    /// A response that doesn't parse, or FORMERR, counts as a failure of that upstream
+    fn check_usable(response: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let parsed = packet::parse_packet(&response)
+            .map_err(|e| anyhow::anyhow!("Malformed response: {}", e))?;
+        if parsed.header.rcode == crate::dns::types::ResponseCode::FormErr {
+            return Err(anyhow::anyhow!("Upstream answered FORMERR"));
+        }
+        Ok(response)
     }
 
     /// Send query to a single upstream and wait for response.
     /// Uses explicit source port randomization (ephemeral range 49152-65535)
     /// with CSPRNG (OsRng) to mitigate DNS cache poisoning attacks (RFC 5452).
-    async fn query_upstream(query: &[u8], addr: SocketAddr, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    /// Datagrams with the wrong source, ID or question are counted and skipped
+    /// while waiting for the real answer.
+    async fn query_upstream(
+        query: &[u8],
+        addr: SocketAddr,
+        timeout: Duration,
+        dscp: Option<u8>,
+        source: Option<IpAddr>,
+        spoof: &SpoofMonitor,
+        loops: &LoopGuard,
+    ) -> anyhow::Result<(Vec<u8>, Transport)> {
         use rand::rngs::OsRng;
         use rand::Rng;
 
         // CSPRNG source port selection in ephemeral range
         let src_port: u16 = OsRng.gen_range(49152..=65535);
-        let bind_addr: SocketAddr = format!("0.0.0.0:{}", src_port)
-            .parse()
-            .unwrap();
+        let local = crate::source_addr::bind_ip(source, &addr);
 
         // Retry with different port on bind failure (port collision)
-        let socket = match UdpSocket::bind(bind_addr).await {
+        let socket = match UdpSocket::bind(SocketAddr::new(local, src_port)).await {
             Ok(s) => s,
-            Err(_) => UdpSocket::bind("0.0.0.0:0").await?,
+            Err(_) => UdpSocket::bind(SocketAddr::new(local, 0)).await?,
         };
+        if let Some(dscp) = dscp {
+            crate::dscp::apply(&socket, dscp);
+        }
+        let _tracked = loops.track(socket.local_addr()?);
 
         socket.send_to(query, addr).await?;
 
+        let question = spoof::query_question(query);
+        let deadline = tokio::time::Instant::now() + timeout;
         let mut buf = vec![0u8; 4096];
-        let len = tokio::time::timeout(timeout, socket.recv(&mut buf))
-            .await
-            .map_err(|_| anyhow::anyhow!("Timeout"))??;
+        let mut rejected = None;
+        let mut accepted = None;
+        for _ in 0..spoof::MAX_REJECTED_DATAGRAMS {
+            let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await else { break };
+            let (len, peer) = received?;
+            let reason = if peer != addr {
+                Some(SpoofReason::WrongSource)
+            } else if len < 12 || query.len() < 2 || buf[..2] != query[..2] {
+                Some(SpoofReason::WrongId)
+            } else {
+                question.as_ref().and_then(|(qname, qtype)| spoof::check_response(&buf[..len], qname, *qtype).err())
+            };
+            match reason {
+                Some(reason) => {
+                    spoof.record(reason, peer);
+                    rejected = Some(reason);
+                }
+                None => {
+                    accepted = Some(len);
+                    break;
+                }
+            }
+        }
+        let len = match (accepted, rejected) {
+            (Some(len), _) => len,
+            (None, Some(reason)) => return Err(anyhow::anyhow!("No valid response from {} ({})", addr, reason.describe())),
+            (None, None) => return Err(anyhow::anyhow!("Timeout")),
+        };
 
-        Ok(buf[..len].to_vec())
+        // TC=1 → fetch the full answer over TCP, otherwise TCP clients would only ever get the truncated copy.
+        // Only once: a TCP answer still carrying TC is settled by the caller (tcp_still_truncated)
+        if len >= 3 && buf[2] & 0x02 != 0 {
+            debug!("Truncated response from {}, retrying over TCP", addr);
+            return crate::dns::tcp::query(query, addr, timeout, dscp, source).await.map(|r| (r, Transport::Tcp));
+        }
+
+        Ok((buf[..len].to_vec(), Transport::Udp))
     }
 
     /// Record latency for trust scoring
     pub async fn record_latency(&self, upstream_name: &str, latency: Duration) {
         if let Some(u) = self.upstreams.iter().find(|u| u.config.name == upstream_name) {
-            let mut history = u.latency_history.write();
-            history.push(latency);
-            // Keep last 100 entries
-            if history.len() > 100 {
-                let drain_to = history.len() - 100;
-                history.drain(..drain_to);
-            }
+            u.push_latency(latency);
         }
     }
 
-    /// Recalculate trust scores for all upstreams
-    pub async fn recalculate_trust_scores(&self, min_score: f64) {
+    /// Recalculate trust scores for all upstreams.
+    /// Returns the upstreams this round disabled (name, score).
+    pub async fn recalculate_trust_scores(&self, min_score: f64) -> Vec<(String, f64)> {
+        let mut newly_disabled = Vec::new();
         for upstream in &self.upstreams {
             let total = upstream.total_queries.load(Ordering::Relaxed);
             let failures = upstream.total_failures.load(Ordering::Relaxed);
@@ -206,7 +731,9 @@ impl UpstreamManager {
 
             // Disable if below threshold
             if score < min_score {
-                *upstream.disabled.write() = true;
+                if !std::mem::replace(&mut *upstream.disabled.write(), true) {
+                    newly_disabled.push((upstream.config.name.clone(), score));
+                }
                 warn!(
                     "Upstream {} disabled (trust score: {:.2}, threshold: {:.2})",
                     upstream.config.name, score, min_score
@@ -220,6 +747,7 @@ impl UpstreamManager {
                 upstream.config.name, score, success_rate, latency_score
             );
         }
+        newly_disabled
     }
 
     fn extract_ttl(response: &[u8]) -> Option<u32> {
@@ -227,73 +755,479 @@ impl UpstreamManager {
         parsed.answers.first().map(|r| r.ttl)
     }
 
-    /// Get upstream stats for Web UI
+    /// Get upstream stats for Web UI (shadow upstreams last, with their comparison counts)
     pub fn get_stats(&self) -> serde_json::Value {
-        let upstreams: Vec<serde_json::Value> = self.upstreams.iter().map(|u| {
-            let history = u.latency_history.read();
-            let avg_latency = if history.is_empty() {
-                0.0
-            } else {
-                history.iter().map(|d| d.as_millis() as f64).sum::<f64>() / history.len() as f64
-            };
+        let shadows = self.shadows.iter().map(|u| u.as_ref());
+        let upstreams: Vec<serde_json::Value> = self.upstreams.iter().chain(shadows).map(|u| {
+            let avg_latency = u.avg_latency_ms().unwrap_or(0.0);
 
-            serde_json::json!({
+            let mut stats = serde_json::json!({
                 "name": u.config.name,
                 "address": format!("{}:{}", u.config.address, u.config.port),
                 "total_queries": u.total_queries.load(Ordering::Relaxed),
                 "total_failures": u.total_failures.load(Ordering::Relaxed),
-                "trust_score": format!("{:.2}", *u.trust_score.read()),
+                "trust_score": format!("{:.2}", u.trust_score()),
                 "avg_latency_ms": format!("{:.1}", avg_latency),
+                "effective_timeout_ms": u.effective_timeout().as_millis() as u64,
                 "disabled": *u.disabled.read(),
-            })
+                "shadow": u.config.shadow,
+                "suspected_filtering": u.suspected_filtering.load(Ordering::Relaxed),
+            });
+            if u.config.shadow {
+                stats["agreements"] = u.agreements.load(Ordering::Relaxed).into();
+                stats["disagreements"] = u.disagreements.load(Ordering::Relaxed).into();
+            }
+            stats
         }).collect();
 
         serde_json::json!(upstreams)
     }
 }
 
-/// Select the first completed future from a vec of JoinHandles
-async fn futures_select_first<T: Send + 'static>(
-    tasks: Vec<tokio::task::JoinHandle<T>>,
-) -> (Result<T, tokio::task::JoinError>, Vec<tokio::task::JoinHandle<T>>) {
-    if tasks.is_empty() {
-        panic!("No tasks to select from");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(name: &str, latencies_ms: &[u64], trust: f64) -> UpstreamState {
+        let u = UpstreamState::new(&UpstreamConfig {
+            name: name.to_string(),
+            address: "192.0.2.1".to_string(),
+            port: 53,
+            timeout_ms: 1000,
+            ..Default::default()
+        });
+        *u.latency_history.write() = latencies_ms.iter().map(|ms| Duration::from_millis(*ms)).collect();
+        *u.trust_score.write() = trust;
+        u
     }
 
-    // Use futures::future::select_all equivalent with tokio
-    // We convert JoinHandles to pinned futures and race them
-    use std::pin::Pin;
-    use std::future::Future;
+    #[test]
+    fn test_slow_start_ramps_trust_with_successes() {
+        let mut new = state("new", &[], 1.0);
+        new.slow_start = (10, 0.2);
+        assert!((new.trust_score() - 0.2).abs() < 1e-9);
+        new.total_queries.store(5, Ordering::Relaxed);
+        assert!((new.trust_score() - 0.6).abs() < 1e-9);
+        // Failures don't count towards the ramp
+        new.total_queries.store(10, Ordering::Relaxed);
+        new.total_failures.store(5, Ordering::Relaxed);
+        assert!((new.trust_score() - 0.6).abs() < 1e-9);
+        new.total_failures.store(0, Ordering::Relaxed);
+        assert_eq!(new.trust_score(), 1.0);
+        // After the ramp the computed score is used as is
+        *new.trust_score.write() = 0.8;
+        assert_eq!(new.trust_score(), 0.8);
+        // A low computed score still wins over the ramp
+        new.total_queries.store(1, Ordering::Relaxed);
+        *new.trust_score.write() = 0.1;
+        assert_eq!(new.trust_score(), 0.1);
+    }
 
-    let mut futs: Vec<Pin<Box<dyn Future<Output = (usize, Result<T, tokio::task::JoinError>)> + Send>>> = 
-        tasks.into_iter().enumerate().map(|(i, t)| {
-            Box::pin(async move { (i, t.await) }) as Pin<Box<dyn Future<Output = _> + Send>>
-        }).collect();
+    fn names(selected: &[&UpstreamState]) -> Vec<String> {
+        selected.iter().map(|u| u.name().to_string()).collect()
+    }
 
-    // Simple approach: spawn a tokio::select on all of them
-    // For now, use the fact that we can select_all via a helper
-    let mut handles: Vec<tokio::task::JoinHandle<T>> = Vec::new();
-    
-    // Actually, the simplest correct approach:
-    // Convert to a FuturesUnordered-like pattern using tokio::select with a loop
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<(usize, Result<T, tokio::task::JoinError>)>(1);
-    
-    let mut task_handles = Vec::new();
-    for fut in futs {
-        let tx = tx.clone();
-        task_handles.push(tokio::spawn(async move {
-            let (idx, result) = fut.await;
-            let _ = tx.send((idx, result)).await;
-        }));
+    #[test]
+    fn test_race_all_selects_everyone() {
+        let states = [state("a", &[], 1.0), state("b", &[], 1.0), state("c", &[], 1.0)];
+        let refs: Vec<&UpstreamState> = states.iter().collect();
+        assert!(RaceAll.races());
+        assert_eq!(names(&RaceAll.select(&refs, &[])), vec!["a", "b", "c"]);
     }
-    drop(tx);
 
-    let (winner_idx, result) = rx.recv().await.expect("At least one task should complete");
+    #[test]
+    fn test_fastest_orders_by_latency() {
+        let states = [state("slow", &[80, 100], 1.0), state("fast", &[10, 12], 1.0), state("new", &[], 1.0)];
+        let refs: Vec<&UpstreamState> = states.iter().collect();
+        assert!(!Fastest.races());
+        assert_eq!(names(&Fastest.select(&refs, &[])), vec!["new", "fast", "slow"]);
+    }
 
-    // Abort remaining
-    for handle in &task_handles {
-        handle.abort();
+    #[test]
+    fn test_weighted_prefers_trusted() {
+        let states = [state("shaky", &[], 0.05), state("trusted", &[], 1.0)];
+        let refs: Vec<&UpstreamState> = states.iter().collect();
+        let mut trusted_first = 0;
+        for _ in 0..500 {
+            let picked = Weighted.select(&refs, &[]);
+            assert_eq!(picked.len(), 2);
+            if picked[0].name() == "trusted" {
+                trusted_first += 1;
+            }
+        }
+        assert!(trusted_first > 400, "trusted first only {} / 500", trusted_first);
     }
 
-    (result, vec![])
+    #[test]
+    fn test_sequential_keeps_config_order() {
+        let states = [state("primary", &[500], 0.2), state("secondary", &[1], 1.0)];
+        let refs: Vec<&UpstreamState> = states.iter().collect();
+        assert!(!Sequential.races());
+        assert_eq!(names(&Sequential.select(&refs, &[])), vec!["primary", "secondary"]);
+    }
+
+    #[test]
+    fn test_adaptive_timeout_follows_latency() {
+        // Normally-5ms upstream: floored at min_timeout_ms instead of waiting the full second
+        assert_eq!(state("fast", &[4, 5, 5, 6, 5, 5], 1.0).effective_timeout(), Duration::from_millis(50));
+        // 100ms ± 10ms → 100 + 4·10
+        assert_eq!(state("steady", &[90, 110, 90, 110, 90, 110], 1.0).effective_timeout(), Duration::from_millis(140));
+        // Erratic upstream: capped at timeout_ms
+        assert_eq!(state("erratic", &[10, 900, 10, 900, 10], 1.0).effective_timeout(), Duration::from_millis(1000));
+        // Not enough samples yet
+        assert_eq!(state("new", &[5, 5], 1.0).effective_timeout(), Duration::from_millis(1000));
+    }
+
+    /// Answers after `delay` with an empty NOERROR
+    async fn spawn_stub(delay: Duration) -> SocketAddr {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut resp = buf[..len].to_vec();
+                resp[2] |= 0x80;
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = socket.send_to(&resp, peer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// Points at a stub, with the fixed timeout so slow stubs time out predictably
+    fn stub_upstream(name: &str, addr: SocketAddr) -> UpstreamConfig {
+        UpstreamConfig {
+            name: name.to_string(),
+            address: addr.ip().to_string(),
+            port: addr.port(),
+            adaptive_timeout: false,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequential_fails_over_after_adaptive_timeout() {
+        let stalled = spawn_stub(Duration::from_secs(5)).await;
+        let backup = spawn_stub(Duration::ZERO).await;
+        let config = |name: &str, addr: SocketAddr| UpstreamConfig { adaptive_timeout: true, ..stub_upstream(name, addr) };
+        let manager = UpstreamManager::new(&[config("primary", stalled), config("backup", backup)]).await.unwrap()
+            .with_selector(Box::new(Sequential));
+        for _ in 0..5 {
+            manager.record_latency("primary", Duration::from_millis(5)).await;
+        }
+
+        let start = Instant::now();
+        let result = manager.race_query(&packet::build_query(0x7777, "example.com", crate::dns::types::RecordType::A, true)).await.unwrap();
+        assert_eq!(result.upstream_name, "backup");
+        assert!(start.elapsed() < Duration::from_millis(500), "failover took {:?}", start.elapsed());
+    }
+
+    /// Answers every query with SERVFAIL, at once
+    async fn spawn_servfail_stub() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(servfail) = packet::build_servfail(&buf[..len]) else { continue };
+                let _ = socket.send_to(&servfail, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_servfail_loses_to_a_later_answer() {
+        let failing = spawn_servfail_stub().await;
+        let working = spawn_stub(Duration::from_millis(50)).await;
+        let query = packet::build_query(0x5e5e, "example.com", crate::dns::types::RecordType::A, true);
+        let rcode = |result: &UpstreamResult| packet::parse_packet(&result.response).unwrap().header.rcode;
+
+        // Racing: the SERVFAIL arrives first but the NOERROR is returned
+        let racing = UpstreamManager::new(&[stub_upstream("failing", failing), stub_upstream("working", working)]).await.unwrap();
+        let result = racing.race_query(&query).await.unwrap();
+        assert_eq!(result.upstream_name, "working");
+        assert_eq!(rcode(&result), ResponseCode::NoError);
+
+        // Walking in order: the next upstream is tried
+        let walking = UpstreamManager::new(&[stub_upstream("failing", failing), stub_upstream("working", working)]).await.unwrap()
+            .with_selector(Box::new(Sequential));
+        assert_eq!(walking.race_query(&query).await.unwrap().upstream_name, "working");
+
+        // Everyone SERVFAILs: that's the answer
+        let alone = UpstreamManager::new(&[stub_upstream("failing", failing)]).await.unwrap();
+        assert_eq!(rcode(&alone.race_query(&query).await.unwrap()), ResponseCode::ServFail);
+
+        // Switched off, the first answer wins whatever it says
+        let first = UpstreamManager::new(&[stub_upstream("failing", failing), stub_upstream("working", working)]).await.unwrap()
+            .with_servfail_retry(false);
+        assert_eq!(first.race_query(&query).await.unwrap().upstream_name, "failing");
+    }
+
+    /// Sends every kind of bogus reply before the real one
+    async fn spawn_spoofing_stub() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                let genuine = {
+                    let mut ok = resp.clone();
+                    ok.answers.push(packet::DnsRecord::new(&ok.questions[0].name, crate::dns::types::RecordType::A, 300, vec![192, 0, 2, 1]));
+                    ok.to_wire()
+                };
+                let _ = other.send_to(&genuine, peer).await;
+                let mut wrong_id = genuine.clone();
+                wrong_id[0] ^= 0xff;
+                let _ = socket.send_to(&wrong_id, peer).await;
+                let mut wrong_question = resp.clone();
+                wrong_question.questions[0].name = "evil.example".to_string();
+                let _ = socket.send_to(&wrong_question.to_wire(), peer).await;
+                let mut hijack = resp.clone();
+                hijack.authorities.push(packet::DnsRecord::new("evil.example", crate::dns::types::RecordType::NS, 3600, packet::encode_name("ns.evil.example")));
+                let _ = socket.send_to(&hijack.to_wire(), peer).await;
+                let _ = socket.send_to(&genuine, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_responses_counted_and_skipped() {
+        let stub = spawn_spoofing_stub().await;
+        let spoof = Arc::new(SpoofMonitor::new());
+        let manager = UpstreamManager::new(&[stub_upstream("stub", stub)]).await.unwrap()
+            .with_spoof_monitor(spoof.clone());
+
+        let query = packet::build_query(0x4242, "www.example.com", crate::dns::types::RecordType::A, true);
+        let result = manager.race_query(&query).await.unwrap();
+
+        let parsed = packet::parse_packet(&result.response).unwrap();
+        assert_eq!(parsed.header.id, 0x4242);
+        assert_eq!(parsed.answers.len(), 1);
+        for reason in SpoofReason::ALL {
+            assert_eq!(spoof.count(reason), 1, "{}", reason.label());
+        }
+    }
+
+    /// Answers with a datagram that has the right ID but doesn't parse
+    async fn spawn_garbage_stub() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                if len < 2 { continue }
+                let garbage = [buf[0], buf[1], 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, 0xc0];
+                let _ = socket.send_to(&garbage, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_malformed_response_loses_the_race() {
+        let garbage = spawn_garbage_stub().await;
+        let valid = spawn_stub(Duration::from_millis(50)).await;
+        let manager = UpstreamManager::new(&[stub_upstream("garbage", garbage), stub_upstream("valid", valid)]).await.unwrap();
+
+        let query = packet::build_query(0x2436, "example.com", crate::dns::types::RecordType::A, true);
+        let result = manager.race_query(&query).await.unwrap();
+        assert_eq!(result.upstream_name, "valid");
+        assert!(packet::parse_packet(&result.response).is_ok());
+        let stats = manager.get_stats();
+        assert_eq!(stats[0]["total_failures"], 1);
+        assert_eq!(stats[1]["total_failures"], 0);
+
+        // Alone, the garbage upstream is a failure rather than a bad answer
+        let alone = UpstreamManager::new(&[stub_upstream("garbage", garbage)]).await.unwrap();
+        assert!(alone.race_query(&query).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_do_and_edns_size_on_forwarded_query() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stub = socket.local_addr().unwrap();
+        let (seen_tx, mut seen) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                let _ = seen_tx.send(resp.additionals.iter().find(|r| r.rtype == RecordType::OPT).map(|o| (o.rclass.to_u16(), o.ttl)));
+                resp.header.qr = true;
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        let config = UpstreamConfig { set_do: true, edns_size: Some(1400), ..stub_upstream("stub", stub) };
+        let manager = UpstreamManager::new(&[config]).await.unwrap();
+
+        // Client without OPT: one is added
+        manager.race_query(&packet::build_query(0x5151, "example.com", RecordType::A, true)).await.unwrap();
+        let (size, ttl) = seen.recv().await.unwrap().unwrap();
+        assert_eq!(size, 1400);
+        assert_ne!(ttl & EDNS_FLAG_DO, 0);
+
+        // Client OPT (512 bytes, DO=0) is rewritten
+        let mut query = packet::build_query(0x5152, "example.com", RecordType::A, true);
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, 0x02, 0, 0, 0, 0, 0, 0, 0]);
+        manager.race_query(&query).await.unwrap();
+        let (size, ttl) = seen.recv().await.unwrap().unwrap();
+        assert_eq!(size, 1400);
+        assert_ne!(ttl & EDNS_FLAG_DO, 0);
+    }
+
+    /// Answers every A query with `ip`
+    async fn spawn_answer_stub(ip: [u8; 4]) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                let name = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&name, crate::dns::types::RecordType::A, 300, ip.to_vec()));
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_shadow_disagreement_counted_but_primary_returned() {
+        let primary = spawn_answer_stub([192, 0, 2, 1]).await;
+        let agreeing = spawn_answer_stub([192, 0, 2, 1]).await;
+        let divergent = spawn_answer_stub([198, 51, 100, 7]).await;
+        let config = |name: &str, addr: SocketAddr, shadow: bool| UpstreamConfig { shadow, ..stub_upstream(name, addr) };
+        assert!(UpstreamManager::new(&[config("only-shadow", divergent, true)]).await.is_err());
+        let manager = UpstreamManager::new(&[
+            config("divergent", divergent, true),
+            config("primary", primary, false),
+            config("agreeing", agreeing, true),
+        ]).await.unwrap();
+
+        let query = packet::build_query(0x5ade, "www.example.com", crate::dns::types::RecordType::A, true);
+        for _ in 0..3 {
+            let result = manager.race_query(&query).await.unwrap();
+            assert_eq!(result.upstream_name, "primary");
+            assert_eq!(packet::parse_packet(&result.response).unwrap().answers[0].rdata, vec![192, 0, 2, 1]);
+        }
+
+        let shadow_stats = |name: &str| {
+            let stats = manager.get_stats();
+            let entry = stats.as_array().unwrap().iter().find(|u| u["name"] == name).unwrap().clone();
+            (entry["agreements"].as_u64().unwrap(), entry["disagreements"].as_u64().unwrap())
+        };
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while (shadow_stats("divergent").1 < 3 || shadow_stats("agreeing").0 < 3) && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(shadow_stats("divergent"), (0, 3));
+        assert_eq!(shadow_stats("agreeing"), (3, 0));
+        let stats = manager.get_stats();
+        assert_eq!(stats[0]["name"], "primary");
+        assert_eq!(stats[0]["shadow"], false);
+        assert!(stats[0].get("agreements").is_none());
+    }
+
+    /// TC=1 over UDP and over TCP alike (one A record in the TCP copy); counts TCP connections
+    async fn spawn_always_truncating_stub(connections: Arc<AtomicU64>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(mut resp) = packet::parse_packet(&buf[..len]) else { continue };
+                resp.header.qr = true;
+                resp.header.tc = true;
+                let _ = socket.send_to(&resp.to_wire(), peer).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                connections.fetch_add(1, Ordering::Relaxed);
+                let Ok(Some(query)) = crate::dns::tcp::read_message(&mut stream).await else { continue };
+                let mut resp = packet::parse_packet(&query).unwrap();
+                resp.header.qr = true;
+                resp.header.tc = true;
+                let qname = resp.questions[0].name.clone();
+                resp.answers.push(packet::DnsRecord::new(&qname, crate::dns::types::RecordType::A, 60, vec![192, 0, 2, 3]));
+                let _ = crate::dns::tcp::write_message(&mut stream, &resp.to_wire()).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_truncated_over_tcp_too_ends_after_one_retry() {
+        let connections = Arc::new(AtomicU64::new(0));
+        let stub = spawn_always_truncating_stub(connections.clone()).await;
+        let config = stub_upstream("stub", stub);
+        let query = packet::build_query(0x7c7c, "big.example.com", crate::dns::types::RecordType::A, true);
+
+        // accept: the TCP copy is the answer, TC cleared so clients don't come back over TCP
+        let accepting = Arc::new(StillTruncated::new(crate::config::StillTruncatedPolicy::Accept));
+        let manager = UpstreamManager::new(std::slice::from_ref(&config)).await.unwrap()
+            .with_still_truncated(accepting.clone());
+        let result = tokio::time::timeout(Duration::from_secs(5), manager.race_query(&query)).await
+            .expect("no TC loop").unwrap();
+        let parsed = packet::parse_packet(&result.response).unwrap();
+        assert!(!parsed.header.tc);
+        assert_eq!(parsed.answers[0].rdata, vec![192, 0, 2, 3]);
+        assert_eq!(result.transport, Transport::Tcp);
+        assert_eq!(accepting.count(), 1);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        // fail: the upstream is charged with a failure instead
+        let failing = Arc::new(StillTruncated::new(crate::config::StillTruncatedPolicy::Fail));
+        let manager = UpstreamManager::new(&[config]).await.unwrap()
+            .with_still_truncated(failing.clone());
+        let result = tokio::time::timeout(Duration::from_secs(5), manager.race_query(&query)).await
+            .expect("no TC loop");
+        assert!(result.is_err());
+        assert_eq!(failing.count(), 1);
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+        assert_eq!(manager.get_stats()[0]["total_failures"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_filtered_empty_answer_loses_to_a_real_one() {
+        let filtering = spawn_stub(Duration::ZERO).await;
+        let real = spawn_answer_stub([192, 0, 2, 1]).await;
+        let query = packet::build_query(0x2514, "blocked.example.com", RecordType::A, true);
+
+        let walking = UpstreamManager::new(&[stub_upstream("filtering", filtering), stub_upstream("real", real)]).await.unwrap()
+            .with_selector(Box::new(Sequential))
+            .with_filtering_detection(true);
+        let result = walking.race_query(&query).await.unwrap();
+        assert_eq!(result.upstream_name, "real");
+        assert_eq!(packet::parse_packet(&result.response).unwrap().answers.len(), 1);
+        assert_eq!(walking.get_stats()[0]["suspected_filtering"], 1);
+
+        let racing = UpstreamManager::new(&[stub_upstream("filtering", filtering), stub_upstream("real", real)]).await.unwrap()
+            .with_filtering_detection(true);
+        assert_eq!(racing.race_query(&query).await.unwrap().upstream_name, "real");
+
+        // Nobody has a better answer: the empty one is returned
+        let alone = UpstreamManager::new(&[stub_upstream("filtering", filtering)]).await.unwrap()
+            .with_filtering_detection(true);
+        assert_eq!(alone.race_query(&query).await.unwrap().upstream_name, "filtering");
+
+        // A real NODATA (with SOA), and other types, are never suspected
+        let mut nodata = packet::parse_packet(&query).unwrap();
+        nodata.header.qr = true;
+        assert!(looks_filtered(&nodata.to_wire()));
+        packet::add_negative_soa(&mut nodata, 300);
+        assert!(!looks_filtered(&nodata.to_wire()));
+        let mx = packet::build_query(0x2515, "blocked.example.com", RecordType::MX, true);
+        assert!(!looks_filtered(&mx));
+    }
 }
@@ -1,17 +1,21 @@
 use std::sync::Arc;
 use axum::{
     Router,
+    body::Bytes,
     extract::{Query, State},
     response::{Html, Json, IntoResponse},
-    routing::get,
-    http::{header, StatusCode},
+    routing::{get, post},
+    http::{header, HeaderMap, StatusCode},
 };
 use serde::Deserialize;
 use tracing::info;
 
 use crate::config::Config;
 use crate::dns::engine::QueryEngine;
+use crate::dns::types::RecordType;
+use crate::maintenance::MaintenanceRequest;
 use crate::metrics;
+use crate::subnet::ClientNet;
 
 /// Web UI server - DNS ウェザーマップ
 /// リアルタイムにクエリフロー、キャッシュヒット率、upstreamレイテンシを表示
@@ -25,6 +29,25 @@ struct AppState {
     engine: Arc<QueryEngine>,
 }
 
+#[derive(Deserialize)]
+struct CacheEntryQuery {
+    name: String,
+    #[serde(rename = "type")]
+    qtype: Option<String>,
+    /// ECS scope of the entry (its "ecs_scope" in /api/cache), unscoped when absent
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NegativeFlushQuery {
+    domain: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OfflineRequest {
+    enabled: bool,
+}
+
 #[derive(Deserialize)]
 struct JournalQuery {
     domain: Option<String>,
@@ -51,10 +74,23 @@ impl WebServer {
             .route("/", get(dashboard))
             .route("/api/stats", get(api_stats))
             .route("/api/cache", get(api_cache))
+            .route("/api/cache/entry", get(api_cache_entry))
+            .route("/api/cache/entry.wire", get(api_cache_entry_wire))
+            .route("/api/cache/inject", post(api_cache_inject))
+            .route("/api/cache/evictions", get(api_cache_evictions))
+            .route("/api/cache/flush", post(api_cache_flush))
+            .route("/api/cache/refresh", post(api_cache_refresh))
+            .route("/api/negative", get(api_negative))
+            .route("/api/negative/flush", post(api_negative_flush))
+            .route("/api/tap", get(api_tap))
             .route("/api/journal", get(api_journal))
             .route("/api/upstreams", get(api_upstreams))
             .route("/api/journey", get(api_journey))
+            .route("/api/maintenance", get(api_maintenance).post(api_maintenance_set))
+            .route("/api/offline", get(api_offline).post(api_offline_set))
             .route("/metrics", get(prometheus_metrics))
+            .route("/readyz", get(readyz))
+            .route("/healthz", get(healthz))
             .with_state(state);
 
         let addr = format!("{}:{}", self.config.web.address, self.config.web.port);
@@ -78,30 +114,188 @@ async fn api_stats(State(state): State<AppState>) -> Json<serde_json::Value> {
 
 /// Cache entries API
 async fn api_cache(State(state): State<AppState>) -> Json<serde_json::Value> {
-    let entries = state.engine.cache.list_entries();
+    let mut entries = state.engine.cache.list_entries();
+    if state.engine.config.web.decode_idn {
+        crate::idn::annotate(&mut entries, "name");
+    }
     Json(serde_json::json!({
         "entries": entries,
         "stats": state.engine.cache.get_stats(),
     }))
 }
 
+/// Cache inspector API - decoded records of one entry (?name=...&type=A)
+async fn api_cache_entry(
+    State(state): State<AppState>,
+    Query(params): Query<CacheEntryQuery>,
+) -> impl IntoResponse {
+    let type_name = params.qtype.as_deref().unwrap_or("A");
+    let Some(qtype) = RecordType::from_name(type_name) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": format!("unknown type {}", type_name)})));
+    };
+    let scope = match params.scope.as_deref().map(ClientNet::parse) {
+        Some(None) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "invalid scope"}))),
+        parsed => parsed.flatten(),
+    };
+    let name = params.name.trim_end_matches('.');
+    match state.engine.cache.inspect_entry(name, &qtype, scope) {
+        Some(entry) => (StatusCode::OK, Json(entry)),
+        None => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "not cached"}))),
+    }
+}
+
+/// Raw cached response of one entry as application/dns-message (?name=...&type=A)
+async fn api_cache_entry_wire(
+    State(state): State<AppState>,
+    Query(params): Query<CacheEntryQuery>,
+) -> impl IntoResponse {
+    let type_name = params.qtype.as_deref().unwrap_or("A");
+    let Some(qtype) = RecordType::from_name(type_name) else {
+        return (StatusCode::BAD_REQUEST, [(header::CONTENT_TYPE, "text/plain")], format!("unknown type {}", type_name).into_bytes());
+    };
+    let scope = match params.scope.as_deref().map(ClientNet::parse) {
+        Some(None) => return (StatusCode::BAD_REQUEST, [(header::CONTENT_TYPE, "text/plain")], b"invalid scope".to_vec()),
+        parsed => parsed.flatten(),
+    };
+    match state.engine.cache.export_entry(params.name.trim_end_matches('.'), &qtype, scope) {
+        Some(wire) => (StatusCode::OK, [(header::CONTENT_TYPE, "application/dns-message")], wire),
+        None => (StatusCode::NOT_FOUND, [(header::CONTENT_TYPE, "text/plain")], b"not cached".to_vec()),
+    }
+}
+
+/// Cache a wire-format response uploaded as the request body (web.allow_cache_inject)
+async fn api_cache_inject(State(state): State<AppState>, body: Bytes) -> impl IntoResponse {
+    if !state.engine.config.web.allow_cache_inject {
+        return (StatusCode::FORBIDDEN, Json(serde_json::json!({"error": "cache injection disabled (web.allow_cache_inject)"})));
+    }
+    match state.engine.inject(&body).await {
+        Ok(injected) => (StatusCode::OK, Json(injected)),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": e.to_string()}))),
+    }
+}
+
+/// Recent cache evictions (cache.eviction_log)
+async fn api_cache_evictions(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(state.engine.cache.recent_evictions())
+}
+
+/// Drop every cache entry (all instances, with the shared redis backend)
+async fn api_cache_flush(State(state): State<AppState>) -> Json<serde_json::Value> {
+    state.engine.cache.flush().await;
+    info!("🐱 Cache flushed via API");
+    Json(serde_json::json!({ "flushed": true }))
+}
+
+/// Re-resolve one name bypassing the cache and replace its entry (?name=...&type=A)
+async fn api_cache_refresh(
+    State(state): State<AppState>,
+    Query(params): Query<CacheEntryQuery>,
+) -> impl IntoResponse {
+    let type_name = params.qtype.as_deref().unwrap_or("A");
+    let Some(qtype) = RecordType::from_name(type_name) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": format!("unknown type {}", type_name)})));
+    };
+    match state.engine.refresh(params.name.trim_end_matches('.'), qtype).await {
+        Ok(refreshed) => (StatusCode::OK, Json(refreshed)),
+        Err(e) => (StatusCode::BAD_GATEWAY, Json(serde_json::json!({"error": e.to_string()}))),
+    }
+}
+
+/// Negative cache entries (NXDOMAIN / NODATA)
+async fn api_negative(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "entries": state.engine.negative.list(),
+        "stats": state.engine.negative.get_stats(),
+    }))
+}
+
+/// Drop negative entries for one domain and its subdomains (?domain=...), or all of them
+async fn api_negative_flush(
+    State(state): State<AppState>,
+    Query(params): Query<NegativeFlushQuery>,
+) -> Json<serde_json::Value> {
+    let flushed = state.engine.negative.flush(params.domain.as_deref());
+    info!("🐱 Negative cache flushed via API: {} ({} entries)", params.domain.as_deref().unwrap_or("everything"), flushed);
+    Json(serde_json::json!({ "flushed": flushed }))
+}
+
+/// Recent outbound queries (debug.query_tap)
+async fn api_tap(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(state.engine.tap.recent())
+}
+
 /// Journal API with search
 async fn api_journal(
     State(state): State<AppState>,
     Query(params): Query<JournalQuery>,
 ) -> Json<serde_json::Value> {
     let limit = params.limit.unwrap_or(100);
+    let decode_idn = state.engine.config.web.decode_idn;
+    let domain = params.domain.as_deref().map(|d| if decode_idn { crate::idn::search_key(d) } else { d.to_string() });
     let entries = state.engine.journal.search(
-        params.domain.as_deref(),
+        domain.as_deref(),
         params.qtype.as_deref(),
         limit,
     );
+    let mut entries: Vec<serde_json::Value> = entries.iter()
+        .filter_map(|e| serde_json::to_value(e).ok())
+        .collect();
+    if decode_idn {
+        crate::idn::annotate(&mut entries, "domain");
+    }
     Json(serde_json::json!({
         "entries": entries,
         "stats": state.engine.journal.get_stats(),
     }))
 }
 
+/// Current maintenance mode
+async fn api_maintenance(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(state.engine.maintenance.status())
+}
+
+/// Toggle maintenance mode ({"enabled": true, "mode": "serve_cache_only", "match": ["example.com"]}).
+/// Kept in memory only, a restart resumes normal operation.
+async fn api_maintenance_set(
+    State(state): State<AppState>,
+    Json(request): Json<MaintenanceRequest>,
+) -> impl IntoResponse {
+    match state.engine.maintenance.apply(&request) {
+        Ok(status) => (StatusCode::OK, Json(status)),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": e.to_string()}))),
+    }
+}
+
+/// Offline mode status
+async fn api_offline(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({"enabled": state.engine.is_offline()}))
+}
+
+/// Toggle offline mode ({"enabled": true}); a restart goes back to resolution.offline
+async fn api_offline_set(
+    State(state): State<AppState>,
+    Json(request): Json<OfflineRequest>,
+) -> Json<serde_json::Value> {
+    state.engine.set_offline(request.enabled);
+    Json(serde_json::json!({"enabled": state.engine.is_offline()}))
+}
+
+/// Readiness probe - 503 until recursion can reach a root server (always ready in forwarding mode)
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    if state.engine.is_ready() {
+        (StatusCode::OK, "ready\n")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "warming up\n")
+    }
+}
+
+/// Health - 503 with the self-test result when the startup canary failed
+async fn healthz(State(state): State<AppState>) -> impl IntoResponse {
+    let (healthy, body) = state.engine.health();
+    let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(body))
+}
+
 /// Upstreams API
 async fn api_upstreams(State(state): State<AppState>) -> Json<serde_json::Value> {
     Json(state.engine.upstream.get_stats())
@@ -121,12 +315,21 @@ async fn api_journey(
     }))
 }
 
-/// Prometheus metrics endpoint - /metrics
-async fn prometheus_metrics(State(state): State<AppState>) -> impl IntoResponse {
-    let body = metrics::render_metrics(&state.engine);
+/// Prometheus metrics endpoint - /metrics (OpenMetrics when the scraper asks for it)
+async fn prometheus_metrics(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let openmetrics = headers.get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    if openmetrics {
+        return (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/openmetrics-text; version=1.0.0; charset=utf-8")],
+            metrics::render_openmetrics(&state.engine),
+        );
+    }
     (
         StatusCode::OK,
         [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
-        body,
+        metrics::render_metrics(&state.engine),
     )
 }